@@ -0,0 +1,239 @@
+use candid::{ CandidType, Principal };
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key,
+    sign_with_ecdsa,
+    EcdsaCurve,
+    EcdsaKeyId,
+    EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use k256::ecdsa::{ signature::hazmat::PrehashVerifier, Signature, VerifyingKey };
+use serde::Deserialize;
+use serde_json::{ Map, Value };
+use sha2::{ Digest, Sha256 };
+
+use crate::{ emr::EmrDisplay, random::CallError, types::Timestamp };
+
+/// version of the attestation payload format, part of the signed payload
+const PAYLOAD_VERSION: &str = "medblock/attestation/v1";
+
+/// emr attestation to show off chain, e.g. to an insurer or employer. verifiers check it by
+/// 1. pinning `public_key` against the one returned by `attestation_public_key`
+/// 2. checking `signature` is a valid secp256k1 signature of `sha256(payload)` under that key
+/// 3. reading the attested records from `payload`
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    /// canonical json of the attested emr, object keys sorted and without whitespace
+    pub payload: String,
+    /// 64 byte `r ++ s` signature over `sha256(payload)`
+    pub signature: Vec<u8>,
+    /// SEC1 compressed public key of the canister attestation key
+    pub public_key: Vec<u8>,
+}
+
+/// canonical json of an emr as attested, `keys` limits the attested records to a subset the patient
+/// chooses to disclose. every selected key must exist in the emr.
+pub fn payload(
+    emr: &EmrDisplay,
+    keys: Option<&[String]>,
+    canister: &Principal,
+    issued_at: Timestamp
+) -> Result<String, String> {
+    let Value::Object(records) = emr.records() else {
+        return Err("emr records are not an object".to_string());
+    };
+
+    let records = match keys {
+        None => records.clone(),
+        Some([]) => {
+            return Err("no records selected".to_string());
+        }
+        Some(keys) =>
+            keys
+                .iter()
+                .map(|key| {
+                    records
+                        .get(key)
+                        .map(|value| (key.clone(), value.clone()))
+                        .ok_or_else(|| format!("record with key {} not found", key))
+                })
+                .collect::<Result<Map<_, _>, _>>()?,
+    };
+
+    let payload =
+        serde_json::json!({
+        "version": PAYLOAD_VERSION,
+        "canister": canister.to_text(),
+        "emr_id": emr.emr_id().to_string(),
+        "created_at": emr.created_at().inner(),
+        "updated_at": emr.updated_at().inner(),
+        "issued_at": issued_at.inner(),
+        "records": records,
+    });
+
+    serde_json::to_string(&payload).map_err(|e| e.to_string())
+}
+
+/// signs attestation payload hashes, backed by threshold ECDSA inside the canister
+pub trait AttestationSigner {
+    /// SEC1 compressed public key signatures verify against
+    async fn public_key(&self) -> Result<Vec<u8>, CallError>;
+
+    /// 64 byte `r ++ s` signature over `message_hash`
+    async fn sign(&self, message_hash: [u8; 32]) -> Result<Vec<u8>, CallError>;
+}
+
+/// signer using the threshold ECDSA key of the subnet through the management canister
+pub struct ThresholdEcdsaSigner {
+    key_name: String,
+}
+
+impl ThresholdEcdsaSigner {
+    /// attestation keys are derived under their own path so they can't be confused with other signatures
+    const DERIVATION_PATH: &'static [u8] = b"emr_attestation";
+
+    pub fn new(key_name: String) -> Self {
+        Self { key_name }
+    }
+
+    fn key_id(&self) -> EcdsaKeyId {
+        EcdsaKeyId { curve: EcdsaCurve::Secp256k1, name: self.key_name.clone() }
+    }
+}
+
+impl AttestationSigner for ThresholdEcdsaSigner {
+    async fn public_key(&self) -> Result<Vec<u8>, CallError> {
+        let argument = EcdsaPublicKeyArgument {
+            canister_id: None,
+            derivation_path: vec![Self::DERIVATION_PATH.to_vec()],
+            key_id: self.key_id(),
+        };
+
+        let (response,) = ecdsa_public_key(argument).await.map_err(CallError::from)?;
+
+        Ok(response.public_key)
+    }
+
+    async fn sign(&self, message_hash: [u8; 32]) -> Result<Vec<u8>, CallError> {
+        let argument = SignWithEcdsaArgument {
+            message_hash: message_hash.to_vec(),
+            derivation_path: vec![Self::DERIVATION_PATH.to_vec()],
+            key_id: self.key_id(),
+        };
+
+        let (response,) = sign_with_ecdsa(argument).await.map_err(CallError::from)?;
+
+        Ok(response.signature)
+    }
+}
+
+/// sign a payload built with [payload]
+pub async fn attest(signer: &impl AttestationSigner, payload: String) -> Result<Attestation, CallError> {
+    let message_hash = Sha256::digest(payload.as_bytes()).into();
+
+    let public_key = signer.public_key().await?;
+    let signature = signer.sign(message_hash).await?;
+
+    Ok(Attestation { payload, signature, public_key })
+}
+
+/// verify an attestation was signed by `trusted_public_key`, the public key embedded in the attestation
+/// alone proves nothing as anyone can sign with a key of their own.
+pub fn verify(attestation: &Attestation, trusted_public_key: &[u8]) -> Result<(), String> {
+    if attestation.public_key != trusted_public_key {
+        return Err("attestation is not signed by the trusted key".to_string());
+    }
+
+    verify_signature(attestation.payload.as_bytes(), &attestation.signature, trusted_public_key)
+}
+
+/// verify a 64 byte `r ++ s` secp256k1 signature over `sha256(message)`
+pub fn verify_signature(message: &[u8], signature: &[u8], public_key: &[u8]) -> Result<(), String> {
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| "invalid public key".to_string())?;
+    let signature = Signature::from_slice(signature).map_err(|_| "invalid signature".to_string())?;
+
+    // k256 only accepts low s signatures, the high s form is equally valid ECDSA
+    let signature = signature.normalize_s().unwrap_or(signature);
+
+    key
+        .verify_prehash(&Sha256::digest(message), &signature)
+        .map_err(|_| "signature does not match the payload".to_string())
+}
+
+/// stand in for the threshold ECDSA key outside the canister
+#[cfg(test)]
+pub mod local {
+    use std::{ future::Future, pin::pin, task::{ Context, Poll, Waker } };
+
+    use k256::ecdsa::{ signature::hazmat::PrehashSigner, Signature, SigningKey };
+
+    use super::{ AttestationSigner, CallError };
+
+    pub struct LocalSigner(pub SigningKey);
+
+    impl AttestationSigner for LocalSigner {
+        async fn public_key(&self) -> Result<Vec<u8>, CallError> {
+            Ok(self.0.verifying_key().to_encoded_point(true).as_bytes().to_vec())
+        }
+
+        async fn sign(&self, message_hash: [u8; 32]) -> Result<Vec<u8>, CallError> {
+            let signature: Signature = self.0.sign_prehash(&message_hash).unwrap();
+            Ok(signature.to_bytes().to_vec())
+        }
+    }
+
+    /// drive a [LocalSigner] future, it never waits so polling once is enough
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => unreachable!("local signer never waits"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use k256::ecdsa::SigningKey;
+
+    use super::{ *, local::{ block_on, LocalSigner } };
+    use crate::{ emr::{ DisplayV001, RecrodsDisplay }, types::Id };
+
+    fn emr() -> EmrDisplay {
+        EmrDisplay::V001(
+            DisplayV001::with_timestamps(
+                Id::from(uuid::Uuid::from_bytes([1; 16])),
+                Timestamp(1_700_000_000_000_000_000),
+                Timestamp(1_700_000_000_000_000_000),
+                RecrodsDisplay(serde_json::json!({ "diagnosis": "Typhoid fever", "note": "rest" }))
+            )
+        )
+    }
+
+    #[test]
+    fn test_attestation_roundtrip() {
+        let signer = LocalSigner(SigningKey::from_slice(&[7; 32]).unwrap());
+        let canister = Principal::anonymous();
+        let keys = ["diagnosis".to_string()];
+
+        let payload = payload(&emr(), Some(&keys), &canister, Timestamp(1)).unwrap();
+        assert!(payload.contains("\"records\":{\"diagnosis\":\"Typhoid fever\"}"));
+        assert!(!payload.contains("rest"));
+
+        let attestation = block_on(attest(&signer, payload)).unwrap();
+        let trusted = block_on(signer.public_key()).unwrap();
+        assert_eq!(verify(&attestation, &trusted), Ok(()));
+
+        // altered payload or another key doesn't verify
+        let mut altered = attestation.clone();
+        altered.payload = altered.payload.replace("Typhoid", "Dengue");
+        assert!(verify(&altered, &trusted).is_err());
+
+        let other = LocalSigner(SigningKey::from_slice(&[8; 32]).unwrap());
+        let forged = block_on(attest(&other, attestation.payload.clone())).unwrap();
+        assert!(verify(&forged, &trusted).is_err());
+
+        // selecting a missing record fails
+        let missing = ["allergy".to_string()];
+        assert!(super::payload(&emr(), Some(&missing), &canister, Timestamp(1)).is_err());
+    }
+}
@@ -1,9 +1,22 @@
 use candid::Principal;
 
+use crate::types::YEAR_IN_NANOS;
+
 pub struct CanisterConfig {
     owner: Principal,
     // TODO: make this configurable
     max_item_per_response: usize,
+    /// age in years at which guardian delegations assigned with a patient birth date terminate
+    age_of_majority: u8,
+    // TODO: make this configurable
+    /// how long break glass access lasts in nanoseconds
+    break_glass_duration: u64,
+    /// minimum time in nanoseconds an emr is kept after its last update before an approved erasure may purge it
+    min_retention_period: u64,
+    /// attachment storage in bytes available to a provider without a quota of its own
+    default_attachment_quota: u64,
+    /// name of the threshold ECDSA key emr attestations are signed with
+    ecdsa_key_name: String,
 }
 
 impl Default for CanisterConfig {
@@ -11,11 +24,15 @@ impl Default for CanisterConfig {
     /// will use current caller as the owner.
     ///
     /// # Panics
-    /// will panic if called outside canister execution environment. don't call this in test,
-    /// use `CanisterConfig::new` instead.
+    /// will panic if called outside canister execution environment, don't call this in test.
     fn default() -> Self {
         Self {
             max_item_per_response: Self::INITIAL_MAX_EMR_RESPONSE,
+            age_of_majority: Self::DEFAULT_AGE_OF_MAJORITY,
+            break_glass_duration: Self::DEFAULT_BREAK_GLASS_DURATION,
+            min_retention_period: Self::DEFAULT_MIN_RETENTION_PERIOD,
+            default_attachment_quota: Self::DEFAULT_ATTACHMENT_QUOTA,
+            ecdsa_key_name: Self::DEFAULT_ECDSA_KEY_NAME.to_string(),
             owner: ic_cdk::caller(),
         }
     }
@@ -28,14 +45,92 @@ impl CanisterConfig {
     /// initially set to 10.
     const INITIAL_MAX_EMR_RESPONSE: usize = 10;
 
-    pub fn new(owner: Principal) -> Self {
-        Self {
-            owner,
-            ..Default::default()
-        }
-    }
+    /// default age of majority in indonesia, used to terminate guardian delegations for minors.
+    const DEFAULT_AGE_OF_MAJORITY: u8 = 18;
+
+    /// break glass access lasts 4 hours by default, long enough to handle an emergency.
+    const DEFAULT_BREAK_GLASS_DURATION: u64 = 4 * 60 * 60 * 1_000_000_000;
+
+    /// medical records must be kept for at least 25 years since the patient's last visit (Permenkes 24/2022).
+    const DEFAULT_MIN_RETENTION_PERIOD: u64 = 25 * YEAR_IN_NANOS;
+
+    /// 5 GiB per provider, enough for a few thousand scanned documents or a few hundred imaging studies.
+    const DEFAULT_ATTACHMENT_QUOTA: u64 = 5 * 1024 * 1024 * 1024;
+
+    /// production threshold ECDSA key, local replicas only have `dfx_test_key` and must set it explicitly.
+    const DEFAULT_ECDSA_KEY_NAME: &'static str = "key_1";
+
+    /// how often expired access grants are swept, in seconds.
+    pub const GRANT_SWEEP_INTERVAL_SECS: u64 = 60;
+
+    /// maximum number of expired access grants revoked per sweep, keeps each timer execution within instruction limits.
+    pub const GRANT_SWEEP_BATCH_SIZE: usize = 100;
+
+    /// how often emrs past their retention period are purged, in seconds. purging is not time critical so once a day is enough.
+    pub const RETENTION_PURGE_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+    /// maximum number of emrs purged per run, keeps each timer execution within instruction limits.
+    pub const RETENTION_PURGE_BATCH_SIZE: usize = 50;
+
+    /// how often abandoned attachment uploads are swept, in seconds.
+    pub const ATTACHMENT_SWEEP_INTERVAL_SECS: u64 = 60 * 60;
+
+    /// unfinished attachment uploads older than this many nanoseconds are considered abandoned.
+    pub const ATTACHMENT_UPLOAD_TIMEOUT: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    /// maximum number of abandoned uploads released per sweep, keeps each timer execution within instruction limits.
+    pub const ATTACHMENT_SWEEP_BATCH_SIZE: usize = 20;
+
+    /// largest patient export bundle accepted by an import, in bytes. bundles are reassembled on the heap.
+    pub const MAX_IMPORT_BUNDLE_SIZE: usize = 32 * 1024 * 1024;
+
+    /// maximum number of imports uploading at the same time, bounds the heap held by unfinished bundles.
+    pub const MAX_PENDING_IMPORTS: usize = 8;
+
+    /// http access tokens are valid for 5 minutes, enough to fetch an emr and its attachments list.
+    pub const HTTP_TOKEN_TTL: u64 = 5 * 60 * 1_000_000_000;
 
     pub fn is_canister_owner(&self, principal: &Principal) -> bool {
         self.owner.eq(principal)
     }
+
+    pub fn max_item_per_response(&self) -> usize {
+        self.max_item_per_response
+    }
+
+    pub fn age_of_majority(&self) -> u8 {
+        self.age_of_majority
+    }
+
+    pub fn set_age_of_majority(&mut self, age: u8) {
+        self.age_of_majority = age;
+    }
+
+    pub fn break_glass_duration(&self) -> u64 {
+        self.break_glass_duration
+    }
+
+    pub fn min_retention_period(&self) -> u64 {
+        self.min_retention_period
+    }
+
+    pub fn set_min_retention_period(&mut self, period: u64) {
+        self.min_retention_period = period;
+    }
+
+    pub fn default_attachment_quota(&self) -> u64 {
+        self.default_attachment_quota
+    }
+
+    pub fn set_default_attachment_quota(&mut self, quota: u64) {
+        self.default_attachment_quota = quota;
+    }
+
+    pub fn ecdsa_key_name(&self) -> &str {
+        &self.ecdsa_key_name
+    }
+
+    pub fn set_ecdsa_key_name(&mut self, name: String) {
+        self.ecdsa_key_name = name;
+    }
 }
@@ -0,0 +1,673 @@
+use candid::{ CandidType, Principal };
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SBTreeSet, SVec },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::{ deref, types::{ Id, Timestamp } };
+
+use super::{ patient::NIK, providers::InternalProviderId, OutOfMemory };
+
+/// how a provider obtained access to a patient's emrs
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    /// access granted by the patient or one of their guardians
+    Consent,
+    /// emergency access obtained without patient consent, see [BreakGlassEvent]
+    BreakGlass,
+}
+
+/// what a grant allows a provider to do with a patient's emrs
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessScope {
+    Read,
+    /// read and add to the emrs, e.g. addenda, attachments or results of a laboratory
+    Write,
+}
+
+/// access of a provider to every emr of a patient, valid until `expires_at`
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy)]
+pub struct AccessGrant {
+    kind: AccessKind,
+    scope: AccessScope,
+    granted_at: Timestamp,
+    expires_at: Timestamp,
+}
+
+impl AccessGrant {
+    pub fn new(kind: AccessKind, scope: AccessScope, expires_at: Timestamp) -> Self {
+        Self { kind, scope, granted_at: Timestamp::new(), expires_at }
+    }
+
+    pub fn is_active(&self, now: &Timestamp) -> bool {
+        self.expires_at.gt(now)
+    }
+
+    pub fn is_writable(&self, now: &Timestamp) -> bool {
+        self.is_active(now) && self.scope == AccessScope::Write
+    }
+
+    pub fn expires_at(&self) -> Timestamp {
+        self.expires_at
+    }
+
+    /// remaining lifetime of the grant in nanoseconds, 0 if already expired
+    pub fn remaining(&self, now: &Timestamp) -> u64 {
+        self.expires_at.inner().saturating_sub(now.inner())
+    }
+}
+
+/// heap copy of an [AccessGrant], returned to patients and providers
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AccessGrantDisplay {
+    provider: InternalProviderId,
+    patient: NIK,
+    kind: AccessKind,
+    scope: AccessScope,
+    granted_at: Timestamp,
+    expires_at: Timestamp,
+    /// remaining lifetime of the grant in nanoseconds
+    remaining: u64,
+}
+
+impl AccessGrantDisplay {
+    pub fn new(
+        provider: InternalProviderId,
+        patient: NIK,
+        grant: &AccessGrant,
+        now: &Timestamp
+    ) -> Self {
+        Self {
+            provider,
+            patient,
+            kind: grant.kind,
+            scope: grant.scope,
+            granted_at: grant.granted_at,
+            expires_at: grant.expires_at,
+            remaining: grant.remaining(now),
+        }
+    }
+}
+
+pub type AccessGrantCollection = SBTreeMap<NIK, AccessGrant>;
+/// grant expiry index entry, ordered by expiry so expired grants can be swept from the front
+type GrantExpiry = (Timestamp, InternalProviderId, NIK);
+/// Provider to access grants map. tracks which patients a provider may read emrs of, keyed by the patient's primary identifier.
+/// uses [InternalProviderId] so grants survive provider principal changes.
+///
+/// every grant is also indexed by its expiry, so that expired grants can be revoked in bounded batches
+/// without scanning the whole map.
+#[derive(Default)]
+pub struct AccessGrants {
+    grants: SBTreeMap<InternalProviderId, AccessGrantCollection>,
+    expiries: SBTreeSet<GrantExpiry>,
+}
+
+impl AccessGrants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// grant `provider` access to `patient`, replacing any previous grant unless it outlives the new one
+    pub fn grant(
+        &mut self,
+        provider: &InternalProviderId,
+        patient: NIK,
+        grant: AccessGrant
+    ) -> Result<(), OutOfMemory> {
+        if !self.grants.contains_key(provider) {
+            self.grants
+                .insert(provider.clone(), AccessGrantCollection::new())
+                .map_err(OutOfMemory::from)?;
+        }
+
+        let mut grants = self.grants.get_mut(provider).unwrap();
+
+        let current = grants.get(&patient).map(|current| current.expires_at());
+
+        if current.is_some_and(|expires_at| expires_at.gt(&grant.expires_at())) {
+            return Ok(());
+        }
+
+        grants.insert(patient.clone(), grant).map_err(OutOfMemory::from)?;
+        drop(grants);
+
+        if let Some(expires_at) = current {
+            self.expiries.remove(&(expires_at, provider.clone(), patient.clone()));
+        }
+
+        self.expiries
+            .insert((grant.expires_at(), provider.clone(), patient))
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    /// revoke the grant of `provider` for `patient`, returns false if there is no such grant
+    pub fn revoke(&mut self, provider: &InternalProviderId, patient: &NIK) -> bool {
+        let Some(mut grants) = self.grants.get_mut(provider) else {
+            return false;
+        };
+
+        let revoked = grants.remove(patient);
+        let is_empty = grants.is_empty();
+        drop(grants);
+
+        if is_empty {
+            self.grants.remove(provider);
+        }
+
+        let Some(revoked) = revoked else {
+            return false;
+        };
+
+        self.expiries.remove(&(revoked.expires_at(), provider.clone(), patient.clone()));
+
+        true
+    }
+
+    /// revoke at most `max` grants that expired at or before `now`, returns the number of revoked grants
+    pub fn sweep_expired(&mut self, now: &Timestamp, max: usize) -> usize {
+        let expired = self.expiries
+            .iter()
+            .take_while(|expiry| expiry.0.le(now))
+            .take(max)
+            .map(|expiry| (*expiry).clone())
+            .collect::<Vec<GrantExpiry>>();
+
+        for (_, provider, patient) in expired.iter() {
+            self.revoke(provider, patient);
+        }
+
+        expired.len()
+    }
+
+    /// move every grant for `from` to `into`, used when merging duplicate patients.
+    /// a provider holding a grant for both keeps the one that expires last.
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) -> Result<(), OutOfMemory> {
+        let moved = self.grants
+            .iter()
+            .filter_map(|(provider, grants)| grants.get(from).map(|grant| ((*provider).clone(), *grant)))
+            .collect::<Vec<_>>();
+
+        for (provider, grant) in moved {
+            self.grant(&provider, into.clone(), grant)?;
+            self.revoke(&provider, from);
+        }
+
+        Ok(())
+    }
+
+    /// returns every patient `provider` currently has an active grant for
+    pub fn patients_of(&self, provider: &InternalProviderId, now: &Timestamp) -> Vec<NIK> {
+        let Some(grants) = self.grants.get(provider) else {
+            return vec![];
+        };
+
+        grants
+            .iter()
+            .filter(|(_, grant)| grant.is_active(now))
+            .map(|(patient, _)| (*patient).clone())
+            .collect()
+    }
+
+    /// check if `provider` currently has a write grant for `patient`
+    pub fn can_write(&self, provider: &InternalProviderId, patient: &NIK, now: &Timestamp) -> bool {
+        self.grants
+            .get(provider)
+            .and_then(|grants| grants.get(patient).map(|grant| grant.is_writable(now)))
+            .unwrap_or_default()
+    }
+
+    /// returns every active grant of `provider`
+    pub fn grants_of_provider(
+        &self,
+        provider: &InternalProviderId,
+        now: &Timestamp
+    ) -> Vec<AccessGrantDisplay> {
+        let Some(grants) = self.grants.get(provider) else {
+            return vec![];
+        };
+
+        grants
+            .iter()
+            .filter(|(_, grant)| grant.is_active(now))
+            .map(|(patient, grant)| {
+                AccessGrantDisplay::new(provider.clone(), (*patient).clone(), &grant, now)
+            })
+            .collect()
+    }
+
+    /// returns at most `max` active grants for `patient`, skipping the first `anchor` of them
+    pub fn grants_of_patient(
+        &self,
+        patient: &NIK,
+        now: &Timestamp,
+        anchor: u64,
+        max: usize
+    ) -> Vec<AccessGrantDisplay> {
+        self.grants
+            .iter()
+            .filter_map(|(provider, grants)| {
+                grants
+                    .get(patient)
+                    .filter(|grant| grant.is_active(now))
+                    .map(|grant| {
+                        AccessGrantDisplay::new((*provider).clone(), patient.clone(), &grant, now)
+                    })
+            })
+            .skip(anchor as usize)
+            .take(max)
+            .collect()
+    }
+}
+
+/// what a provider did with a patient's emr
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessAction {
+    Read,
+    Update,
+    Export,
+    BreakGlass,
+}
+
+/// a single provider access to a patient's emrs
+#[derive(StableType, AsFixedSizeBytes, Debug, Clone)]
+pub struct AccessRecord {
+    provider: InternalProviderId,
+    action: AccessAction,
+    /// accessed emr, none if the action covers every emr of the patient (e.g. break glass) or data kept per patient
+    emr_id: Option<Id>,
+    timestamp: Timestamp,
+}
+
+impl AccessRecord {
+    pub fn new(provider: InternalProviderId, action: AccessAction, emr_id: Option<Id>) -> Self {
+        Self { provider, action, emr_id, timestamp: Timestamp::new() }
+    }
+}
+
+/// heap copy of an [AccessRecord], returned to patients
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AccessRecordDisplay {
+    provider: InternalProviderId,
+    /// encrypted display name of the provider
+    provider_display_name: Option<String>,
+    pub(crate) action: AccessAction,
+    emr_id: Option<Id>,
+    pub(crate) timestamp: Timestamp,
+}
+
+impl AccessRecordDisplay {
+    pub fn new(record: &AccessRecord, provider_display_name: Option<String>) -> Self {
+        Self {
+            provider: record.provider.clone(),
+            provider_display_name,
+            action: record.action,
+            emr_id: record.emr_id.clone(),
+            timestamp: record.timestamp,
+        }
+    }
+}
+
+/// filter for access history queries. records are returned in chronological order.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AccessHistoryFilter {
+    /// only return records at or after this time in nanoseconds
+    pub from: Option<Timestamp>,
+    /// only return records at or before this time in nanoseconds
+    pub to: Option<Timestamp>,
+    /// number of matching records to skip, used to paginate the result
+    pub anchor: u64,
+    /// maximum number of records to return
+    pub max: u8,
+}
+
+pub type AccessRecordCollection = SVec<AccessRecord>;
+/// Patient to access history map, keyed by the patient's primary identifier. records are appended as they happen,
+/// so each history is ordered by timestamp and can be binary searched for date filters.
+#[derive(Default)]
+pub struct AccessHistory(SBTreeMap<NIK, AccessRecordCollection>);
+deref!(mut AccessHistory: SBTreeMap<NIK, AccessRecordCollection>);
+
+impl AccessHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, patient: &NIK, record: AccessRecord) -> Result<(), OutOfMemory> {
+        if !self.0.contains_key(patient) {
+            self.0
+                .insert(patient.clone(), AccessRecordCollection::new())
+                .map_err(OutOfMemory::from)?;
+        }
+
+        let mut history = self.0.get_mut(patient).unwrap();
+
+        history.push(record).map_err(OutOfMemory::from)
+    }
+
+    /// merge the history of `from` into the history of `into`, keeping it ordered by timestamp.
+    /// used when merging duplicate patients, both histories are left untouched if memory runs out.
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) -> Result<(), OutOfMemory> {
+        let Some(moved) = self.0.get(from) else {
+            return Ok(());
+        };
+        let kept = self.0.get(into);
+
+        let mut merged = AccessRecordCollection::new();
+        let (mut i, mut j) = (0, 0);
+
+        loop {
+            let next_moved = moved.get(i);
+            let next_kept = kept.as_ref().and_then(|kept| kept.get(j));
+
+            let record = match (next_moved, next_kept) {
+                (Some(a), Some(b)) if b.timestamp.le(&a.timestamp) => {
+                    j += 1;
+                    (*b).clone()
+                }
+                (Some(a), _) => {
+                    i += 1;
+                    (*a).clone()
+                }
+                (None, Some(b)) => {
+                    j += 1;
+                    (*b).clone()
+                }
+                (None, None) => {
+                    break;
+                }
+            };
+
+            merged.push(record).map_err(OutOfMemory::from)?;
+        }
+
+        drop(moved);
+        drop(kept);
+
+        self.0.insert(into.clone(), merged).map_err(OutOfMemory::from)?;
+        self.0.remove(from);
+
+        Ok(())
+    }
+
+    /// returns the access history of `patient` matching `filter`, `display_name` resolves the provider display name
+    pub fn history_of(
+        &self,
+        patient: &NIK,
+        filter: &AccessHistoryFilter,
+        display_name: impl Fn(&InternalProviderId) -> Option<String>
+    ) -> Vec<AccessRecordDisplay> {
+        let Some(history) = self.0.get(patient) else {
+            return vec![];
+        };
+
+        // first record at or after `from`, equal timestamps are treated as greater so we land on the first of them
+        let start = match filter.from {
+            Some(from) =>
+                history
+                    .binary_search_by(|record| {
+                        record.timestamp.cmp(&from).then(std::cmp::Ordering::Greater)
+                    })
+                    .unwrap_or_else(|index| index),
+            None => 0,
+        };
+
+        (start..history.len())
+            .filter_map(|index| history.get(index))
+            .take_while(|record| filter.to.map(|to| record.timestamp.le(&to)).unwrap_or(true))
+            .skip(filter.anchor as usize)
+            .take(filter.max as usize)
+            .map(|record| AccessRecordDisplay::new(&record, display_name(&record.provider)))
+            .collect()
+    }
+}
+
+/// admin review of a break glass event
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct BreakGlassReview {
+    admin: Principal,
+    reviewed_at: Timestamp,
+    note: SBox<String>,
+}
+
+/// emergency access of a provider to a patient's emrs without prior consent.
+/// kept for after the fact review by admins and visible to the patient.
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct BreakGlassEvent {
+    patient: NIK,
+    provider: InternalProviderId,
+    justification: SBox<String>,
+    accessed_at: Timestamp,
+    expires_at: Timestamp,
+    review: Option<BreakGlassReview>,
+}
+
+impl BreakGlassEvent {
+    pub fn new(
+        patient: NIK,
+        provider: InternalProviderId,
+        justification: String,
+        expires_at: Timestamp
+    ) -> Result<Self, OutOfMemory> {
+        Ok(Self {
+            patient,
+            provider,
+            justification: SBox::new(justification)?,
+            accessed_at: Timestamp::new(),
+            expires_at,
+            review: None,
+        })
+    }
+
+    pub fn is_reviewed(&self) -> bool {
+        self.review.is_some()
+    }
+}
+
+/// heap copy of a [BreakGlassEvent], returned to patients and admins
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct BreakGlassEventDisplay {
+    event_id: Id,
+    patient: NIK,
+    provider: InternalProviderId,
+    justification: String,
+    accessed_at: Timestamp,
+    expires_at: Timestamp,
+    reviewed_by: Option<Principal>,
+    reviewed_at: Option<Timestamp>,
+    review_note: Option<String>,
+}
+
+impl BreakGlassEventDisplay {
+    pub fn new(event_id: Id, event: &BreakGlassEvent) -> Self {
+        Self {
+            event_id,
+            patient: event.patient.clone(),
+            provider: event.provider.clone(),
+            justification: (*event.justification).clone(),
+            accessed_at: event.accessed_at,
+            expires_at: event.expires_at,
+            reviewed_by: event.review.as_ref().map(|review| review.admin),
+            reviewed_at: event.review.as_ref().map(|review| review.reviewed_at),
+            review_note: event.review.as_ref().map(|review| (*review.note).clone()),
+        }
+    }
+}
+
+type EventId = Id;
+/// Break glass event map, keyed by event id. event ids are v7 uuid so iteration order is chronological.
+#[derive(Default)]
+pub struct BreakGlassEvents(SBTreeMap<EventId, BreakGlassEvent>);
+deref!(mut BreakGlassEvents: SBTreeMap<EventId, BreakGlassEvent>);
+
+impl BreakGlassEvents {
+    pub fn record(&mut self, event_id: EventId, event: BreakGlassEvent) -> Result<(), OutOfMemory> {
+        self.0
+            .insert(event_id, event)
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    /// mark an event as reviewed by an admin
+    pub fn review(&mut self, event_id: &EventId, admin: Principal, note: String) -> Result<(), String> {
+        let Some(mut event) = self.0.get_mut(event_id) else {
+            return Err("break glass event not found".to_string());
+        };
+
+        if event.is_reviewed() {
+            return Err("break glass event already reviewed".to_string());
+        }
+
+        event.review = Some(BreakGlassReview {
+            admin,
+            reviewed_at: Timestamp::new(),
+            note: SBox::new(note).map_err(OutOfMemory::from)?,
+        });
+
+        Ok(())
+    }
+
+    /// point every event for `from` to `into`, used when merging duplicate patients
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) {
+        let event_ids = self.0
+            .iter()
+            .filter(|(_, event)| event.patient.eq(from))
+            .map(|(event_id, _)| (*event_id).clone())
+            .collect::<Vec<_>>();
+
+        for event_id in event_ids {
+            self.0.get_mut(&event_id).unwrap().patient = into.clone();
+        }
+    }
+
+    /// returns every event for `patient` in chronological order
+    pub fn events_of(&self, patient: &NIK) -> Vec<BreakGlassEventDisplay> {
+        self.0
+            .iter()
+            .filter(|(_, event)| event.patient.eq(patient))
+            .map(|(event_id, event)| BreakGlassEventDisplay::new((*event_id).clone(), &event))
+            .collect()
+    }
+
+    /// returns at most `max` events that have not been reviewed by an admin yet in chronological order,
+    /// skipping the first `anchor` of them
+    pub fn pending_review(&self, anchor: u64, max: usize) -> Vec<BreakGlassEventDisplay> {
+        self.0
+            .iter()
+            .filter(|(_, event)| !event.is_reviewed())
+            .skip(anchor as usize)
+            .take(max)
+            .map(|(event_id, event)| BreakGlassEventDisplay::new((*event_id).clone(), &event))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::emr::patient::{ IdentifierType, InternalBindingKey };
+
+    #[test]
+    fn test_grant_is_not_shortened() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut grants = AccessGrants::new();
+        let provider = Id::from(uuid::Uuid::new_v4());
+        let patient = "3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709"
+            .parse::<InternalBindingKey>()
+            .unwrap();
+
+        let now = Timestamp::new();
+        let later = Timestamp(now.inner() + 1_000);
+
+        let grant = |expires_at| AccessGrant::new(AccessKind::BreakGlass, AccessScope::Read, expires_at);
+        grants.grant(&provider, patient.clone(), grant(later)).unwrap();
+        grants.grant(&provider, patient.clone(), grant(now)).unwrap();
+
+        assert_eq!(grants.patients_of(&provider, &now), vec![patient.clone()]);
+        assert!(grants.patients_of(&provider, &later).is_empty());
+    }
+
+    #[test]
+    fn test_sweep_expired_in_batches() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut grants = AccessGrants::new();
+        let provider = Id::from(uuid::Uuid::new_v4());
+        let now = Timestamp::new();
+
+        for i in 0..3u8 {
+            let patient = InternalBindingKey::new(IdentifierType::Nik, [i; 32]);
+            let expires_at = Timestamp(now.inner() - 1);
+            let grant = AccessGrant::new(AccessKind::Consent, AccessScope::Read, expires_at);
+            grants.grant(&provider, patient, grant).unwrap();
+        }
+
+        assert_eq!(grants.sweep_expired(&now, 2), 2);
+        assert_eq!(grants.sweep_expired(&now, 2), 1);
+        assert_eq!(grants.sweep_expired(&now, 2), 0);
+    }
+
+    #[test]
+    fn test_pending_review_pages() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut events = BreakGlassEvents::default();
+        let provider = Id::from(uuid::Uuid::new_v4());
+        let patient = InternalBindingKey::new(IdentifierType::Nik, [0; 32]);
+        let event_ids = (1..=3u8).map(|byte| Id::from(uuid::Uuid::from_bytes([byte; 16]))).collect::<Vec<_>>();
+
+        for event_id in event_ids.iter() {
+            let event = BreakGlassEvent::new(patient.clone(), provider.clone(), "trauma".to_string(), Timestamp::new());
+            events.record(event_id.clone(), event.unwrap()).unwrap();
+        }
+
+        events.review(&event_ids[0], Principal::anonymous(), "justified".to_string()).unwrap();
+
+        let page = |anchor, max| {
+            events
+                .pending_review(anchor, max)
+                .into_iter()
+                .map(|event| event.event_id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(page(0, 1), vec![event_ids[1].clone()]);
+        assert_eq!(page(1, 10), vec![event_ids[2].clone()]);
+        assert!(page(2, 10).is_empty());
+    }
+
+    #[test]
+    fn test_history_date_filter() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut history = AccessHistory::new();
+        let provider = Id::from(uuid::Uuid::new_v4());
+        let patient = InternalBindingKey::new(IdentifierType::Nik, [0; 32]);
+
+        for timestamp in [10, 20, 20, 30, 40] {
+            let record = AccessRecord {
+                provider: provider.clone(),
+                action: AccessAction::Read,
+                emr_id: None,
+                timestamp: Timestamp(timestamp),
+            };
+            history.record(&patient, record).unwrap();
+        }
+
+        let filter = AccessHistoryFilter {
+            from: Some(Timestamp(20)),
+            to: Some(Timestamp(30)),
+            anchor: 1,
+            max: 10,
+        };
+
+        let records = history.history_of(&patient, &filter, |_| None);
+        let timestamps = records.iter().map(|record| record.timestamp.inner()).collect::<Vec<_>>();
+
+        assert_eq!(timestamps, vec![20, 30]);
+    }
+}
@@ -0,0 +1,472 @@
+use candid::CandidType;
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SBTreeSet, SVec },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+use sha2::{ Digest, Sha256 };
+
+use crate::types::{ AsciiRecordsKey, Id, Timestamp };
+
+use super::{ providers::InternalProviderId, EmrId, OutOfMemory };
+
+pub type AttachmentId = Id;
+
+/// maximum size of a single uploaded or downloaded chunk, stays below the 2MB ingress and response limit
+/// with room for the rest of the message.
+pub const ATTACHMENT_CHUNK_SIZE: usize = 1_500_000;
+
+/// MIME types are short, longer values are most likely garbage
+const MAX_MIME_TYPE_LEN: usize = 127;
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentStatus {
+    /// chunks are still being uploaded, not downloadable yet
+    Uploading,
+    /// every chunk was received and the content hash matched
+    Complete,
+}
+
+/// binary blob such as an image or scanned document, linked to a records key of an emr
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Attachment {
+    emr_id: EmrId,
+    key: AsciiRecordsKey,
+    /// uploading provider, the size of the attachment counts towards its quota
+    provider: InternalProviderId,
+    mime_type: SBox<String>,
+    /// declared size in bytes, reserved from the provider quota when the upload begins
+    size: u64,
+    /// declared sha256 of the whole content, checked when the upload finishes
+    sha256: [u8; 32],
+    received: u64,
+    chunks: SVec<SBox<Vec<u8>>>,
+    status: AttachmentStatus,
+    created_at: Timestamp,
+    completed_at: Option<Timestamp>,
+}
+
+/// heap copy of an [Attachment] without its content
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AttachmentDisplay {
+    attachment_id: AttachmentId,
+    emr_id: EmrId,
+    key: String,
+    provider: InternalProviderId,
+    mime_type: String,
+    size: u64,
+    /// hex encoded sha256 of the content
+    sha256: String,
+    chunks: u64,
+    status: AttachmentStatus,
+    created_at: Timestamp,
+    completed_at: Option<Timestamp>,
+}
+
+impl AttachmentDisplay {
+    pub fn new(attachment_id: AttachmentId, attachment: &Attachment) -> Self {
+        Self {
+            attachment_id,
+            emr_id: attachment.emr_id.clone(),
+            key: attachment.key.to_string(),
+            provider: attachment.provider.clone(),
+            mime_type: (*attachment.mime_type).clone(),
+            size: attachment.size,
+            sha256: hex::encode(attachment.sha256),
+            chunks: attachment.chunks.len() as u64,
+            status: attachment.status,
+            created_at: attachment.created_at,
+            completed_at: attachment.completed_at,
+        }
+    }
+
+    pub fn emr_id(&self) -> &EmrId {
+        &self.emr_id
+    }
+}
+
+/// attachment storage used by a provider and its quota, in bytes
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttachmentUsage {
+    pub used: u64,
+    pub quota: u64,
+}
+
+/// Attachment blobs of every emr, stored chunk by chunk in stable memory.
+///
+/// uploads reserve their declared size from the provider quota up front, so concurrent uploads can't
+/// overshoot it. the reservation is released when the upload is aborted, fails its hash check or is swept
+/// after being abandoned.
+#[derive(Default)]
+pub struct AttachmentStore {
+    attachments: SBTreeMap<AttachmentId, Attachment>,
+    /// reverse index, emr id to its attachments
+    emr_attachments: SBTreeMap<EmrId, SBTreeSet<AttachmentId>>,
+    /// bytes reserved or stored per provider
+    usage: SBTreeMap<InternalProviderId, u64>,
+    /// per provider quota overriding the default quota
+    quotas: SBTreeMap<InternalProviderId, u64>,
+}
+
+impl AttachmentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn quota_of(&self, provider: &InternalProviderId, default_quota: u64) -> AttachmentUsage {
+        AttachmentUsage {
+            used: self.usage.get(provider).map(|used| *used).unwrap_or_default(),
+            quota: self.quotas.get(provider).map(|quota| *quota).unwrap_or(default_quota),
+        }
+    }
+
+    /// override the quota of `provider`, existing attachments are kept even if they exceed the new quota
+    pub fn set_quota(&mut self, provider: InternalProviderId, quota: u64) -> Result<(), OutOfMemory> {
+        self.quotas
+            .insert(provider, quota)
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    /// start a new upload, reserving `size` bytes from the provider quota
+    #[allow(clippy::too_many_arguments)]
+    pub fn begin(
+        &mut self,
+        attachment_id: AttachmentId,
+        emr_id: EmrId,
+        key: AsciiRecordsKey,
+        provider: InternalProviderId,
+        mime_type: String,
+        size: u64,
+        sha256: [u8; 32],
+        default_quota: u64
+    ) -> Result<(), String> {
+        if size == 0 {
+            return Err("attachment must not be empty".to_string());
+        }
+
+        if mime_type.is_empty() || mime_type.len() > MAX_MIME_TYPE_LEN || !mime_type.contains('/') {
+            return Err("invalid MIME type".to_string());
+        }
+
+        let usage = self.quota_of(&provider, default_quota);
+        if usage.used.saturating_add(size) > usage.quota {
+            return Err(
+                format!("attachment exceeds provider storage quota, {} of {} bytes used", usage.used, usage.quota)
+            );
+        }
+
+        let attachment = Attachment {
+            emr_id: emr_id.clone(),
+            key,
+            provider: provider.clone(),
+            mime_type: SBox::new(mime_type).map_err(OutOfMemory::from)?,
+            size,
+            sha256,
+            received: 0,
+            chunks: SVec::new(),
+            status: AttachmentStatus::Uploading,
+            created_at: Timestamp::new(),
+            completed_at: None,
+        };
+
+        self.attachments.insert(attachment_id.clone(), attachment).map_err(OutOfMemory::from)?;
+
+        if !self.emr_attachments.contains_key(&emr_id) {
+            self.emr_attachments.insert(emr_id.clone(), SBTreeSet::new()).map_err(OutOfMemory::from)?;
+        }
+
+        self.emr_attachments
+            .get_mut(&emr_id)
+            .expect("index was just created")
+            .insert(attachment_id)
+            .map_err(OutOfMemory::from)?;
+
+        self.usage.insert(provider, usage.used + size).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// append chunk `index` of an upload by `provider`. chunks must arrive in order, resending the last
+    /// received chunk is accepted and ignored so a retried call doesn't fail the upload.
+    pub fn append(
+        &mut self,
+        attachment_id: &AttachmentId,
+        provider: &InternalProviderId,
+        index: u64,
+        data: Vec<u8>
+    ) -> Result<(), String> {
+        let Some(mut attachment) = self.attachments.get_mut(attachment_id) else {
+            return Err("attachment not found".to_string());
+        };
+
+        if attachment.provider.ne(provider) {
+            return Err("attachment was not uploaded by this provider".to_string());
+        }
+
+        if attachment.status != AttachmentStatus::Uploading {
+            return Err("attachment upload is already finished".to_string());
+        }
+
+        let expected = attachment.chunks.len() as u64;
+        if index.checked_add(1) == Some(expected) {
+            return Ok(());
+        }
+
+        if index != expected {
+            return Err(format!("expected chunk {}, got chunk {}", expected, index));
+        }
+
+        if data.is_empty() || data.len() > ATTACHMENT_CHUNK_SIZE {
+            return Err(format!("chunk size must be between 1 and {} bytes", ATTACHMENT_CHUNK_SIZE));
+        }
+
+        let received = attachment.received + (data.len() as u64);
+        if received > attachment.size {
+            return Err("chunk exceeds the declared attachment size".to_string());
+        }
+
+        let chunk = SBox::new(data).map_err(OutOfMemory::from)?;
+        attachment.chunks.push(chunk).map_err(OutOfMemory::from)?;
+        attachment.received = received;
+
+        Ok(())
+    }
+
+    /// complete an upload once every byte was received. an upload whose content doesn't match the declared
+    /// hash is discarded and its quota released.
+    pub fn finish(
+        &mut self,
+        attachment_id: &AttachmentId,
+        provider: &InternalProviderId
+    ) -> Result<AttachmentDisplay, String> {
+        let matches = {
+            let Some(attachment) = self.attachments.get(attachment_id) else {
+                return Err("attachment not found".to_string());
+            };
+
+            if attachment.provider.ne(provider) {
+                return Err("attachment was not uploaded by this provider".to_string());
+            }
+
+            if attachment.status != AttachmentStatus::Uploading {
+                return Err("attachment upload is already finished".to_string());
+            }
+
+            if attachment.received != attachment.size {
+                return Err(
+                    format!("attachment is incomplete, {} of {} bytes received", attachment.received, attachment.size)
+                );
+            }
+
+            let mut hasher = Sha256::new();
+            for chunk in attachment.chunks.iter() {
+                hasher.update(&**chunk);
+            }
+
+            hasher.finalize().as_slice() == attachment.sha256
+        };
+
+        if !matches {
+            self.remove(attachment_id);
+            return Err("attachment content does not match the declared sha256, upload discarded".to_string());
+        }
+
+        let mut attachment = self.attachments.get_mut(attachment_id).expect("attachment exists");
+        attachment.status = AttachmentStatus::Complete;
+        attachment.completed_at = Some(Timestamp::new());
+
+        Ok(AttachmentDisplay::new(attachment_id.clone(), &attachment))
+    }
+
+    /// discard an unfinished upload by `provider`
+    pub fn abort(&mut self, attachment_id: &AttachmentId, provider: &InternalProviderId) -> Result<(), String> {
+        let Some(attachment) = self.attachments.get(attachment_id) else {
+            return Err("attachment not found".to_string());
+        };
+
+        if attachment.provider.ne(provider) {
+            return Err("attachment was not uploaded by this provider".to_string());
+        }
+
+        if attachment.status != AttachmentStatus::Uploading {
+            return Err("attachment upload is already finished".to_string());
+        }
+
+        drop(attachment);
+        self.remove(attachment_id);
+
+        Ok(())
+    }
+
+    /// content of chunk `index` of a complete attachment
+    pub fn chunk(&self, attachment_id: &AttachmentId, index: u64) -> Result<Vec<u8>, String> {
+        let Some(attachment) = self.attachments.get(attachment_id) else {
+            return Err("attachment not found".to_string());
+        };
+
+        if attachment.status != AttachmentStatus::Complete {
+            return Err("attachment upload is not finished".to_string());
+        }
+
+        let chunk = usize
+            ::try_from(index)
+            .ok()
+            .and_then(|index| attachment.chunks.get(index))
+            .ok_or("chunk index out of range".to_string())?;
+
+        Ok((**chunk).clone())
+    }
+
+    pub fn get(&self, attachment_id: &AttachmentId) -> Option<AttachmentDisplay> {
+        self.attachments
+            .get(attachment_id)
+            .map(|attachment| AttachmentDisplay::new(attachment_id.clone(), &attachment))
+    }
+
+    /// returns every attachment of `emr_id`, including unfinished uploads
+    pub fn of_emr(&self, emr_id: &EmrId) -> Vec<AttachmentDisplay> {
+        let Some(ids) = self.emr_attachments.get(emr_id) else {
+            return vec![];
+        };
+
+        ids.iter()
+            .filter_map(|attachment_id| self.get(&attachment_id))
+            .collect()
+    }
+
+    /// remove every attachment of an erased emr, returns the number of removed attachments
+    pub fn remove_emr(&mut self, emr_id: &EmrId) -> usize {
+        let ids = match self.emr_attachments.get(emr_id) {
+            Some(ids) =>
+                ids
+                    .iter()
+                    .map(|id| (*id).clone())
+                    .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        ids.iter().for_each(|attachment_id| self.remove(attachment_id));
+        self.emr_attachments.remove(emr_id);
+
+        ids.len()
+    }
+
+    /// remove at most `max` uploads started before `cutoff` that were never finished,
+    /// releasing their quota. returns the number of removed uploads.
+    pub fn sweep_abandoned(&mut self, cutoff: &Timestamp, max: usize) -> usize {
+        let abandoned = self.attachments
+            .iter()
+            .filter(|(_, attachment)| {
+                attachment.status == AttachmentStatus::Uploading && attachment.created_at.lt(cutoff)
+            })
+            .take(max)
+            .map(|(attachment_id, _)| (*attachment_id).clone())
+            .collect::<Vec<_>>();
+
+        abandoned.iter().for_each(|attachment_id| self.remove(attachment_id));
+
+        abandoned.len()
+    }
+
+    /// free an attachment and its chunks, releasing its size from the provider quota
+    fn remove(&mut self, attachment_id: &AttachmentId) {
+        let Some(attachment) = self.attachments.remove(attachment_id) else {
+            return;
+        };
+
+        if let Some(mut ids) = self.emr_attachments.get_mut(&attachment.emr_id) {
+            ids.remove(attachment_id);
+        }
+
+        if let Some(mut used) = self.usage.get_mut(&attachment.provider) {
+            *used = used.saturating_sub(attachment.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::fixtures::id;
+
+    fn digest(data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+
+    fn begin(store: &mut AttachmentStore, attachment: u8, data: &[u8], quota: u64) -> Result<(), String> {
+        store.begin(
+            id(attachment),
+            id(1),
+            AsciiRecordsKey::new("xray_chest").unwrap(),
+            id(9),
+            "image/png".to_string(),
+            data.len() as u64,
+            digest(data),
+            quota
+        )
+    }
+
+    #[test]
+    fn test_chunked_upload_and_download() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut store = AttachmentStore::new();
+        let data = (0..3_200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let chunks = data.chunks(ATTACHMENT_CHUNK_SIZE).collect::<Vec<_>>();
+
+        begin(&mut store, 2, &data, 10_000_000).unwrap();
+        store.append(&id(2), &id(9), 0, chunks[0].to_vec()).unwrap();
+        assert!(store.append(&id(2), &id(9), 2, chunks[2].to_vec()).is_err());
+        assert!(store.append(&id(2), &id(8), 1, chunks[1].to_vec()).is_err());
+
+        // a retried chunk is ignored
+        store.append(&id(2), &id(9), 0, chunks[0].to_vec()).unwrap();
+        store.append(&id(2), &id(9), 1, chunks[1].to_vec()).unwrap();
+        assert!(store.finish(&id(2), &id(9)).is_err());
+        assert!(store.chunk(&id(2), 0).is_err());
+
+        store.append(&id(2), &id(9), 2, chunks[2].to_vec()).unwrap();
+        let attachment = store.finish(&id(2), &id(9)).unwrap();
+        assert_eq!(attachment.chunks, 3);
+        assert_eq!(attachment.sha256, hex::encode(digest(&data)));
+
+        let downloaded = (0..attachment.chunks)
+            .flat_map(|index| store.chunk(&id(2), index).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(downloaded, data);
+        assert_eq!(store.of_emr(&id(1)).len(), 1);
+
+        assert_eq!(store.remove_emr(&id(1)), 1);
+        assert!(store.get(&id(2)).is_none());
+        assert_eq!(store.quota_of(&id(9), 0).used, 0);
+    }
+
+    #[test]
+    fn test_quota_and_hash_mismatch() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut store = AttachmentStore::new();
+
+        begin(&mut store, 2, &[1; 600], 1_000).unwrap();
+        assert!(begin(&mut store, 3, &[1; 600], 1_000).is_err());
+
+        store.set_quota(id(9), 2_000).unwrap();
+        begin(&mut store, 3, &[1; 600], 1_000).unwrap();
+        assert_eq!(store.quota_of(&id(9), 1_000), AttachmentUsage { used: 1_200, quota: 2_000 });
+
+        // corrupted content is discarded and its quota released
+        store.append(&id(2), &id(9), 0, vec![2; 600]).unwrap();
+        assert!(store.finish(&id(2), &id(9)).is_err());
+        assert!(store.get(&id(2)).is_none());
+
+        store.abort(&id(3), &id(9)).unwrap();
+        assert_eq!(store.quota_of(&id(9), 1_000).used, 0);
+
+        begin(&mut store, 4, &[1; 600], 1_000).unwrap();
+        assert_eq!(store.sweep_abandoned(&Timestamp(0), 10), 0);
+        assert_eq!(store.sweep_abandoned(&Timestamp(u64::MAX), 10), 1);
+        assert_eq!(store.quota_of(&id(9), 1_000).used, 0);
+    }
+}
@@ -0,0 +1,503 @@
+use std::collections::BTreeMap;
+
+use candid::CandidType;
+use ciborium::value::Value as Cbor;
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SBTreeSet },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::{ Deserialize, Serialize };
+
+use crate::{ attestation, types::{ Id, Timestamp } };
+
+use super::{ providers::InternalProviderId, EmrDisplay, EmrId, OutOfMemory };
+
+pub type CertificateId = Id;
+
+/// prefix of the QR code text, versions the code format
+const CODE_PREFIX: &str = "MB1:";
+
+/// version of the signed claims format
+const CLAIMS_VERSION: u8 = 1;
+
+/// QR alphanumeric mode alphabet, see RFC 9285
+const BASE45_ALPHABET: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+#[derive(
+    StableType,
+    AsFixedSizeBytes,
+    CandidType,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq
+)]
+pub enum CertificateKind {
+    Vaccination,
+    LabResult,
+}
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateStatus {
+    Valid,
+    /// revoked by the issuing provider, e.g. issued from a wrong record
+    Revoked,
+}
+
+/// what a certificate states, the only chart data a verifier ever sees. field names are kept short as
+/// the signed claims end up in a QR code.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Claims {
+    #[serde(rename = "v")]
+    version: u8,
+    #[serde(rename = "id")]
+    certificate_id: CertificateId,
+    #[serde(rename = "k")]
+    kind: CertificateKind,
+    /// display name of the issuing provider at the time of issuance
+    #[serde(rename = "iss")]
+    issuer: String,
+    #[serde(rename = "iat")]
+    issued_at: u64,
+    #[serde(rename = "r")]
+    records: BTreeMap<String, String>,
+}
+
+/// CBOR encoded claims of a new certificate over the records in `keys`, the bytes to sign
+pub fn claims(
+    certificate_id: &CertificateId,
+    kind: CertificateKind,
+    issuer: String,
+    emr: &EmrDisplay,
+    keys: &[String],
+    issued_at: Timestamp
+) -> Result<Vec<u8>, String> {
+    if keys.is_empty() {
+        return Err("no records selected".to_string());
+    }
+
+    let records = keys
+        .iter()
+        .map(|key| {
+            let value = emr
+                .records()
+                .get(key)
+                .ok_or_else(|| format!("record with key {} not found", key))?;
+
+            let value = value
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| value.to_string());
+
+            Ok((key.clone(), value))
+        })
+        .collect::<Result<BTreeMap<_, _>, String>>()?;
+
+    let claims = Claims {
+        version: CLAIMS_VERSION,
+        certificate_id: certificate_id.clone(),
+        kind,
+        issuer,
+        issued_at: issued_at.inner(),
+        records,
+    };
+
+    let mut buf = vec![];
+    ciborium::ser::into_writer(&claims, &mut buf).map_err(|e| e.to_string())?;
+
+    Ok(buf)
+}
+
+/// a certificate signed by the canister, ready to be stored
+pub struct IssuedCertificate {
+    pub certificate_id: CertificateId,
+    pub emr_id: EmrId,
+    pub issuer: InternalProviderId,
+    pub kind: CertificateKind,
+    pub issued_at: Timestamp,
+    /// claims built with [claims]
+    pub claims: Vec<u8>,
+    /// 64 byte `r ++ s` signature over `sha256(claims)`
+    pub signature: Vec<u8>,
+    /// SEC1 compressed key the signature verifies against, kept per certificate since the key may be rotated
+    pub public_key: Vec<u8>,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Certificate {
+    emr_id: EmrId,
+    issuer: InternalProviderId,
+    kind: CertificateKind,
+    status: CertificateStatus,
+    issued_at: Timestamp,
+    revoked_at: Option<Timestamp>,
+    revocation_reason: Option<SBox<String>>,
+    claims: SBox<Vec<u8>>,
+    signature: SBox<Vec<u8>>,
+    public_key: SBox<Vec<u8>>,
+}
+
+/// heap copy of a [Certificate] for the patient and issuing provider, `code` is the text to render as QR code
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CertificateDisplay {
+    certificate_id: CertificateId,
+    emr_id: EmrId,
+    issuer: InternalProviderId,
+    kind: CertificateKind,
+    status: CertificateStatus,
+    issued_at: Timestamp,
+    revoked_at: Option<Timestamp>,
+    revocation_reason: Option<String>,
+    code: String,
+}
+
+impl CertificateDisplay {
+    pub fn new(certificate_id: CertificateId, certificate: &Certificate) -> Self {
+        Self {
+            certificate_id,
+            emr_id: certificate.emr_id.clone(),
+            issuer: certificate.issuer.clone(),
+            kind: certificate.kind,
+            status: certificate.status,
+            issued_at: certificate.issued_at,
+            revoked_at: certificate.revoked_at,
+            revocation_reason: certificate.revocation_reason.as_ref().map(|reason| (**reason).clone()),
+            code: encode(&certificate.claims, &certificate.signature),
+        }
+    }
+}
+
+/// content of a verified certificate
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CertificateContent {
+    pub certificate_id: CertificateId,
+    pub kind: CertificateKind,
+    pub issuer: String,
+    pub issued_at: Timestamp,
+    pub records: Vec<(String, String)>,
+}
+
+impl From<Claims> for CertificateContent {
+    fn from(claims: Claims) -> Self {
+        Self {
+            certificate_id: claims.certificate_id,
+            kind: claims.kind,
+            issuer: claims.issuer,
+            issued_at: Timestamp(claims.issued_at),
+            records: claims.records.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CertificateVerification {
+    Valid(CertificateContent),
+    Revoked {
+        certificate: CertificateContent,
+        revoked_at: Timestamp,
+    },
+    /// malformed, forged or unknown code, carries the reason
+    Invalid(String),
+}
+
+/// QR code text of signed claims, `MB1:` followed by base45 of the CBOR array `[claims, signature]`
+pub fn encode(claims: &[u8], signature: &[u8]) -> String {
+    let envelope = Cbor::Array(vec![Cbor::Bytes(claims.to_vec()), Cbor::Bytes(signature.to_vec())]);
+
+    let mut buf = vec![];
+    ciborium::ser::into_writer(&envelope, &mut buf).expect("writing to a vec never fails");
+
+    format!("{}{}", CODE_PREFIX, base45_encode(&buf))
+}
+
+/// claims and signature of a QR code text
+fn decode(code: &str) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let invalid = || "malformed certificate code".to_string();
+
+    let code = code.strip_prefix(CODE_PREFIX).ok_or_else(invalid)?;
+    let envelope = base45_decode(code)?;
+
+    match ciborium::de::from_reader::<Cbor, _>(envelope.as_slice()).map_err(|_| invalid())? {
+        Cbor::Array(items) =>
+            match <[Cbor; 2]>::try_from(items).map_err(|_| invalid())? {
+                [Cbor::Bytes(claims), Cbor::Bytes(signature)] => Ok((claims, signature)),
+                _ => Err(invalid()),
+            }
+        _ => Err(invalid()),
+    }
+}
+
+fn base45_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(2) * 3);
+
+    for chunk in bytes.chunks(2) {
+        let (mut n, digits) = match chunk {
+            [a, b] => ((*a as usize) * 256 + (*b as usize), 3),
+            [a] => (*a as usize, 2),
+            _ => unreachable!("chunks are never empty"),
+        };
+
+        for _ in 0..digits {
+            out.push(BASE45_ALPHABET[n % 45] as char);
+            n /= 45;
+        }
+    }
+
+    out
+}
+
+fn base45_decode(text: &str) -> Result<Vec<u8>, String> {
+    let invalid = || "malformed certificate code".to_string();
+
+    let digits = text
+        .bytes()
+        .map(|c| BASE45_ALPHABET.iter().position(|a| *a == c).ok_or_else(invalid))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut out = Vec::with_capacity(digits.len() / 3 * 2 + 1);
+
+    for chunk in digits.chunks(3) {
+        let n = chunk
+            .iter()
+            .rev()
+            .fold(0, |n, digit| n * 45 + digit);
+
+        match chunk.len() {
+            3 if n <= 0xffff => out.extend([(n >> 8) as u8, n as u8]),
+            2 if n <= 0xff => out.push(n as u8),
+            _ => {
+                return Err(invalid());
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Vaccination and lab result certificates issued from emr records.
+#[derive(Default)]
+pub struct Certificates {
+    certificates: SBTreeMap<CertificateId, Certificate>,
+    /// reverse index, emr id to the certificates issued from it
+    emr_certificates: SBTreeMap<EmrId, SBTreeSet<CertificateId>>,
+}
+
+impl Certificates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, issued: IssuedCertificate) -> Result<CertificateDisplay, String> {
+        let certificate = Certificate {
+            emr_id: issued.emr_id.clone(),
+            issuer: issued.issuer,
+            kind: issued.kind,
+            status: CertificateStatus::Valid,
+            issued_at: issued.issued_at,
+            revoked_at: None,
+            revocation_reason: None,
+            claims: SBox::new(issued.claims).map_err(OutOfMemory::from)?,
+            signature: SBox::new(issued.signature).map_err(OutOfMemory::from)?,
+            public_key: SBox::new(issued.public_key).map_err(OutOfMemory::from)?,
+        };
+
+        let display = CertificateDisplay::new(issued.certificate_id.clone(), &certificate);
+
+        self.certificates.insert(issued.certificate_id.clone(), certificate).map_err(OutOfMemory::from)?;
+
+        if !self.emr_certificates.contains_key(&issued.emr_id) {
+            self.emr_certificates.insert(issued.emr_id.clone(), SBTreeSet::new()).map_err(OutOfMemory::from)?;
+        }
+
+        self.emr_certificates
+            .get_mut(&issued.emr_id)
+            .expect("index was just created")
+            .insert(issued.certificate_id)
+            .map_err(OutOfMemory::from)?;
+
+        Ok(display)
+    }
+
+    /// revoke a certificate, only its issuing provider may revoke it
+    pub fn revoke(
+        &mut self,
+        certificate_id: &CertificateId,
+        provider: &InternalProviderId,
+        reason: String
+    ) -> Result<(), String> {
+        let Some(mut certificate) = self.certificates.get_mut(certificate_id) else {
+            return Err("certificate not found".to_string());
+        };
+
+        if certificate.issuer.ne(provider) {
+            return Err("only the issuing provider can revoke this certificate".to_string());
+        }
+
+        if certificate.status == CertificateStatus::Revoked {
+            return Err("certificate is already revoked".to_string());
+        }
+
+        certificate.revocation_reason = Some(SBox::new(reason).map_err(OutOfMemory::from)?);
+        certificate.revoked_at = Some(Timestamp::new());
+        certificate.status = CertificateStatus::Revoked;
+
+        Ok(())
+    }
+
+    pub fn get(&self, certificate_id: &CertificateId) -> Option<CertificateDisplay> {
+        self.certificates
+            .get(certificate_id)
+            .map(|certificate| CertificateDisplay::new(certificate_id.clone(), &certificate))
+    }
+
+    /// returns every certificate issued from `emr_id`
+    pub fn of_emr(&self, emr_id: &EmrId) -> Vec<CertificateDisplay> {
+        let Some(ids) = self.emr_certificates.get(emr_id) else {
+            return vec![];
+        };
+
+        ids.iter()
+            .filter_map(|certificate_id| self.get(&certificate_id))
+            .collect()
+    }
+
+    /// check the signature and revocation status of a QR code text. only the claims of the certificate
+    /// itself are returned, never anything else from the emr it was issued from.
+    pub fn verify(&self, code: &str) -> CertificateVerification {
+        match self.try_verify(code) {
+            Ok(verification) => verification,
+            Err(reason) => CertificateVerification::Invalid(reason),
+        }
+    }
+
+    fn try_verify(&self, code: &str) -> Result<CertificateVerification, String> {
+        let (claims, signature) = decode(code)?;
+
+        let content: Claims = ciborium::de
+            ::from_reader(claims.as_slice())
+            .map_err(|_| "malformed certificate claims".to_string())?;
+
+        let Some(certificate) = self.certificates.get(&content.certificate_id) else {
+            return Err("unknown certificate".to_string());
+        };
+
+        // the code must be exactly the one issued, not just some claims signed by the same key
+        if *certificate.claims != claims || *certificate.signature != signature {
+            return Err("certificate does not match the issued certificate".to_string());
+        }
+
+        attestation::verify_signature(&claims, &signature, &certificate.public_key)?;
+
+        Ok(match (certificate.status, certificate.revoked_at) {
+            (CertificateStatus::Revoked, Some(revoked_at)) =>
+                CertificateVerification::Revoked { certificate: content.into(), revoked_at },
+            _ => CertificateVerification::Valid(content.into()),
+        })
+    }
+
+    /// remove every certificate of an erased emr, their codes no longer verify afterwards
+    pub fn remove_emr(&mut self, emr_id: &EmrId) {
+        let ids = match self.emr_certificates.get(emr_id) {
+            Some(ids) =>
+                ids
+                    .iter()
+                    .map(|id| (*id).clone())
+                    .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        ids.iter().for_each(|certificate_id| {
+            self.certificates.remove(certificate_id);
+        });
+        self.emr_certificates.remove(emr_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use k256::ecdsa::SigningKey;
+    use sha2::{ Digest, Sha256 };
+
+    use super::*;
+    use crate::{
+        attestation::{ local::{ block_on, LocalSigner }, AttestationSigner },
+        emr::{ DisplayV001, RecrodsDisplay },
+        types::fixtures::id,
+    };
+
+    fn emr() -> EmrDisplay {
+        EmrDisplay::V001(
+            DisplayV001::with_timestamps(
+                id(1),
+                Timestamp(1_700_000_000_000_000_000),
+                Timestamp(1_700_000_000_000_000_000),
+                RecrodsDisplay(
+                    serde_json::json!({ "vaccine": "BCG", "dose": "1", "diagnosis": "Typhoid fever" })
+                )
+            )
+        )
+    }
+
+    #[test]
+    fn test_base45_rfc_9285() {
+        assert_eq!(base45_encode(b"AB"), "BB8");
+        assert_eq!(base45_encode(b"Hello!!"), "%69 VD92EX0");
+        assert_eq!(base45_decode("QED8WEX0").unwrap(), b"ietf!");
+        assert!(base45_decode("GGW").is_err());
+    }
+
+    #[test]
+    fn test_issue_verify_revoke() {
+        ic_stable_memory::stable_memory_init();
+
+        let signer = LocalSigner(SigningKey::from_slice(&[7; 32]).unwrap());
+        let provider = id(9);
+        let keys = ["vaccine".to_string(), "dose".to_string()];
+
+        let kind = CertificateKind::Vaccination;
+        let claims = claims(&id(2), kind, "RS Sehat".to_string(), &emr(), &keys, Timestamp(1)).unwrap();
+        let signature = block_on(signer.sign(Sha256::digest(&claims).into())).unwrap();
+
+        let mut certificates = Certificates::new();
+        let display = certificates
+            .insert(IssuedCertificate {
+                certificate_id: id(2),
+                emr_id: id(1),
+                issuer: provider.clone(),
+                kind,
+                issued_at: Timestamp(1),
+                claims,
+                signature,
+                public_key: block_on(signer.public_key()).unwrap(),
+            })
+            .unwrap();
+
+        let CertificateVerification::Valid(content) = certificates.verify(&display.code) else {
+            panic!("certificate should be valid");
+        };
+        // other records of the emr are never disclosed
+        assert_eq!(
+            content.records,
+            vec![("dose".to_string(), "1".to_string()), ("vaccine".to_string(), "BCG".to_string())]
+        );
+
+        // a code signed by another key doesn't verify, even with the same claims
+        let (claims, _) = decode(&display.code).unwrap();
+        let other = LocalSigner(SigningKey::from_slice(&[8; 32]).unwrap());
+        let forged = encode(&claims, &block_on(other.sign(Sha256::digest(&claims).into())).unwrap());
+        assert!(matches!(certificates.verify(&forged), CertificateVerification::Invalid(_)));
+        assert!(matches!(certificates.verify("MB1:not base45"), CertificateVerification::Invalid(_)));
+
+        assert!(certificates.revoke(&id(2), &id(10), "wrong patient".to_string()).is_err());
+        certificates.revoke(&id(2), &provider, "wrong patient".to_string()).unwrap();
+        assert!(matches!(certificates.verify(&display.code), CertificateVerification::Revoked { .. }));
+
+        certificates.remove_emr(&id(1));
+        assert!(certificates.of_emr(&id(1)).is_empty());
+        assert!(matches!(certificates.verify(&display.code), CertificateVerification::Invalid(_)));
+    }
+}
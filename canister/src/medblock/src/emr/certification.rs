@@ -0,0 +1,166 @@
+use candid::CandidType;
+use ic_stable_memory::{
+    collections::SCertifiedBTreeMap,
+    derive::{ AsFixedSizeBytes, StableType },
+    utils::certification::{ merge_hash_trees, Hash, HashTree },
+    labeled,
+    labeled_hash,
+    leaf,
+    leaf_hash,
+    AsHashTree,
+};
+use serde::Deserialize;
+use sha2::{ Digest, Sha256 };
+
+use super::{ EmrDisplay, EmrId, OutOfMemory };
+
+/// sha256 of the canonical json of an [EmrDisplay], the leaf of its emr in the certified data tree
+#[derive(StableType, AsFixedSizeBytes, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentHash([u8; 32]);
+
+impl ContentHash {
+    /// canonical json is serde_json without whitespace, object keys sorted
+    pub fn of(emr: &EmrDisplay) -> Self {
+        let json = serde_json::to_vec(emr).expect("emr display is always serializable");
+        Self(Sha256::digest(json).into())
+    }
+}
+
+impl AsHashTree for ContentHash {
+    fn root_hash(&self) -> Hash {
+        leaf_hash(&self.0)
+    }
+
+    fn hash_tree(&self) -> HashTree {
+        leaf(self.0.to_vec())
+    }
+}
+
+/// certified emr response, clients verify it by
+/// 1. checking the certificate signature against the IC root key and reading the certified data of the canister
+/// 2. checking the witness reconstructs to that certified data
+/// 3. checking the witness holds `sha256(canonical json)` of every returned emr at `emr/<emr id bytes>`
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CertifiedEmrs {
+    pub emrs: Vec<EmrDisplay>,
+    /// certificate returned by the system API, only available in query calls
+    pub certificate: Vec<u8>,
+    /// CBOR encoded hash tree, self-describe tagged as the IC interface spec requires
+    pub witness: Vec<u8>,
+}
+
+/// Certified data tree of every emr, content hashes keyed by emr id under the `emr` label.
+///
+/// must be committed after every emr mutation, which also updates the certified data of the canister.
+#[derive(Default)]
+pub struct CertifiedEmrTree(SCertifiedBTreeMap<EmrId, ContentHash>);
+
+impl CertifiedEmrTree {
+    const LABEL: &'static [u8] = b"emr";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// stage the new content of an emr, call [CertifiedEmrTree::commit] once all changes are staged
+    pub fn stage(&mut self, emr_id: EmrId, emr: &EmrDisplay) -> Result<(), OutOfMemory> {
+        self.0
+            .insert(emr_id, ContentHash::of(emr))
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    /// stage the removal of an erased emr
+    pub fn stage_removal(&mut self, emr_id: &EmrId) {
+        self.0.remove(emr_id);
+    }
+
+    /// recompute the tree and hand its root hash to the IC
+    pub fn commit(&mut self) {
+        self.0.commit();
+        let root_hash = self.root_hash();
+
+        #[cfg(target_arch = "wasm32")]
+        ic_cdk::api::set_certified_data(&root_hash);
+
+        // there is no certified data to set outside the canister, e.g. in tests
+        #[cfg(not(target_arch = "wasm32"))]
+        let _ = root_hash;
+    }
+
+    /// the certified data of the canister
+    pub fn root_hash(&self) -> Hash {
+        labeled_hash(Self::LABEL, &self.0.root_hash())
+    }
+
+    /// witness proving the content hash of every emr in `emr_ids`, ids not in the tree are proven absent
+    pub fn witness(&self, emr_ids: &[EmrId]) -> HashTree {
+        let tree = emr_ids
+            .iter()
+            .map(|emr_id| {
+                match self.0.contains_key(emr_id) {
+                    true => self.0.witness(emr_id),
+                    false => self.0.prove_absence(emr_id),
+                }
+            })
+            .reduce(merge_hash_trees)
+            .unwrap_or(HashTree::Empty);
+
+        labeled(Self::LABEL.to_vec(), tree)
+    }
+}
+
+/// encode a witness as CBOR with the self-describe tag
+pub fn encode_witness(witness: &HashTree) -> Vec<u8> {
+    let mut buf = vec![];
+    ciborium::ser
+        ::into_writer(&ciborium::tag::Required::<_, 55799>(witness), &mut buf)
+        .expect("writing to a vec never fails");
+
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ emr::{ DisplayV001, RecrodsDisplay }, types::{ fixtures::id, Timestamp } };
+
+    fn emr(byte: u8, diagnosis: &str) -> EmrDisplay {
+        EmrDisplay::V001(
+            DisplayV001::with_timestamps(
+                id(byte),
+                Timestamp(1_700_000_000_000_000_000),
+                Timestamp(1_700_000_000_000_000_000),
+                RecrodsDisplay(serde_json::json!({ "diagnosis": diagnosis }))
+            )
+        )
+    }
+
+    #[test]
+    fn test_witness_reconstructs_root() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut tree = CertifiedEmrTree::new();
+        for byte in 1..=20 {
+            tree.stage(id(byte), &emr(byte, "Typhoid fever")).unwrap();
+        }
+        tree.commit();
+
+        let root = tree.root_hash();
+        let witness = tree.witness(&[id(3), id(17), id(99)]);
+        assert_eq!(witness.reconstruct(), root);
+
+        // changing a single emr changes the certified data
+        tree.stage(id(3), &emr(3, "Dengue fever")).unwrap();
+        tree.commit();
+        assert_ne!(tree.root_hash(), root);
+        assert_eq!(tree.witness(&[id(3)]).reconstruct(), tree.root_hash());
+
+        tree.stage_removal(&id(3));
+        tree.commit();
+        assert_eq!(tree.witness(&[id(3)]).reconstruct(), tree.root_hash());
+
+        // self-describe tag 55799
+        assert_eq!(&encode_witness(&witness)[..3], &[0xd9, 0xd9, 0xf7]);
+    }
+}
@@ -0,0 +1,269 @@
+use candid::{ CandidType, Principal };
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SVec },
+    derive::{ AsFixedSizeBytes, StableType },
+};
+use serde::Deserialize;
+use sha2::{ Digest, Sha256 };
+
+use crate::types::{ AsciiRecordsKey, Timestamp };
+
+use super::{ EmrId, OutOfMemory };
+
+type Digest32 = [u8; 32];
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// record present when the emr was created
+    Create,
+    /// record added to or overwritten in an existing emr
+    Add,
+    /// value of an existing record replaced
+    Update,
+    /// record removed
+    Remove,
+}
+
+impl ChangeKind {
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Create => 0,
+            Self::Add => 1,
+            Self::Update => 2,
+            Self::Remove => 3,
+        }
+    }
+}
+
+/// a single change to an emr, chained to the previous change by its hash
+#[derive(StableType, AsFixedSizeBytes, Debug, Clone)]
+pub struct ChainLink {
+    previous: Digest32,
+    kind: ChangeKind,
+    key: AsciiRecordsKey,
+    /// sha256 of the new value, zeroed for removals
+    value_digest: Digest32,
+    actor: Principal,
+    timestamp: Timestamp,
+    hash: Digest32,
+}
+
+impl ChainLink {
+    fn new(
+        previous: Digest32,
+        kind: ChangeKind,
+        key: AsciiRecordsKey,
+        value: Option<&str>,
+        actor: Principal
+    ) -> Self {
+        let value_digest = value.map(|value| Sha256::digest(value).into()).unwrap_or_default();
+
+        let mut link = Self {
+            previous,
+            kind,
+            key,
+            value_digest,
+            actor,
+            timestamp: Timestamp::new(),
+            hash: Digest32::default(),
+        };
+
+        link.hash = link.compute_hash();
+        link
+    }
+
+    /// sha256 over every field but the hash itself, variable length fields are length prefixed
+    fn compute_hash(&self) -> Digest32 {
+        let key = self.key.to_string();
+        let actor = self.actor.as_slice();
+
+        Sha256::new()
+            .chain_update(self.previous)
+            .chain_update([self.kind.tag()])
+            .chain_update([key.len() as u8])
+            .chain_update(key.as_bytes())
+            .chain_update(self.value_digest)
+            .chain_update([actor.len() as u8])
+            .chain_update(actor)
+            .chain_update(self.timestamp.inner().to_be_bytes())
+            .finalize()
+            .into()
+    }
+}
+
+/// result of recomputing the chain of an emr
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainVerification {
+    /// number of links in the chain
+    pub length: u64,
+    /// hex encoded hash of the last link, the genesis hash if the chain is empty
+    pub head: String,
+    /// index of the first link that doesn't match, or `length` if the chain is intact but the current
+    /// records don't match it
+    pub first_inconsistent: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl ChainVerification {
+    pub fn is_intact(&self) -> bool {
+        self.first_inconsistent.is_none()
+    }
+}
+
+/// Tamper evident history of every emr. each record change appends a link hashing the previous link,
+/// so altering or dropping a past change breaks every link after it.
+#[derive(Default)]
+pub struct EmrChains(SBTreeMap<EmrId, SVec<ChainLink>>);
+
+impl EmrChains {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the hash the first link of an emr chains to, binds the chain to its emr
+    fn genesis(emr_id: &EmrId) -> Digest32 {
+        Sha256::new()
+            .chain_update(b"medblock/emr-chain")
+            .chain_update(uuid::Uuid::from(emr_id.clone()).as_bytes())
+            .finalize()
+            .into()
+    }
+
+    fn head(&self, emr_id: &EmrId) -> Digest32 {
+        self.0
+            .get(emr_id)
+            .and_then(|links| links.get(links.len().checked_sub(1)?).map(|link| link.hash))
+            .unwrap_or_else(|| Self::genesis(emr_id))
+    }
+
+    /// append a change of `key` by `actor`, `value` is none for removals
+    pub fn append(
+        &mut self,
+        emr_id: &EmrId,
+        kind: ChangeKind,
+        key: AsciiRecordsKey,
+        value: Option<&str>,
+        actor: Principal
+    ) -> Result<(), OutOfMemory> {
+        let link = ChainLink::new(self.head(emr_id), kind, key, value, actor);
+
+        if !self.0.contains_key(emr_id) {
+            self.0.insert(emr_id.clone(), SVec::new()).map_err(OutOfMemory::from)?;
+        }
+
+        let mut links = self.0.get_mut(emr_id).expect("chain was just created");
+        links.push(link).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// drop the chain of an erased emr
+    pub fn remove(&mut self, emr_id: &EmrId) {
+        self.0.remove(emr_id);
+    }
+
+    /// recompute the chain of an emr and replay it against `records`, the current records of the emr.
+    /// reports the first link whose hash doesn't match, or the records that don't match the chain.
+    pub fn verify(&self, emr_id: &EmrId, records: &[(AsciiRecordsKey, String)]) -> ChainVerification {
+        let mut head = Self::genesis(emr_id);
+        let mut latest = std::collections::BTreeMap::new();
+        let mut length = 0;
+
+        let inconsistent = |head: &Digest32, length: u64, index: u64, reason: String| ChainVerification {
+            length,
+            head: hex::encode(head),
+            first_inconsistent: Some(index),
+            reason: Some(reason),
+        };
+
+        if let Some(links) = self.0.get(emr_id) {
+            length = links.len() as u64;
+
+            for (index, link) in links.iter().enumerate() {
+                let index = index as u64;
+
+                if link.previous != head {
+                    return inconsistent(&head, length, index, "link does not chain to the previous link".to_string());
+                }
+
+                if link.compute_hash() != link.hash {
+                    return inconsistent(&head, length, index, "link hash does not match its content".to_string());
+                }
+
+                match link.kind {
+                    ChangeKind::Remove => latest.remove(&link.key),
+                    _ => latest.insert(link.key.clone(), link.value_digest),
+                };
+
+                head = link.hash;
+            }
+        }
+
+        for (key, value) in records {
+            let digest: Digest32 = Sha256::digest(value).into();
+
+            if latest.remove(key) != Some(digest) {
+                return inconsistent(&head, length, length, format!("record {} does not match the chain", key));
+            }
+        }
+
+        if let Some(key) = latest.keys().next() {
+            return inconsistent(&head, length, length, format!("record {} is missing", key));
+        }
+
+        ChainVerification { length, head: hex::encode(head), first_inconsistent: None, reason: None }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::Id;
+
+    fn key(key: &str) -> AsciiRecordsKey {
+        AsciiRecordsKey::new(key).unwrap()
+    }
+
+    fn records(pairs: &[(&str, &str)]) -> Vec<(AsciiRecordsKey, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (key(k), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_chain_detects_tampering() {
+        ic_stable_memory::stable_memory_init();
+
+        let emr_id = Id::from(uuid::Uuid::from_bytes([1; 16]));
+        let actor = Principal::anonymous();
+        let mut chains = EmrChains::new();
+
+        chains.append(&emr_id, ChangeKind::Create, key("diagnosis"), Some("Typhoid fever"), actor).unwrap();
+        chains.append(&emr_id, ChangeKind::Create, key("note"), Some("rest"), actor).unwrap();
+        chains.append(&emr_id, ChangeKind::Update, key("diagnosis"), Some("Dengue fever"), actor).unwrap();
+        chains.append(&emr_id, ChangeKind::Remove, key("note"), None, actor).unwrap();
+
+        let current = records(&[("diagnosis", "Dengue fever")]);
+        let verification = chains.verify(&emr_id, &current);
+        assert!(verification.is_intact());
+        assert_eq!(verification.length, 4);
+
+        // a record altered outside of the chain
+        let altered = records(&[("diagnosis", "Common cold")]);
+        assert_eq!(chains.verify(&emr_id, &altered).first_inconsistent, Some(4));
+
+        // a rewritten link breaks at that link
+        chains.0.get_mut(&emr_id).unwrap().get_mut(2).unwrap().value_digest = [0; 32];
+        let verification = chains.verify(&emr_id, &current);
+        assert_eq!(verification.first_inconsistent, Some(2));
+        assert_eq!(verification.reason.as_deref(), Some("link hash does not match its content"));
+
+        // a chain of another emr doesn't verify against this one
+        let other = Id::from(uuid::Uuid::from_bytes([2; 16]));
+        chains.append(&other, ChangeKind::Create, key("diagnosis"), Some("Dengue fever"), actor).unwrap();
+        let moved = chains.0.get(&other).unwrap().get(0).unwrap().clone();
+        *chains.0.get_mut(&emr_id).unwrap().get_mut(0).unwrap() = moved;
+        assert_eq!(chains.verify(&emr_id, &current).first_inconsistent, Some(0));
+    }
+}
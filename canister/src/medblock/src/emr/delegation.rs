@@ -0,0 +1,532 @@
+use candid::{ CandidType, Principal };
+use ic_stable_memory::{ collections::SBTreeMap, derive::{ AsFixedSizeBytes, StableType }, SBox };
+use serde::Deserialize;
+
+use crate::{ deref, types::{ Id, Timestamp, YEAR_IN_NANOS } };
+
+use super::{ patient::NIK, OutOfMemory };
+
+pub type Guardian = Principal;
+type RequestId = Id;
+
+/// what a guardian is allowed to do on behalf of a patient
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DelegationScope {
+    /// guardian may read the patient's emrs
+    Read,
+    /// guardian may read the patient's emrs and manage consent on the patient's behalf
+    Manage,
+}
+
+impl DelegationScope {
+    /// check if this scope covers the `required` scope, [DelegationScope::Manage] covers everything
+    pub fn allows(&self, required: DelegationScope) -> bool {
+        matches!((self, required), (Self::Manage, _) | (Self::Read, Self::Read))
+    }
+}
+
+/// arguments to assign a guardian for a patient
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct AssignGuardianRequest {
+    pub guardian: Principal,
+    pub scope: DelegationScope,
+    /// time in nanoseconds after which the delegation is no longer valid, none means no explicit expiry
+    pub expires_at: Option<Timestamp>,
+    /// patient birth date in nanoseconds. when set, the delegation terminates once the patient reaches
+    /// the configured age of majority.
+    pub birth_date: Option<Timestamp>,
+}
+
+/// a single guardian delegation for a patient
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Delegation {
+    scope: DelegationScope,
+
+    /// principal that assigned the guardian, either the patient or a provider
+    granted_by: Principal,
+
+    /// time when the delegation was assigned in nanosecond
+    granted_at: Timestamp,
+
+    /// time when the delegation expires in nanosecond
+    expires_at: Option<Timestamp>,
+
+    /// patient birth date in nanosecond, the delegation terminates automatically once the patient comes of age
+    birth_date: Option<Timestamp>,
+
+    /// reference to the document that gives a provider authority to assign the guardian
+    /// (e.g. birth certificate or court order number), none if assigned by the patient
+    authority: Option<SBox<String>>,
+}
+
+impl Delegation {
+    /// fails if the requested expiry has already passed
+    pub fn new(
+        request: &AssignGuardianRequest,
+        granted_by: Principal,
+        authority: Option<String>
+    ) -> Result<Self, String> {
+        let granted_at = Timestamp::new();
+
+        if request.expires_at.is_some_and(|expires_at| expires_at.le(&granted_at)) {
+            return Err("delegation expiry must be in the future".to_string());
+        }
+
+        let authority = match authority {
+            Some(authority) => Some(SBox::new(authority).map_err(OutOfMemory::from)?),
+            None => None,
+        };
+
+        Ok(Self {
+            scope: request.scope,
+            granted_by,
+            granted_at,
+            expires_at: request.expires_at,
+            birth_date: request.birth_date,
+            authority,
+        })
+    }
+
+    /// time when the patient comes of age under the given `age_of_majority`, none if the birth date is unknown.
+    /// derived on every check so changing the configured age of majority applies to existing delegations.
+    pub fn minor_until(&self, age_of_majority: u8) -> Option<Timestamp> {
+        self.birth_date.map(|birth_date| {
+            Timestamp(
+                birth_date.inner().saturating_add(YEAR_IN_NANOS.saturating_mul(age_of_majority as u64))
+            )
+        })
+    }
+
+    /// a delegation is active until it expires or the patient comes of age, whichever comes first
+    pub fn is_active(&self, now: &Timestamp, age_of_majority: u8) -> bool {
+        let expired = self.expires_at.is_some_and(|expires_at| expires_at.le(now));
+        let came_of_age = self.minor_until(age_of_majority).is_some_and(|minor_until| minor_until.le(now));
+
+        !expired && !came_of_age
+    }
+
+    pub fn scope(&self) -> DelegationScope {
+        self.scope
+    }
+
+    pub fn granted_by(&self) -> Principal {
+        self.granted_by
+    }
+
+    /// restrict a delegation assigned by a guardian to what the guardian's own `grantor` delegation allows.
+    /// the scope can't be wider, the delegation can't expire later and the patient birth date of the grantor
+    /// applies so it terminates when the patient comes of age at the latest.
+    fn bound_by(&mut self, grantor: &Delegation) -> Result<(), String> {
+        if !grantor.scope.allows(self.scope) {
+            return Err("guardian can't grant a scope beyond their own".to_string());
+        }
+
+        if let Some(limit) = grantor.expires_at {
+            if self.expires_at.is_none_or(|expires_at| expires_at.gt(&limit)) {
+                return Err("guardian can't grant a delegation that outlives their own".to_string());
+            }
+        }
+
+        if grantor.birth_date.is_some() {
+            self.birth_date = grantor.birth_date;
+        }
+
+        Ok(())
+    }
+}
+
+/// heap copy of a [Delegation], returned to patients and guardians
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct DelegationDisplay {
+    guardian: Guardian,
+    patient: NIK,
+    scope: DelegationScope,
+    granted_by: Principal,
+    granted_at: Timestamp,
+    expires_at: Option<Timestamp>,
+    minor_until: Option<Timestamp>,
+    authority: Option<String>,
+    active: bool,
+}
+
+impl DelegationDisplay {
+    pub fn new(
+        guardian: Guardian,
+        patient: NIK,
+        delegation: &Delegation,
+        now: &Timestamp,
+        age_of_majority: u8
+    ) -> Self {
+        Self {
+            guardian,
+            patient,
+            scope: delegation.scope,
+            granted_by: delegation.granted_by,
+            granted_at: delegation.granted_at,
+            expires_at: delegation.expires_at,
+            minor_until: delegation.minor_until(age_of_majority),
+            authority: delegation.authority.as_ref().map(|authority| (**authority).clone()),
+            active: delegation.is_active(now, age_of_majority),
+        }
+    }
+}
+
+pub type DelegationCollection = SBTreeMap<NIK, Delegation>;
+/// Guardian principal to delegations map. tracks which patients a guardian may act for, keyed by the patient's
+/// primary identifier. a patient may have several guardians and a guardian may act for several patients.
+#[derive(Default)]
+pub struct DelegationMap(SBTreeMap<Guardian, DelegationCollection>);
+deref!(mut DelegationMap: SBTreeMap<Guardian, DelegationCollection>);
+
+impl DelegationMap {
+    /// assign or replace the delegation of `guardian` for `patient`. a replaced delegation keeps the patient
+    /// birth date it was given, so a new request can't postpone its termination.
+    pub fn assign(
+        &mut self,
+        guardian: Guardian,
+        patient: NIK,
+        mut delegation: Delegation
+    ) -> Result<(), OutOfMemory> {
+        if !self.0.contains_key(&guardian) {
+            self.0.insert(guardian, DelegationCollection::new()).map_err(OutOfMemory::from)?;
+        }
+
+        let mut delegations = self.0.get_mut(&guardian).unwrap();
+
+        if let Some(birth_date) = delegations.get(&patient).and_then(|current| current.birth_date) {
+            delegation.birth_date = Some(birth_date);
+        }
+
+        delegations
+            .insert(patient, delegation)
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    /// revoke the delegation of `guardian` for `patient`, returns false if there is no such delegation
+    pub fn revoke(&mut self, guardian: &Guardian, patient: &NIK) -> bool {
+        let Some(mut delegations) = self.0.get_mut(guardian) else {
+            return false;
+        };
+
+        let revoked = delegations.remove(patient).is_some();
+        let is_empty = delegations.is_empty();
+        drop(delegations);
+
+        if is_empty {
+            self.0.remove(guardian);
+        }
+
+        revoked
+    }
+
+    /// returns the scope of the active delegation of `guardian` for `patient`, if any
+    pub fn active_scope(
+        &self,
+        guardian: &Guardian,
+        patient: &NIK,
+        now: &Timestamp,
+        age_of_majority: u8
+    ) -> Option<DelegationScope> {
+        let delegations = self.0.get(guardian)?;
+        let delegation = delegations.get(patient)?;
+
+        delegation.is_active(now, age_of_majority).then(|| delegation.scope())
+    }
+
+    /// restrict `delegation` to the active delegation `grantor` holds for `patient`, see [Delegation::bound_by].
+    /// fails if the grantor has no active delegation.
+    pub fn bound_by(
+        &self,
+        grantor: &Guardian,
+        patient: &NIK,
+        delegation: &mut Delegation,
+        now: &Timestamp,
+        age_of_majority: u8
+    ) -> Result<(), String> {
+        let Some(delegations) = self.0.get(grantor) else {
+            return Err("guardian delegation not found".to_string());
+        };
+        let Some(own) = delegations.get(patient) else {
+            return Err("guardian delegation not found".to_string());
+        };
+
+        if !own.is_active(now, age_of_majority) {
+            return Err("guardian delegation is no longer active".to_string());
+        }
+
+        delegation.bound_by(&own)
+    }
+
+    /// returns every patient `guardian` currently has an active delegation for
+    pub fn patients_of(&self, guardian: &Guardian, now: &Timestamp, age_of_majority: u8) -> Vec<NIK> {
+        let Some(delegations) = self.0.get(guardian) else {
+            return vec![];
+        };
+
+        delegations
+            .iter()
+            .filter(|(_, delegation)| delegation.is_active(now, age_of_majority))
+            .map(|(patient, _)| (*patient).clone())
+            .collect()
+    }
+
+    /// returns every guardian delegation assigned for `patient`, including inactive ones
+    pub fn guardians_of(&self, patient: &NIK, now: &Timestamp, age_of_majority: u8) -> Vec<DelegationDisplay> {
+        self.0
+            .iter()
+            .filter_map(|(guardian, delegations)| {
+                delegations
+                    .get(patient)
+                    .map(|delegation| {
+                        DelegationDisplay::new(*guardian, patient.clone(), &delegation, now, age_of_majority)
+                    })
+            })
+            .collect()
+    }
+
+    /// move every delegation for `from` to `into`, used when merging duplicate patients
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) -> Result<(), OutOfMemory> {
+        let guardians = self.0
+            .iter()
+            .filter(|(_, delegations)| delegations.contains_key(from))
+            .map(|(guardian, _)| *guardian)
+            .collect::<Vec<_>>();
+
+        for guardian in guardians {
+            let mut delegations = self.0.get_mut(&guardian).unwrap();
+
+            if let Some(delegation) = delegations.remove(from) {
+                delegations.insert(into.clone(), delegation).map_err(OutOfMemory::from)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardianRequestStatus {
+    /// waiting for admin review
+    Pending,
+    /// approved by an admin, the delegation has been assigned
+    Approved,
+    /// rejected by an admin
+    Rejected,
+}
+
+/// provider request to assign a guardian for a patient that can't do it themselves.
+/// the delegation is only assigned once an admin has reviewed the authority document.
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct GuardianRequest {
+    patient: NIK,
+    guardian: Guardian,
+    scope: DelegationScope,
+    expires_at: Option<Timestamp>,
+    birth_date: Option<Timestamp>,
+    requested_by: Principal,
+    requested_at: Timestamp,
+    /// reference to the document giving the provider authority to assign the guardian
+    authority: SBox<String>,
+    status: GuardianRequestStatus,
+    decided_by: Option<Principal>,
+    decided_at: Option<Timestamp>,
+    /// rejection reason given by the admin
+    reason: Option<SBox<String>>,
+}
+
+impl GuardianRequest {
+    pub fn new(
+        patient: NIK,
+        request: &AssignGuardianRequest,
+        requested_by: Principal,
+        authority: String
+    ) -> Result<Self, OutOfMemory> {
+        Ok(Self {
+            patient,
+            guardian: request.guardian,
+            scope: request.scope,
+            expires_at: request.expires_at,
+            birth_date: request.birth_date,
+            requested_by,
+            requested_at: Timestamp::new(),
+            authority: SBox::new(authority)?,
+            status: GuardianRequestStatus::Pending,
+            decided_by: None,
+            decided_at: None,
+            reason: None,
+        })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, GuardianRequestStatus::Pending)
+    }
+}
+
+/// heap copy of a [GuardianRequest], returned to admins
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct GuardianRequestDisplay {
+    pub request_id: RequestId,
+    pub patient: NIK,
+    pub request: AssignGuardianRequest,
+    pub requested_by: Principal,
+    pub requested_at: Timestamp,
+    pub authority: String,
+    pub status: GuardianRequestStatus,
+    pub decided_by: Option<Principal>,
+    pub decided_at: Option<Timestamp>,
+    pub reason: Option<String>,
+}
+
+impl GuardianRequestDisplay {
+    pub fn new(request_id: RequestId, request: &GuardianRequest) -> Self {
+        Self {
+            request_id,
+            patient: request.patient.clone(),
+            request: AssignGuardianRequest {
+                guardian: request.guardian,
+                scope: request.scope,
+                expires_at: request.expires_at,
+                birth_date: request.birth_date,
+            },
+            requested_by: request.requested_by,
+            requested_at: request.requested_at,
+            authority: (*request.authority).clone(),
+            status: request.status,
+            decided_by: request.decided_by,
+            decided_at: request.decided_at,
+            reason: request.reason.as_ref().map(|reason| (**reason).clone()),
+        }
+    }
+}
+
+/// Guardian assignments requested by providers, waiting for or decided by an admin.
+#[derive(Default)]
+pub struct GuardianRequests(SBTreeMap<RequestId, GuardianRequest>);
+
+impl GuardianRequests {
+    pub fn request(&mut self, request_id: RequestId, request: GuardianRequest) -> Result<(), OutOfMemory> {
+        self.0
+            .insert(request_id, request)
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    /// returns the request if it's still waiting for admin review
+    pub fn pending_request(&self, request_id: &RequestId) -> Result<GuardianRequestDisplay, String> {
+        let Some(request) = self.0.get(request_id) else {
+            return Err("guardian request not found".to_string());
+        };
+
+        if !request.is_pending() {
+            return Err("guardian request already decided".to_string());
+        }
+
+        Ok(GuardianRequestDisplay::new(request_id.clone(), &request))
+    }
+
+    /// mark a pending request as approved or rejected
+    pub fn decide(
+        &mut self,
+        request_id: &RequestId,
+        admin: Principal,
+        approved: bool,
+        reason: Option<String>
+    ) -> Result<GuardianRequestDisplay, String> {
+        self.pending_request(request_id)?;
+
+        let reason = match reason {
+            Some(reason) => Some(SBox::new(reason).map_err(OutOfMemory::from)?),
+            None => None,
+        };
+
+        let mut request = self.0.get_mut(request_id).unwrap();
+
+        request.status = match approved {
+            true => GuardianRequestStatus::Approved,
+            false => GuardianRequestStatus::Rejected,
+        };
+        request.decided_by = Some(admin);
+        request.decided_at = Some(Timestamp::new());
+        request.reason = reason;
+
+        Ok(GuardianRequestDisplay::new(request_id.clone(), &request))
+    }
+
+    /// returns at most `max` requests waiting for admin review in chronological order, skipping the first
+    /// `anchor` of them
+    pub fn pending(&self, anchor: u64, max: usize) -> Vec<GuardianRequestDisplay> {
+        self.0
+            .iter()
+            .filter(|(_, request)| request.is_pending())
+            .skip(anchor as usize)
+            .take(max)
+            .map(|(request_id, request)| GuardianRequestDisplay::new((*request_id).clone(), &request))
+            .collect()
+    }
+
+    /// point every request for `from` to `into`, used when merging duplicate patients
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) {
+        let request_ids = self.0
+            .iter()
+            .filter(|(_, request)| request.patient.eq(from))
+            .map(|(request_id, _)| (*request_id).clone())
+            .collect::<Vec<_>>();
+
+        for request_id in request_ids {
+            self.0.get_mut(&request_id).unwrap().patient = into.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_delegation_terminates_when_minor_comes_of_age() {
+        ic_stable_memory::stable_memory_init();
+
+        let now = Timestamp::new();
+        let ten_years_ago = Timestamp(now.inner() - 10 * YEAR_IN_NANOS);
+
+        let request = AssignGuardianRequest {
+            guardian: Principal::anonymous(),
+            scope: DelegationScope::Read,
+            expires_at: None,
+            birth_date: Some(ten_years_ago),
+        };
+
+        let delegation = Delegation::new(&request, Principal::anonymous(), None).unwrap();
+        assert!(delegation.is_active(&now, 18));
+
+        // lowering the age of majority terminates the existing delegation
+        assert!(!delegation.is_active(&now, 10));
+    }
+
+    #[test]
+    fn test_expiry_must_be_in_the_future() {
+        ic_stable_memory::stable_memory_init();
+
+        let now = Timestamp::new();
+        let mut request = AssignGuardianRequest {
+            guardian: Principal::anonymous(),
+            scope: DelegationScope::Read,
+            expires_at: Some(Timestamp(now.inner() - 1)),
+            birth_date: None,
+        };
+
+        assert!(Delegation::new(&request, Principal::anonymous(), None).is_err());
+
+        request.expires_at = Some(Timestamp(now.inner() + YEAR_IN_NANOS));
+        assert!(Delegation::new(&request, Principal::anonymous(), None).is_ok());
+    }
+
+    #[test]
+    fn test_scope() {
+        assert!(DelegationScope::Manage.allows(DelegationScope::Read));
+        assert!(DelegationScope::Manage.allows(DelegationScope::Manage));
+        assert!(DelegationScope::Read.allows(DelegationScope::Read));
+        assert!(!DelegationScope::Read.allows(DelegationScope::Manage));
+    }
+}
@@ -0,0 +1,324 @@
+use candid::CandidType;
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SBTreeSet },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::types::{ Id, Timestamp };
+
+use super::{ patient::NIK, providers::InternalProviderId, EmrId, OutOfMemory };
+
+pub type EncounterId = Id;
+
+const MAX_FACILITY_LEN: usize = 128;
+
+/// kind of visit, mirrors the HL7 v3 ActEncounterCode used as FHIR Encounter class
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncounterType {
+    Ambulatory,
+    Emergency,
+    Inpatient,
+    HomeHealth,
+    Virtual,
+}
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncounterStatus {
+    InProgress,
+    Finished,
+}
+
+/// encounter as opened by the attending provider
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct EncounterInput {
+    pub kind: EncounterType,
+    /// where the visit takes place, e.g. the clinic or ward name
+    pub facility: String,
+    /// start of the visit in nanoseconds, defaults to the time the encounter is opened
+    pub started_at: Option<Timestamp>,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Encounter {
+    patient: NIK,
+    kind: EncounterType,
+    status: EncounterStatus,
+    facility: SBox<String>,
+    attending: InternalProviderId,
+    started_at: Timestamp,
+    ended_at: Option<Timestamp>,
+}
+
+/// heap copy of an [Encounter] with the emrs linked to it
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct EncounterDisplay {
+    encounter_id: EncounterId,
+    kind: EncounterType,
+    status: EncounterStatus,
+    facility: String,
+    attending: InternalProviderId,
+    started_at: Timestamp,
+    ended_at: Option<Timestamp>,
+    emrs: Vec<EmrId>,
+}
+
+/// Encounters group the emrs written during a single visit of a patient.
+#[derive(Default)]
+pub struct Encounters {
+    encounters: SBTreeMap<EncounterId, Encounter>,
+    /// emrs linked to every encounter
+    encounter_emrs: SBTreeMap<EncounterId, SBTreeSet<EmrId>>,
+    /// reverse index, an emr links to at most one encounter
+    emr_encounter: SBTreeMap<EmrId, EncounterId>,
+    patient_encounters: SBTreeMap<NIK, SBTreeSet<EncounterId>>,
+}
+
+impl Encounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn display(&self, encounter_id: &EncounterId, encounter: &Encounter) -> EncounterDisplay {
+        let emrs = self.encounter_emrs
+            .get(encounter_id)
+            .map(|emrs| emrs.iter().map(|id| (*id).clone()).collect())
+            .unwrap_or_default();
+
+        EncounterDisplay {
+            encounter_id: encounter_id.clone(),
+            kind: encounter.kind,
+            status: encounter.status,
+            facility: (*encounter.facility).clone(),
+            attending: encounter.attending.clone(),
+            started_at: encounter.started_at,
+            ended_at: encounter.ended_at,
+            emrs,
+        }
+    }
+
+    fn index<K>(map: &mut SBTreeMap<K, SBTreeSet<Id>>, key: &K, id: Id) -> Result<(), OutOfMemory>
+        where K: ic_stable_memory::StableType + ic_stable_memory::AsFixedSizeBytes + Ord + Clone
+    {
+        if !map.contains_key(key) {
+            map.insert(key.clone(), SBTreeSet::new()).map_err(OutOfMemory::from)?;
+        }
+
+        map.get_mut(key).expect("index was just created").insert(id).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// open an encounter of `patient` attended by `attending`
+    pub fn open(
+        &mut self,
+        encounter_id: EncounterId,
+        patient: NIK,
+        attending: InternalProviderId,
+        input: EncounterInput,
+        now: Timestamp
+    ) -> Result<EncounterDisplay, String> {
+        let facility = input.facility.trim().to_string();
+        if facility.is_empty() || facility.len() > MAX_FACILITY_LEN {
+            return Err(format!("facility must be between 1 and {} characters", MAX_FACILITY_LEN));
+        }
+
+        let started_at = input.started_at.unwrap_or(now);
+        if started_at > now {
+            return Err("encounter can't start in the future".to_string());
+        }
+
+        let encounter = Encounter {
+            patient: patient.clone(),
+            kind: input.kind,
+            status: EncounterStatus::InProgress,
+            facility: SBox::new(facility).map_err(OutOfMemory::from)?,
+            attending,
+            started_at,
+            ended_at: None,
+        };
+
+        let display = self.display(&encounter_id, &encounter);
+
+        self.encounters.insert(encounter_id.clone(), encounter).map_err(OutOfMemory::from)?;
+        Self::index(&mut self.patient_encounters, &patient, encounter_id)?;
+
+        Ok(display)
+    }
+
+    /// close an encounter in progress, only the attending provider may close it
+    pub fn close(
+        &mut self,
+        encounter_id: &EncounterId,
+        attending: &InternalProviderId,
+        ended_at: Option<Timestamp>,
+        now: Timestamp
+    ) -> Result<EncounterDisplay, String> {
+        let Some(mut encounter) = self.encounters.get_mut(encounter_id) else {
+            return Err("encounter not found".to_string());
+        };
+
+        if encounter.attending.ne(attending) {
+            return Err("only the attending provider can close this encounter".to_string());
+        }
+
+        if encounter.status != EncounterStatus::InProgress {
+            return Err("encounter is already closed".to_string());
+        }
+
+        let ended_at = ended_at.unwrap_or(now);
+        if ended_at < encounter.started_at || ended_at > now {
+            return Err("encounter must end between its start and now".to_string());
+        }
+
+        encounter.status = EncounterStatus::Finished;
+        encounter.ended_at = Some(ended_at);
+        drop(encounter);
+
+        Ok(self.get(encounter_id).expect("encounter was just closed"))
+    }
+
+    pub fn get(&self, encounter_id: &EncounterId) -> Option<EncounterDisplay> {
+        self.encounters.get(encounter_id).map(|encounter| self.display(encounter_id, &encounter))
+    }
+
+    /// patient the encounter belongs to
+    pub fn patient_of(&self, encounter_id: &EncounterId) -> Option<NIK> {
+        self.encounters.get(encounter_id).map(|encounter| encounter.patient.clone())
+    }
+
+    /// link an emr to an encounter, replacing its previous encounter if any. the caller checks the emr belongs
+    /// to the patient of the encounter.
+    pub fn link(&mut self, emr_id: &EmrId, encounter_id: &EncounterId) -> Result<(), String> {
+        if !self.encounters.contains_key(encounter_id) {
+            return Err("encounter not found".to_string());
+        }
+
+        self.unlink(emr_id);
+
+        Self::index(&mut self.encounter_emrs, encounter_id, emr_id.clone())?;
+        self.emr_encounter.insert(emr_id.clone(), encounter_id.clone()).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// detach an emr from its encounter, e.g. because the emr was moved to another patient or erased
+    pub fn unlink(&mut self, emr_id: &EmrId) {
+        let Some(encounter_id) = self.emr_encounter.remove(emr_id) else {
+            return;
+        };
+
+        if let Some(mut emrs) = self.encounter_emrs.get_mut(&encounter_id) {
+            emrs.remove(emr_id);
+        }
+    }
+
+    /// remove every encounter of `patient`, used when the patient's records are erased
+    pub fn remove_patient(&mut self, patient: &NIK) {
+        let Some(ids) = self.patient_encounters.remove(patient) else {
+            return;
+        };
+
+        for encounter_id in ids.iter() {
+            self.encounters.remove(&encounter_id);
+
+            let Some(emrs) = self.encounter_emrs.remove(&encounter_id) else {
+                continue;
+            };
+
+            for emr_id in emrs.iter() {
+                self.emr_encounter.remove(&emr_id);
+            }
+        }
+    }
+
+    pub fn has_patient(&self, patient: &NIK) -> bool {
+        self.patient_encounters.contains_key(patient)
+    }
+
+    /// encounters of `patient`, ordered by start
+    pub fn of_patient(&self, patient: &NIK) -> Vec<EncounterDisplay> {
+        let Some(ids) = self.patient_encounters.get(patient) else {
+            return vec![];
+        };
+
+        let mut encounters = ids
+            .iter()
+            .filter_map(|encounter_id| self.get(&encounter_id))
+            .collect::<Vec<_>>();
+
+        encounters.sort_by_key(|encounter| encounter.started_at);
+        encounters
+    }
+
+    /// move every encounter of `from` to `into`, used when two patient records are merged
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) -> Result<(), OutOfMemory> {
+        let Some(ids) = self.patient_encounters.remove(from) else {
+            return Ok(());
+        };
+
+        for encounter_id in ids.iter() {
+            if let Some(mut encounter) = self.encounters.get_mut(&encounter_id) {
+                encounter.patient = into.clone();
+            }
+
+            Self::index(&mut self.patient_encounters, into, (*encounter_id).clone())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::fixtures::{ id, nik };
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn visit(started_at: u64) -> EncounterInput {
+        EncounterInput {
+            kind: EncounterType::Ambulatory,
+            facility: "Poli Umum".to_string(),
+            started_at: Some(Timestamp(started_at * HOUR)),
+        }
+    }
+
+    #[test]
+    fn test_encounter_lifecycle() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut encounters = Encounters::new();
+        let now = Timestamp(100 * HOUR);
+        let attending = id(9);
+
+        encounters.open(id(2), nik(1), attending.clone(), visit(50), now).unwrap();
+        encounters.open(id(1), nik(1), attending.clone(), visit(10), now).unwrap();
+        assert!(encounters.open(id(3), nik(1), attending.clone(), visit(200), now).is_err());
+
+        encounters.link(&id(20), &id(1)).unwrap();
+        // relinking moves the emr to the other encounter
+        encounters.link(&id(20), &id(2)).unwrap();
+
+        let listed = encounters.of_patient(&nik(1));
+        assert_eq!(listed.iter().map(|e| e.encounter_id.clone()).collect::<Vec<_>>(), vec![id(1), id(2)]);
+        assert!(listed[0].emrs.is_empty());
+        assert_eq!(listed[1].emrs, vec![id(20)]);
+
+        // only the attending provider closes, and only once
+        assert!(encounters.close(&id(1), &id(8), None, now).is_err());
+        assert!(encounters.close(&id(1), &attending, Some(Timestamp(5 * HOUR)), now).is_err());
+        let closed = encounters.close(&id(1), &attending, None, now).unwrap();
+        assert_eq!((closed.status, closed.ended_at), (EncounterStatus::Finished, Some(now)));
+        assert!(encounters.close(&id(1), &attending, None, now).is_err());
+
+        encounters.rekey(&nik(1), &nik(2)).unwrap();
+        assert!(encounters.of_patient(&nik(1)).is_empty());
+        assert_eq!(encounters.of_patient(&nik(2)).len(), 2);
+        assert_eq!(encounters.patient_of(&id(1)), Some(nik(2)));
+
+        encounters.unlink(&id(20));
+        assert!(encounters.get(&id(2)).unwrap().emrs.is_empty());
+    }
+}
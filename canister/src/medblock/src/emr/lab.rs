@@ -0,0 +1,525 @@
+use candid::{ CandidType, Principal };
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SBTreeSet, SVec },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::types::{ AsciiRecordsKey, Id, Timestamp };
+
+use super::{ providers::{ InternalLaboratoryId, InternalProviderId }, EmrId, OutOfMemory };
+
+pub type LabOrderId = Id;
+
+/// test codes, values and units are short, longer values are most likely garbage
+const MAX_TEXT_LEN: usize = 256;
+
+/// free text notes on an order
+const MAX_NOTE_LEN: usize = 2048;
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabOrderStatus {
+    /// routed to the laboratory, waiting for results
+    Pending,
+    /// results were reported and attached to the emr
+    Completed,
+    /// cancelled by the ordering provider before results were reported
+    Cancelled,
+}
+
+/// abnormal flag of a result, see HL7 table 0078
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbnormalFlag {
+    Normal,
+    Low,
+    High,
+    CriticalLow,
+    CriticalHigh,
+    Abnormal,
+}
+
+impl AbnormalFlag {
+    /// HL7 code of the flag, normal results are not flagged
+    fn code(&self) -> Option<&'static str> {
+        match self {
+            Self::Normal => None,
+            Self::Low => Some("L"),
+            Self::High => Some("H"),
+            Self::CriticalLow => Some("LL"),
+            Self::CriticalHigh => Some("HH"),
+            Self::Abnormal => Some("A"),
+        }
+    }
+}
+
+fn check_text(field: &str, value: &str, max: usize) -> Result<(), String> {
+    match value.trim().is_empty() || value.len() > max {
+        true => Err(format!("{} must be between 1 and {} characters", field, max)),
+        false => Ok(()),
+    }
+}
+
+/// lab order as written by the ordering provider
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LabOrderInput {
+    /// laboratory the order is routed to
+    pub laboratory: InternalLaboratoryId,
+    /// codes of the requested tests, e.g. LOINC codes
+    pub tests: Vec<String>,
+    pub note: Option<String>,
+}
+
+impl LabOrderInput {
+    fn validate(&self) -> Result<(), String> {
+        if self.tests.is_empty() {
+            return Err("at least one test must be ordered".to_string());
+        }
+
+        for test in &self.tests {
+            check_text("test code", test, MAX_TEXT_LEN)?;
+        }
+
+        if let Some(note) = &self.note {
+            check_text("note", note, MAX_NOTE_LEN)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// structured result reported by a laboratory
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LabResultInput {
+    /// test code, e.g. LOINC `2345-7`
+    pub code: String,
+    pub value: String,
+    pub unit: Option<String>,
+    /// reference range as printed by the laboratory, e.g. `3.9-5.5`
+    pub reference_range: Option<String>,
+    pub flag: AbnormalFlag,
+}
+
+impl LabResultInput {
+    fn validate(&self) -> Result<(), String> {
+        check_text("result code", &self.code, MAX_TEXT_LEN)?;
+        check_text("result value", &self.value, MAX_TEXT_LEN)?;
+
+        for (field, value) in [
+            ("unit", &self.unit),
+            ("reference range", &self.reference_range),
+        ] {
+            if let Some(value) = value {
+                check_text(field, value, MAX_TEXT_LEN)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// emr record key of the result, keyed like HL7 results without a coding system, e.g. `lab.2345-7`
+    fn record_key(&self) -> Result<AsciiRecordsKey, String> {
+        let key = format!("lab.{}", self.code.trim());
+        AsciiRecordsKey::new(&key).map_err(|e| format!("invalid record key {} : {}", key, e))
+    }
+
+    /// emr record value of the result, formatted like HL7 results, e.g. `5.4 mmol/L (H)`
+    fn record_value(&self) -> String {
+        let mut value = self.value.trim().to_string();
+
+        if let Some(unit) = &self.unit {
+            value = format!("{} {}", value, unit.trim());
+        }
+
+        if let Some(flag) = self.flag.code() {
+            value = format!("{} ({})", value, flag);
+        }
+
+        value
+    }
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct LabResult {
+    code: SBox<String>,
+    value: SBox<String>,
+    unit: Option<SBox<String>>,
+    reference_range: Option<SBox<String>>,
+    flag: AbnormalFlag,
+}
+
+impl LabResult {
+    fn new(input: LabResultInput) -> Result<Self, OutOfMemory> {
+        let optional = |value: Option<String>| value.map(SBox::new).transpose().map_err(OutOfMemory::from);
+
+        Ok(Self {
+            code: SBox::new(input.code).map_err(OutOfMemory::from)?,
+            value: SBox::new(input.value).map_err(OutOfMemory::from)?,
+            unit: optional(input.unit)?,
+            reference_range: optional(input.reference_range)?,
+            flag: input.flag,
+        })
+    }
+}
+
+/// heap copy of a [LabResult]
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LabResultDisplay {
+    code: String,
+    value: String,
+    unit: Option<String>,
+    reference_range: Option<String>,
+    flag: AbnormalFlag,
+}
+
+impl LabResultDisplay {
+    pub fn new(result: &LabResult) -> Self {
+        let optional = |value: &Option<SBox<String>>| value.as_ref().map(|value| (**value).clone());
+
+        Self {
+            code: (*result.code).clone(),
+            value: (*result.value).clone(),
+            unit: optional(&result.unit),
+            reference_range: optional(&result.reference_range),
+            flag: result.flag,
+        }
+    }
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct LabOrder {
+    emr_id: EmrId,
+    provider: InternalProviderId,
+    laboratory: InternalLaboratoryId,
+    status: LabOrderStatus,
+    tests: SVec<SBox<String>>,
+    note: Option<SBox<String>>,
+    ordered_at: Timestamp,
+    /// provenance of the results, when and by which laboratory principal they were reported
+    reported_at: Option<Timestamp>,
+    reported_by: Option<Principal>,
+    results: SVec<LabResult>,
+}
+
+/// heap copy of a [LabOrder]
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct LabOrderDisplay {
+    order_id: LabOrderId,
+    emr_id: EmrId,
+    provider: InternalProviderId,
+    laboratory: InternalLaboratoryId,
+    pub(crate) status: LabOrderStatus,
+    tests: Vec<String>,
+    note: Option<String>,
+    ordered_at: Timestamp,
+    reported_at: Option<Timestamp>,
+    reported_by: Option<Principal>,
+    results: Vec<LabResultDisplay>,
+}
+
+impl LabOrderDisplay {
+    pub fn new(order_id: LabOrderId, order: &LabOrder) -> Self {
+        Self {
+            order_id,
+            emr_id: order.emr_id.clone(),
+            provider: order.provider.clone(),
+            laboratory: order.laboratory.clone(),
+            status: order.status,
+            tests: order.tests
+                .iter()
+                .map(|test| (**test).clone())
+                .collect(),
+            note: order.note.as_ref().map(|note| (**note).clone()),
+            ordered_at: order.ordered_at,
+            reported_at: order.reported_at,
+            reported_by: order.reported_by,
+            results: order.results
+                .iter()
+                .map(|result| LabResultDisplay::new(&result))
+                .collect(),
+        }
+    }
+
+    pub fn emr_id(&self) -> &EmrId {
+        &self.emr_id
+    }
+
+    /// whether `provider` placed the order
+    pub fn is_ordered_by(&self, provider: &InternalProviderId) -> bool {
+        self.provider.eq(provider)
+    }
+}
+
+/// Lab orders placed by providers against emrs and routed to a laboratory, which reports structured results.
+#[derive(Default)]
+pub struct LabOrders {
+    orders: SBTreeMap<LabOrderId, LabOrder>,
+    /// reverse index, emr id to the orders placed against it
+    emr_orders: SBTreeMap<EmrId, SBTreeSet<LabOrderId>>,
+    /// orders waiting for results per laboratory
+    pending: SBTreeMap<InternalLaboratoryId, SBTreeSet<LabOrderId>>,
+}
+
+impl LabOrders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn index(
+        map: &mut SBTreeMap<Id, SBTreeSet<LabOrderId>>,
+        key: &Id,
+        order_id: LabOrderId
+    ) -> Result<(), OutOfMemory> {
+        if !map.contains_key(key) {
+            map.insert(key.clone(), SBTreeSet::new()).map_err(OutOfMemory::from)?;
+        }
+
+        map.get_mut(key).expect("index was just created").insert(order_id).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    fn unindex_pending(&mut self, laboratory: &InternalLaboratoryId, order_id: &LabOrderId) {
+        if let Some(mut ids) = self.pending.get_mut(laboratory) {
+            ids.remove(order_id);
+        }
+    }
+
+    pub fn create(
+        &mut self,
+        order_id: LabOrderId,
+        emr_id: EmrId,
+        provider: InternalProviderId,
+        input: LabOrderInput
+    ) -> Result<LabOrderDisplay, String> {
+        input.validate()?;
+
+        let mut tests = SVec::new();
+        for test in input.tests {
+            tests.push(SBox::new(test).map_err(OutOfMemory::from)?).map_err(OutOfMemory::from)?;
+        }
+
+        let order = LabOrder {
+            emr_id: emr_id.clone(),
+            provider,
+            laboratory: input.laboratory.clone(),
+            status: LabOrderStatus::Pending,
+            tests,
+            note: input.note.map(SBox::new).transpose().map_err(OutOfMemory::from)?,
+            ordered_at: Timestamp::new(),
+            reported_at: None,
+            reported_by: None,
+            results: SVec::new(),
+        };
+
+        let display = LabOrderDisplay::new(order_id.clone(), &order);
+
+        self.orders.insert(order_id.clone(), order).map_err(OutOfMemory::from)?;
+        Self::index(&mut self.emr_orders, &emr_id, order_id.clone())?;
+        Self::index(&mut self.pending, &input.laboratory, order_id)?;
+
+        Ok(display)
+    }
+
+    /// cancel a pending order, only the ordering provider may cancel it
+    pub fn cancel(&mut self, order_id: &LabOrderId, provider: &InternalProviderId) -> Result<(), String> {
+        let Some(mut order) = self.orders.get_mut(order_id) else {
+            return Err("lab order not found".to_string());
+        };
+
+        if order.provider.ne(provider) {
+            return Err("only the ordering provider can cancel this order".to_string());
+        }
+
+        if order.status != LabOrderStatus::Pending {
+            return Err("lab order is not pending".to_string());
+        }
+
+        order.status = LabOrderStatus::Cancelled;
+        let laboratory = order.laboratory.clone();
+        drop(order);
+
+        self.unindex_pending(&laboratory, order_id);
+
+        Ok(())
+    }
+
+    /// check a report for a pending order routed to `laboratory` without storing anything. every result must be
+    /// for a test on the order. returns the emr records the results would be attached to the originating emr with.
+    pub fn check_report(
+        &self,
+        order_id: &LabOrderId,
+        laboratory: &InternalLaboratoryId,
+        results: &[LabResultInput]
+    ) -> Result<Vec<(AsciiRecordsKey, String)>, String> {
+        let Some(order) = self.orders.get(order_id) else {
+            return Err("lab order not found".to_string());
+        };
+
+        if order.laboratory.ne(laboratory) {
+            return Err("lab order is not routed to this laboratory".to_string());
+        }
+
+        if order.status != LabOrderStatus::Pending {
+            return Err("lab order is not pending".to_string());
+        }
+
+        if results.is_empty() {
+            return Err("at least one result must be reported".to_string());
+        }
+
+        let mut records = Vec::with_capacity(results.len());
+        for result in results {
+            result.validate()?;
+
+            if !order.tests.iter().any(|test| test.trim() == result.code.trim()) {
+                return Err(format!("test {} was not ordered", result.code));
+            }
+
+            let key = result.record_key()?;
+            if records.iter().any(|(existing, _)| existing == &key) {
+                return Err(format!("duplicate result for test {}", result.code));
+            }
+
+            records.push((key, result.record_value()));
+        }
+
+        Ok(records)
+    }
+
+    /// record the results of a pending order routed to `laboratory`, reported by `reporter`.
+    /// returns the emr records the results are attached to the originating emr with.
+    pub fn report(
+        &mut self,
+        order_id: &LabOrderId,
+        laboratory: &InternalLaboratoryId,
+        reporter: Principal,
+        results: Vec<LabResultInput>
+    ) -> Result<Vec<(AsciiRecordsKey, String)>, String> {
+        // validate everything before storing anything, a rejected report leaves the order untouched
+        let records = self.check_report(order_id, laboratory, &results)?;
+
+        let mut order = self.orders.get_mut(order_id).expect("order was just checked");
+
+        for result in results {
+            order.results.push(LabResult::new(result)?).map_err(OutOfMemory::from)?;
+        }
+
+        order.status = LabOrderStatus::Completed;
+        order.reported_at = Some(Timestamp::new());
+        order.reported_by = Some(reporter);
+        drop(order);
+
+        self.unindex_pending(laboratory, order_id);
+
+        Ok(records)
+    }
+
+    pub fn get(&self, order_id: &LabOrderId) -> Option<LabOrderDisplay> {
+        self.orders.get(order_id).map(|order| LabOrderDisplay::new(order_id.clone(), &order))
+    }
+
+    /// returns every order placed against `emr_id`
+    pub fn of_emr(&self, emr_id: &EmrId) -> Vec<LabOrderDisplay> {
+        let Some(ids) = self.emr_orders.get(emr_id) else {
+            return vec![];
+        };
+
+        ids.iter()
+            .filter_map(|order_id| self.get(&order_id))
+            .collect()
+    }
+
+    /// returns every order routed to `laboratory` still waiting for results
+    pub fn pending_of(&self, laboratory: &InternalLaboratoryId) -> Vec<LabOrderDisplay> {
+        let Some(ids) = self.pending.get(laboratory) else {
+            return vec![];
+        };
+
+        ids.iter()
+            .filter_map(|order_id| self.get(&order_id))
+            .collect()
+    }
+
+    /// remove every order of an erased emr
+    pub fn remove_emr(&mut self, emr_id: &EmrId) {
+        let ids = match self.emr_orders.get(emr_id) {
+            Some(ids) =>
+                ids
+                    .iter()
+                    .map(|id| (*id).clone())
+                    .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        for order_id in ids {
+            if let Some(order) = self.orders.remove(&order_id) {
+                self.unindex_pending(&order.laboratory, &order_id);
+            }
+        }
+
+        self.emr_orders.remove(emr_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::fixtures::id;
+
+    fn result(code: &str, value: &str, flag: AbnormalFlag) -> LabResultInput {
+        LabResultInput {
+            code: code.to_string(),
+            value: value.to_string(),
+            unit: Some("mmol/L".to_string()),
+            reference_range: Some("3.9-5.5".to_string()),
+            flag,
+        }
+    }
+
+    #[test]
+    fn test_order_workflow() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut orders = LabOrders::new();
+        let (provider, laboratory, other_lab) = (id(8), id(9), id(10));
+        let input = LabOrderInput {
+            laboratory: laboratory.clone(),
+            tests: vec!["2345-7".to_string()],
+            note: None,
+        };
+
+        orders.create(id(2), id(1), provider.clone(), input.clone()).unwrap();
+        assert_eq!(orders.pending_of(&laboratory).len(), 1);
+        assert!(orders.pending_of(&other_lab).is_empty());
+
+        let reporter = Principal::anonymous();
+        let glucose = result("2345-7", "5.4", AbnormalFlag::High);
+
+        // only the routed laboratory can report, and a rejected report leaves the order pending
+        assert!(orders.report(&id(2), &other_lab, reporter, vec![glucose.clone()]).is_err());
+        assert!(orders.report(&id(2), &laboratory, reporter, vec![glucose.clone(), glucose.clone()]).is_err());
+        let hemoglobin = result("718-7", "13.5", AbnormalFlag::Normal);
+        assert!(orders.report(&id(2), &laboratory, reporter, vec![glucose.clone(), hemoglobin]).is_err());
+        assert_eq!(orders.get(&id(2)).unwrap().status, LabOrderStatus::Pending);
+
+        let records = orders.report(&id(2), &laboratory, reporter, vec![glucose.clone()]).unwrap();
+        assert_eq!(records, vec![(AsciiRecordsKey::new("lab.2345-7").unwrap(), "5.4 mmol/L (H)".to_string())]);
+
+        let order = orders.get(&id(2)).unwrap();
+        assert_eq!(order.status, LabOrderStatus::Completed);
+        assert_eq!(order.reported_by, Some(reporter));
+        assert_eq!(order.results.len(), 1);
+        assert!(orders.pending_of(&laboratory).is_empty());
+        assert!(orders.report(&id(2), &laboratory, reporter, vec![glucose]).is_err());
+
+        // cancelled orders leave the laboratory queue
+        orders.create(id(3), id(1), provider.clone(), input).unwrap();
+        assert!(orders.cancel(&id(3), &laboratory).is_err());
+        orders.cancel(&id(3), &provider).unwrap();
+        assert!(orders.pending_of(&laboratory).is_empty());
+
+        orders.remove_emr(&id(1));
+        assert!(orders.of_emr(&id(1)).is_empty());
+    }
+}
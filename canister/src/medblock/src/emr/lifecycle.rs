@@ -0,0 +1,272 @@
+use candid::{ CandidType, Principal };
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SVec },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::types::{ AsciiRecordsKey, Timestamp };
+
+use super::{ EmrId, OutOfMemory };
+
+const MAX_REASON_LEN: usize = 1024;
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmrStatus {
+    /// records may be edited freely, every emr starts as a draft
+    Draft,
+    /// signed off by the issuing provider, existing records can't change anymore
+    Final,
+    /// final with at least one addendum
+    Amended,
+    /// written for the wrong patient or visit, kept for the audit trail but no longer writable
+    EnteredInError,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Addendum {
+    /// record key the addendum text is written to
+    key: AsciiRecordsKey,
+    author: Principal,
+    written_at: Timestamp,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct ErrorMark {
+    marked_by: Principal,
+    marked_at: Timestamp,
+    reason: SBox<String>,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Lifecycle {
+    status: EmrStatus,
+    finalized_by: Option<Principal>,
+    finalized_at: Option<Timestamp>,
+    addenda: SVec<Addendum>,
+    entered_in_error: Option<ErrorMark>,
+}
+
+impl Lifecycle {
+    fn draft() -> Self {
+        Self {
+            status: EmrStatus::Draft,
+            finalized_by: None,
+            finalized_at: None,
+            addenda: SVec::new(),
+            entered_in_error: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddendumDisplay {
+    pub(crate) key: AsciiRecordsKey,
+    author: Principal,
+    written_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ErrorMarkDisplay {
+    marked_by: Principal,
+    marked_at: Timestamp,
+    reason: String,
+}
+
+/// heap copy of a [Lifecycle]
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LifecycleDisplay {
+    pub(crate) status: EmrStatus,
+    finalized_by: Option<Principal>,
+    finalized_at: Option<Timestamp>,
+    pub(crate) addenda: Vec<AddendumDisplay>,
+    entered_in_error: Option<ErrorMarkDisplay>,
+}
+
+impl LifecycleDisplay {
+    pub fn new(lifecycle: &Lifecycle) -> Self {
+        Self {
+            status: lifecycle.status,
+            finalized_by: lifecycle.finalized_by,
+            finalized_at: lifecycle.finalized_at,
+            addenda: lifecycle.addenda
+                .iter()
+                .map(|addendum| AddendumDisplay {
+                    key: addendum.key.clone(),
+                    author: addendum.author,
+                    written_at: addendum.written_at,
+                })
+                .collect(),
+            entered_in_error: lifecycle.entered_in_error.as_ref().map(|mark| ErrorMarkDisplay {
+                marked_by: mark.marked_by,
+                marked_at: mark.marked_at,
+                reason: (*mark.reason).clone(),
+            }),
+        }
+    }
+}
+
+/// Lifecycle state of every emr. emrs without an entry are drafts, which covers emrs created before lifecycles
+/// existed, so an entry is only created once an emr leaves the draft state.
+#[derive(Default)]
+pub struct Lifecycles(SBTreeMap<EmrId, Lifecycle>);
+
+impl Lifecycles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn status(&self, emr_id: &EmrId) -> EmrStatus {
+        self.0
+            .get(emr_id)
+            .map(|lifecycle| lifecycle.status)
+            .unwrap_or(EmrStatus::Draft)
+    }
+
+    pub fn get(&self, emr_id: &EmrId) -> LifecycleDisplay {
+        match self.0.get(emr_id) {
+            Some(lifecycle) => LifecycleDisplay::new(&lifecycle),
+            None => LifecycleDisplay::new(&Lifecycle::draft()),
+        }
+    }
+
+    /// check a record may be written to the emr. drafts accept anything, final emrs only accept records under
+    /// new keys, e.g. addenda or late lab results, as overwriting would edit what was signed off.
+    pub fn check_write(&self, emr_id: &EmrId, overwrite: bool) -> Result<(), String> {
+        match (self.status(emr_id), overwrite) {
+            (EmrStatus::Draft, _) => Ok(()),
+            (EmrStatus::Final | EmrStatus::Amended, false) => Ok(()),
+            (EmrStatus::Final | EmrStatus::Amended, true) => {
+                Err("emr is final, records can't be edited anymore, write an addendum instead".to_string())
+            }
+            (EmrStatus::EnteredInError, _) => Err("emr is entered in error".to_string()),
+        }
+    }
+
+    fn entry(&mut self, emr_id: &EmrId) -> Result<(), OutOfMemory> {
+        if !self.0.contains_key(emr_id) {
+            self.0.insert(emr_id.clone(), Lifecycle::draft()).map_err(OutOfMemory::from)?;
+        }
+
+        Ok(())
+    }
+
+    /// sign off a draft, recording who finalized it and when
+    pub fn finalize(&mut self, emr_id: &EmrId, by: Principal, now: Timestamp) -> Result<(), String> {
+        if self.status(emr_id) != EmrStatus::Draft {
+            return Err("only draft emrs can be finalized".to_string());
+        }
+
+        self.entry(emr_id)?;
+
+        let mut lifecycle = self.0.get_mut(emr_id).expect("entry was just created");
+        lifecycle.status = EmrStatus::Final;
+        lifecycle.finalized_by = Some(by);
+        lifecycle.finalized_at = Some(now);
+
+        Ok(())
+    }
+
+    /// check an addendum may be written, drafts are edited directly instead
+    pub fn check_amend(&self, emr_id: &EmrId) -> Result<(), String> {
+        match self.status(emr_id) {
+            EmrStatus::Final | EmrStatus::Amended => Ok(()),
+            EmrStatus::Draft => Err("emr is a draft, edit it directly".to_string()),
+            EmrStatus::EnteredInError => Err("emr is entered in error".to_string()),
+        }
+    }
+
+    /// record an addendum written to `key`, see [Self::check_amend]
+    pub fn amend(
+        &mut self,
+        emr_id: &EmrId,
+        key: AsciiRecordsKey,
+        author: Principal,
+        now: Timestamp
+    ) -> Result<(), String> {
+        self.check_amend(emr_id)?;
+
+        let mut lifecycle = self.0.get_mut(emr_id).expect("final emrs have an entry");
+        lifecycle.addenda
+            .push(Addendum { key, author, written_at: now })
+            .map_err(OutOfMemory::from)?;
+        lifecycle.status = EmrStatus::Amended;
+
+        Ok(())
+    }
+
+    /// mark an emr as entered in error, from then on it can't be written to
+    pub fn mark_entered_in_error(
+        &mut self,
+        emr_id: &EmrId,
+        by: Principal,
+        reason: String,
+        now: Timestamp
+    ) -> Result<(), String> {
+        let reason = reason.trim().to_string();
+        if reason.is_empty() || reason.len() > MAX_REASON_LEN {
+            return Err(format!("reason must be between 1 and {} characters", MAX_REASON_LEN));
+        }
+
+        if self.status(emr_id) == EmrStatus::EnteredInError {
+            return Err("emr is already entered in error".to_string());
+        }
+
+        let mark = ErrorMark {
+            marked_by: by,
+            marked_at: now,
+            reason: SBox::new(reason).map_err(OutOfMemory::from)?,
+        };
+
+        self.entry(emr_id)?;
+
+        let mut lifecycle = self.0.get_mut(emr_id).expect("entry was just created");
+        lifecycle.status = EmrStatus::EnteredInError;
+        lifecycle.entered_in_error = Some(mark);
+
+        Ok(())
+    }
+
+    /// drop the lifecycle of an erased emr
+    pub fn remove_emr(&mut self, emr_id: &EmrId) {
+        self.0.remove(emr_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::Id;
+
+    #[test]
+    fn test_lifecycle_transitions() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut lifecycles = Lifecycles::new();
+        let emr_id = Id::from(uuid::Uuid::from_bytes([1; 16]));
+        let doctor = Principal::anonymous();
+        let addendum = AsciiRecordsKey::new("addendum.1").unwrap();
+
+        // drafts are edited directly
+        assert_eq!(lifecycles.status(&emr_id), EmrStatus::Draft);
+        assert!(lifecycles.check_write(&emr_id, true).is_ok());
+        assert!(lifecycles.amend(&emr_id, addendum.clone(), doctor, Timestamp(1)).is_err());
+
+        lifecycles.finalize(&emr_id, doctor, Timestamp(2)).unwrap();
+        assert!(lifecycles.finalize(&emr_id, doctor, Timestamp(3)).is_err());
+        assert!(lifecycles.check_write(&emr_id, true).is_err());
+        assert!(lifecycles.check_write(&emr_id, false).is_ok());
+
+        lifecycles.amend(&emr_id, addendum, doctor, Timestamp(4)).unwrap();
+        let display = lifecycles.get(&emr_id);
+        assert_eq!(display.status, EmrStatus::Amended);
+        assert_eq!((display.finalized_by, display.finalized_at), (Some(doctor), Some(Timestamp(2))));
+        assert_eq!(lifecycles.get(&emr_id).addenda.len(), 1);
+
+        lifecycles.mark_entered_in_error(&emr_id, doctor, "wrong patient".to_string(), Timestamp(5)).unwrap();
+        assert!(lifecycles.check_write(&emr_id, false).is_err());
+        assert!(lifecycles.check_amend(&emr_id).is_err());
+        assert!(lifecycles.mark_entered_in_error(&emr_id, doctor, "again".to_string(), Timestamp(6)).is_err());
+    }
+}
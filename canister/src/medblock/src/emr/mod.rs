@@ -1,11 +1,25 @@
+pub mod access;
+pub mod attachment;
+pub mod certificate;
+pub mod certification;
+pub mod chain;
+pub mod delegation;
+pub mod encounter;
+pub mod lab;
+pub mod lifecycle;
 pub mod patient;
+pub mod prescription;
 pub mod providers;
+pub mod retention;
+pub mod summary;
+pub mod vitals;
 
 use candid::{ CandidType, Principal };
 use ic_stable_memory::{
     collections::SHashMap,
     derive::{ AsFixedSizeBytes, StableType },
     primitive::{ s_ref::SRef, s_ref_mut::SRefMut },
+    utils::certification::HashTree,
     AsFixedSizeBytes,
     SBox,
     StableType,
@@ -31,90 +45,1202 @@ pub trait ToResponse<T: ResonpseMarker> {
     fn to_response(&self) -> T;
 }
 
-use crate::{ deref, measure_alloc, types::{ AsciiRecordsKey, Id, Timestamp } };
+use crate::{
+    deref,
+    measure_alloc,
+    satusehat::submission::{ SubmissionDisplay, SubmissionResult, Submissions },
+    terminology::CodedRecord,
+    types::{ AsciiRecordsKey, Id, Timestamp },
+};
 
-use self::{ patient::{ EmrBindingMap, OwnerMap, NIK, InternalBindingKey } };
+use self::{
+    attachment::{ AttachmentDisplay, AttachmentId, AttachmentStore, AttachmentUsage },
+    certificate::{
+        CertificateDisplay,
+        CertificateId,
+        CertificateVerification,
+        Certificates,
+        IssuedCertificate,
+    },
+    certification::CertifiedEmrTree,
+    chain::{ ChainVerification, ChangeKind, EmrChains },
+    access::{
+        AccessAction,
+        AccessGrant,
+        AccessGrantDisplay,
+        AccessGrants,
+        AccessHistory,
+        AccessHistoryFilter,
+        AccessKind,
+        AccessRecord,
+        AccessRecordDisplay,
+        AccessScope,
+        BreakGlassEvent,
+        BreakGlassEventDisplay,
+        BreakGlassEvents,
+    },
+    delegation::{
+        AssignGuardianRequest,
+        Delegation,
+        DelegationDisplay,
+        DelegationMap,
+        DelegationScope,
+        Guardian,
+        GuardianRequest,
+        GuardianRequestDisplay,
+        GuardianRequests,
+    },
+    encounter::{ EncounterDisplay, EncounterId, EncounterInput, Encounters },
+    lifecycle::{ EmrStatus, LifecycleDisplay, Lifecycles },
+    lab::{ LabOrderDisplay, LabOrderId, LabOrderInput, LabOrders, LabResultInput },
+    patient::{ BindingTransition, EmrBindingMap, OwnerMap, NIK, InternalBindingKey },
+    prescription::{
+        PrescriptionCode,
+        PrescriptionDisplay,
+        PrescriptionId,
+        PrescriptionInput,
+        Prescriptions,
+    },
+    providers::{ InternalLaboratoryId, InternalPharmacyId, InternalProviderId },
+    retention::{ ErasureRequest, ErasureRequestDisplay, RetentionRegistry, Tombstone },
+    summary::{ ClinicalSummaries, ClinicalSummary },
+    vitals::{ ObservationSource, VitalInput, Vitals, VitalSeries, VitalsQuery },
+};
 
 #[derive(Default)]
 pub struct EmrRegistry {
     owners: OwnerMap,
     owner_emrs: EmrBindingMap,
     core_emrs: EmrCollection,
+    delegations: DelegationMap,
+    guardian_requests: GuardianRequests,
+    grants: AccessGrants,
+    break_glass: BreakGlassEvents,
+    access_history: AccessHistory,
+    retention: RetentionRegistry,
+    attachments: AttachmentStore,
+    certified: CertifiedEmrTree,
+    chains: EmrChains,
+    certificates: Certificates,
+    prescriptions: Prescriptions,
+    lab_orders: LabOrders,
+    vitals: Vitals,
+    summaries: ClinicalSummaries,
+    encounters: Encounters,
+    lifecycles: Lifecycles,
+    satusehat: Submissions,
 }
 
-impl EmrRegistry {
-    pub fn new() -> Self {
-        Self::default()
+impl EmrRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// register new emr to the system, returns [OutOfMemory] if stable memory is exhausted
+    pub fn register_emr(
+        &mut self,
+        emr: Emr,
+        user_id: InternalBindingKey,
+        actor: Principal
+    ) -> Result<EmrId, OutOfMemory> {
+        let emr_id = emr.id().clone();
+
+        let now = Timestamp::new();
+        let records = emr.records().sorted_pairs();
+
+        self.core_emrs.new_emr(emr)?;
+        self.owner_emrs.issue_for(&user_id, emr_id.clone())?;
+
+        for (key, value) in records {
+            self.summaries.apply(&emr_id, &key, &value, now)?;
+            self.chains.append(&emr_id, ChangeKind::Create, key, Some(&value), actor)?;
+        }
+
+        self.certify(&emr_id)?;
+
+        Ok(emr_id)
+    }
+
+    /// register new patient to the system, returns [OutOfMemory] if stable memory is exhausted
+    pub fn register_patient(
+        &mut self,
+        owner: Principal,
+        hashed_nik: NIK
+    ) -> Result<(), OutOfMemory> {
+        self.owners.bind(owner, hashed_nik)
+    }
+
+    /// rebind patient to a new hashed_nik, returns [OutOfMemory] if stable memory is exhausted
+    pub fn rebind_patient(&mut self, owner: Principal, hashed_nik: NIK) -> Result<(), OutOfMemory> {
+        self.owners.bind(owner, hashed_nik)
+    }
+
+    /// revoke patient access, if this method is called then the patient will no longer be able to access their emr. it will remove the [NIK]
+    /// from the owner map so attempting to access NIK owner will fail.
+    pub fn revoke_patient_access(&mut self, owner: &Principal) {
+        self.owners.revoke(owner)
+    }
+
+    /// merge every emr bound to `from` into `into`, used when the same patient was registered under two [NIK].
+    /// principals bound to `from` are rebound to `into` so they keep access to the merged emrs.
+    /// returns the binding state of both [NIK] before and after the merge for auditing.
+    pub fn merge_patients(&mut self, from: &NIK, into: &NIK) -> Result<BindingTransition, String> {
+        if self.owner_emrs.resolve(from).eq(&self.owner_emrs.resolve(into)) {
+            return Err("cannot merge patient into itself".to_string());
+        }
+
+        let before = vec![self.owner_emrs.snapshot(from), self.owner_emrs.snapshot(into)];
+
+        let (from, into) = (self.owner_emrs.resolve(from), self.owner_emrs.resolve(into));
+
+        self.owner_emrs.merge(&from, &into)?;
+        self.owners.rebind_all(&from, &into)?;
+        self.delegations.rekey(&from, &into)?;
+        self.guardian_requests.rekey(&from, &into);
+        self.grants.rekey(&from, &into)?;
+        self.access_history.rekey(&from, &into)?;
+        self.break_glass.rekey(&from, &into);
+        self.retention.rekey(&from, &into);
+        self.vitals.rekey(&from, &into)?;
+        self.encounters.rekey(&from, &into)?;
+
+        let after = vec![self.owner_emrs.snapshot(&from), self.owner_emrs.snapshot(&into)];
+
+        Ok(BindingTransition { before, after })
+    }
+
+    /// move a single emr that was issued to the wrong patient from `from` to `to`.
+    /// returns the binding state of both [NIK] before and after the move for auditing.
+    pub fn move_emr(
+        &mut self,
+        emr_id: &EmrId,
+        from: &NIK,
+        to: &NIK
+    ) -> Result<BindingTransition, String> {
+        if self.owner_emrs.resolve(from).eq(&self.owner_emrs.resolve(to)) {
+            return Err("source and destination patient must differ".to_string());
+        }
+
+        let before = vec![self.owner_emrs.snapshot(from), self.owner_emrs.snapshot(to)];
+
+        if !self.owner_emrs.move_emr(emr_id, from, to)? {
+            return Err(format!("emr {} is not bound to the source patient", emr_id));
+        }
+
+        // the visit belongs to the source patient, the emr no longer does
+        self.encounters.unlink(emr_id);
+
+        let after = vec![self.owner_emrs.snapshot(from), self.owner_emrs.snapshot(to)];
+
+        Ok(BindingTransition { before, after })
+    }
+
+    /// register an additional identifier for the patient known by `identifier`, after this call emr lookups
+    /// using either identifier resolve to the same patient. fails if `alias` is already in use.
+    /// returns the binding state of both identifiers before and after the link for auditing.
+    pub fn link_patient_identifier(&mut self, identifier: &NIK, alias: NIK) -> Result<BindingTransition, String> {
+        if self.owner_emrs.is_known(&alias) {
+            return Err(format!("identifier {} is already in use", alias.to_text()));
+        }
+
+        let before = vec![self.owner_emrs.snapshot(identifier), self.owner_emrs.snapshot(&alias)];
+
+        self.owner_emrs.link(identifier, alias.clone())?;
+
+        let after = vec![self.owner_emrs.snapshot(identifier), self.owner_emrs.snapshot(&alias)];
+
+        Ok(BindingTransition { before, after })
+    }
+
+    /// returns every identifier the patient known by `identifier` can be looked up with, primary identifier first
+    pub fn patient_identifiers(&self, identifier: &NIK) -> Vec<NIK> {
+        let mut identifiers = vec![self.owner_emrs.resolve(identifier)];
+        identifiers.extend(self.owner_emrs.aliases_of(identifier));
+
+        identifiers
+    }
+
+    /// returns the primary identifier of the patient bound to `owner`, if any
+    pub fn patient_of(&self, owner: &Principal) -> Option<NIK> {
+        self.owners.get_nik(owner).map(|nik| self.owner_emrs.resolve(&nik))
+    }
+
+    /// returns every emr id bound to `patient`
+    pub fn emr_list(&self, patient: &NIK) -> Vec<EmrId> {
+        self.owner_emrs.snapshot(patient).emrs
+    }
+
+    /// assign `guardian` to act for `patient` with the given delegation, replacing any previous delegation
+    /// of the same guardian for the same patient. a delegation granted by one of the patient's guardians can't
+    /// go beyond the guardian's own delegation, and a guardian can't reassign themselves.
+    pub fn assign_guardian(
+        &mut self,
+        patient: &NIK,
+        guardian: Guardian,
+        mut delegation: Delegation,
+        age_of_majority: u8
+    ) -> Result<(), String> {
+        let patient = self.owner_emrs.resolve(patient);
+
+        if self.patient_of(&guardian).is_some_and(|nik| nik.eq(&patient)) {
+            return Err("patient cannot be their own guardian".to_string());
+        }
+
+        let grantor = delegation.granted_by();
+        let now = Timestamp::new();
+        let by_patient = self.patient_of(&grantor).is_some_and(|nik| nik.eq(&patient));
+
+        if !by_patient && self.delegations.active_scope(&grantor, &patient, &now, age_of_majority).is_some() {
+            if guardian.eq(&grantor) {
+                return Err("guardian cannot reassign themselves".to_string());
+            }
+
+            self.delegations.bound_by(&grantor, &patient, &mut delegation, &now, age_of_majority)?;
+        }
+
+        Ok(self.delegations.assign(guardian, patient, delegation)?)
+    }
+
+    /// revoke the delegation of `guardian` for `patient`
+    pub fn revoke_guardian(&mut self, patient: &NIK, guardian: &Guardian) -> Result<(), String> {
+        match self.delegations.revoke(guardian, &self.owner_emrs.resolve(patient)) {
+            true => Ok(()),
+            false => Err("guardian not found".to_string()),
+        }
+    }
+
+    /// file a provider request to assign a guardian for `patient`, the delegation is only assigned once an admin
+    /// approves the request. a provider can't request a delegation for itself.
+    pub fn request_guardian(
+        &mut self,
+        request_id: Id,
+        patient: &NIK,
+        request: &AssignGuardianRequest,
+        requested_by: Principal,
+        authority: String
+    ) -> Result<(), String> {
+        let patient = self.owner_emrs.resolve(patient);
+
+        if !self.owner_emrs.is_known(&patient) {
+            return Err("patient not found".to_string());
+        }
+
+        if request.guardian.eq(&requested_by) {
+            return Err("provider cannot assign itself as guardian".to_string());
+        }
+
+        let request = GuardianRequest::new(patient, request, requested_by, authority)?;
+
+        Ok(self.guardian_requests.request(request_id, request)?)
+    }
+
+    /// approve a pending guardian request and assign the requested delegation, granted by `admin` under the
+    /// authority document referenced by the requesting provider. returns the approved request.
+    pub fn approve_guardian_request(
+        &mut self,
+        request_id: &Id,
+        admin: Principal,
+        age_of_majority: u8
+    ) -> Result<GuardianRequestDisplay, String> {
+        let pending = self.guardian_requests.pending_request(request_id)?;
+
+        let delegation = Delegation::new(&pending.request, admin, Some(pending.authority.clone()))?;
+        self.assign_guardian(&pending.patient, pending.request.guardian, delegation, age_of_majority)?;
+
+        self.guardian_requests.decide(request_id, admin, true, None)
+    }
+
+    /// reject a pending guardian request, returns the rejected request
+    pub fn reject_guardian_request(
+        &mut self,
+        request_id: &Id,
+        admin: Principal,
+        reason: String
+    ) -> Result<GuardianRequestDisplay, String> {
+        self.guardian_requests.decide(request_id, admin, false, Some(reason))
+    }
+
+    /// returns a page of the guardian requests waiting for admin review
+    pub fn pending_guardian_requests(&self, anchor: u64, max: usize) -> Vec<GuardianRequestDisplay> {
+        self.guardian_requests.pending(anchor, max)
+    }
+
+    /// returns every guardian assigned for `patient`, including the ones no longer active
+    pub fn guardians_of(&self, patient: &NIK, age_of_majority: u8) -> Vec<DelegationDisplay> {
+        self.delegations.guardians_of(&self.owner_emrs.resolve(patient), &Timestamp::new(), age_of_majority)
+    }
+
+    /// returns every patient `guardian` can currently act for
+    pub fn patients_of_guardian(&self, guardian: &Guardian, age_of_majority: u8) -> Vec<NIK> {
+        self.delegations.patients_of(guardian, &Timestamp::new(), age_of_majority)
+    }
+
+    /// check if `principal` has at least one active guardian delegation
+    pub fn is_valid_guardian(&self, principal: &Principal, age_of_majority: u8) -> bool {
+        !self.patients_of_guardian(principal, age_of_majority).is_empty()
+    }
+
+    /// check if `principal` is allowed to act for `patient` with the `required` scope,
+    /// either because it is the patient itself or an active guardian with a sufficient scope.
+    pub fn can_act_for(
+        &self,
+        principal: &Principal,
+        patient: &NIK,
+        required: DelegationScope,
+        age_of_majority: u8
+    ) -> bool {
+        let patient = self.owner_emrs.resolve(patient);
+
+        if self.patient_of(principal).is_some_and(|nik| nik.eq(&patient)) {
+            return true;
+        }
+
+        self.delegations
+            .active_scope(principal, &patient, &Timestamp::new(), age_of_majority)
+            .is_some_and(|scope| scope.allows(required))
+    }
+
+    /// check if `principal` may read the emr, either as its owner or as an active guardian of its owner
+    pub fn can_read_emr(&self, principal: &Principal, emr_id: &EmrId, age_of_majority: u8) -> bool {
+        self.is_owner_of_emr(principal, emr_id) ||
+            self
+                .patients_of_guardian(principal, age_of_majority)
+                .iter()
+                .any(|patient| self.owner_emrs.is_owner_of(patient, emr_id))
+    }
+
+    /// grant `provider` emergency read access to every emr of `patient` until `expires_at`, without patient consent.
+    /// the event is kept for admin review and is visible to the patient.
+    pub fn break_glass(
+        &mut self,
+        event_id: Id,
+        patient: &NIK,
+        provider: &InternalProviderId,
+        justification: String,
+        expires_at: Timestamp
+    ) -> Result<(), String> {
+        let patient = self.owner_emrs.resolve(patient);
+
+        if !self.owner_emrs.is_known(&patient) {
+            return Err("patient not found".to_string());
+        }
+
+        let event = BreakGlassEvent::new(patient.clone(), provider.clone(), justification, expires_at)?;
+        self.break_glass.record(event_id, event)?;
+
+        let grant = AccessGrant::new(AccessKind::BreakGlass, AccessScope::Read, expires_at);
+        self.grants.grant(provider, patient.clone(), grant)?;
+
+        let record = AccessRecord::new(provider.clone(), AccessAction::BreakGlass, None);
+        Ok(self.access_history.record(&patient, record)?)
+    }
+
+    /// record a provider access to an emr in the access history of the patient owning it
+    pub fn record_access(
+        &mut self,
+        emr_id: &EmrId,
+        provider: &InternalProviderId,
+        action: AccessAction
+    ) -> Result<(), String> {
+        let Some(patient) = self.owner_emrs.owner_of(emr_id) else {
+            return Err("emr owner not found".to_string());
+        };
+
+        let record = AccessRecord::new(provider.clone(), action, Some(emr_id.clone()));
+        Ok(self.access_history.record(&patient, record)?)
+    }
+
+    /// record a provider access to data kept per patient rather than per emr, e.g. vital signs
+    pub fn record_patient_access(
+        &mut self,
+        patient: &NIK,
+        provider: &InternalProviderId,
+        action: AccessAction
+    ) -> Result<(), String> {
+        let record = AccessRecord::new(provider.clone(), action, None);
+        Ok(self.access_history.record(&self.owner_emrs.resolve(patient), record)?)
+    }
+
+    /// returns the access history of `patient` matching `filter`, `display_name` resolves the provider display name
+    pub fn access_history(
+        &self,
+        patient: &NIK,
+        filter: &AccessHistoryFilter,
+        display_name: impl Fn(&InternalProviderId) -> Option<String>
+    ) -> Vec<AccessRecordDisplay> {
+        self.access_history.history_of(&self.owner_emrs.resolve(patient), filter, display_name)
+    }
+
+    /// mark a break glass event as reviewed by an admin
+    pub fn review_break_glass(
+        &mut self,
+        event_id: &Id,
+        admin: Principal,
+        note: String
+    ) -> Result<(), String> {
+        self.break_glass.review(event_id, admin, note)
+    }
+
+    /// returns every break glass event for `patient` in chronological order
+    pub fn break_glass_events_of(&self, patient: &NIK) -> Vec<BreakGlassEventDisplay> {
+        self.break_glass.events_of(&self.owner_emrs.resolve(patient))
+    }
+
+    /// returns a page of the break glass events not yet reviewed by an admin
+    pub fn pending_break_glass_reviews(&self, anchor: u64, max: usize) -> Vec<BreakGlassEventDisplay> {
+        self.break_glass.pending_review(anchor, max)
+    }
+
+    /// grant `provider` access with `scope` to every emr of `patient` until `expires_at` on the patient's behalf.
+    /// replaces any previous consent of the same provider for the same patient.
+    pub fn grant_access(
+        &mut self,
+        patient: &NIK,
+        provider: &InternalProviderId,
+        scope: AccessScope,
+        expires_at: Timestamp
+    ) -> Result<(), String> {
+        if expires_at.le(&Timestamp::new()) {
+            return Err("grant expiry must be in the future".to_string());
+        }
+
+        let patient = self.owner_emrs.resolve(patient);
+
+        self.grants.revoke(provider, &patient);
+
+        let grant = AccessGrant::new(AccessKind::Consent, scope, expires_at);
+        Ok(self.grants.grant(provider, patient, grant)?)
+    }
+
+    /// revoke the access of `provider` to the emrs of `patient`
+    pub fn revoke_access(&mut self, patient: &NIK, provider: &InternalProviderId) -> Result<(), String> {
+        match self.grants.revoke(provider, &self.owner_emrs.resolve(patient)) {
+            true => Ok(()),
+            false => Err("access grant not found".to_string()),
+        }
+    }
+
+    /// revoke at most `max` expired access grants, returns the number of revoked grants
+    pub fn sweep_expired_grants(&mut self, max: usize) -> usize {
+        self.grants.sweep_expired(&Timestamp::new(), max)
+    }
+
+    /// returns every active access grant for `patient` with its remaining lifetime
+    pub fn access_grants_of_patient(&self, patient: &NIK, anchor: u64, max: usize) -> Vec<AccessGrantDisplay> {
+        self.grants.grants_of_patient(&self.owner_emrs.resolve(patient), &Timestamp::new(), anchor, max)
+    }
+
+    /// returns every active access grant of `provider` with its remaining lifetime
+    pub fn access_grants_of_provider(&self, provider: &InternalProviderId) -> Vec<AccessGrantDisplay> {
+        self.grants.grants_of_provider(provider, &Timestamp::new())
+    }
+
+    /// check if `provider` currently has a grant covering the owner of the emr
+    pub fn has_access_to_emr(&self, provider: &InternalProviderId, emr_id: &EmrId) -> bool {
+        self.grants
+            .patients_of(provider, &Timestamp::new())
+            .iter()
+            .any(|patient| self.owner_emrs.is_owner_of(patient, emr_id))
+    }
+
+    /// check if `provider` currently has a write grant covering the owner of the emr
+    pub fn has_write_access_to_emr(&self, provider: &InternalProviderId, emr_id: &EmrId) -> bool {
+        let Some(patient) = self.owner_emrs.owner_of(emr_id) else {
+            return false;
+        };
+
+        self.grants.can_write(provider, &patient, &Timestamp::new())
+    }
+
+    /// check if `provider` currently has a grant covering `patient`
+    pub fn has_access_to_patient(&self, provider: &InternalProviderId, patient: &NIK) -> bool {
+        let patient = self.owner_emrs.resolve(patient);
+
+        self.grants
+            .patients_of(provider, &Timestamp::new())
+            .contains(&patient)
+    }
+
+    /// returns the primary identifier of the patient owning the emr
+    pub fn owner_of_emr(&self, emr_id: &EmrId) -> Option<NIK> {
+        self.owner_emrs.owner_of(emr_id)
+    }
+
+    pub fn is_owner_of_emr(&self, owner: &Principal, emr_id: &Id) -> bool {
+        let Some(nik) = self.owners.get_nik(owner) else {
+            return false;
+        };
+
+        self.owner_emrs.is_owner_of(&nik, emr_id)
+    }
+
+    pub fn update_emr(
+        &mut self,
+        emr_id: &Id,
+        key: AsciiRecordsKey,
+        value: impl Into<EmrRecordsValue>,
+        actor: Principal
+    ) -> Result<(), String> {
+        self.lifecycles.check_write(emr_id, true)?;
+
+        let Some(mut emr) = self.core_emrs.get_emr_mut(&emr_id) else {
+            return Err("emr not found".to_string());
+        };
+
+        let value = value.into();
+        let digest_input = value.as_str().to_string();
+
+        let update = emr.update_record(key.clone(), value)?;
+        drop(emr);
+
+        if !update {
+            return Err(format!("record with key {} not found", key));
+        }
+
+        self.summaries.apply(emr_id, &key, &digest_input, Timestamp::new())?;
+        self.chains.append(emr_id, ChangeKind::Update, key, Some(&digest_input), actor)?;
+
+        Ok(self.certify(emr_id)?)
+    }
+
+    /// add a record to the emr, overwriting the value if the key already exists. a record added to a final emr
+    /// is recorded as an addendum and moves the emr to [EmrStatus::Amended].
+    pub fn add_emr_record(
+        &mut self,
+        emr_id: &Id,
+        key: AsciiRecordsKey,
+        value: impl Into<EmrRecordsValue>,
+        actor: Principal
+    ) -> Result<(), String> {
+        let Some(mut emr) = self.core_emrs.get_emr_mut(emr_id) else {
+            return Err("emr not found".to_string());
+        };
+
+        self.lifecycles.check_write(emr_id, emr.records().contains_key(&key))?;
+
+        let value = value.into();
+        let digest_input = value.as_str().to_string();
+
+        emr.add_emr_record(key.clone(), value)?;
+        drop(emr);
+
+        let now = Timestamp::new();
+
+        if self.lifecycles.status(emr_id) != EmrStatus::Draft {
+            self.lifecycles.amend(emr_id, key.clone(), actor, now)?;
+        }
+
+        self.summaries.apply(emr_id, &key, &digest_input, now)?;
+        self.chains.append(emr_id, ChangeKind::Add, key, Some(&digest_input), actor)?;
+
+        Ok(self.certify(emr_id)?)
+    }
+
+    /// add several records to the emr at once, used for message feeds and lab reports that must be applied whole.
+    /// every key is checked against the emr lifecycle before anything is written, so a rejected record leaves
+    /// the emr untouched. the only failure left while writing is running out of stable memory, which traps
+    /// and rolls back the records already written.
+    pub fn add_emr_records(
+        &mut self,
+        emr_id: &Id,
+        records: Vec<(AsciiRecordsKey, String)>,
+        actor: Principal
+    ) -> Result<(), String> {
+        self.check_records_write(emr_id, &records)?;
+
+        for (key, value) in records {
+            self.add_emr_record(emr_id, key, value, actor).expect("stable memory exhausted while writing records");
+        }
+
+        Ok(())
+    }
+
+    /// add result records, e.g. lab results, to an emr without ever overwriting earlier results. a key already
+    /// used by the emr is written as `<key>.<n>` with the first free `n` starting from 2, the same way
+    /// [Self::add_addendum] picks its key. returns the keys the records were written to.
+    pub fn add_emr_results(
+        &mut self,
+        emr_id: &Id,
+        records: Vec<(AsciiRecordsKey, String)>,
+        actor: Principal
+    ) -> Result<Vec<AsciiRecordsKey>, String> {
+        let records = self.free_keys(emr_id, records)?;
+        let keys = records
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        self.add_emr_records(emr_id, records, actor)?;
+
+        Ok(keys)
+    }
+
+    /// move every record of a batch to a key not used by the emr nor by an earlier record of the batch
+    fn free_keys(
+        &self,
+        emr_id: &Id,
+        records: Vec<(AsciiRecordsKey, String)>
+    ) -> Result<Vec<(AsciiRecordsKey, String)>, String> {
+        let Some(emr) = self.core_emrs.get_emr(emr_id) else {
+            return Err("emr not found".to_string());
+        };
+
+        let mut taken = std::collections::HashSet::new();
+        let mut free = Vec::with_capacity(records.len());
+
+        for (key, value) in records {
+            let mut n = 1;
+            let mut candidate = key.clone();
+
+            while emr.records().contains_key(&candidate) || taken.contains(&candidate) {
+                n += 1;
+                candidate = AsciiRecordsKey::new(format!("{}.{}", key, n)).map_err(|e| e.to_string())?;
+            }
+
+            taken.insert(candidate.clone());
+            free.push((candidate, value));
+        }
+
+        Ok(free)
+    }
+
+    /// check every record of a batch may be written to the emr, a key repeated within the batch counts as an overwrite
+    fn check_records_write(&self, emr_id: &Id, records: &[(AsciiRecordsKey, String)]) -> Result<(), String> {
+        let Some(emr) = self.core_emrs.get_emr(emr_id) else {
+            return Err("emr not found".to_string());
+        };
+
+        let mut seen = std::collections::HashSet::new();
+
+        for (key, _) in records {
+            let overwrite = emr.records().contains_key(key) || !seen.insert(key);
+            self.lifecycles.check_write(emr_id, overwrite)?;
+        }
+
+        Ok(())
+    }
+
+    /// sign off a draft emr, from then on its records can only be amended with addenda
+    pub fn finalize_emr(&mut self, emr_id: &EmrId, by: Principal) -> Result<(), String> {
+        if self.core_emrs.get_emr(emr_id).is_none() {
+            return Err("emr not found".to_string());
+        }
+
+        self.lifecycles.finalize(emr_id, by, Timestamp::new())
+    }
+
+    /// append an addendum to a final emr as a new `addendum.<n>` record, returns the key it was written to.
+    /// see [Self::add_emr_record] for how the addendum is recorded in the lifecycle.
+    pub fn add_addendum(&mut self, emr_id: &EmrId, text: String, author: Principal) -> Result<AsciiRecordsKey, String> {
+        self.lifecycles.check_amend(emr_id)?;
+
+        if text.trim().is_empty() {
+            return Err("addendum must not be empty".to_string());
+        }
+
+        let Some(emr) = self.core_emrs.get_emr(emr_id) else {
+            return Err("emr not found".to_string());
+        };
+
+        // lab results written after sign off count as addenda too, so look for the first free key instead
+        let mut n = 1;
+        let key = loop {
+            let key = AsciiRecordsKey::new(format!("addendum.{}", n)).map_err(|e| e.to_string())?;
+
+            if !emr.records().contains_key(&key) {
+                break key;
+            }
+
+            n += 1;
+        };
+        drop(emr);
+
+        self.add_emr_record(emr_id, key.clone(), text, author)?;
+
+        Ok(key)
+    }
+
+    /// mark an emr written for the wrong patient or visit, it stays readable but can't be written to anymore
+    pub fn mark_emr_entered_in_error(&mut self, emr_id: &EmrId, by: Principal, reason: String) -> Result<(), String> {
+        if self.core_emrs.get_emr(emr_id).is_none() {
+            return Err("emr not found".to_string());
+        }
+
+        self.lifecycles.mark_entered_in_error(emr_id, by, reason, Timestamp::new())
+    }
+
+    pub fn emr_lifecycle(&self, emr_id: &EmrId) -> Option<LifecycleDisplay> {
+        self.core_emrs.get_emr(emr_id)?;
+
+        Some(self.lifecycles.get(emr_id))
+    }
+
+    /// recompute the change history chain of an emr and check its current records against it
+    pub fn verify_emr_chain(&self, emr_id: &EmrId) -> Option<ChainVerification> {
+        let emr = self.core_emrs.get_emr(emr_id)?;
+
+        Some(self.chains.verify(emr_id, &emr.records().sorted_pairs()))
+    }
+
+    /// restage the content hash of an emr in the certified data tree and commit it
+    fn certify(&mut self, emr_id: &EmrId) -> Result<(), OutOfMemory> {
+        match self.core_emrs.get_emr(emr_id) {
+            Some(emr) => {
+                let emr = EmrDisplay::from_stable_ref(&*emr);
+                self.certified.stage(emr_id.clone(), &emr)?;
+            }
+            None => self.certified.stage_removal(emr_id),
+        }
+
+        self.certified.commit();
+
+        Ok(())
+    }
+
+    /// witness of the content hashes of `emr_ids` in the certified data tree
+    pub fn emr_witness(&self, emr_ids: &[EmrId]) -> HashTree {
+        self.certified.witness(emr_ids)
+    }
+
+    pub fn is_valid_patient(&self, owner: &patient::Owner) -> bool {
+        self.owners.is_valid_owner(owner)
+    }
+
+    pub fn get_emr(&self, emr_id: &Id) -> Option<SRef<'_, Emr>> {
+        self.core_emrs.get_emr(emr_id)
+    }
+
+    /// check the emr exists and is not entered in error, which only keeps it around for the audit trail
+    pub fn check_in_use(&self, emr_id: &EmrId) -> Result<(), String> {
+        if self.core_emrs.get_emr(emr_id).is_none() {
+            return Err("emr not found".to_string());
+        }
+
+        match self.lifecycles.status(emr_id) {
+            EmrStatus::EnteredInError => Err("emr is entered in error".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// start a chunked attachment upload linked to records key `key` of an existing emr
+    #[allow(clippy::too_many_arguments)]
+    pub fn begin_attachment(
+        &mut self,
+        attachment_id: AttachmentId,
+        emr_id: &EmrId,
+        key: AsciiRecordsKey,
+        provider: InternalProviderId,
+        mime_type: String,
+        size: u64,
+        sha256: [u8; 32],
+        default_quota: u64
+    ) -> Result<(), String> {
+        self.check_in_use(emr_id)?;
+
+        self.attachments.begin(attachment_id, emr_id.clone(), key, provider, mime_type, size, sha256, default_quota)
+    }
+
+    pub fn append_attachment_chunk(
+        &mut self,
+        attachment_id: &AttachmentId,
+        provider: &InternalProviderId,
+        index: u64,
+        data: Vec<u8>
+    ) -> Result<(), String> {
+        self.attachments.append(attachment_id, provider, index, data)
+    }
+
+    pub fn finish_attachment(
+        &mut self,
+        attachment_id: &AttachmentId,
+        provider: &InternalProviderId
+    ) -> Result<AttachmentDisplay, String> {
+        self.attachments.finish(attachment_id, provider)
+    }
+
+    pub fn abort_attachment(&mut self, attachment_id: &AttachmentId, provider: &InternalProviderId) -> Result<(), String> {
+        self.attachments.abort(attachment_id, provider)
+    }
+
+    pub fn attachment(&self, attachment_id: &AttachmentId) -> Option<AttachmentDisplay> {
+        self.attachments.get(attachment_id)
+    }
+
+    pub fn attachment_chunk(&self, attachment_id: &AttachmentId, index: u64) -> Result<Vec<u8>, String> {
+        self.attachments.chunk(attachment_id, index)
+    }
+
+    pub fn attachments_of_emr(&self, emr_id: &EmrId) -> Vec<AttachmentDisplay> {
+        self.attachments.of_emr(emr_id)
+    }
+
+    pub fn attachment_usage(&self, provider: &InternalProviderId, default_quota: u64) -> AttachmentUsage {
+        self.attachments.quota_of(provider, default_quota)
+    }
+
+    pub fn set_attachment_quota(&mut self, provider: InternalProviderId, quota: u64) -> Result<(), OutOfMemory> {
+        self.attachments.set_quota(provider, quota)
+    }
+
+    /// release at most `max` uploads started before `cutoff` that were never finished
+    pub fn sweep_abandoned_attachments(&mut self, cutoff: &Timestamp, max: usize) -> usize {
+        self.attachments.sweep_abandoned(cutoff, max)
+    }
+
+    pub fn store_certificate(&mut self, issued: IssuedCertificate) -> Result<CertificateDisplay, String> {
+        // the emr may have been erased or entered in error while the certificate was being signed
+        self.check_in_use(&issued.emr_id)?;
+
+        self.certificates.insert(issued)
+    }
+
+    pub fn revoke_certificate(
+        &mut self,
+        certificate_id: &CertificateId,
+        provider: &InternalProviderId,
+        reason: String
+    ) -> Result<(), String> {
+        self.certificates.revoke(certificate_id, provider, reason)
+    }
+
+    pub fn certificates_of_emr(&self, emr_id: &EmrId) -> Vec<CertificateDisplay> {
+        self.certificates.of_emr(emr_id)
+    }
+
+    pub fn verify_certificate(&self, code: &str) -> CertificateVerification {
+        self.certificates.verify(code)
+    }
+
+    pub fn issue_prescription(
+        &mut self,
+        prescription_id: PrescriptionId,
+        code: PrescriptionCode,
+        emr_id: &EmrId,
+        prescriber: InternalProviderId,
+        input: PrescriptionInput
+    ) -> Result<PrescriptionDisplay, String> {
+        self.check_in_use(emr_id)?;
+
+        self.prescriptions.issue(prescription_id, code, emr_id.clone(), prescriber, input)
+    }
+
+    pub fn cancel_prescription(
+        &mut self,
+        prescription_id: &PrescriptionId,
+        prescriber: &InternalProviderId,
+        reason: String
+    ) -> Result<(), String> {
+        self.prescriptions.cancel(prescription_id, prescriber, reason)
+    }
+
+    pub fn prescription_by_code(&self, code: &PrescriptionCode) -> Option<PrescriptionDisplay> {
+        self.prescriptions.lookup(code, &Timestamp::new())
+    }
+
+    pub fn dispense_prescription(
+        &mut self,
+        code: &PrescriptionCode,
+        pharmacy: InternalPharmacyId,
+        amount: u32
+    ) -> Result<PrescriptionDisplay, String> {
+        let now = Timestamp::new();
+
+        // a prescription of an emr entered in error was never meant for the patient
+        if let Some(prescription) = self.prescriptions.lookup(code, &now) {
+            if self.lifecycles.status(prescription.emr_id()) == EmrStatus::EnteredInError {
+                return Err("prescription belongs to an emr entered in error".to_string());
+            }
+        }
+
+        self.prescriptions.dispense(code, pharmacy, amount, &now)
+    }
+
+    pub fn prescriptions_of_emr(&self, emr_id: &EmrId) -> Vec<PrescriptionDisplay> {
+        self.prescriptions.of_emr(emr_id, &Timestamp::new())
+    }
+
+    pub fn create_lab_order(
+        &mut self,
+        order_id: LabOrderId,
+        emr_id: &EmrId,
+        provider: InternalProviderId,
+        input: LabOrderInput
+    ) -> Result<LabOrderDisplay, String> {
+        self.check_in_use(emr_id)?;
+
+        self.lab_orders.create(order_id, emr_id.clone(), provider, input)
+    }
+
+    pub fn cancel_lab_order(&mut self, order_id: &LabOrderId, provider: &InternalProviderId) -> Result<(), String> {
+        self.lab_orders.cancel(order_id, provider)
+    }
+
+    /// record the results of a lab order and attach them to the originating emr as records, with the reporting
+    /// laboratory principal as the actor of the change history
+    pub fn report_lab_results(
+        &mut self,
+        order_id: &LabOrderId,
+        laboratory: &InternalLaboratoryId,
+        reporter: Principal,
+        results: Vec<LabResultInput>
+    ) -> Result<LabOrderDisplay, String> {
+        let Some(order) = self.lab_orders.get(order_id) else {
+            return Err("lab order not found".to_string());
+        };
+
+        let emr_id = order.emr_id().clone();
+
+        // the emr must accept every result before the order is marked completed. results of an earlier order
+        // for the same test are kept, so every order gets keys of its own
+        let records = self.lab_orders.check_report(order_id, laboratory, &results)?;
+        let records = self.free_keys(&emr_id, records)?;
+        self.check_records_write(&emr_id, &records)?;
+
+        self.lab_orders.report(order_id, laboratory, reporter, results)?;
+        self.add_emr_records(&emr_id, records, reporter)?;
+
+        Ok(self.lab_orders.get(order_id).expect("order was just reported"))
+    }
+
+    pub fn lab_order(&self, order_id: &LabOrderId) -> Option<LabOrderDisplay> {
+        self.lab_orders.get(order_id)
+    }
+
+    pub fn lab_orders_of_emr(&self, emr_id: &EmrId) -> Vec<LabOrderDisplay> {
+        self.lab_orders.of_emr(emr_id)
+    }
+
+    pub fn pending_lab_orders(&self, laboratory: &InternalLaboratoryId) -> Vec<LabOrderDisplay> {
+        self.lab_orders.pending_of(laboratory)
+    }
+
+    pub fn prepare_submission(&mut self, emr_id: &EmrId, provider: &InternalProviderId) -> Result<(), String> {
+        self.satusehat.prepare(emr_id.clone(), provider.clone())
+    }
+
+    pub fn report_submission(&mut self, emr_id: &EmrId, result: SubmissionResult) -> Result<(), String> {
+        self.satusehat.report(emr_id, result)
+    }
+
+    pub fn reject_submission(&mut self, emr_id: &EmrId, reason: String) -> Result<(), String> {
+        self.satusehat.reject(emr_id, reason)
+    }
+
+    pub fn submission_status(&self, emr_id: &EmrId) -> Option<SubmissionDisplay> {
+        self.satusehat.status(emr_id)
+    }
+
+    pub fn pending_submissions_of(&self, provider: &InternalProviderId) -> Vec<SubmissionDisplay> {
+        self.satusehat.pending_of(provider)
+    }
+
+    pub fn open_encounter(
+        &mut self,
+        encounter_id: EncounterId,
+        patient: &NIK,
+        attending: InternalProviderId,
+        input: EncounterInput
+    ) -> Result<EncounterDisplay, String> {
+        let patient = self.owner_emrs.resolve(patient);
+
+        self.encounters.open(encounter_id, patient, attending, input, Timestamp::new())
+    }
+
+    pub fn close_encounter(
+        &mut self,
+        encounter_id: &EncounterId,
+        attending: &InternalProviderId,
+        ended_at: Option<Timestamp>
+    ) -> Result<EncounterDisplay, String> {
+        self.encounters.close(encounter_id, attending, ended_at, Timestamp::new())
+    }
+
+    /// link an emr to an encounter of the patient owning the emr
+    pub fn link_emr_to_encounter(&mut self, emr_id: &EmrId, encounter_id: &EncounterId) -> Result<(), String> {
+        let Some(owner) = self.owner_of_emr(emr_id) else {
+            return Err("emr not found".to_string());
+        };
+
+        let Some(patient) = self.encounters.patient_of(encounter_id) else {
+            return Err("encounter not found".to_string());
+        };
+
+        if self.owner_emrs.resolve(&patient).ne(&self.owner_emrs.resolve(&owner)) {
+            return Err("emr and encounter belong to different patients".to_string());
+        }
+
+        self.encounters.link(emr_id, encounter_id)
     }
 
-    /// register new emr to the system, returns [OutOfMemory] if stable memory is exhausted
-    pub fn register_emr(
-        &mut self,
-        emr: Emr,
-        user_id: InternalBindingKey
-    ) -> Result<EmrId, OutOfMemory> {
-        let emr_id = emr.id().clone();
+    /// encounters of `patient` with their linked emrs, ordered by start
+    pub fn encounters_of(&self, patient: &NIK) -> Vec<EncounterDisplay> {
+        self.encounters.of_patient(&self.owner_emrs.resolve(patient))
+    }
 
-        self.core_emrs.new_emr(emr)?;
-        self.owner_emrs.issue_for(&user_id, emr_id.clone());
+    /// problems, allergies and medications recorded across every emr bound to `patient`
+    pub fn clinical_summary(&self, patient: &NIK) -> ClinicalSummary {
+        // emrs entered in error don't describe the patient
+        let emr_ids = self
+            .emr_list(patient)
+            .into_iter()
+            .filter(|emr_id| self.lifecycles.status(emr_id) != EmrStatus::EnteredInError)
+            .collect::<Vec<_>>();
 
-        Ok(emr_id)
+        self.summaries.summary(&emr_ids)
     }
 
-    /// register new patient to the system, returns [OutOfMemory] if stable memory is exhausted
-    pub fn register_patient(
+    /// record vital signs or other measurements of `patient`
+    pub fn record_vitals(
         &mut self,
-        owner: Principal,
-        hashed_nik: NIK
-    ) -> Result<(), OutOfMemory> {
-        self.owners.bind(owner, hashed_nik)
+        patient: &NIK,
+        source: ObservationSource,
+        observations: Vec<VitalInput>
+    ) -> Result<(), String> {
+        self.vitals.record(&self.owner_emrs.resolve(patient), source, observations, Timestamp::new())
     }
 
-    /// rebind patient to a new hashed_nik, returns [OutOfMemory] if stable memory is exhausted
-    pub fn rebind_patient(&mut self, owner: Principal, hashed_nik: NIK) -> Result<(), OutOfMemory> {
-        self.owners.bind(owner, hashed_nik)
+    pub fn vitals_of(&self, patient: &NIK, query: &VitalsQuery) -> Result<VitalSeries, String> {
+        self.vitals.query(&self.owner_emrs.resolve(patient), query)
     }
 
-    /// revoke patient access, if this method is called then the patient will no longer be able to access their emr. it will remove the [NIK]
-    /// from the owner map so attempting to access NIK owner will fail.
-    pub fn revoke_patient_access(&mut self, owner: &Principal) {
-        self.owners.revoke(owner)
-    }
+    /// file an erasure request for every emr of `patient`, the request waits for admin approval
+    pub fn request_erasure(
+        &mut self,
+        request_id: Id,
+        patient: &NIK,
+        requested_by: Principal
+    ) -> Result<(), String> {
+        let patient = self.owner_emrs.resolve(patient);
 
-    pub fn is_owner_of_emr(&self, owner: &Principal, emr_id: &Id) -> bool {
-        let Some(nik) = self.owners.get_nik(owner) else {
-            return false;
-        };
+        // a patient may only have measurements or encounters and no emr yet
+        let known = self.owner_emrs.is_known(&patient) ||
+            self.vitals.has_patient(&patient) ||
+            self.encounters.has_patient(&patient);
 
-        self.owner_emrs.is_owner_of(&nik, emr_id)
+        if !known {
+            return Err("patient not found".to_string());
+        }
+
+        self.retention.request(request_id, ErasureRequest::new(patient, requested_by))
     }
 
-    pub fn update_emr(
+    /// approve a pending erasure request. every emr of the patient is scheduled to be purged once it has been
+    /// kept for `min_retention` nanoseconds since its last update, emrs already past that are purged on the next sweep.
+    /// returns the patient of the request.
+    pub fn approve_erasure(
         &mut self,
-        emr_id: &Id,
-        key: AsciiRecordsKey,
-        value: impl Into<EmrRecordsValue>
-    ) -> Result<(), String> {
-        let Some(mut emr) = self.core_emrs.get_emr_mut(&emr_id) else {
-            return Err("emr not found".to_string());
-        };
+        request_id: &Id,
+        admin: Principal,
+        min_retention: u64
+    ) -> Result<NIK, String> {
+        let patient = self.retention.decide(request_id, admin, true, None)?;
+        let now = Timestamp::new();
+        let mut scheduled = false;
+
+        for emr_id in self.emr_list(&patient) {
+            let Some(emr) = self.core_emrs.get_emr(&emr_id) else {
+                continue;
+            };
+
+            let retained_until = Timestamp(emr.updated_at().inner().saturating_add(min_retention));
+            drop(emr);
+
+            let eligible_at = std::cmp::max(now, retained_until);
+            self.retention.schedule(eligible_at, emr_id, request_id.clone())?;
+            scheduled = true;
+        }
 
-        let value = value.into();
+        // measurements and encounters are erased with the last emr, or right away if there is no emr left to wait for
+        if !scheduled {
+            self.vitals.remove_patient(&patient);
+            self.encounters.remove_patient(&patient);
+        }
 
-        let update = emr.update_record(key.clone(), value)?;
+        Ok(patient)
+    }
+
+    /// reject a pending erasure request, returns the patient of the request
+    pub fn reject_erasure(
+        &mut self,
+        request_id: &Id,
+        admin: Principal,
+        reason: String
+    ) -> Result<NIK, String> {
+        self.retention.decide(request_id, admin, false, Some(reason))
+    }
 
-        match update {
-            true => { Ok(()) }
-            false => Err(format!("record with key {} not found", key)),
+    /// purge at most `max` emrs whose retention period has passed, freeing their stable memory and leaving a
+    /// [Tombstone] in their place. patient and provider bindings are kept so the emr id keeps resolving.
+    /// the patient's vital signs and encounters are purged together with the last emr of the erasure request.
+    /// a purge is only taken off the queue once its emr is erased, so running out of memory retries it on the next sweep.
+    /// an emr updated since the request was approved is kept for `min_retention` nanoseconds after that update.
+    /// returns the number of purged emrs.
+    pub fn purge_expired_emrs(&mut self, max: usize, min_retention: u64) -> Result<usize, OutOfMemory> {
+        let now = Timestamp::new();
+        let due = self.retention.due(&now, max);
+        let mut purged = 0;
+
+        for purge in due {
+            let (_, emr_id, request_id) = purge.clone();
+
+            let Some((created_at, updated_at)) = self.core_emrs
+                .get_emr(&emr_id)
+                .map(|emr| (emr.created_at(), emr.updated_at())) else {
+                self.retention.unschedule(&purge);
+                continue;
+            };
+
+            let retained_until = Timestamp(updated_at.inner().saturating_add(min_retention));
+
+            if retained_until.gt(&now) {
+                self.retention.schedule(retained_until, emr_id, request_id)?;
+                self.retention.unschedule(&purge);
+                continue;
+            }
+
+            // bury first, if it fails the emr and its queue entry are left untouched
+            self.retention.bury(emr_id.clone(), Tombstone::new(request_id.clone(), created_at))?;
+
+            self.core_emrs.remove(&emr_id);
+            self.attachments.remove_emr(&emr_id);
+            self.certificates.remove_emr(&emr_id);
+            self.prescriptions.remove_emr(&emr_id);
+            self.lab_orders.remove_emr(&emr_id);
+            self.summaries.remove_emr(&emr_id);
+            self.encounters.unlink(&emr_id);
+            self.lifecycles.remove_emr(&emr_id);
+            self.satusehat.remove_emr(&emr_id);
+            self.certified.stage_removal(&emr_id);
+            self.chains.remove(&emr_id);
+            self.retention.unschedule(&purge);
+            purged += 1;
+
+            if !self.retention.is_scheduled(&request_id) {
+                if let Some(patient) = self.retention.patient_of(&request_id) {
+                    self.vitals.remove_patient(&patient);
+                    self.encounters.remove_patient(&patient);
+                }
+            }
         }
+
+        self.certified.commit();
+
+        Ok(purged)
     }
 
-    pub fn is_valid_patient(&self, owner: &patient::Owner) -> bool {
-        self.owners.is_valid_owner(owner)
+    /// returns the tombstone of an erased emr, none if the emr was never erased
+    pub fn emr_tombstone(&self, emr_id: &EmrId) -> Option<Tombstone> {
+        self.retention.tombstone(emr_id)
     }
 
-    pub fn get_emr(&self, emr_id: &Id) -> Option<SRef<'_, Emr>> {
-        self.core_emrs.get_emr(emr_id)
+    /// returns every erasure request of `patient`
+    pub fn erasure_requests_of(&self, patient: &NIK) -> Vec<ErasureRequestDisplay> {
+        self.retention.requests_of(&self.owner_emrs.resolve(patient))
+    }
+
+    /// returns a page of the erasure requests waiting for admin review
+    pub fn pending_erasure_requests(&self, anchor: u64, max: usize) -> Vec<ErasureRequestDisplay> {
+        self.retention.pending(anchor, max)
     }
 }
 
@@ -246,6 +1372,24 @@ impl Emr {
             Self::V001(v) => &v.emr_id,
         }
     }
+
+    pub fn created_at(&self) -> Timestamp {
+        match self {
+            Self::V001(v) => v.created_at,
+        }
+    }
+
+    pub fn updated_at(&self) -> Timestamp {
+        match self {
+            Self::V001(v) => v.updated_at,
+        }
+    }
+
+    pub fn records(&self) -> &Records {
+        match self {
+            Self::V001(v) => &v.records,
+        }
+    }
 }
 
 impl std::cmp::Ord for Emr {
@@ -260,13 +1404,47 @@ impl std::cmp::PartialOrd for Emr {
     }
 }
 
-#[derive(Clone, CandidType, Deserialize)]
+#[derive(Clone, CandidType, Deserialize, serde::Serialize)]
 pub enum EmrDisplay {
     V001(DisplayV001),
 }
 
 impl ResonpseMarker for EmrDisplay {}
 
+impl EmrDisplay {
+    pub fn emr_id(&self) -> &Id {
+        match self {
+            Self::V001(v) => &v.emr_id,
+        }
+    }
+
+    pub fn created_at(&self) -> Timestamp {
+        match self {
+            Self::V001(v) => v.created_at,
+        }
+    }
+
+    pub fn updated_at(&self) -> Timestamp {
+        match self {
+            Self::V001(v) => v.updated_at,
+        }
+    }
+
+    /// records as a json object of key to string value
+    pub fn records(&self) -> &serde_json::Value {
+        match self {
+            Self::V001(v) => &v.records.0,
+        }
+    }
+
+    /// attach the resolved coded records, see [crate::terminology::Terminology::codings]
+    pub fn with_codings(self, coded: Vec<CodedRecord>) -> Self {
+        match self {
+            Self::V001(v) => Self::V001(DisplayV001 { coded, ..v }),
+        }
+    }
+}
+
 impl FromStableRef for EmrDisplay {
     type From = Emr;
 
@@ -389,6 +1567,44 @@ impl Records {
         Self::default()
     }
 
+    /// build records from plain key value pairs, returns an error if a key is not a valid [AsciiRecordsKey]
+    /// or stable memory is exhausted
+    pub fn from_pairs(
+        pairs: impl IntoIterator<Item = (String, String)>
+    ) -> Result<Self, String> {
+        let mut records = Records::default();
+
+        for (k, v) in pairs {
+            records
+                .insert(
+                    AsciiRecordsKey::new(k).map_err(|e| e.to_string())?,
+                    EmrRecordsValue::new(v)?
+                )
+                .map_err(OutOfMemory::from)?;
+        }
+
+        Ok(records)
+    }
+
+    /// returns the records as plain key value pairs
+    /// records ordered by key, the order records of a new emr are appended to its change history in
+    pub fn sorted_pairs(&self) -> Vec<(AsciiRecordsKey, String)> {
+        let mut pairs = self
+            .iter()
+            .map(|(key, value)| ((*key).clone(), value.as_str().to_string()))
+            .collect::<Vec<_>>();
+
+        pairs.sort_by(|a, b| a.0.cmp(&b.0));
+        pairs
+    }
+
+    pub fn to_pairs(&self) -> std::collections::BTreeMap<String, String> {
+        self.0
+            .iter()
+            .map(|(k, v)| (k.to_string(), (*v.0).clone()))
+            .collect()
+    }
+
     pub fn to_value(&self) -> serde_json::Value {
         self.0
             .iter()
@@ -464,6 +1680,14 @@ impl<'de> serde::Deserialize<'de> for RecrodsDisplay {
     }
 }
 
+// serialized as the json object itself for json consumers such as the http gateway, unlike candid which
+// carries it as text
+impl serde::Serialize for RecrodsDisplay {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
+        self.0.serialize(serializer)
+    }
+}
+
 impl CandidType for RecrodsDisplay {
     fn _ty() -> candid::types::Type {
         candid::types::Type::Text
@@ -504,11 +1728,20 @@ impl ModifyEmr for V001 {
         key: AsciiRecordsKey,
         value: EmrRecordsValue
     ) -> Result<(), OutOfMemory> {
-        self.records.add_emr_record(key, value)
+        self.records.add_emr_record(key, value)?;
+        self.updated_at = Timestamp::new();
+
+        Ok(())
     }
 
     fn remove_record(&mut self, key: &AsciiRecordsKey) -> bool {
-        self.records.remove_record(key)
+        let removed = self.records.remove_record(key);
+
+        if removed {
+            self.updated_at = Timestamp::new();
+        }
+
+        removed
     }
 
     fn update_record(
@@ -516,7 +1749,13 @@ impl ModifyEmr for V001 {
         key: AsciiRecordsKey,
         value: EmrRecordsValue
     ) -> Result<bool, OutOfMemory> {
-        self.records.update_record(key, value)
+        let updated = self.records.update_record(key, value)?;
+
+        if updated {
+            self.updated_at = Timestamp::new();
+        }
+
+        Ok(updated)
     }
 }
 
@@ -568,19 +1807,570 @@ impl FromStableRef for DisplayV001 {
             created_at: sref.created_at,
             updated_at: sref.updated_at,
             records: RecrodsDisplay::from_stable_ref(&sref.records),
+            coded: vec![],
         }
     }
 }
-#[derive(Debug, CandidType, Clone, Deserialize)]
+#[derive(Debug, CandidType, Clone, Deserialize, serde::Serialize)]
 pub struct DisplayV001 {
     emr_id: Id,
     created_at: Timestamp,
     updated_at: Timestamp,
     records: RecrodsDisplay,
+    /// coded records resolved to their display names, only filled on reads. left out of the json when empty
+    /// so certified and exported emrs keep their content.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    coded: Vec<CodedRecord>,
 }
 
 impl DisplayV001 {
     pub fn new(emr_id: Id, records: RecrodsDisplay) -> Self {
-        Self { emr_id, created_at: Timestamp::new(), updated_at: Timestamp::new(), records }
+        Self { emr_id, created_at: Timestamp::new(), updated_at: Timestamp::new(), records, coded: vec![] }
+    }
+
+    #[cfg(test)]
+    pub fn with_timestamps(
+        emr_id: Id,
+        created_at: Timestamp,
+        updated_at: Timestamp,
+        records: RecrodsDisplay
+    ) -> Self {
+        Self { emr_id, created_at, updated_at, records, coded: vec![] }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ emr::patient::IdentifierType, types::fixtures::nik };
+
+    fn actor() -> Principal {
+        Principal::anonymous()
+    }
+
+    fn new_emr(records: &[(&str, &str)]) -> Emr {
+        let mut emr = V001::new(Id::from(uuid::Uuid::new_v4()), Records::new());
+
+        for (key, value) in records {
+            emr.add_emr_record(AsciiRecordsKey::new(key).unwrap(), EmrRecordsValue::new(*value).unwrap()).unwrap();
+        }
+
+        emr.into()
+    }
+
+    fn registry_with_emr(patient: &NIK, records: &[(&str, &str)]) -> (EmrRegistry, EmrId) {
+        ic_stable_memory::stable_memory_init();
+
+        let mut registry = EmrRegistry::new();
+        let emr_id = registry.register_emr(new_emr(records), patient.clone(), actor()).unwrap();
+
+        (registry, emr_id)
+    }
+
+    #[test]
+    fn test_merged_patient_resolves_to_surviving_patient() {
+        let (mut registry, first) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        registry.register_emr(new_emr(&[("diagnosis", "cough")]), nik(2), actor()).unwrap();
+
+        registry.merge_patients(&nik(1), &nik(2)).unwrap();
+
+        assert_eq!(registry.patient_identifiers(&nik(1))[0], nik(2));
+        assert_eq!(registry.owner_of_emr(&first), Some(nik(2)));
+
+        // emrs issued with the merged identifier land on the surviving patient
+        let second = registry.register_emr(new_emr(&[("diagnosis", "fever")]), nik(1), actor()).unwrap();
+        assert!(registry.emr_list(&nik(2)).contains(&second));
+        assert_eq!(registry.emr_list(&nik(1)), registry.emr_list(&nik(2)));
+
+        assert!(registry.merge_patients(&nik(1), &nik(2)).is_err());
+    }
+
+    #[test]
+    fn test_linked_identifier_transition() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let bpjs = NIK::new(IdentifierType::Bpjs, [1; 32]);
+
+        let transition = registry.link_patient_identifier(&nik(1), bpjs.clone()).unwrap();
+
+        assert!(transition.before[1].emrs.is_empty());
+        assert_eq!(transition.after[1].emrs, vec![emr_id]);
+        assert_eq!(registry.patient_identifiers(&bpjs), vec![nik(1), bpjs.clone()]);
+
+        assert!(registry.link_patient_identifier(&nik(2), bpjs).is_err());
+    }
+
+    #[test]
+    fn test_grants_follow_merged_patient() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+        let expires_at = Timestamp(Timestamp::new().inner() + 60 * 1_000_000_000);
+
+        registry.grant_access(&nik(1), &provider, AccessScope::Read, expires_at).unwrap();
+        registry.register_emr(new_emr(&[("diagnosis", "cough")]), nik(2), actor()).unwrap();
+
+        registry.merge_patients(&nik(1), &nik(2)).unwrap();
+
+        assert!(registry.has_access_to_patient(&provider, &nik(2)));
+        assert!(registry.has_access_to_emr(&provider, &emr_id));
+        assert_eq!(registry.access_grants_of_patient(&nik(2), 0, 10).len(), 1);
+        assert_eq!(registry.access_grants_of_provider(&provider).len(), 1);
+
+        // revoking through either identifier removes the merged grant
+        registry.revoke_access(&nik(1), &provider).unwrap();
+        assert!(!registry.has_access_to_emr(&provider, &emr_id));
+    }
+
+    #[test]
+    fn test_access_history_follows_merged_patient() {
+        let (mut registry, from_emr) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let into_emr = registry.register_emr(new_emr(&[("diagnosis", "cough")]), nik(2), actor()).unwrap();
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+        let expires_at = Timestamp(Timestamp::new().inner() + 60 * 1_000_000_000);
+
+        registry.record_access(&into_emr, &provider, AccessAction::Read).unwrap();
+        let event_id = Id::from(uuid::Uuid::new_v4());
+        registry.break_glass(event_id, &nik(1), &provider, "unconscious".to_string(), expires_at).unwrap();
+        registry.record_access(&from_emr, &provider, AccessAction::Read).unwrap();
+        registry.record_access(&into_emr, &provider, AccessAction::Update).unwrap();
+
+        registry.merge_patients(&nik(1), &nik(2)).unwrap();
+
+        let filter = AccessHistoryFilter { from: None, to: None, anchor: 0, max: 10 };
+        let history = registry.access_history(&nik(2), &filter, |_| None);
+        assert_eq!(history.len(), 4);
+        assert!(history.windows(2).all(|pair| pair[0].timestamp.le(&pair[1].timestamp)));
+        assert_eq!(history[3].action, AccessAction::Update);
+
+        // the merged identifier resolves to the same history
+        assert_eq!(registry.access_history(&nik(1), &filter, |_| None).len(), 4);
+        assert_eq!(registry.break_glass_events_of(&nik(2)).len(), 1);
+    }
+
+    #[test]
+    fn test_erasure_follows_merged_patient() {
+        let (mut registry, from_emr) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let into_emr = registry.register_emr(new_emr(&[("diagnosis", "cough")]), nik(2), actor()).unwrap();
+        let request_id = Id::from(uuid::Uuid::new_v4());
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+
+        registry.prepare_submission(&from_emr, &provider).unwrap();
+        registry.request_erasure(request_id.clone(), &nik(1), actor()).unwrap();
+        registry.merge_patients(&nik(1), &nik(2)).unwrap();
+
+        assert_eq!(registry.erasure_requests_of(&nik(2)).len(), 1);
+        assert!(registry.request_erasure(Id::from(uuid::Uuid::new_v4()), &nik(2), actor()).is_err());
+
+        registry.approve_erasure(&request_id, actor(), 0).unwrap();
+
+        assert_eq!(registry.purge_expired_emrs(1, 0).unwrap(), 1);
+        assert_eq!(registry.purge_expired_emrs(10, 0).unwrap(), 1);
+        assert_eq!(registry.purge_expired_emrs(10, 0).unwrap(), 0);
+
+        for emr_id in [from_emr, into_emr] {
+            assert!(registry.get_emr(&emr_id).is_none());
+            assert!(registry.emr_tombstone(&emr_id).is_some());
+            assert!(registry.submission_status(&emr_id).is_none());
+        }
+    }
+
+    #[test]
+    fn test_vitals_are_purged_with_the_last_emr() {
+        use crate::emr::vitals::{ VitalKind, VitalPoints };
+
+        let (mut registry, _) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        registry.register_emr(new_emr(&[("diagnosis", "cough")]), nik(1), actor()).unwrap();
+
+        let input = VitalInput { kind: VitalKind::HeartRate, value: 72.0, measured_at: None, device: None };
+        registry.record_vitals(&nik(1), ObservationSource::Patient(actor()), vec![input]).unwrap();
+
+        let query = VitalsQuery { kind: VitalKind::HeartRate, from: None, to: None, bucket: None };
+        let samples = |registry: &EmrRegistry| {
+            let VitalPoints::Samples(samples) = registry.vitals_of(&nik(1), &query).unwrap().points else {
+                panic!("expected raw samples");
+            };
+            samples.len()
+        };
+
+        let request_id = Id::from(uuid::Uuid::new_v4());
+        registry.request_erasure(request_id.clone(), &nik(1), actor()).unwrap();
+        registry.approve_erasure(&request_id, actor(), 0).unwrap();
+
+        // measurements outlive every emr but the last one
+        assert_eq!(registry.purge_expired_emrs(1, 0).unwrap(), 1);
+        assert_eq!(samples(&registry), 1);
+
+        assert_eq!(registry.purge_expired_emrs(1, 0).unwrap(), 1);
+        assert_eq!(samples(&registry), 0);
+    }
+
+    #[test]
+    fn test_first_visit_opens_the_encounter_before_the_emr() {
+        use crate::emr::encounter::EncounterType;
+
+        ic_stable_memory::stable_memory_init();
+
+        let mut registry = EmrRegistry::new();
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+        let encounter_id = Id::from(uuid::Uuid::new_v4());
+
+        let input = EncounterInput {
+            kind: EncounterType::Ambulatory,
+            facility: "Poli Umum".to_string(),
+            started_at: None,
+        };
+        registry.open_encounter(encounter_id.clone(), &nik(1), provider, input).unwrap();
+
+        let emr_id = registry.register_emr(new_emr(&[("diagnosis", "flu")]), nik(1), actor()).unwrap();
+        registry.link_emr_to_encounter(&emr_id, &encounter_id).unwrap();
+
+        assert_eq!(registry.encounters_of(&nik(1)).len(), 1);
+    }
+
+    #[test]
+    fn test_encounters_are_purged_with_the_last_emr() {
+        use crate::emr::encounter::EncounterType;
+
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+        let encounter_id = Id::from(uuid::Uuid::new_v4());
+
+        let input = EncounterInput {
+            kind: EncounterType::Ambulatory,
+            facility: "Poli Umum".to_string(),
+            started_at: None,
+        };
+        registry.open_encounter(encounter_id.clone(), &nik(1), provider, input).unwrap();
+        registry.link_emr_to_encounter(&emr_id, &encounter_id).unwrap();
+
+        let request_id = Id::from(uuid::Uuid::new_v4());
+        registry.request_erasure(request_id.clone(), &nik(1), actor()).unwrap();
+        registry.approve_erasure(&request_id, actor(), 0).unwrap();
+        assert_eq!(registry.encounters_of(&nik(1)).len(), 1);
+
+        assert_eq!(registry.purge_expired_emrs(10, 0).unwrap(), 1);
+        assert!(registry.encounters_of(&nik(1)).is_empty());
+    }
+
+    #[test]
+    fn test_patient_with_only_vitals_can_request_erasure() {
+        use crate::emr::vitals::{ VitalKind, VitalPoints };
+
+        ic_stable_memory::stable_memory_init();
+
+        let mut registry = EmrRegistry::new();
+        let request_id = Id::from(uuid::Uuid::new_v4());
+
+        assert!(registry.request_erasure(request_id.clone(), &nik(1), actor()).is_err());
+
+        let input = VitalInput { kind: VitalKind::HeartRate, value: 72.0, measured_at: None, device: None };
+        registry.record_vitals(&nik(1), ObservationSource::Patient(actor()), vec![input]).unwrap();
+
+        registry.request_erasure(request_id.clone(), &nik(1), actor()).unwrap();
+        registry.approve_erasure(&request_id, actor(), 0).unwrap();
+
+        // with no emr to purge the measurements go right away
+        let query = VitalsQuery { kind: VitalKind::HeartRate, from: None, to: None, bucket: None };
+        let VitalPoints::Samples(samples) = registry.vitals_of(&nik(1), &query).unwrap().points else {
+            panic!("expected raw samples");
+        };
+        assert!(samples.is_empty());
+    }
+
+    #[test]
+    fn test_emr_updated_after_approval_is_retained() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let request_id = Id::from(uuid::Uuid::new_v4());
+
+        registry.request_erasure(request_id.clone(), &nik(1), actor()).unwrap();
+        registry.approve_erasure(&request_id, actor(), 0).unwrap();
+        let note = AsciiRecordsKey::new("note").unwrap();
+        registry.add_emr_record(&emr_id, note, "follow up", actor()).unwrap();
+
+        // the update moves the purge past the retention period instead of erasing the emr
+        let min_retention = 60 * 1_000_000_000;
+        assert_eq!(registry.purge_expired_emrs(10, min_retention).unwrap(), 0);
+        assert!(registry.get_emr(&emr_id).is_some());
+        assert!(registry.emr_tombstone(&emr_id).is_none());
+    }
+
+    #[test]
+    fn test_hl7_results_keep_earlier_results() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("lab.ln.718-7", "13.5 g/dL")]);
+        registry.finalize_emr(&emr_id, actor()).unwrap();
+
+        // the second result was already reported on the signed off emr
+        let message = format!(
+            "MSH|^~\\&|LIS|LAB|MEDBLOCK|MEDBLOCK|20231114221320||ORU^R01|LAB042|P|2.5\r\
+             PID|1||{}^^^^NI\r\
+             PV1|1|O|||||||||||||||||{}\r\
+             OBX|1|NM|2345-7^Glucose^LN||5.4|mmol/L|||||F\r\
+             OBX|2|NM|718-7^Hemoglobin^LN||12.1|g/dL|||||F",
+            nik(1).to_text(),
+            emr_id
+        );
+        let message = crate::hl7::parser::Message::parse(&message).unwrap();
+        let ingestion = crate::hl7::Ingestion::plan(&message).unwrap();
+
+        let records = ingestion
+            .records()
+            .iter()
+            .map(|(key, value)| (AsciiRecordsKey::new(key).unwrap(), value.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 2);
+
+        let keys = registry.add_emr_results(&emr_id, records, actor()).unwrap();
+        let key = |key: &str| AsciiRecordsKey::new(key).unwrap();
+        assert_eq!(keys, vec![key("lab.ln.2345-7"), key("lab.ln.718-7.2")]);
+
+        let emr = registry.get_emr(&emr_id).unwrap();
+        assert_eq!(emr.records().get(&key("lab.ln.718-7")).unwrap().as_str(), "13.5 g/dL");
+    }
+
+    #[test]
+    fn test_lab_report_rejected_by_emr_leaves_order_pending() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("lab.2345-7", "5.1 mmol/L")]);
+        let (provider, laboratory) = (Id::from(uuid::Uuid::new_v4()), Id::from(uuid::Uuid::new_v4()));
+        let order_id = Id::from(uuid::Uuid::new_v4());
+
+        let input = LabOrderInput { laboratory: laboratory.clone(), tests: vec!["2345-7".to_string()], note: None };
+        registry.create_lab_order(order_id.clone(), &emr_id, provider, input).unwrap();
+
+        let result = |code: &str| LabResultInput {
+            code: code.to_string(),
+            value: "5.4".to_string(),
+            unit: Some("mmol/L".to_string()),
+            reference_range: None,
+            flag: lab::AbnormalFlag::Normal,
+        };
+        let pending = |registry: &EmrRegistry| {
+            registry.lab_order(&order_id).unwrap().status == lab::LabOrderStatus::Pending
+        };
+
+        // tests that were not ordered are rejected
+        assert!(registry.report_lab_results(&order_id, &laboratory, actor(), vec![result("718-7")]).is_err());
+        assert!(pending(&registry));
+
+        registry.mark_emr_entered_in_error(&emr_id, actor(), "wrong patient".to_string()).unwrap();
+        assert!(registry.report_lab_results(&order_id, &laboratory, actor(), vec![result("2345-7")]).is_err());
+        assert!(pending(&registry));
+
+        let records = registry.get_emr(&emr_id).unwrap().records().sorted_pairs();
+        assert_eq!(records, vec![(AsciiRecordsKey::new("lab.2345-7").unwrap(), "5.1 mmol/L".to_string())]);
+    }
+
+    #[test]
+    fn test_repeated_lab_order_keeps_earlier_results() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("lab.2345-7", "5.1 mmol/L")]);
+        let (provider, laboratory) = (Id::from(uuid::Uuid::new_v4()), Id::from(uuid::Uuid::new_v4()));
+        let order_id = Id::from(uuid::Uuid::new_v4());
+
+        registry.finalize_emr(&emr_id, actor()).unwrap();
+
+        let input = LabOrderInput { laboratory: laboratory.clone(), tests: vec!["2345-7".to_string()], note: None };
+        registry.create_lab_order(order_id.clone(), &emr_id, provider, input).unwrap();
+
+        let result = LabResultInput {
+            code: "2345-7".to_string(),
+            value: "5.4".to_string(),
+            unit: Some("mmol/L".to_string()),
+            reference_range: None,
+            flag: lab::AbnormalFlag::Normal,
+        };
+        let order = registry.report_lab_results(&order_id, &laboratory, actor(), vec![result]).unwrap();
+        assert_eq!(order.status, lab::LabOrderStatus::Completed);
+
+        let key = |key: &str| AsciiRecordsKey::new(key).unwrap();
+        let records = registry.get_emr(&emr_id).unwrap().records().sorted_pairs();
+        assert_eq!(records, vec![
+            (key("lab.2345-7"), "5.1 mmol/L".to_string()),
+            (key("lab.2345-7.2"), "5.4 mmol/L".to_string())
+        ]);
+        assert_eq!(registry.emr_lifecycle(&emr_id).unwrap().status, EmrStatus::Amended);
+    }
+
+    #[test]
+    fn test_provider_guardian_request_needs_approval() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let provider = Principal::from_slice(&[1; 29]);
+        let guardian = Principal::from_slice(&[2; 29]);
+        let admin = Principal::from_slice(&[3; 29]);
+        let request_id = Id::from(uuid::Uuid::new_v4());
+
+        let mut request = AssignGuardianRequest {
+            guardian: provider,
+            scope: DelegationScope::Manage,
+            expires_at: None,
+            birth_date: Some(Timestamp(Timestamp::new().inner() - 10 * crate::types::YEAR_IN_NANOS)),
+        };
+
+        let authority = "birth certificate 123".to_string();
+        let requested = registry.request_guardian(request_id.clone(), &nik(1), &request, provider, authority.clone());
+        assert!(requested.is_err());
+
+        request.guardian = guardian;
+        registry.request_guardian(request_id.clone(), &nik(1), &request, provider, authority).unwrap();
+
+        // nothing is assigned until an admin approves
+        assert!(!registry.can_read_emr(&guardian, &emr_id, 18));
+        assert_eq!(registry.pending_guardian_requests(0, 10).len(), 1);
+
+        registry.approve_guardian_request(&request_id, admin, 18).unwrap();
+
+        assert!(registry.can_act_for(&guardian, &nik(1), DelegationScope::Manage, 18));
+        assert!(registry.can_read_emr(&guardian, &emr_id, 18));
+        assert!(registry.pending_guardian_requests(0, 10).is_empty());
+        assert!(registry.approve_guardian_request(&request_id, admin, 18).is_err());
+
+        // minority is derived from the current age of majority
+        assert!(!registry.can_read_emr(&guardian, &emr_id, 10));
+        assert!(!registry.is_valid_guardian(&guardian, 10));
+    }
+
+    #[test]
+    fn test_guardian_cannot_grant_beyond_their_own_delegation() {
+        let (mut registry, _) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let (guardian, other) = (Principal::from_slice(&[2; 29]), Principal::from_slice(&[3; 29]));
+        let now = Timestamp::new();
+        let expires_at = Timestamp(now.inner() + crate::types::YEAR_IN_NANOS);
+
+        let request = AssignGuardianRequest {
+            guardian,
+            scope: DelegationScope::Manage,
+            expires_at: Some(expires_at),
+            birth_date: Some(Timestamp(now.inner() - 12 * crate::types::YEAR_IN_NANOS)),
+        };
+        let delegation = Delegation::new(&request, actor(), None).unwrap();
+        registry.assign_guardian(&nik(1), guardian, delegation, 18).unwrap();
+
+        // a guardian can't replace their own delegation, e.g. to drop the birth date
+        let endless = AssignGuardianRequest { expires_at: None, birth_date: None, ..request.clone() };
+        let delegation = Delegation::new(&endless, guardian, None).unwrap();
+        assert!(registry.assign_guardian(&nik(1), guardian, delegation, 18).is_err());
+
+        // nor assign another guardian that outlives them
+        let endless = AssignGuardianRequest { guardian: other, ..endless };
+        let delegation = Delegation::new(&endless, guardian, None).unwrap();
+        assert!(registry.assign_guardian(&nik(1), other, delegation, 18).is_err());
+        assert!(!registry.can_act_for(&other, &nik(1), DelegationScope::Read, 18));
+
+        // the assigned guardian terminates with the patient's minority like the one assigning it
+        let bounded = AssignGuardianRequest { expires_at: Some(expires_at), ..endless };
+        let delegation = Delegation::new(&bounded, guardian, None).unwrap();
+        registry.assign_guardian(&nik(1), other, delegation, 18).unwrap();
+
+        assert!(registry.can_act_for(&other, &nik(1), DelegationScope::Manage, 18));
+        assert!(!registry.can_act_for(&other, &nik(1), DelegationScope::Read, 10));
+    }
+
+    #[test]
+    fn test_records_added_after_sign_off_are_addenda() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let key = |key: &str| AsciiRecordsKey::new(key).unwrap();
+
+        // drafts are edited freely without any addendum
+        registry.add_emr_record(&emr_id, key("lab.718-7"), "13.5 g/dL", actor()).unwrap();
+        assert_eq!(registry.emr_lifecycle(&emr_id).unwrap().status, EmrStatus::Draft);
+
+        registry.finalize_emr(&emr_id, actor()).unwrap();
+
+        // a late lab result is a new key, accepted but recorded as an addendum
+        let records = vec![(key("lab.2345-7"), "5.4 mmol/L".to_string())];
+        registry.add_emr_records(&emr_id, records, actor()).unwrap();
+
+        let lifecycle = registry.emr_lifecycle(&emr_id).unwrap();
+        assert_eq!(lifecycle.status, EmrStatus::Amended);
+        assert_eq!(lifecycle.addenda.len(), 1);
+        assert_eq!(lifecycle.addenda[0].key, key("lab.2345-7"));
+
+        // a written addendum is recorded once
+        let addendum = registry.add_addendum(&emr_id, "recheck in a week".to_string(), actor()).unwrap();
+        assert_eq!(addendum, key("addendum.1"));
+        assert_eq!(registry.emr_lifecycle(&emr_id).unwrap().addenda.len(), 2);
+
+        assert!(registry.add_emr_record(&emr_id, key("diagnosis"), "cough", actor()).is_err());
+        assert_eq!(registry.emr_lifecycle(&emr_id).unwrap().addenda.len(), 2);
+    }
+
+    #[test]
+    fn test_entered_in_error_emr_rejects_new_work() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+
+        registry.mark_emr_entered_in_error(&emr_id, actor(), "wrong patient".to_string()).unwrap();
+
+        let attachment = registry.begin_attachment(
+            Id::from(uuid::Uuid::new_v4()),
+            &emr_id,
+            AsciiRecordsKey::new("xray").unwrap(),
+            provider.clone(),
+            "image/png".to_string(),
+            1024,
+            [0; 32],
+            1024 * 1024
+        );
+        assert!(attachment.is_err());
+
+        let prescription = PrescriptionInput {
+            drug: "paracetamol".to_string(),
+            dose: "500 mg".to_string(),
+            quantity: 10,
+            refills: 0,
+            valid_for: 60 * 1_000_000_000,
+        };
+        let code = PrescriptionCode::new([1; 10]);
+        let prescription_id = Id::from(uuid::Uuid::new_v4());
+        assert!(registry.issue_prescription(prescription_id, code, &emr_id, provider.clone(), prescription).is_err());
+
+        let laboratory = Id::from(uuid::Uuid::new_v4());
+        let order = LabOrderInput { laboratory, tests: vec!["2345-7".to_string()], note: None };
+        assert!(registry.create_lab_order(Id::from(uuid::Uuid::new_v4()), &emr_id, provider, order).is_err());
+
+        assert!(registry.attachments_of_emr(&emr_id).is_empty());
+        assert!(registry.prescriptions_of_emr(&emr_id).is_empty());
+        assert!(registry.lab_orders_of_emr(&emr_id).is_empty());
+    }
+
+    #[test]
+    fn test_entered_in_error_prescription_is_not_dispensed() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+        let pharmacy = Id::from(uuid::Uuid::new_v4());
+
+        let prescription = PrescriptionInput {
+            drug: "paracetamol".to_string(),
+            dose: "500 mg".to_string(),
+            quantity: 10,
+            refills: 0,
+            valid_for: 60 * 1_000_000_000,
+        };
+        let code = PrescriptionCode::new([1; 10]);
+        let prescription_id = Id::from(uuid::Uuid::new_v4());
+        registry.issue_prescription(prescription_id, code, &emr_id, provider, prescription).unwrap();
+
+        registry.mark_emr_entered_in_error(&emr_id, actor(), "wrong patient".to_string()).unwrap();
+
+        assert!(registry.dispense_prescription(&code, pharmacy, 10).is_err());
+        assert!(registry.check_in_use(&emr_id).is_err());
+    }
+
+    #[test]
+    fn test_read_grant_does_not_allow_writes() {
+        let (mut registry, emr_id) = registry_with_emr(&nik(1), &[("diagnosis", "flu")]);
+        let provider = InternalProviderId::from(uuid::Uuid::new_v4());
+        let expires_at = Timestamp(Timestamp::new().inner() + 60 * 1_000_000_000);
+
+        registry.grant_access(&nik(1), &provider, AccessScope::Read, expires_at).unwrap();
+        assert!(registry.has_access_to_emr(&provider, &emr_id));
+        assert!(!registry.has_write_access_to_emr(&provider, &emr_id));
+
+        registry.grant_access(&nik(1), &provider, AccessScope::Write, expires_at).unwrap();
+        assert!(registry.has_write_access_to_emr(&provider, &emr_id));
+
+        // break glass only ever grants read access
+        let other = InternalProviderId::from(uuid::Uuid::new_v4());
+        let event_id = Id::from(uuid::Uuid::new_v4());
+        registry.break_glass(event_id, &nik(1), &other, "unconscious".to_string(), expires_at).unwrap();
+        assert!(registry.has_access_to_emr(&other, &emr_id));
+        assert!(!registry.has_write_access_to_emr(&other, &emr_id));
     }
 }
@@ -5,6 +5,8 @@ use ic_stable_memory::{
     primitive::s_ref::SRef,
 };
 
+use serde::Deserialize;
+
 use crate::{ deref, types::Id };
 
 use super::OutOfMemory;
@@ -12,18 +14,114 @@ use super::OutOfMemory;
 type EmrId = Id;
 const KEY_LEN: usize = 32;
 
-/// hex encoded SHA3-256 hash of NIK, used as key for [BindingMap].
+/// kind of identifier a patient is registered with. NIK is the default for indonesian citizens, other kinds
+/// are used for patients that don't have one (foreigners, newborns) or are identified by their insurance membership.
+#[derive(
+    StableType,
+    AsFixedSizeBytes,
+    CandidType,
+    Deserialize,
+    Hash,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Clone,
+    Copy,
+    Debug
+)]
+pub enum IdentifierType {
+    /// indonesian national identity number (Nomor Induk Kependudukan)
+    Nik,
+    /// passport number, for foreign patients
+    Passport,
+    /// BPJS Kesehatan member id
+    Bpjs,
+    /// facility issued medical record number, for patients without any national identifier such as newborns
+    MedicalRecordNumber,
+}
+
+impl IdentifierType {
+    const NIK_PREFIX: &'static str = "nik";
+    const PASSPORT_PREFIX: &'static str = "passport";
+    const BPJS_PREFIX: &'static str = "bpjs";
+    const MEDICAL_RECORD_NUMBER_PREFIX: &'static str = "mrn";
+
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::Nik => Self::NIK_PREFIX,
+            Self::Passport => Self::PASSPORT_PREFIX,
+            Self::Bpjs => Self::BPJS_PREFIX,
+            Self::MedicalRecordNumber => Self::MEDICAL_RECORD_NUMBER_PREFIX,
+        }
+    }
+}
+
+impl std::str::FromStr for IdentifierType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            Self::NIK_PREFIX => Ok(Self::Nik),
+            Self::PASSPORT_PREFIX => Ok(Self::Passport),
+            Self::BPJS_PREFIX => Ok(Self::Bpjs),
+            Self::MEDICAL_RECORD_NUMBER_PREFIX => Ok(Self::MedicalRecordNumber),
+            _ => Err(format!("unknown identifier type {}", s)),
+        }
+    }
+}
+
+/// SHA3-256 hash of a patient identifier tagged with the identifier type, used as key for [EmrBindingMap].
 /// we can't check for hash validity, so we assume it's valid by checking it's length.
+///
+/// textual representation is `<type>:<hex encoded hash>`, e.g. `passport:3fe9...`. a bare hex encoded hash
+/// is treated as a NIK to stay compatible with clients that predate identifier types, NIK is also serialized that way.
 #[derive(StableType, AsFixedSizeBytes, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
-pub struct InternalBindingKey([u8; KEY_LEN]);
+pub struct InternalBindingKey {
+    kind: IdentifierType,
+    hash: [u8; KEY_LEN],
+}
 
 impl InternalBindingKey {
-    pub fn as_str(&self) -> &str {
-        std::str::from_utf8(&self.0).expect("key must be ascii")
+    pub fn new(kind: IdentifierType, hash: [u8; KEY_LEN]) -> Self {
+        Self { kind, hash }
+    }
+
+    pub fn kind(&self) -> IdentifierType {
+        self.kind
+    }
+
+    /// hex representation of the hash, without the identifier type
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.hash)
+    }
+
+    /// textual representation of the key, mirrors the format accepted by the deserializer
+    pub fn to_text(&self) -> String {
+        match self.kind {
+            IdentifierType::Nik => self.to_hex(),
+            kind => format!("{}:{}", kind.prefix(), self.to_hex()),
+        }
     }
 }
 
-deref!(InternalBindingKey: [u8; KEY_LEN]);
+impl std::str::FromStr for InternalBindingKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, hash) = match s.split_once(':') {
+            Some((kind, hash)) => (kind.parse::<IdentifierType>()?, hash),
+            None => (IdentifierType::Nik, s),
+        };
+
+        let hash = hex::decode(hash).map_err(|e| e.to_string())?;
+        let hash = <[u8; KEY_LEN]>::try_from(hash).map_err(|_| "invalid identifier hash length")?;
+
+        Ok(Self::new(kind, hash))
+    }
+}
+
+deref!(InternalBindingKey: [u8; KEY_LEN] |_self| => &_self.hash);
 
 mod deserialize {
     use super::*;
@@ -33,17 +131,8 @@ mod deserialize {
             where D: serde::Deserializer<'de>
         {
             let s = String::deserialize(deserializer)?;
-            let s = hex::decode(s).map_err(serde::de::Error::custom)?;
-
-            if s.len() != KEY_LEN {
-                return Err(serde::de::Error::custom("invalid nik hash length"));
-            }
 
-            // TODO: unnecessary copy
-            let mut key = [0u8; KEY_LEN];
-            key[..s.len()].copy_from_slice(&s);
-
-            Ok(Self(key))
+            s.parse().map_err(serde::de::Error::custom)
         }
     }
 
@@ -55,12 +144,13 @@ mod deserialize {
         fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
             where S: candid::types::Serializer
         {
-            serializer.serialize_text(self.as_str())
+            serializer.serialize_text(&self.to_text())
         }
     }
 }
 
 pub type Owner = Principal;
+/// patient identifier, kept under this name for historical reasons. may be any [IdentifierType], not only NIK.
 pub type NIK = InternalBindingKey;
 /// Principal to NIK Map. meant to enforce 1:1 relationship between principal and NIK.
 /// used to claim emrs ownership. This level of inderction is needed because principal that map to a particular BindingKey effectively owns
@@ -84,53 +174,244 @@ impl OwnerMap {
         self.0.get(owner)
     }
 
-    pub fn new() -> Self {
-        Self::default()
-    }
-
     pub fn is_valid_owner(&self, owner: &Owner) -> bool {
         self.0.contains_key(owner)
     }
+
+    /// rebind every principal currently bound to `from` to `into`. used when merging duplicate patients
+    /// so that the surviving [NIK] is still claimable by the principals of the merged one.
+    pub fn rebind_all(&mut self, from: &NIK, into: &NIK) -> Result<(), OutOfMemory> {
+        let owners = self.0
+            .iter()
+            .filter(|(_, nik)| (**nik).eq(from))
+            .map(|(owner, _)| *owner)
+            .collect::<Vec<Owner>>();
+
+        for owner in owners {
+            self.bind(owner, into.clone())?;
+        }
+
+        Ok(())
+    }
 }
 
 deref!(mut OwnerMap: SBTreeMap<Owner, NIK>);
 
 pub type EmrIdCollection = SBTreeSet<EmrId>;
 /// track emr issued for a particular user by storing it's emr id in this map. also used as blind index for emr search.
-/// we use hashed (SHA3-256) NIK as key and emr id as value.
+/// we use hashed (SHA3-256) patient identifier as key and emr id as value.
 ///
 /// we don't use the principal directly because we want users to be able to change it's internet identity
 /// and still be able to own and access their emr.
 ///
-/// NIK MUST be hashed offchain before being used as key.
+/// a patient may be known by several identifiers (e.g. NIK and BPJS member id), one of them is the primary identifier
+/// that emrs are bound to and the rest are aliases resolving to it. every lookup resolves aliases first,
+/// so any identifier of the patient can be used.
+///
+/// identifiers MUST be hashed offchain before being used as key.
 #[derive(Default)]
-pub struct EmrBindingMap(SBTreeMap<NIK, EmrIdCollection>);
-
-deref!(mut EmrBindingMap: SBTreeMap<NIK, EmrIdCollection>);
+pub struct EmrBindingMap {
+    bindings: SBTreeMap<NIK, EmrIdCollection>,
+    /// alias identifier to primary identifier map
+    aliases: SBTreeMap<NIK, NIK>,
+    /// reverse index, emr id to the primary identifier of the patient owning it
+    emr_owners: SBTreeMap<EmrId, NIK>,
+}
 
 impl EmrBindingMap {
     pub fn new() -> Self {
         Self::default()
     }
 
-    pub fn is_owner_of(&self, nik: &NIK, emr_id: &EmrId) -> bool {
-        self.0
+    /// resolve an identifier to the primary identifier its emrs are bound to.
+    /// returns the identifier itself if it's not an alias.
+    pub fn resolve(&self, nik: &NIK) -> NIK {
+        self.aliases
             .get(nik)
+            .map(|primary| (*primary).clone())
+            .unwrap_or_else(|| nik.clone())
+    }
+
+    /// check if the identifier is already in use, either as primary identifier or as alias
+    pub fn is_known(&self, nik: &NIK) -> bool {
+        self.bindings.contains_key(nik) || self.aliases.contains_key(nik)
+    }
+
+    /// make `alias` resolve to the same patient as `identifier`
+    pub fn link(&mut self, identifier: &NIK, alias: NIK) -> Result<(), OutOfMemory> {
+        let primary = self.resolve(identifier);
+
+        self.aliases
+            .insert(alias, primary)
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    /// returns every alias that resolves to the same patient as `identifier`, excluding the primary identifier
+    pub fn aliases_of(&self, identifier: &NIK) -> Vec<NIK> {
+        let primary = self.resolve(identifier);
+
+        self.aliases
+            .iter()
+            .filter(|(_, target)| (**target).eq(&primary))
+            .map(|(alias, _)| (*alias).clone())
+            .collect()
+    }
+
+    pub fn is_owner_of(&self, nik: &NIK, emr_id: &EmrId) -> bool {
+        self.bindings
+            .get(&self.resolve(nik))
             .map(|emr_ids| emr_ids.contains(emr_id))
             .unwrap_or(false)
     }
 
     pub fn issue_for(&mut self, nik: &NIK, emr_id: EmrId) -> Result<(), OutOfMemory> {
-        if !self.0.contains_key(nik) {
+        let nik = self.resolve(nik);
+
+        if !self.bindings.contains_key(&nik) {
             let issue_map = EmrIdCollection::new();
-            self.0.insert(nik.clone(), issue_map);
+            self.bindings.insert(nik.clone(), issue_map).map_err(OutOfMemory::from)?;
         }
 
-        let mut issue_map = self.0.get_mut(nik).unwrap();
+        let mut issue_map = self.bindings.get_mut(&nik).unwrap();
+        issue_map.insert(emr_id.clone()).map_err(OutOfMemory::from)?;
+        drop(issue_map);
 
-        issue_map
-            .insert(emr_id)
+        self.emr_owners
+            .insert(emr_id, nik)
             .map_err(OutOfMemory::from)
             .map(|_| ())
     }
+
+    /// returns the primary identifier of the patient owning the emr
+    pub fn owner_of(&self, emr_id: &EmrId) -> Option<NIK> {
+        self.emr_owners.get(emr_id).map(|nik| (*nik).clone())
+    }
+
+    /// returns a heap copy of the emr ids currently bound to `nik`
+    pub fn snapshot(&self, nik: &NIK) -> BindingSnapshot {
+        let nik = self.resolve(nik);
+
+        let emrs = self.bindings
+            .get(&nik)
+            .map(|emr_ids| emr_ids.iter().map(|id| id.to_owned()).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        BindingSnapshot { nik, emrs }
+    }
+
+    /// bind every emr of `from` to `into`. `from` is only cleared after all emrs have been bound to `into`,
+    /// so running out of memory halfway never leaves an emr without an owner.
+    /// aliases of `from` are moved to `into` as well, and `from` itself becomes an alias of `into`
+    /// so lookups made with the merged identifier keep working.
+    pub fn merge(&mut self, from: &NIK, into: &NIK) -> Result<(), OutOfMemory> {
+        let BindingSnapshot { nik: from, emrs } = self.snapshot(from);
+
+        for emr_id in emrs {
+            self.issue_for(into, emr_id)?;
+        }
+
+        for alias in self.aliases_of(&from) {
+            self.link(into, alias)?;
+        }
+
+        self.link(into, from.clone())?;
+        self.bindings.remove(&from);
+
+        Ok(())
+    }
+
+    /// move a single emr from `from` to `to`. returns false if `from` does not own the emr.
+    pub fn move_emr(&mut self, emr_id: &EmrId, from: &NIK, to: &NIK) -> Result<bool, OutOfMemory> {
+        if !self.is_owner_of(from, emr_id) {
+            return Ok(false);
+        }
+
+        self.issue_for(to, emr_id.clone())?;
+
+        if let Some(mut emr_ids) = self.bindings.get_mut(&self.resolve(from)) {
+            emr_ids.remove(emr_id);
+        }
+
+        Ok(true)
+    }
+}
+
+/// heap copy of a single [NIK] binding, used to record the state of [EmrBindingMap] around admin corrections.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct BindingSnapshot {
+    nik: NIK,
+    pub(crate) emrs: Vec<EmrId>,
+}
+
+/// binding state of every [NIK] involved in a merge or move, before and after the change was applied.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct BindingTransition {
+    pub before: Vec<BindingSnapshot>,
+    pub after: Vec<BindingSnapshot>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge_and_move() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut bindings = EmrBindingMap::new();
+        let from = InternalBindingKey::new(IdentifierType::Nik, [1u8; KEY_LEN]);
+        let into = InternalBindingKey::new(IdentifierType::Nik, [2u8; KEY_LEN]);
+        let other = InternalBindingKey::new(IdentifierType::Nik, [3u8; KEY_LEN]);
+
+        let emr_id = Id::from(uuid::Uuid::new_v4());
+        bindings.issue_for(&from, emr_id.clone()).unwrap();
+
+        bindings.merge(&from, &into).unwrap();
+
+        // the merged identifier is an alias of the surviving one
+        assert_eq!(bindings.resolve(&from), into);
+        assert_eq!(bindings.owner_of(&emr_id), Some(into.clone()));
+        assert!(bindings.is_owner_of(&from, &emr_id));
+        assert!(bindings.is_owner_of(&into, &emr_id));
+
+        assert!(bindings.move_emr(&emr_id, &into, &other).unwrap());
+        assert!(!bindings.move_emr(&emr_id, &into, &other).unwrap());
+
+        assert!(bindings.is_owner_of(&other, &emr_id));
+        assert!(!bindings.is_owner_of(&into, &emr_id));
+    }
+
+    #[test]
+    fn test_alias_lookup() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut bindings = EmrBindingMap::new();
+        let nik = InternalBindingKey::new(IdentifierType::Nik, [1u8; KEY_LEN]);
+        let bpjs = InternalBindingKey::new(IdentifierType::Bpjs, [1u8; KEY_LEN]);
+
+        bindings.link(&nik, bpjs.clone()).unwrap();
+
+        let emr_id = Id::from(uuid::Uuid::new_v4());
+        bindings.issue_for(&bpjs, emr_id.clone()).unwrap();
+
+        assert!(bindings.is_owner_of(&nik, &emr_id));
+        assert!(bindings.is_owner_of(&bpjs, &emr_id));
+        assert_eq!(bindings.aliases_of(&bpjs), vec![bpjs.clone()]);
+    }
+
+    #[test]
+    fn test_binding_key_text() {
+        let hash = "3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709";
+
+        let nik = hash.parse::<InternalBindingKey>().unwrap();
+        assert_eq!(nik.kind(), IdentifierType::Nik);
+        assert_eq!(nik.to_text(), hash);
+
+        let passport = format!("passport:{}", hash).parse::<InternalBindingKey>().unwrap();
+        assert_eq!(passport.kind(), IdentifierType::Passport);
+        assert_eq!(passport.to_text(), format!("passport:{}", hash));
+
+        assert!(format!("unknown:{}", hash).parse::<InternalBindingKey>().is_err());
+    }
 }
@@ -0,0 +1,488 @@
+use candid::CandidType;
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SBTreeSet, SVec },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::types::{ Id, Timestamp };
+
+use super::{ providers::{ InternalPharmacyId, InternalProviderId }, EmrId, OutOfMemory };
+
+pub type PrescriptionId = Id;
+
+/// drug names and doses are short, longer values are most likely garbage
+const MAX_TEXT_LEN: usize = 256;
+
+/// prescriptions are dispensable for at most 180 days after issuance
+const MAX_VALIDITY: u64 = 180 * 24 * 60 * 60 * 1_000_000_000;
+
+/// Code the patient presents at the pharmacy, 80 random bits shown as 16 Crockford base32 characters
+/// in groups of 4, e.g. `7K3M-Q9ZD-1XR4-HV8T`. long enough that pharmacies can't guess codes of other patients.
+#[derive(StableType, AsFixedSizeBytes, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PrescriptionCode([u8; 10]);
+
+impl PrescriptionCode {
+    const ALPHABET: &'static [u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    const LEN: usize = 16;
+
+    pub fn new(bytes: [u8; 10]) -> Self {
+        Self(bytes)
+    }
+
+    /// parse a code as typed by a person, case insensitive, ignoring separators and reading `O` as `0`
+    /// and `I` or `L` as `1`
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || "invalid prescription code".to_string();
+
+        let digits = text
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .map(|c| {
+                let c = match c.to_ascii_uppercase() {
+                    'O' => '0',
+                    'I' | 'L' => '1',
+                    c => c,
+                };
+
+                Self::ALPHABET.iter()
+                    .position(|a| *a as char == c)
+                    .ok_or_else(invalid)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if digits.len() != Self::LEN {
+            return Err(invalid());
+        }
+
+        let n = digits.iter().fold(0u128, |n, digit| (n << 5) | (*digit as u128));
+        let bytes = n.to_be_bytes()[6..].try_into().expect("80 bits fit in 10 bytes");
+
+        Ok(Self(bytes))
+    }
+}
+
+impl std::fmt::Display for PrescriptionCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let n = self.0.iter().fold(0u128, |n, byte| (n << 8) | (*byte as u128));
+
+        for i in 0..Self::LEN {
+            if i > 0 && i % 4 == 0 {
+                write!(f, "-")?;
+            }
+
+            let digit = (n >> (5 * (Self::LEN - 1 - i))) & 31;
+            write!(f, "{}", Self::ALPHABET[digit as usize] as char)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// prescription as written by the prescribing provider
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PrescriptionInput {
+    pub drug: String,
+    pub dose: String,
+    /// units dispensed per fill
+    pub quantity: u32,
+    /// fills allowed after the first one
+    pub refills: u8,
+    /// nanoseconds the prescription stays dispensable after issuance
+    pub valid_for: u64,
+}
+
+impl PrescriptionInput {
+    fn validate(&self) -> Result<(), String> {
+        for (field, value) in [
+            ("drug", &self.drug),
+            ("dose", &self.dose),
+        ] {
+            if value.trim().is_empty() || value.len() > MAX_TEXT_LEN {
+                return Err(format!("{} must be between 1 and {} characters", field, MAX_TEXT_LEN));
+            }
+        }
+
+        if self.quantity == 0 {
+            return Err("quantity must be positive".to_string());
+        }
+
+        if self.valid_for == 0 || self.valid_for > MAX_VALIDITY {
+            return Err("validity must be between 1 nanosecond and 180 days".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrescriptionStatus {
+    /// can still be dispensed
+    Active,
+    /// every fill was dispensed in full
+    Dispensed,
+    /// cancelled by the prescriber
+    Cancelled,
+    /// validity passed before it was fully dispensed, never stored, derived when displayed
+    Expired,
+}
+
+/// a single dispensing by a pharmacy
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone)]
+pub struct Dispensing {
+    pharmacy: InternalPharmacyId,
+    amount: u32,
+    dispensed_at: Timestamp,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Prescription {
+    emr_id: EmrId,
+    prescriber: InternalProviderId,
+    code: PrescriptionCode,
+    drug: SBox<String>,
+    dose: SBox<String>,
+    quantity: u32,
+    refills: u8,
+    /// units dispensed over every fill
+    dispensed: u64,
+    status: PrescriptionStatus,
+    issued_at: Timestamp,
+    valid_until: Timestamp,
+    cancellation_reason: Option<SBox<String>>,
+    dispensings: SVec<Dispensing>,
+}
+
+impl Prescription {
+    /// units allowed over every fill
+    fn total(&self) -> u64 {
+        (self.quantity as u64) * (1 + (self.refills as u64))
+    }
+
+    /// units left in the fill being dispensed, a fill must be completed before the next refill starts
+    fn fill_remaining(&self) -> u64 {
+        (self.quantity as u64) - (self.dispensed % (self.quantity as u64))
+    }
+
+    fn status_at(&self, now: &Timestamp) -> PrescriptionStatus {
+        match self.status {
+            PrescriptionStatus::Active if self.valid_until.le(now) => PrescriptionStatus::Expired,
+            status => status,
+        }
+    }
+}
+
+/// heap copy of a [Prescription]
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct PrescriptionDisplay {
+    prescription_id: PrescriptionId,
+    emr_id: EmrId,
+    prescriber: InternalProviderId,
+    code: String,
+    drug: String,
+    dose: String,
+    quantity: u32,
+    refills: u8,
+    dispensed: u64,
+    /// units left over every fill
+    remaining: u64,
+    /// units left in the fill being dispensed
+    fill_remaining: u64,
+    status: PrescriptionStatus,
+    issued_at: Timestamp,
+    valid_until: Timestamp,
+    cancellation_reason: Option<String>,
+    dispensings: Vec<Dispensing>,
+}
+
+impl PrescriptionDisplay {
+    pub fn new(prescription_id: PrescriptionId, prescription: &Prescription, now: &Timestamp) -> Self {
+        let remaining = prescription.total() - prescription.dispensed;
+
+        Self {
+            prescription_id,
+            emr_id: prescription.emr_id.clone(),
+            prescriber: prescription.prescriber.clone(),
+            code: prescription.code.to_string(),
+            drug: (*prescription.drug).clone(),
+            dose: (*prescription.dose).clone(),
+            quantity: prescription.quantity,
+            refills: prescription.refills,
+            dispensed: prescription.dispensed,
+            remaining,
+            fill_remaining: match remaining {
+                0 => 0,
+                _ => prescription.fill_remaining(),
+            },
+            status: prescription.status_at(now),
+            issued_at: prescription.issued_at,
+            valid_until: prescription.valid_until,
+            cancellation_reason: prescription.cancellation_reason.as_ref().map(|reason| (**reason).clone()),
+            dispensings: prescription.dispensings
+                .iter()
+                .map(|dispensing| dispensing.clone())
+                .collect(),
+        }
+    }
+
+    pub fn emr_id(&self) -> &EmrId {
+        &self.emr_id
+    }
+}
+
+/// Structured prescriptions issued against emrs, looked up and dispensed by pharmacies with the code the
+/// patient presents.
+#[derive(Default)]
+pub struct Prescriptions {
+    prescriptions: SBTreeMap<PrescriptionId, Prescription>,
+    codes: SBTreeMap<PrescriptionCode, PrescriptionId>,
+    /// reverse index, emr id to the prescriptions issued against it
+    emr_prescriptions: SBTreeMap<EmrId, SBTreeSet<PrescriptionId>>,
+}
+
+impl Prescriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn issue(
+        &mut self,
+        prescription_id: PrescriptionId,
+        code: PrescriptionCode,
+        emr_id: EmrId,
+        prescriber: InternalProviderId,
+        input: PrescriptionInput
+    ) -> Result<PrescriptionDisplay, String> {
+        input.validate()?;
+
+        if self.codes.contains_key(&code) {
+            return Err("prescription code is already in use".to_string());
+        }
+
+        let issued_at = Timestamp::new();
+
+        let prescription = Prescription {
+            emr_id: emr_id.clone(),
+            prescriber,
+            code,
+            drug: SBox::new(input.drug).map_err(OutOfMemory::from)?,
+            dose: SBox::new(input.dose).map_err(OutOfMemory::from)?,
+            quantity: input.quantity,
+            refills: input.refills,
+            dispensed: 0,
+            status: PrescriptionStatus::Active,
+            issued_at,
+            valid_until: Timestamp(issued_at.inner().saturating_add(input.valid_for)),
+            cancellation_reason: None,
+            dispensings: SVec::new(),
+        };
+
+        let display = PrescriptionDisplay::new(prescription_id.clone(), &prescription, &issued_at);
+
+        self.prescriptions.insert(prescription_id.clone(), prescription).map_err(OutOfMemory::from)?;
+        self.codes.insert(code, prescription_id.clone()).map_err(OutOfMemory::from)?;
+
+        if !self.emr_prescriptions.contains_key(&emr_id) {
+            self.emr_prescriptions.insert(emr_id.clone(), SBTreeSet::new()).map_err(OutOfMemory::from)?;
+        }
+
+        self.emr_prescriptions
+            .get_mut(&emr_id)
+            .expect("index was just created")
+            .insert(prescription_id)
+            .map_err(OutOfMemory::from)?;
+
+        Ok(display)
+    }
+
+    /// cancel an active prescription, only its prescriber may cancel it. units already dispensed stay recorded.
+    pub fn cancel(
+        &mut self,
+        prescription_id: &PrescriptionId,
+        prescriber: &InternalProviderId,
+        reason: String
+    ) -> Result<(), String> {
+        let Some(mut prescription) = self.prescriptions.get_mut(prescription_id) else {
+            return Err("prescription not found".to_string());
+        };
+
+        if prescription.prescriber.ne(prescriber) {
+            return Err("only the prescriber can cancel this prescription".to_string());
+        }
+
+        if prescription.status != PrescriptionStatus::Active {
+            return Err("prescription is not active".to_string());
+        }
+
+        prescription.cancellation_reason = Some(SBox::new(reason).map_err(OutOfMemory::from)?);
+        prescription.status = PrescriptionStatus::Cancelled;
+
+        Ok(())
+    }
+
+    pub fn lookup(&self, code: &PrescriptionCode, now: &Timestamp) -> Option<PrescriptionDisplay> {
+        let prescription_id = self.codes.get(code)?;
+        self.get(&prescription_id, now)
+    }
+
+    pub fn get(&self, prescription_id: &PrescriptionId, now: &Timestamp) -> Option<PrescriptionDisplay> {
+        self.prescriptions
+            .get(prescription_id)
+            .map(|prescription| PrescriptionDisplay::new(prescription_id.clone(), &prescription, now))
+    }
+
+    /// dispense `amount` units of the prescription with `code`. dispensing may be partial but never goes past
+    /// the fill being dispensed, so a prescription can't be dispensed twice or beyond its refills.
+    pub fn dispense(
+        &mut self,
+        code: &PrescriptionCode,
+        pharmacy: InternalPharmacyId,
+        amount: u32,
+        now: &Timestamp
+    ) -> Result<PrescriptionDisplay, String> {
+        let Some(prescription_id) = self.codes.get(code).map(|id| (*id).clone()) else {
+            return Err("prescription not found".to_string());
+        };
+
+        let mut prescription = self.prescriptions.get_mut(&prescription_id).expect("codes index prescriptions");
+
+        match prescription.status_at(now) {
+            PrescriptionStatus::Active => (),
+            PrescriptionStatus::Dispensed => {
+                return Err("prescription is already fully dispensed".to_string());
+            }
+            PrescriptionStatus::Cancelled => {
+                return Err("prescription is cancelled".to_string());
+            }
+            PrescriptionStatus::Expired => {
+                return Err("prescription is expired".to_string());
+            }
+        }
+
+        if amount == 0 {
+            return Err("amount must be positive".to_string());
+        }
+
+        let fill_remaining = prescription.fill_remaining();
+        if (amount as u64) > fill_remaining {
+            return Err(format!("only {} units are left in the current fill", fill_remaining));
+        }
+
+        let dispensing = Dispensing { pharmacy, amount, dispensed_at: *now };
+        prescription.dispensings.push(dispensing).map_err(OutOfMemory::from)?;
+        prescription.dispensed += amount as u64;
+
+        if prescription.dispensed == prescription.total() {
+            prescription.status = PrescriptionStatus::Dispensed;
+        }
+
+        Ok(PrescriptionDisplay::new(prescription_id, &prescription, now))
+    }
+
+    /// returns every prescription issued against `emr_id`
+    pub fn of_emr(&self, emr_id: &EmrId, now: &Timestamp) -> Vec<PrescriptionDisplay> {
+        let Some(ids) = self.emr_prescriptions.get(emr_id) else {
+            return vec![];
+        };
+
+        ids.iter()
+            .filter_map(|prescription_id| self.get(&prescription_id, now))
+            .collect()
+    }
+
+    /// remove every prescription of an erased emr, their codes no longer resolve afterwards
+    pub fn remove_emr(&mut self, emr_id: &EmrId) {
+        let ids = match self.emr_prescriptions.get(emr_id) {
+            Some(ids) =>
+                ids
+                    .iter()
+                    .map(|id| (*id).clone())
+                    .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        for prescription_id in ids {
+            if let Some(prescription) = self.prescriptions.remove(&prescription_id) {
+                self.codes.remove(&prescription.code);
+            }
+        }
+
+        self.emr_prescriptions.remove(emr_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::fixtures::id;
+
+    const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+    fn input() -> PrescriptionInput {
+        PrescriptionInput {
+            drug: "Amoxicillin 500 mg".to_string(),
+            dose: "3 times a day".to_string(),
+            quantity: 10,
+            refills: 1,
+            valid_for: 30 * DAY,
+        }
+    }
+
+    #[test]
+    fn test_code_roundtrip() {
+        let code = PrescriptionCode::new([0xa5; 10]);
+        let text = code.to_string();
+
+        assert_eq!(text.len(), 19);
+        assert_eq!(PrescriptionCode::parse(&text).unwrap(), code);
+        assert_eq!(PrescriptionCode::parse(&text.to_lowercase().replace('-', " ")).unwrap(), code);
+        assert!(PrescriptionCode::parse("7K3M-Q9ZD").is_err());
+        assert!(PrescriptionCode::parse("7K3M-Q9ZD-1XR4-HV8U").is_err());
+    }
+
+    #[test]
+    fn test_dispensing() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut prescriptions = Prescriptions::new();
+        let code = PrescriptionCode::new([1; 10]);
+        let (pharmacy, prescriber) = (id(8), id(9));
+
+        let mut invalid = input();
+        invalid.quantity = 0;
+        assert!(prescriptions.issue(id(2), code, id(1), prescriber.clone(), invalid).is_err());
+
+        prescriptions.issue(id(2), code, id(1), prescriber.clone(), input()).unwrap();
+        assert!(prescriptions.issue(id(3), code, id(1), prescriber.clone(), input()).is_err());
+
+        let now = Timestamp::new();
+
+        // partial dispensing never goes past the current fill
+        prescriptions.dispense(&code, pharmacy.clone(), 4, &now).unwrap();
+        assert!(prescriptions.dispense(&code, pharmacy.clone(), 7, &now).is_err());
+        let display = prescriptions.dispense(&code, pharmacy.clone(), 6, &now).unwrap();
+        assert_eq!((display.dispensed, display.remaining, display.fill_remaining), (10, 10, 10));
+
+        // the refill, after which the prescription can't be dispensed again
+        let display = prescriptions.dispense(&code, pharmacy.clone(), 10, &now).unwrap();
+        assert_eq!(display.status, PrescriptionStatus::Dispensed);
+        assert_eq!(display.dispensings.len(), 3);
+        assert!(prescriptions.dispense(&code, pharmacy.clone(), 1, &now).is_err());
+
+        // expired and cancelled prescriptions can't be dispensed
+        let other = PrescriptionCode::new([2; 10]);
+        prescriptions.issue(id(3), other, id(1), prescriber.clone(), input()).unwrap();
+        let later = Timestamp(now.inner() + 31 * DAY);
+        assert_eq!(prescriptions.lookup(&other, &later).unwrap().status, PrescriptionStatus::Expired);
+        assert!(prescriptions.dispense(&other, pharmacy.clone(), 1, &later).is_err());
+
+        assert!(prescriptions.cancel(&id(3), &pharmacy, "wrong drug".to_string()).is_err());
+        prescriptions.cancel(&id(3), &prescriber, "wrong drug".to_string()).unwrap();
+        assert!(prescriptions.dispense(&other, pharmacy.clone(), 1, &now).is_err());
+
+        prescriptions.remove_emr(&id(1));
+        assert!(prescriptions.of_emr(&id(1), &now).is_empty());
+        assert!(prescriptions.lookup(&code, &now).is_none());
+    }
+}
@@ -42,6 +42,10 @@ pub struct ProviderRegistry {
     providers: Providers,
     providers_bindings: ProvidersBindings,
     issued: Issued,
+    pharmacies: Facilities,
+    pharmacies_bindings: ProvidersBindings,
+    laboratories: Facilities,
+    laboratories_bindings: ProvidersBindings,
 }
 
 impl ProviderRegistry {
@@ -55,6 +59,21 @@ impl ProviderRegistry {
         self.issued.is_issued_by(&id, emr_id)
     }
 
+    /// returns the encrypted display name of a provider
+    pub fn display_name(&self, provider: &InternalProviderId) -> Option<String> {
+        self.providers.get(provider).map(|provider| provider.display_name())
+    }
+
+    /// resolve a provider principal to its internal provider id
+    pub fn internal_id(&self, provider: &Principal) -> Option<InternalProviderId> {
+        self.providers_bindings.get_internal_id(provider).map(|id| (*id).clone())
+    }
+
+    /// returns the internal id of the provider that issued the emr, if any
+    pub fn issuer_of(&self, emr_id: &Id) -> Option<InternalProviderId> {
+        self.issued.issuer_of(emr_id)
+    }
+
     pub fn issue_emr(&mut self, provider: &Principal, emr_id: Id) -> Result<(), &'static str> {
         let Some(id) = self.providers_bindings.get_internal_id(provider) else {
             return Err("provider not found");
@@ -87,12 +106,15 @@ impl ProviderRegistry {
     }
 
     /// register a new provider, this function will create a new provider and bind the principal to the internal id.
+    /// fails if the principal is already registered as pharmacy or laboratory, a principal can only hold a single role.
     pub fn register_new_provider(
         &mut self,
         provider_principal: ProviderPrincipal,
         display_name: String,
         id: Id
-    ) -> Result<(), OutOfMemory> {
+    ) -> Result<(), String> {
+        self.check_not_facility(&provider_principal)?;
+
         // create a new provider, note that this might change version depending on the version of the emr used.
         let provider = ProviderV001::new(display_name, provider_principal, id)?;
 
@@ -149,18 +171,129 @@ impl ProviderRegistry {
 
         self.issued.get_issued(&*internal_id, anchor, max).ok_or("no emr collection found")
     }
+
+    fn facilities(&self, kind: FacilityKind) -> (&Facilities, &ProvidersBindings) {
+        match kind {
+            FacilityKind::Pharmacy => (&self.pharmacies, &self.pharmacies_bindings),
+            FacilityKind::Laboratory => (&self.laboratories, &self.laboratories_bindings),
+        }
+    }
+
+    fn check_not_facility(&self, principal: &Principal) -> Result<(), String> {
+        for registered in [FacilityKind::Pharmacy, FacilityKind::Laboratory] {
+            if self.facilities(registered).1.contains_key(principal) {
+                return Err(format!("principal is already registered as {}", registered.name()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn facilities_mut(&mut self, kind: FacilityKind) -> (&mut Facilities, &mut ProvidersBindings) {
+        match kind {
+            FacilityKind::Pharmacy => (&mut self.pharmacies, &mut self.pharmacies_bindings),
+            FacilityKind::Laboratory => (&mut self.laboratories, &mut self.laboratories_bindings),
+        }
+    }
+
+    /// register a new pharmacy or laboratory and bind the principal to its internal id. a principal can only
+    /// hold a single role.
+    pub fn register_new_facility(
+        &mut self,
+        kind: FacilityKind,
+        facility_principal: FacilityPrincipal,
+        display_name: String,
+        id: Id
+    ) -> Result<(), String> {
+        if self.is_valid_provider(&facility_principal) {
+            return Err("principal is already registered as provider".to_string());
+        }
+
+        self.check_not_facility(&facility_principal)?;
+
+        let facility = Facility::new(display_name, facility_principal, id.clone())?;
+        let (facilities, bindings) = self.facilities_mut(kind);
+
+        bindings.bind(facility_principal, id.clone())?;
+        facilities.insert(id, facility).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// suspend a pharmacy or laboratory, suspended facilities can't call any of their role's methods.
+    pub fn suspend_facility(
+        &mut self,
+        kind: FacilityKind,
+        facility_principal: &FacilityPrincipal
+    ) -> Result<(), String> {
+        let not_found = || format!("{} not found", kind.name());
+
+        let internal_id = self.facility_internal_id(kind, facility_principal).ok_or_else(not_found)?;
+        let mut facility = self.facilities_mut(kind).0.get_mut(&internal_id).ok_or_else(not_found)?;
+
+        facility.activation_status = Status::Suspended;
+
+        Ok(())
+    }
+
+    /// check a given principal is registered as a facility of `kind` that is not suspended
+    pub fn is_valid_facility(&self, kind: FacilityKind, facility: &FacilityPrincipal) -> bool {
+        self.facility_internal_id(kind, facility)
+            .and_then(|id| {
+                self.facilities(kind)
+                    .0.get(&id)
+                    .map(|facility| facility.activation_status.is_verified())
+            })
+            .unwrap_or(false)
+    }
+
+    /// check a facility of `kind` with the given internal id exists and is not suspended
+    pub fn is_active_facility_id(&self, kind: FacilityKind, id: &InternalFacilityId) -> bool {
+        self.facilities(kind)
+            .0.get(id)
+            .map(|facility| facility.activation_status.is_verified())
+            .unwrap_or(false)
+    }
+
+    /// resolve a facility principal to its internal id
+    pub fn facility_internal_id(&self, kind: FacilityKind, facility: &FacilityPrincipal) -> Option<InternalFacilityId> {
+        self.facilities(kind)
+            .1.get_internal_id(facility)
+            .map(|id| (*id).clone())
+    }
+
+    /// internal id and encrypted display name of every facility of `kind` that is not suspended
+    pub fn active_facilities(&self, kind: FacilityKind) -> Vec<(InternalFacilityId, String)> {
+        self.facilities(kind)
+            .0.iter()
+            .filter(|(_, facility)| facility.activation_status.is_verified())
+            .map(|(id, facility)| ((*id).clone(), (*facility.display_name).clone()))
+            .collect()
+    }
 }
 
 pub type InternalProviderId = Id;
 pub type ProviderPrincipal = Principal;
+pub type InternalFacilityId = Id;
+pub type InternalPharmacyId = InternalFacilityId;
+pub type InternalLaboratoryId = InternalFacilityId;
+pub type FacilityPrincipal = Principal;
 /// Issued emr map. used to track emr issued by a particular provider.
 #[derive(Default)]
 pub struct Issued(SBTreeMap<InternalProviderId, EmrIdCollection>);
 deref!(mut Issued: SBTreeMap<InternalProviderId, EmrIdCollection>);
 
 impl Issued {
-    pub fn is_issued_by(&self, provider: &InternalProviderId, _emr_id: &Id) -> bool {
-        self.contains_key(provider)
+    pub fn is_issued_by(&self, provider: &InternalProviderId, emr_id: &Id) -> bool {
+        self.get(provider)
+            .map(|emr_ids| emr_ids.contains(emr_id))
+            .unwrap_or(false)
+    }
+
+    pub fn issuer_of(&self, emr_id: &Id) -> Option<InternalProviderId> {
+        self.iter()
+            .find(|(_, emr_ids)| emr_ids.contains(emr_id))
+            .map(|(provider, _)| (*provider).clone())
     }
 
     pub fn issue_emr(
@@ -245,6 +378,12 @@ impl EssentialProviderAttributes for Provider {
             Provider::V001(provider) => provider.internal_id(),
         }
     }
+
+    fn display_name(&self) -> String {
+        match self {
+            Provider::V001(provider) => provider.display_name(),
+        }
+    }
 }
 
 impl Billable for Provider {
@@ -285,6 +424,9 @@ impl std::cmp::Ord for Provider {
 pub trait EssentialProviderAttributes {
     /// used to automatically derive [PartialEq], [PartialOrd], [Ord] and [Eq] for [Provider] enum members at enum level.
     fn internal_id(&self) -> &InternalProviderId;
+
+    /// encrypted display name of the provider, copied to the heap
+    fn display_name(&self) -> String;
 }
 
 /// Billable trait, this trait must be implemented for all [Provider] enum members.
@@ -413,6 +555,74 @@ impl EssentialProviderAttributes for ProviderV001 {
     fn internal_id(&self) -> &InternalProviderId {
         &self.internal_id
     }
+
+    fn display_name(&self) -> String {
+        (*self.display_name).clone()
+    }
 }
 
 // END ------------------------------ PROVIDER V1 ------------------------------ END
+
+// START ------------------------------ FACILITY ------------------------------ START
+
+/// role of a non provider principal, see [Facility]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FacilityKind {
+    /// looks up and dispenses prescriptions
+    Pharmacy,
+    /// receives lab orders and reports their results
+    Laboratory,
+}
+
+impl FacilityKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Pharmacy => "pharmacy",
+            Self::Laboratory => "laboratory",
+        }
+    }
+}
+
+/// Facility map. used to track pharmacies or laboratories using [InternalFacilityId] as key.
+#[derive(Default)]
+pub struct Facilities(SBTreeMap<InternalFacilityId, Facility>);
+
+deref!(mut Facilities: SBTreeMap<InternalFacilityId, Facility>);
+
+/// Pharmacy or laboratory. registered and bound to its principal the same way as [Provider], so the principal
+/// can be changed without touching the records it produced, but it can't issue or read emrs.
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Facility {
+    /// facility activation status, suspended facilities can't act in their role
+    activation_status: Status,
+
+    /// encrypted display name of the facility
+    display_name: SBox<String>,
+
+    /// internal identifier for this facility
+    internal_id: InternalFacilityId,
+
+    /// principal acting on behalf of this facility
+    owner_principal: Principal,
+
+    /// time when this facility was registered in nanosecond
+    registered_at: Timestamp,
+}
+
+impl Facility {
+    pub fn new(
+        encrypted_display_name: String,
+        initial_principal: Principal,
+        id: Id
+    ) -> Result<Self, OutOfMemory> {
+        Ok(Facility {
+            activation_status: Status::Verified,
+            display_name: SBox::new(encrypted_display_name).map_err(OutOfMemory::from)?,
+            internal_id: id,
+            owner_principal: initial_principal,
+            registered_at: Timestamp::new(),
+        })
+    }
+}
+
+// END ------------------------------ FACILITY ------------------------------ END
@@ -0,0 +1,322 @@
+use candid::{ CandidType, Principal };
+use ic_stable_memory::{
+    collections::{ SBTreeMap, SBTreeSet },
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::types::{ Id, Timestamp };
+
+use super::{ patient::NIK, EmrId, OutOfMemory };
+
+type RequestId = Id;
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErasureStatus {
+    /// waiting for admin review
+    Pending,
+    /// approved by an admin, emrs are purged once their retention period has passed
+    Approved,
+    /// rejected by an admin
+    Rejected,
+}
+
+/// patient request to erase every emr bound to them
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct ErasureRequest {
+    patient: NIK,
+    requested_by: Principal,
+    requested_at: Timestamp,
+    status: ErasureStatus,
+    decided_by: Option<Principal>,
+    decided_at: Option<Timestamp>,
+    /// rejection reason given by the admin
+    reason: Option<SBox<String>>,
+}
+
+impl ErasureRequest {
+    pub fn new(patient: NIK, requested_by: Principal) -> Self {
+        Self {
+            patient,
+            requested_by,
+            requested_at: Timestamp::new(),
+            status: ErasureStatus::Pending,
+            decided_by: None,
+            decided_at: None,
+            reason: None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self.status, ErasureStatus::Pending)
+    }
+}
+
+/// heap copy of an [ErasureRequest], returned to patients and admins
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ErasureRequestDisplay {
+    request_id: RequestId,
+    patient: NIK,
+    requested_by: Principal,
+    requested_at: Timestamp,
+    status: ErasureStatus,
+    decided_by: Option<Principal>,
+    decided_at: Option<Timestamp>,
+    reason: Option<String>,
+}
+
+impl ErasureRequestDisplay {
+    pub fn new(request_id: RequestId, request: &ErasureRequest) -> Self {
+        Self {
+            request_id,
+            patient: request.patient.clone(),
+            requested_by: request.requested_by,
+            requested_at: request.requested_at,
+            status: request.status,
+            decided_by: request.decided_by,
+            decided_at: request.decided_at,
+            reason: request.reason.as_ref().map(|reason| (**reason).clone()),
+        }
+    }
+}
+
+/// what remains of an erased emr. the emr id stays bound to its patient and issuing provider
+/// so references to it keep resolving, but the records themselves are gone.
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone)]
+pub struct Tombstone {
+    request_id: RequestId,
+    created_at: Timestamp,
+    erased_at: Timestamp,
+}
+
+impl Tombstone {
+    pub fn new(request_id: RequestId, created_at: Timestamp) -> Self {
+        Self { request_id, created_at, erased_at: Timestamp::new() }
+    }
+}
+
+/// scheduled purge, ordered by the time the emr becomes eligible for erasure
+pub type ScheduledPurge = (Timestamp, EmrId, RequestId);
+
+/// Erasure requests, scheduled purges and tombstones of erased emrs.
+#[derive(Default)]
+pub struct RetentionRegistry {
+    requests: SBTreeMap<RequestId, ErasureRequest>,
+    purge_queue: SBTreeSet<ScheduledPurge>,
+    /// number of purges still queued for every request, so the end of a request is known without scanning the queue
+    scheduled: SBTreeMap<RequestId, u64>,
+    tombstones: SBTreeMap<EmrId, Tombstone>,
+}
+
+impl RetentionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// file a new erasure request, a patient can only have one pending request at a time
+    pub fn request(&mut self, request_id: RequestId, request: ErasureRequest) -> Result<(), String> {
+        let has_pending = self.requests
+            .iter()
+            .any(|(_, pending)| pending.is_pending() && pending.patient.eq(&request.patient));
+
+        if has_pending {
+            return Err("patient already has a pending erasure request".to_string());
+        }
+
+        Ok(
+            self.requests
+                .insert(request_id, request)
+                .map_err(OutOfMemory::from)
+                .map(|_| ())?
+        )
+    }
+
+    /// mark a pending request as approved or rejected, returns the patient of the request
+    pub fn decide(
+        &mut self,
+        request_id: &RequestId,
+        admin: Principal,
+        approved: bool,
+        reason: Option<String>
+    ) -> Result<NIK, String> {
+        let Some(mut request) = self.requests.get_mut(request_id) else {
+            return Err("erasure request not found".to_string());
+        };
+
+        if !request.is_pending() {
+            return Err("erasure request already decided".to_string());
+        }
+
+        let reason = match reason {
+            Some(reason) => Some(SBox::new(reason).map_err(OutOfMemory::from)?),
+            None => None,
+        };
+
+        request.status = match approved {
+            true => ErasureStatus::Approved,
+            false => ErasureStatus::Rejected,
+        };
+        request.decided_by = Some(admin);
+        request.decided_at = Some(Timestamp::new());
+        request.reason = reason;
+
+        Ok(request.patient.clone())
+    }
+
+    /// schedule an emr to be purged once `eligible_at` has passed
+    pub fn schedule(
+        &mut self,
+        eligible_at: Timestamp,
+        emr_id: EmrId,
+        request_id: RequestId
+    ) -> Result<(), OutOfMemory> {
+        let count = self.scheduled.get(&request_id).map(|count| *count).unwrap_or_default();
+        self.scheduled.insert(request_id.clone(), count + 1).map_err(OutOfMemory::from)?;
+
+        let queued = self.purge_queue.insert((eligible_at, emr_id, request_id.clone())).map_err(OutOfMemory::from)?;
+
+        // the purge was already queued, keep the count as it was
+        if queued {
+            self.release(&request_id);
+        }
+
+        Ok(())
+    }
+
+    fn release(&mut self, request_id: &RequestId) {
+        let Some(mut count) = self.scheduled.get_mut(request_id) else {
+            return;
+        };
+
+        *count -= 1;
+        let done = *count == 0;
+        drop(count);
+
+        if done {
+            self.scheduled.remove(request_id);
+        }
+    }
+
+    /// returns at most `max` scheduled purges that are eligible at `now`. entries stay on the queue until
+    /// [RetentionRegistry::unschedule] is called, so a purge that fails halfway is retried on the next sweep.
+    pub fn due(&self, now: &Timestamp, max: usize) -> Vec<ScheduledPurge> {
+        self.purge_queue
+            .iter()
+            .take_while(|purge| purge.0.le(now))
+            .take(max)
+            .map(|purge| (*purge).clone())
+            .collect()
+    }
+
+    /// remove a purge from the queue once the emr has been erased or the purge rescheduled
+    pub fn unschedule(&mut self, purge: &ScheduledPurge) {
+        if self.purge_queue.remove(purge) {
+            self.release(&purge.2);
+        }
+    }
+
+    /// returns true if an emr of the erasure request is still waiting to be purged
+    pub fn is_scheduled(&self, request_id: &RequestId) -> bool {
+        self.scheduled.contains_key(request_id)
+    }
+
+    pub fn patient_of(&self, request_id: &RequestId) -> Option<NIK> {
+        self.requests.get(request_id).map(|request| request.patient.clone())
+    }
+
+    /// point every erasure request for `from` to `into`, used when merging duplicate patients
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) {
+        let request_ids = self.requests
+            .iter()
+            .filter(|(_, request)| request.patient.eq(from))
+            .map(|(request_id, _)| (*request_id).clone())
+            .collect::<Vec<_>>();
+
+        for request_id in request_ids {
+            self.requests.get_mut(&request_id).unwrap().patient = into.clone();
+        }
+    }
+
+    pub fn bury(&mut self, emr_id: EmrId, tombstone: Tombstone) -> Result<(), OutOfMemory> {
+        self.tombstones
+            .insert(emr_id, tombstone)
+            .map_err(OutOfMemory::from)
+            .map(|_| ())
+    }
+
+    pub fn tombstone(&self, emr_id: &EmrId) -> Option<Tombstone> {
+        self.tombstones.get(emr_id).map(|tombstone| (*tombstone).clone())
+    }
+
+    /// returns every erasure request of `patient` in chronological order
+    pub fn requests_of(&self, patient: &NIK) -> Vec<ErasureRequestDisplay> {
+        self.requests
+            .iter()
+            .filter(|(_, request)| request.patient.eq(patient))
+            .map(|(request_id, request)| ErasureRequestDisplay::new((*request_id).clone(), &request))
+            .collect()
+    }
+
+    /// returns at most `max` erasure requests waiting for admin review in chronological order, skipping the first
+    /// `anchor` of them
+    pub fn pending(&self, anchor: u64, max: usize) -> Vec<ErasureRequestDisplay> {
+        self.requests
+            .iter()
+            .filter(|(_, request)| request.is_pending())
+            .skip(anchor as usize)
+            .take(max)
+            .map(|(request_id, request)| ErasureRequestDisplay::new((*request_id).clone(), &request))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::fixtures::id;
+
+    #[test]
+    fn test_single_pending_request_per_patient() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut registry = RetentionRegistry::new();
+        let patient = "a".repeat(64).parse::<NIK>().unwrap();
+
+        registry.request(id(1), ErasureRequest::new(patient.clone(), Principal::anonymous())).unwrap();
+        assert!(
+            registry.request(id(2), ErasureRequest::new(patient.clone(), Principal::anonymous())).is_err()
+        );
+
+        registry.decide(&id(1), Principal::anonymous(), false, Some("legal hold".to_string())).unwrap();
+        assert!(registry.decide(&id(1), Principal::anonymous(), true, None).is_err());
+        assert!(registry.pending(0, 10).is_empty());
+
+        registry.request(id(2), ErasureRequest::new(patient.clone(), Principal::anonymous())).unwrap();
+        assert_eq!(registry.requests_of(&patient).len(), 2);
+    }
+
+    #[test]
+    fn test_due_respects_schedule_and_batch() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut registry = RetentionRegistry::new();
+        let now = Timestamp::new();
+
+        registry.schedule(Timestamp(now.inner() - 2), id(1), id(9)).unwrap();
+        registry.schedule(Timestamp(now.inner() - 1), id(2), id(9)).unwrap();
+        registry.schedule(Timestamp(now.inner() + 1_000_000_000), id(3), id(9)).unwrap();
+
+        let first = registry.due(&now, 1);
+        assert_eq!(first, vec![(Timestamp(now.inner() - 2), id(1), id(9))]);
+
+        // entries stay queued until they are unscheduled
+        assert_eq!(registry.due(&now, 10).len(), 2);
+
+        registry.unschedule(&first[0]);
+        assert_eq!(registry.due(&now, 10), vec![(Timestamp(now.inner() - 1), id(2), id(9))]);
+
+        registry.unschedule(&(Timestamp(now.inner() - 1), id(2), id(9)));
+        assert!(registry.due(&now, 10).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+
+use candid::CandidType;
+use ic_stable_memory::{
+    collections::SBTreeMap,
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::types::{ AsciiRecordsKey, Timestamp };
+
+use super::{ EmrId, OutOfMemory };
+
+/// section of the clinical summary a record belongs to, decided by the part of its key before the first `.`,
+/// e.g. `diagnosis` and `diagnosis.secondary` are both problems.
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummarySection {
+    Problem,
+    Allergy,
+    Medication,
+}
+
+impl SummarySection {
+    pub fn of(key: &AsciiRecordsKey) -> Option<Self> {
+        let prefix = key.to_ascii_str().split('.').next().unwrap_or_default();
+
+        match prefix {
+            "diagnosis" | "problem" => Some(Self::Problem),
+            "allergy" => Some(Self::Allergy),
+            "medication" => Some(Self::Medication),
+            _ => None,
+        }
+    }
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct SummaryEntry {
+    section: SummarySection,
+    value: SBox<String>,
+    recorded_at: Timestamp,
+}
+
+/// an item of the summary, the same value recorded in several emrs shows up once
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SummaryItem {
+    pub value: String,
+    /// emrs the value is recorded in
+    pub sources: Vec<EmrId>,
+    pub first_recorded_at: Timestamp,
+    pub last_recorded_at: Timestamp,
+}
+
+/// overview of a patient across every emr bound to them, most recently recorded items first
+#[derive(CandidType, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClinicalSummary {
+    pub problems: Vec<SummaryItem>,
+    pub allergies: Vec<SummaryItem>,
+    pub medications: Vec<SummaryItem>,
+}
+
+/// Summary relevant records of every emr, updated on every emr mutation so building a patient summary only
+/// touches these entries instead of every record of every emr. entries are kept per emr rather than per patient
+/// so merging patients or moving an emr to another patient doesn't need to touch them.
+#[derive(Default)]
+pub struct ClinicalSummaries(SBTreeMap<EmrId, SBTreeMap<AsciiRecordsKey, SummaryEntry>>);
+
+impl ClinicalSummaries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// reflect a record written to an emr, records outside of the summary sections are ignored
+    pub fn apply(
+        &mut self,
+        emr_id: &EmrId,
+        key: &AsciiRecordsKey,
+        value: &str,
+        now: Timestamp
+    ) -> Result<(), OutOfMemory> {
+        let Some(section) = SummarySection::of(key) else {
+            return Ok(());
+        };
+
+        if !self.0.contains_key(emr_id) {
+            self.0.insert(emr_id.clone(), SBTreeMap::new()).map_err(OutOfMemory::from)?;
+        }
+
+        let entry = SummaryEntry {
+            section,
+            value: SBox::new(value.trim().to_string()).map_err(OutOfMemory::from)?,
+            recorded_at: now,
+        };
+
+        self.0
+            .get_mut(emr_id)
+            .expect("index was just created")
+            .insert(key.clone(), entry)
+            .map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// drop the entries of an erased emr
+    pub fn remove_emr(&mut self, emr_id: &EmrId) {
+        self.0.remove(emr_id);
+    }
+
+    /// summary over the given emrs, values are deduplicated ignoring case and surrounding whitespace
+    pub fn summary(&self, emr_ids: &[EmrId]) -> ClinicalSummary {
+        let mut sections: [BTreeMap<String, SummaryItem>; 3] = Default::default();
+
+        for emr_id in emr_ids {
+            let Some(entries) = self.0.get(emr_id) else {
+                continue;
+            };
+
+            for (_, entry) in entries.iter() {
+                let items = &mut sections[entry.section as usize];
+
+                let item = items.entry(entry.value.to_lowercase()).or_insert_with(|| SummaryItem {
+                    value: (*entry.value).clone(),
+                    sources: vec![],
+                    first_recorded_at: entry.recorded_at,
+                    last_recorded_at: entry.recorded_at,
+                });
+
+                if !item.sources.contains(emr_id) {
+                    item.sources.push(emr_id.clone());
+                }
+
+                item.first_recorded_at = item.first_recorded_at.min(entry.recorded_at);
+                if entry.recorded_at > item.last_recorded_at {
+                    item.last_recorded_at = entry.recorded_at;
+                    item.value = (*entry.value).clone();
+                }
+            }
+        }
+
+        let [problems, allergies, medications] = sections.map(|items| {
+            let mut items = items.into_values().collect::<Vec<_>>();
+            items.sort_by_key(|item| std::cmp::Reverse(item.last_recorded_at));
+            items
+        });
+
+        ClinicalSummary { problems, allergies, medications }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::fixtures::id;
+
+    fn key(key: &str) -> AsciiRecordsKey {
+        AsciiRecordsKey::new(key).unwrap()
+    }
+
+    #[test]
+    fn test_summary_across_emrs() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut summaries = ClinicalSummaries::new();
+
+        summaries.apply(&id(1), &key("diagnosis"), "Typhoid fever", Timestamp(1)).unwrap();
+        summaries.apply(&id(1), &key("allergy"), "Penicillin", Timestamp(1)).unwrap();
+        summaries.apply(&id(1), &key("note"), "rest", Timestamp(1)).unwrap();
+        summaries.apply(&id(2), &key("allergy.drug"), "penicillin ", Timestamp(2)).unwrap();
+        summaries.apply(&id(2), &key("medication"), "Chloramphenicol 500mg", Timestamp(2)).unwrap();
+
+        // an updated record replaces the previous value of the same emr
+        summaries.apply(&id(1), &key("diagnosis"), "Dengue fever", Timestamp(3)).unwrap();
+
+        let summary = summaries.summary(&[id(1), id(2)]);
+        assert_eq!(summary.problems.len(), 1);
+        assert_eq!(summary.problems[0].value, "Dengue fever");
+        assert_eq!(summary.allergies.len(), 1);
+        assert_eq!(summary.allergies[0].sources, vec![id(1), id(2)]);
+        assert_eq!(summary.allergies[0].value, "penicillin");
+        assert_eq!(summary.medications.len(), 1);
+
+        // only emrs bound to the patient count
+        assert!(summaries.summary(&[id(1)]).medications.is_empty());
+
+        summaries.remove_emr(&id(2));
+        assert_eq!(summaries.summary(&[id(1), id(2)]).allergies[0].sources, vec![id(1)]);
+    }
+}
@@ -0,0 +1,450 @@
+use candid::{ CandidType, Principal };
+use ic_stable_memory::{
+    collections::SBTreeMap,
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::types::Timestamp;
+
+use super::{ patient::NIK, providers::InternalProviderId, OutOfMemory };
+
+/// max observations submitted in a single call
+const MAX_BATCH: usize = 100;
+
+/// max points returned by a single query, larger ranges must be downsampled
+const MAX_POINTS: usize = 2000;
+
+/// smallest downsampling bucket, one minute
+const MIN_BUCKET: u64 = 60 * 1_000_000_000;
+
+/// measurements may be timestamped slightly ahead of the canister clock, devices clocks drift
+const MAX_CLOCK_SKEW: u64 = 5 * 60 * 1_000_000_000;
+
+const MAX_DEVICE_LEN: usize = 64;
+
+/// kind of a vital sign or measurement. every kind has a fixed LOINC code and unit so a series stays comparable
+/// regardless of who measured it.
+#[derive(
+    StableType,
+    AsFixedSizeBytes,
+    CandidType,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Clone,
+    Copy,
+    Debug
+)]
+pub enum VitalKind {
+    SystolicBloodPressure,
+    DiastolicBloodPressure,
+    HeartRate,
+    RespiratoryRate,
+    BodyTemperature,
+    OxygenSaturation,
+    BloodGlucose,
+    BodyWeight,
+    BodyHeight,
+}
+
+impl VitalKind {
+    pub fn loinc(&self) -> &'static str {
+        match self {
+            Self::SystolicBloodPressure => "8480-6",
+            Self::DiastolicBloodPressure => "8462-4",
+            Self::HeartRate => "8867-4",
+            Self::RespiratoryRate => "9279-1",
+            Self::BodyTemperature => "8310-5",
+            Self::OxygenSaturation => "59408-5",
+            Self::BloodGlucose => "2339-0",
+            Self::BodyWeight => "29463-7",
+            Self::BodyHeight => "8302-2",
+        }
+    }
+
+    /// UCUM unit values of this kind are expressed in
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Self::SystolicBloodPressure | Self::DiastolicBloodPressure => "mm[Hg]",
+            Self::HeartRate | Self::RespiratoryRate => "/min",
+            Self::BodyTemperature => "Cel",
+            Self::OxygenSaturation => "%",
+            Self::BloodGlucose => "mg/dL",
+            Self::BodyWeight => "kg",
+            Self::BodyHeight => "cm",
+        }
+    }
+
+    /// physiologically plausible values, anything outside is a typo or a unit mixup
+    fn plausible(&self) -> (f64, f64) {
+        match self {
+            Self::SystolicBloodPressure => (20.0, 300.0),
+            Self::DiastolicBloodPressure => (10.0, 200.0),
+            Self::HeartRate => (10.0, 300.0),
+            Self::RespiratoryRate => (1.0, 100.0),
+            Self::BodyTemperature => (25.0, 45.0),
+            Self::OxygenSaturation => (0.0, 100.0),
+            Self::BloodGlucose => (10.0, 1000.0),
+            Self::BodyWeight => (0.2, 700.0),
+            Self::BodyHeight => (20.0, 300.0),
+        }
+    }
+}
+
+/// who took a measurement
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ObservationSource {
+    /// measured by a provider, e.g. during a visit
+    Provider(InternalProviderId),
+    /// home measurement submitted by the patient, or a guardian acting for them
+    Patient(Principal),
+}
+
+/// a single measurement as submitted
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct VitalInput {
+    pub kind: VitalKind,
+    /// value in the unit of `kind`, see [VitalKind::unit]
+    pub value: f64,
+    /// time of the measurement in nanoseconds, defaults to the time of submission
+    pub measured_at: Option<Timestamp>,
+    /// device the measurement was taken with, e.g. a blood pressure monitor model
+    pub device: Option<String>,
+}
+
+impl VitalInput {
+    fn validate(&self, now: &Timestamp) -> Result<(), String> {
+        let (min, max) = self.kind.plausible();
+
+        if !(min..=max).contains(&self.value) {
+            return Err(
+                format!("{:?} must be between {} and {} {}", self.kind, min, max, self.kind.unit())
+            );
+        }
+
+        if self.measured_at.is_some_and(|at| at.inner() > now.inner().saturating_add(MAX_CLOCK_SKEW)) {
+            return Err("measurement time is in the future".to_string());
+        }
+
+        if let Some(device) = &self.device {
+            if device.trim().is_empty() || device.len() > MAX_DEVICE_LEN {
+                return Err(format!("device must be between 1 and {} characters", MAX_DEVICE_LEN));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// series key, orders observations of a patient by kind then time. `seq` tells apart observations of the same
+/// kind taken at the same time.
+#[derive(StableType, AsFixedSizeBytes, Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
+struct ObservationKey {
+    kind: VitalKind,
+    measured_at: Timestamp,
+    seq: u32,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Observation {
+    value: f64,
+    source: ObservationSource,
+    device: Option<SBox<String>>,
+    recorded_at: Timestamp,
+}
+
+/// range query over the series of a single kind
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct VitalsQuery {
+    pub kind: VitalKind,
+    /// inclusive start of the range in nanoseconds, unbounded if none
+    pub from: Option<Timestamp>,
+    /// exclusive end of the range in nanoseconds, unbounded if none
+    pub to: Option<Timestamp>,
+    /// downsample into buckets of this many nanoseconds, aligned to the unix epoch. raw samples if none
+    pub bucket: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct VitalSample {
+    pub measured_at: Timestamp,
+    pub value: f64,
+    pub source: ObservationSource,
+    pub device: Option<String>,
+    pub recorded_at: Timestamp,
+}
+
+/// aggregate of the samples within a bucket
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct VitalBucket {
+    pub start: Timestamp,
+    pub count: u32,
+    /// how many of the samples were reported by the patient rather than measured by a provider
+    pub patient_reported: u32,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub enum VitalPoints {
+    Samples(Vec<VitalSample>),
+    Buckets(Vec<VitalBucket>),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct VitalSeries {
+    pub kind: VitalKind,
+    pub loinc: String,
+    pub unit: String,
+    pub points: VitalPoints,
+}
+
+/// Vital signs and other repeated measurements per patient, kept apart from emr records because every
+/// measurement is kept instead of overwriting the previous one.
+#[derive(Default)]
+pub struct Vitals(SBTreeMap<NIK, SBTreeMap<ObservationKey, Observation>>);
+
+impl Vitals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(
+        series: &mut SBTreeMap<ObservationKey, Observation>,
+        kind: VitalKind,
+        measured_at: Timestamp,
+        observation: Observation
+    ) -> Result<(), OutOfMemory> {
+        let mut key = ObservationKey { kind, measured_at, seq: 0 };
+        while series.contains_key(&key) {
+            key.seq += 1;
+        }
+
+        series.insert(key, observation).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// record a batch of measurements of `patient`, either every measurement is recorded or none is
+    pub fn record(
+        &mut self,
+        patient: &NIK,
+        source: ObservationSource,
+        observations: Vec<VitalInput>,
+        now: Timestamp
+    ) -> Result<(), String> {
+        if observations.is_empty() || observations.len() > MAX_BATCH {
+            return Err(format!("between 1 and {} observations can be recorded at once", MAX_BATCH));
+        }
+
+        for observation in &observations {
+            observation.validate(&now)?;
+        }
+
+        if !self.0.contains_key(patient) {
+            self.0.insert(patient.clone(), SBTreeMap::new()).map_err(OutOfMemory::from)?;
+        }
+
+        let mut series = self.0.get_mut(patient).expect("series was just created");
+
+        for input in observations {
+            let observation = Observation {
+                value: input.value,
+                source: source.clone(),
+                device: input.device.map(SBox::new).transpose().map_err(OutOfMemory::from)?,
+                recorded_at: now,
+            };
+
+            Self::insert(&mut series, input.kind, input.measured_at.unwrap_or(now), observation)?;
+        }
+
+        Ok(())
+    }
+
+    /// query the series of a single kind of `patient`, ordered by measurement time
+    pub fn query(&self, patient: &NIK, query: &VitalsQuery) -> Result<VitalSeries, String> {
+        if query.bucket.is_some_and(|bucket| bucket < MIN_BUCKET) {
+            return Err(format!("bucket must be at least {} nanoseconds", MIN_BUCKET));
+        }
+
+        let in_range = |key: &ObservationKey| {
+            key.kind == query.kind &&
+                query.from.is_none_or(|from| key.measured_at >= from) &&
+                query.to.is_none_or(|to| key.measured_at < to)
+        };
+
+        let samples = match self.0.get(patient) {
+            Some(series) =>
+                series
+                    .iter()
+                    .filter(|(key, _)| in_range(key))
+                    .map(|(key, observation)| VitalSample {
+                        measured_at: key.measured_at,
+                        value: observation.value,
+                        source: observation.source.clone(),
+                        device: observation.device.as_ref().map(|device| (**device).clone()),
+                        recorded_at: observation.recorded_at,
+                    })
+                    .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        let points = match query.bucket {
+            None => VitalPoints::Samples(samples),
+            Some(bucket) => VitalPoints::Buckets(downsample(&samples, bucket)),
+        };
+
+        let len = match &points {
+            VitalPoints::Samples(samples) => samples.len(),
+            VitalPoints::Buckets(buckets) => buckets.len(),
+        };
+
+        if len > MAX_POINTS {
+            return Err(format!("query returns more than {} points, narrow the range or downsample", MAX_POINTS));
+        }
+
+        Ok(VitalSeries {
+            kind: query.kind,
+            loinc: query.kind.loinc().to_string(),
+            unit: query.kind.unit().to_string(),
+            points,
+        })
+    }
+
+    /// move every measurement of `from` to `into`, used when two patient records are merged
+    pub fn rekey(&mut self, from: &NIK, into: &NIK) -> Result<(), OutOfMemory> {
+        let Some(mut moved) = self.0.remove(from) else {
+            return Ok(());
+        };
+
+        if !self.0.contains_key(into) {
+            self.0.insert(into.clone(), SBTreeMap::new()).map_err(OutOfMemory::from)?;
+        }
+
+        let mut series = self.0.get_mut(into).expect("series was just created");
+
+        let keys = moved
+            .iter()
+            .map(|(key, _)| (*key).clone())
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            let observation = moved.remove(&key).expect("key was just listed");
+            Self::insert(&mut series, key.kind, key.measured_at, observation)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn has_patient(&self, patient: &NIK) -> bool {
+        self.0.contains_key(patient)
+    }
+
+    /// remove every measurement of `patient`, used when the patient's records are erased
+    pub fn remove_patient(&mut self, patient: &NIK) {
+        self.0.remove(patient);
+    }
+}
+
+/// aggregate samples sorted by measurement time into epoch aligned buckets
+fn downsample(samples: &[VitalSample], bucket: u64) -> Vec<VitalBucket> {
+    let mut buckets: Vec<VitalBucket> = vec![];
+
+    for sample in samples {
+        let start = Timestamp(sample.measured_at.inner() - (sample.measured_at.inner() % bucket));
+        let patient_reported = matches!(sample.source, ObservationSource::Patient(_)) as u32;
+
+        match buckets.last_mut() {
+            Some(last) if last.start == start => {
+                last.mean = (last.mean * (last.count as f64) + sample.value) / ((last.count + 1) as f64);
+                last.count += 1;
+                last.patient_reported += patient_reported;
+                last.min = last.min.min(sample.value);
+                last.max = last.max.max(sample.value);
+            }
+            _ =>
+                buckets.push(VitalBucket {
+                    start,
+                    count: 1,
+                    patient_reported,
+                    min: sample.value,
+                    max: sample.value,
+                    mean: sample.value,
+                }),
+        }
+    }
+
+    buckets
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{ fixtures::nik, Id };
+
+    const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+    fn glucose(value: f64, hour: u64) -> VitalInput {
+        VitalInput {
+            kind: VitalKind::BloodGlucose,
+            value,
+            measured_at: Some(Timestamp(hour * HOUR)),
+            device: None,
+        }
+    }
+
+    fn provider_source() -> ObservationSource {
+        ObservationSource::Provider(Id::from(uuid::Uuid::from_bytes([1; 16])))
+    }
+
+    #[test]
+    fn test_series_and_downsampling() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut vitals = Vitals::new();
+        let now = Timestamp(100 * HOUR);
+        let patient = ObservationSource::Patient(Principal::anonymous());
+
+        vitals.record(&nik(1), provider_source(), vec![glucose(100.0, 1), glucose(140.0, 2)], now).unwrap();
+        // same time twice is kept as two measurements
+        vitals.record(&nik(1), patient, vec![glucose(120.0, 2), glucose(90.0, 30)], now).unwrap();
+
+        // implausible values and future measurements reject the whole batch
+        assert!(vitals.record(&nik(1), provider_source(), vec![glucose(95.0, 3), glucose(5000.0, 3)], now).is_err());
+        assert!(vitals.record(&nik(1), provider_source(), vec![glucose(95.0, 200)], now).is_err());
+
+        let query = VitalsQuery { kind: VitalKind::BloodGlucose, from: Some(Timestamp(2 * HOUR)), to: None, bucket: None };
+        let VitalPoints::Samples(samples) = vitals.query(&nik(1), &query).unwrap().points else {
+            panic!("expected raw samples");
+        };
+        assert_eq!(samples.len(), 3);
+        assert!(samples.windows(2).all(|pair| pair[0].measured_at <= pair[1].measured_at));
+
+        let query = VitalsQuery { from: None, bucket: Some(24 * HOUR), ..query };
+        let VitalPoints::Buckets(buckets) = vitals.query(&nik(1), &query).unwrap().points else {
+            panic!("expected buckets");
+        };
+        assert_eq!(buckets.len(), 2);
+        assert_eq!((buckets[0].count, buckets[0].patient_reported), (3, 1));
+        assert_eq!((buckets[0].min, buckets[0].max, buckets[0].mean), (100.0, 140.0, 120.0));
+        assert_eq!(buckets[1].start, Timestamp(24 * HOUR));
+
+        // other kinds are not part of the series
+        let query = VitalsQuery { kind: VitalKind::HeartRate, ..query };
+        assert_eq!(vitals.query(&nik(1), &query).unwrap().points, VitalPoints::Buckets(vec![]));
+
+        vitals.record(&nik(2), provider_source(), vec![glucose(100.0, 1)], now).unwrap();
+        vitals.rekey(&nik(2), &nik(1)).unwrap();
+
+        let query = VitalsQuery { kind: VitalKind::BloodGlucose, from: None, to: None, bucket: None };
+        let VitalPoints::Samples(samples) = vitals.query(&nik(1), &query).unwrap().points else {
+            panic!("expected raw samples");
+        };
+        assert_eq!(samples.len(), 5);
+    }
+}
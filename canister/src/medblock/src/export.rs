@@ -0,0 +1,330 @@
+use std::collections::{ BTreeMap, HashMap };
+
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+use crate::{
+    emr::{ patient::NIK, providers::InternalProviderId, Emr },
+    types::{ Id, Timestamp },
+};
+
+/// identifies a medblock patient export bundle, checked on import so unrelated payloads are rejected early
+pub const BUNDLE_KIND: &str = "medblock/patient-export";
+
+/// current bundle layout version, bump when [ExportBundle] changes in a non backward compatible way
+pub const BUNDLE_VERSION: u16 = 1;
+
+/// maximum bytes of bundle data per chunk, leaves headroom below the 2MB response and ingress limit
+/// for the candid envelope of [ExportChunk].
+pub const CHUNK_SIZE: usize = 1_500_000;
+
+/// serialization format of an [ExportBundle]
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Cbor,
+    Json,
+}
+
+/// self describing, versioned bundle of every emr of a patient
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ExportBundle {
+    kind: String,
+    version: u16,
+    /// text form of the patient binding key, see [NIK]
+    patient: String,
+    /// latest update time among the exported emrs, the bundle reflects the patient data as of this time.
+    /// used instead of the export time so repeated exports of unchanged data are byte identical.
+    as_of: Timestamp,
+    emrs: Vec<ExportedEmr>,
+}
+
+/// a single emr inside an [ExportBundle]
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ExportedEmr {
+    emr_id: Id,
+    created_at: Timestamp,
+    updated_at: Timestamp,
+    /// internal id of the provider that issued the emr, none if the issuer is unknown
+    issued_by: Option<InternalProviderId>,
+    records: BTreeMap<String, String>,
+}
+
+impl ExportedEmr {
+    pub fn new(emr: &Emr, issued_by: Option<InternalProviderId>) -> Self {
+        Self {
+            emr_id: emr.id().clone(),
+            created_at: emr.created_at(),
+            updated_at: emr.updated_at(),
+            issued_by,
+            records: emr.records().to_pairs(),
+        }
+    }
+
+    pub fn emr_id(&self) -> &Id {
+        &self.emr_id
+    }
+
+    pub fn records(&self) -> &BTreeMap<String, String> {
+        &self.records
+    }
+}
+
+impl ExportBundle {
+    pub fn new(patient: &NIK, emrs: Vec<ExportedEmr>) -> Self {
+        let as_of = emrs
+            .iter()
+            .map(|emr| emr.updated_at)
+            .max()
+            .unwrap_or(Timestamp(0));
+
+        Self {
+            kind: BUNDLE_KIND.to_string(),
+            version: BUNDLE_VERSION,
+            patient: patient.to_text(),
+            as_of,
+            emrs,
+        }
+    }
+
+    pub fn patient(&self) -> &str {
+        &self.patient
+    }
+
+    pub fn as_of(&self) -> Timestamp {
+        self.as_of
+    }
+
+    pub fn emrs(&self) -> &[ExportedEmr] {
+        &self.emrs
+    }
+
+    pub fn encode(&self, format: ExportFormat) -> Result<Vec<u8>, String> {
+        match format {
+            ExportFormat::Json => serde_json::to_vec(self).map_err(|e| e.to_string()),
+            ExportFormat::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(self, &mut buffer).map_err(|e| e.to_string())?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    /// decode a bundle, returns an error if the payload is not a medblock bundle or its version is not supported
+    pub fn decode(format: ExportFormat, bytes: &[u8]) -> Result<Self, String> {
+        let bundle: Self = match format {
+            ExportFormat::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string())?,
+            ExportFormat::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string())?,
+        };
+
+        if bundle.kind != BUNDLE_KIND {
+            return Err("not a medblock export bundle".to_string());
+        }
+
+        if bundle.version > BUNDLE_VERSION {
+            return Err(format!("unsupported bundle version {}", bundle.version));
+        }
+
+        Ok(bundle)
+    }
+}
+
+/// a chunk of an encoded [ExportBundle]. every chunk carries the digest of the whole bundle so the receiver
+/// can detect chunks taken from different snapshots and verify the reassembled bundle.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct ExportChunk {
+    pub format: ExportFormat,
+    pub index: u32,
+    pub total: u32,
+    /// hex encoded sha256 of the whole encoded bundle
+    pub digest: String,
+    pub data: Vec<u8>,
+}
+
+impl ExportChunk {
+    /// take chunk `index` of an encoded bundle
+    pub fn from_bundle(format: ExportFormat, bundle: &[u8], index: u32) -> Result<Self, String> {
+        let total = bundle.len().div_ceil(CHUNK_SIZE).max(1) as u32;
+
+        if index >= total {
+            return Err(format!("chunk index out of range, bundle has {} chunks", total));
+        }
+
+        let data = bundle
+            .chunks(CHUNK_SIZE)
+            .nth(index as usize)
+            .unwrap_or_default()
+            .to_vec();
+
+        Ok(Self { format, index, total, digest: digest(bundle), data })
+    }
+}
+
+fn digest(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// bundle being uploaded chunk by chunk
+struct PendingImport {
+    format: ExportFormat,
+    digest: String,
+    total: u32,
+    next: u32,
+    data: Vec<u8>,
+}
+
+/// Bundles being imported, keyed by the uploading provider. a provider uploads one bundle at a time.
+/// kept on the heap because uploads are short lived, an upload interrupted by a canister upgrade must be restarted.
+#[derive(Default)]
+pub struct PendingImports(HashMap<Principal, PendingImport>);
+
+impl PendingImports {
+    /// append a chunk to the upload of `provider`. chunks must be sent in order, starting from index 0 which
+    /// also restarts any previous upload. returns the reassembled bundle once the last chunk arrives and the
+    /// digest matches. bundles larger than `max_bundle_size` bytes are rejected, and a new upload is refused
+    /// while `max_pending` other uploads are in progress.
+    pub fn push(
+        &mut self,
+        provider: Principal,
+        chunk: ExportChunk,
+        max_bundle_size: usize,
+        max_pending: usize
+    ) -> Result<Option<(ExportFormat, Vec<u8>)>, String> {
+        if chunk.data.len() > CHUNK_SIZE {
+            self.0.remove(&provider);
+            return Err(format!("chunk exceeds {} bytes, restart the import from chunk 0", CHUNK_SIZE));
+        }
+
+        if chunk.index == 0 {
+            if (chunk.total as usize) > max_bundle_size.div_ceil(CHUNK_SIZE) {
+                return Err(format!("bundle exceeds the maximum import size of {} bytes", max_bundle_size));
+            }
+
+            if !self.0.contains_key(&provider) && self.0.len() >= max_pending {
+                return Err("too many imports in progress, try again later".to_string());
+            }
+
+            self.0.insert(provider, PendingImport {
+                format: chunk.format,
+                digest: chunk.digest.clone(),
+                total: chunk.total,
+                next: 0,
+                data: Vec::new(),
+            });
+        }
+
+        let Some(pending) = self.0.get_mut(&provider) else {
+            return Err("no import in progress, start from chunk 0".to_string());
+        };
+
+        let same_bundle =
+            pending.format == chunk.format &&
+            pending.digest == chunk.digest &&
+            pending.total == chunk.total;
+
+        if !same_bundle || pending.next != chunk.index {
+            self.0.remove(&provider);
+            return Err("unexpected chunk, restart the import from chunk 0".to_string());
+        }
+
+        if pending.data.len() + chunk.data.len() > max_bundle_size {
+            self.0.remove(&provider);
+            return Err(format!("bundle exceeds the maximum import size of {} bytes", max_bundle_size));
+        }
+
+        pending.data.extend(chunk.data);
+        pending.next += 1;
+
+        if pending.next < pending.total {
+            return Ok(None);
+        }
+
+        let pending = self.0.remove(&provider).unwrap();
+
+        if digest(&pending.data) != pending.digest {
+            return Err("bundle digest mismatch".to_string());
+        }
+
+        Ok(Some((pending.format, pending.data)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bundle() -> ExportBundle {
+        let patient = "3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709"
+            .parse::<NIK>()
+            .unwrap();
+
+        let emr = ExportedEmr {
+            emr_id: Id::from(uuid::Uuid::new_v4()),
+            created_at: Timestamp(1),
+            updated_at: Timestamp(2),
+            issued_by: Some(Id::from(uuid::Uuid::new_v4())),
+            records: BTreeMap::from([("diagnosis".to_string(), "J06.9".to_string())]),
+        };
+
+        ExportBundle::new(&patient, vec![emr])
+    }
+
+    #[test]
+    fn test_bundle_roundtrip() {
+        let bundle = bundle();
+
+        for format in [ExportFormat::Cbor, ExportFormat::Json] {
+            let bytes = bundle.encode(format).unwrap();
+            assert_eq!(ExportBundle::decode(format, &bytes).unwrap(), bundle);
+        }
+
+        assert!(ExportBundle::decode(ExportFormat::Json, b"{\"kind\":\"other\"}").is_err());
+    }
+
+    #[test]
+    fn test_chunked_import() {
+        let bytes = vec![7u8; CHUNK_SIZE * 2 + 10];
+        let provider = Principal::anonymous();
+        let mut imports = PendingImports::default();
+        let max_bundle_size = bytes.len();
+
+        let total = ExportChunk::from_bundle(ExportFormat::Cbor, &bytes, 0).unwrap().total;
+        assert_eq!(total, 3);
+        assert!(ExportChunk::from_bundle(ExportFormat::Cbor, &bytes, 3).is_err());
+
+        // out of order chunk aborts the upload
+        let chunk = ExportChunk::from_bundle(ExportFormat::Cbor, &bytes, 1).unwrap();
+        assert!(imports.push(provider, chunk, max_bundle_size, 1).is_err());
+
+        let mut assembled = None;
+        for index in 0..total {
+            let chunk = ExportChunk::from_bundle(ExportFormat::Cbor, &bytes, index).unwrap();
+            assembled = imports.push(provider, chunk, max_bundle_size, 1).unwrap();
+        }
+
+        assert_eq!(assembled, Some((ExportFormat::Cbor, bytes)));
+    }
+
+    #[test]
+    fn test_import_limits() {
+        let bytes = vec![7u8; CHUNK_SIZE * 2 + 10];
+        let provider = Principal::anonymous();
+        let other = Principal::management_canister();
+        let mut imports = PendingImports::default();
+
+        // the bundle is larger than allowed
+        let chunk = ExportChunk::from_bundle(ExportFormat::Cbor, &bytes, 0).unwrap();
+        assert!(imports.push(provider, chunk, CHUNK_SIZE * 2, 1).is_err());
+
+        // a chunk larger than the chunk size aborts the upload
+        let mut chunk = ExportChunk::from_bundle(ExportFormat::Cbor, &bytes, 0).unwrap();
+        chunk.data.push(0);
+        assert!(imports.push(provider, chunk, bytes.len(), 1).is_err());
+
+        // only one upload may be pending, restarting it does not count against the limit
+        let chunk = ExportChunk::from_bundle(ExportFormat::Cbor, &bytes, 0).unwrap();
+        assert_eq!(imports.push(provider, chunk.clone(), bytes.len(), 1), Ok(None));
+        assert_eq!(imports.push(provider, chunk.clone(), bytes.len(), 1), Ok(None));
+        assert!(imports.push(other, chunk, bytes.len(), 1).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::{ BTreeMap, HashMap };
+
+use candid::CandidType;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::types::Id;
+
+use super::mapping::{ get_path, FhirMapping, MappingRule, ResourceType };
+
+/// what happened to a single resource of an imported bundle
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ResourceStatus {
+    /// the resource was mapped into the records of the emr with the given id
+    Imported(Id),
+    /// the resource is supported but could not be mapped
+    Rejected(String),
+    /// the resource type is not supported and was ignored
+    Skipped(String),
+}
+
+/// import outcome of a single bundle entry
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResourceOutcome {
+    /// position of the entry in the bundle
+    pub index: u32,
+    pub resource_type: Option<String>,
+    pub id: Option<String>,
+    pub status: ResourceStatus,
+}
+
+/// records collected for a single Encounter, becomes one emr
+#[derive(Debug, Default)]
+pub struct EncounterDraft {
+    records: BTreeMap<String, String>,
+    /// reference to the Patient of the encounter
+    subject: Option<String>,
+    /// index of the encounter entry and of every entry mapped into its records
+    entries: Vec<usize>,
+}
+
+impl EncounterDraft {
+    pub fn records(&self) -> &BTreeMap<String, String> {
+        &self.records
+    }
+
+    fn insert(&mut self, key: &str, value: String, entry: usize) -> Result<(), String> {
+        if self.records.contains_key(key) {
+            return Err(format!("duplicate record key {}", key));
+        }
+
+        self.records.insert(key.to_string(), value);
+        self.entries.push(entry);
+
+        Ok(())
+    }
+}
+
+/// Result of mapping a FHIR Bundle, one [EncounterDraft] per Encounter. outcomes of entries mapped into a draft
+/// are only settled once the emr is created, see [ImportPlan::settle].
+#[derive(Debug)]
+pub struct ImportPlan {
+    encounters: Vec<EncounterDraft>,
+    outcomes: Vec<ResourceOutcome>,
+}
+
+impl ImportPlan {
+    pub fn encounters(&self) -> &[EncounterDraft] {
+        &self.encounters
+    }
+
+    /// settle the outcome of every entry of an encounter with the result of creating its emr
+    pub fn settle(&mut self, encounter: usize, result: Result<Id, String>) {
+        let status = match result {
+            Ok(emr_id) => ResourceStatus::Imported(emr_id),
+            Err(e) => ResourceStatus::Rejected(e),
+        };
+
+        for entry in self.encounters[encounter].entries.iter() {
+            self.outcomes[*entry].status = status.clone();
+        }
+    }
+
+    pub fn into_outcomes(self) -> Vec<ResourceOutcome> {
+        self.outcomes
+    }
+}
+
+/// Maps FHIR R4 Bundles back into records, the inverse of [super::FhirRenderer] driven by the same [FhirMapping].
+pub struct FhirImporter<'a> {
+    mapping: &'a FhirMapping,
+}
+
+impl<'a> FhirImporter<'a> {
+    pub fn new(mapping: &'a FhirMapping) -> Self {
+        Self { mapping }
+    }
+
+    /// validate and map a bundle. returns an error if the payload is not a Bundle at all, problems with
+    /// individual entries are reported in their outcome instead.
+    pub fn plan(&self, bundle: &str) -> Result<ImportPlan, String> {
+        let bundle: Value = serde_json::from_str(bundle).map_err(|e| format!("invalid json : {}", e))?;
+
+        if bundle.get("resourceType").and_then(Value::as_str) != Some("Bundle") {
+            return Err("resourceType must be Bundle".to_string());
+        }
+
+        let entries = bundle
+            .get("entry")
+            .and_then(Value::as_array)
+            .ok_or("bundle has no entry".to_string())?;
+
+        let resources = entries
+            .iter()
+            .map(|entry| entry.get("resource"))
+            .collect::<Vec<_>>();
+
+        let mut outcomes = resources
+            .iter()
+            .enumerate()
+            .map(|(index, resource)| ResourceOutcome {
+                index: index as u32,
+                resource_type: resource
+                    .and_then(|r| r.get("resourceType"))
+                    .and_then(Value::as_str)
+                    .map(String::from),
+                id: resource
+                    .and_then(|r| r.get("id"))
+                    .and_then(Value::as_str)
+                    .map(String::from),
+                status: ResourceStatus::Skipped("unsupported resource type".to_string()),
+            })
+            .collect::<Vec<_>>();
+
+        // first pass, encounters and patients. every reference form an entry can be addressed with is
+        // mapped to the entry so later entries can resolve their encounter and subject.
+        let mut encounters = vec![];
+        let mut encounter_refs = HashMap::new();
+        let mut patients = HashMap::<String, Vec<(&str, String)>>::new();
+
+        for (index, resource) in resources.iter().enumerate() {
+            let Some(resource) = resource else {
+                outcomes[index].status = ResourceStatus::Rejected("entry has no resource".to_string());
+                continue;
+            };
+
+            let resource_type = outcomes[index].resource_type.as_deref().and_then(ResourceType::from_name);
+            let references = Self::references(&entries[index], resource);
+
+            match resource_type {
+                Some(ResourceType::Encounter) => {
+                    if references.is_empty() {
+                        outcomes[index].status = ResourceStatus::Rejected("encounter has no id".to_string());
+                        continue;
+                    }
+
+                    let mut draft = EncounterDraft {
+                        subject: get_path(resource, "subject.reference").and_then(Value::as_str).map(String::from),
+                        entries: vec![index],
+                        ..Default::default()
+                    };
+
+                    if let Err(e) = self.map_shared(ResourceType::Encounter, resource, index, &mut draft) {
+                        outcomes[index].status = ResourceStatus::Rejected(e);
+                        continue;
+                    }
+
+                    for reference in references {
+                        encounter_refs.insert(reference, encounters.len());
+                    }
+
+                    encounters.push(draft);
+                }
+                Some(ResourceType::Patient) => {
+                    let records = self
+                        .mapping
+                        .rules_for(ResourceType::Patient)
+                        .filter_map(|rule| Some((rule.key.as_str(), Self::text_at(resource, &rule.path)?)))
+                        .collect::<Vec<_>>();
+
+                    for reference in references {
+                        patients.insert(reference, records.clone());
+                    }
+
+                    // the patient is identified by the import owner, the resource only contributes records
+                    outcomes[index].status = ResourceStatus::Skipped(
+                        "patient fields are copied into its encounters".to_string()
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        // patient fields are copied into every encounter of that patient,
+        // encounter fields take precedence over patient fields with the same key
+        for draft in encounters.iter_mut() {
+            let fields = draft.subject.as_ref().and_then(|subject| patients.get(subject));
+
+            for (key, value) in fields.into_iter().flatten() {
+                draft.records.entry(key.to_string()).or_insert_with(|| value.clone());
+            }
+        }
+
+        // second pass, per record resources are mapped into the records of their encounter
+        for (index, resource) in resources.iter().enumerate() {
+            let Some(resource) = resource else {
+                continue;
+            };
+
+            let Some(resource_type) = outcomes[index].resource_type.as_deref().and_then(ResourceType::from_name) else {
+                continue;
+            };
+
+            if !resource_type.is_per_record() {
+                continue;
+            }
+
+            let reference_path = match resource_type {
+                ResourceType::MedicationStatement => "context.reference",
+                _ => "encounter.reference",
+            };
+
+            let reference = get_path(resource, reference_path).and_then(Value::as_str);
+            let Some(encounter) = reference.and_then(|reference| encounter_refs.get(reference)) else {
+                outcomes[index].status = ResourceStatus::Rejected(
+                    "resource does not reference an encounter in the bundle".to_string()
+                );
+                continue;
+            };
+
+            let mapped = self
+                .record_of(resource_type, resource)
+                .and_then(|(key, value)| encounters[*encounter].insert(&key, value, index));
+
+            if let Err(e) = mapped {
+                outcomes[index].status = ResourceStatus::Rejected(e);
+            }
+        }
+
+        Ok(ImportPlan { encounters, outcomes })
+    }
+
+    /// every reference form the resource of an entry can be addressed with, `<type>/<id>` and the entry fullUrl
+    fn references(entry: &Value, resource: &Value) -> Vec<String> {
+        let resource_type = resource.get("resourceType").and_then(Value::as_str);
+        let id = resource.get("id").and_then(Value::as_str);
+        let full_url = entry.get("fullUrl").and_then(Value::as_str);
+
+        resource_type
+            .zip(id)
+            .map(|(resource_type, id)| format!("{}/{}", resource_type, id))
+            .into_iter()
+            .chain(full_url.map(String::from))
+            .collect()
+    }
+
+    /// map the rules of a shared resource, every rule with a value at its path becomes a record
+    fn map_shared(
+        &self,
+        resource_type: ResourceType,
+        resource: &Value,
+        entry: usize,
+        draft: &mut EncounterDraft
+    ) -> Result<(), String> {
+        for rule in self.mapping.rules_for(resource_type) {
+            if let Some(value) = Self::text_at(resource, &rule.path) {
+                draft.insert(&rule.key, value, entry)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// find the records key and value of a per record resource. rules with a code are matched against the
+    /// resource codings, rules without one match any resource of their type. resources without a matching rule
+    /// fall back to their code text as key when unmapped records are included, mirroring the export.
+    fn record_of(&self, resource_type: ResourceType, resource: &Value) -> Result<(String, String), String> {
+        let code_element = match resource_type {
+            ResourceType::MedicationStatement => "medicationCodeableConcept",
+            _ => "code",
+        };
+
+        let codings = get_path(resource, &format!("{}.coding", code_element))
+            .and_then(Value::as_array)
+            .map(|codings| codings.as_slice())
+            .unwrap_or_default();
+
+        let code_matches = |rule: &&MappingRule| {
+            rule.code.as_ref().is_some_and(|code| {
+                codings.iter().any(|coding| {
+                    coding.get("system").and_then(Value::as_str) == Some(code.system.as_str()) &&
+                        coding.get("code").and_then(Value::as_str) == Some(code.code.as_str())
+                })
+            })
+        };
+
+        let rule = self
+            .mapping
+            .rules_for(resource_type)
+            .find(code_matches)
+            .or_else(|| self.mapping.rules_for(resource_type).find(|rule| rule.code.is_none()));
+
+        if let Some(rule) = rule {
+            let value = Self::text_at(resource, &rule.path).ok_or(
+                format!("no value at {} for key {}", rule.path, rule.key)
+            )?;
+
+            return Ok((rule.key.clone(), value));
+        }
+
+        let fallback = (self.mapping.include_unmapped() && resource_type == ResourceType::Observation)
+            .then(|| {
+                let key = Self::text_at(resource, "code.text")?;
+                let value = Self::text_at(resource, "valueString")?;
+                Some((key, value))
+            })
+            .flatten();
+
+        fallback.ok_or("no mapping rule matches the resource".to_string())
+    }
+
+    /// text of a primitive value at `path`, numbers and booleans are rendered as text
+    fn text_at(resource: &Value, path: &str) -> Option<String> {
+        match get_path(resource, path)? {
+            Value::String(value) => Some(value.clone()),
+            value @ (Value::Number(_) | Value::Bool(_)) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_import_exported_bundle() {
+        let mapping = FhirMapping::default();
+        let bundle = include_str!("testdata/default_mapping.json");
+
+        let plan = FhirImporter::new(&mapping).plan(bundle).unwrap();
+        assert_eq!(plan.encounters().len(), 1);
+
+        let expected = BTreeMap::from(
+            [
+                ("body_temperature", "38.9 Cel"),
+                ("diagnosis", "Typhoid fever"),
+                ("gender", "male"),
+                ("medication", "Chloramphenicol 500mg"),
+                ("nurse_note", "patient is dehydrated"),
+                ("patient_name", "Budi Santoso"),
+                ("reason", "fever for three days"),
+            ].map(|(k, v)| (k.to_string(), v.to_string()))
+        );
+
+        assert_eq!(plan.encounters()[0].records(), &expected);
+    }
+
+    #[test]
+    fn test_rejection_reasons() {
+        let mapping = FhirMapping::new(vec![], false).unwrap();
+        let bundle =
+            r#"{
+            "resourceType": "Bundle",
+            "entry": [
+                { "resource": { "resourceType": "Encounter", "id": "e1" } },
+                { "resource": { "resourceType": "Observation", "id": "o1", "encounter": { "reference": "Encounter/e2" } } },
+                { "resource": { "resourceType": "Observation", "id": "o2", "encounter": { "reference": "Encounter/e1" } } },
+                { "resource": { "resourceType": "Practitioner", "id": "p1" } }
+            ]
+        }"#;
+
+        let mut plan = FhirImporter::new(&mapping).plan(bundle).unwrap();
+        let emr_id = Id::from(uuid::Uuid::new_v4());
+        plan.settle(0, Ok(emr_id.clone()));
+
+        let statuses = plan
+            .into_outcomes()
+            .into_iter()
+            .map(|outcome| outcome.status)
+            .collect::<Vec<_>>();
+
+        assert_eq!(statuses, vec![
+            ResourceStatus::Imported(emr_id),
+            ResourceStatus::Rejected("resource does not reference an encounter in the bundle".to_string()),
+            ResourceStatus::Rejected("no mapping rule matches the resource".to_string()),
+            ResourceStatus::Skipped("unsupported resource type".to_string())
+        ]);
+
+        assert!(FhirImporter::new(&mapping).plan(r#"{ "resourceType": "Patient" }"#).is_err());
+    }
+}
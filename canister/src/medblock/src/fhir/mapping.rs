@@ -0,0 +1,245 @@
+use candid::CandidType;
+use serde::Deserialize;
+use serde_json::{ Map, Value };
+
+/// FHIR resource a record is rendered into
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceType {
+    Patient,
+    Encounter,
+    Observation,
+    Condition,
+    MedicationStatement,
+}
+
+impl ResourceType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "Patient" => Some(Self::Patient),
+            "Encounter" => Some(Self::Encounter),
+            "Observation" => Some(Self::Observation),
+            "Condition" => Some(Self::Condition),
+            "MedicationStatement" => Some(Self::MedicationStatement),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Patient => "Patient",
+            Self::Encounter => "Encounter",
+            Self::Observation => "Observation",
+            Self::Condition => "Condition",
+            Self::MedicationStatement => "MedicationStatement",
+        }
+    }
+
+    /// whether every mapped record gets its own resource, [ResourceType::Patient] and [ResourceType::Encounter]
+    /// are shared by every record of an emr
+    pub fn is_per_record(&self) -> bool {
+        !matches!(self, Self::Patient | Self::Encounter)
+    }
+}
+
+/// FHIR Coding, e.g. a LOINC code of an observation
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Coding {
+    pub system: String,
+    pub code: String,
+    pub display: Option<String>,
+}
+
+impl Coding {
+    pub fn to_value(&self) -> Value {
+        let mut coding = Map::new();
+        coding.insert("system".to_string(), self.system.clone().into());
+        coding.insert("code".to_string(), self.code.clone().into());
+
+        if let Some(display) = &self.display {
+            coding.insert("display".to_string(), display.clone().into());
+        }
+
+        Value::Object(coding)
+    }
+}
+
+/// maps a single records key to a path inside a FHIR resource
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MappingRule {
+    /// records key, see [crate::types::AsciiRecordsKey]
+    pub key: String,
+    pub resource: ResourceType,
+    /// dot separated path the record value is written to, numeric segments index into arrays.
+    /// e.g. `valueString`, `code.text` or `name.0.text`
+    pub path: String,
+    /// code of the rendered resource, only used for per record resources
+    pub code: Option<Coding>,
+}
+
+/// Configurable records key to FHIR path mapping used when rendering emrs as FHIR resources.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FhirMapping {
+    rules: Vec<MappingRule>,
+    /// render records without a rule as [ResourceType::Observation] with the key as code text,
+    /// so no data is silently dropped
+    include_unmapped: bool,
+}
+
+impl Default for FhirMapping {
+    fn default() -> Self {
+        let rule = |key: &str, resource, path: &str, code: Option<(&str, &str)>| MappingRule {
+            key: key.to_string(),
+            resource,
+            path: path.to_string(),
+            code: code.map(|(code, display)| Coding {
+                system: Self::LOINC.to_string(),
+                code: code.to_string(),
+                display: Some(display.to_string()),
+            }),
+        };
+
+        Self {
+            rules: vec![
+                rule("patient_name", ResourceType::Patient, "name.0.text", None),
+                rule("gender", ResourceType::Patient, "gender", None),
+                rule("birth_date", ResourceType::Patient, "birthDate", None),
+                rule("reason", ResourceType::Encounter, "reasonCode.0.text", None),
+                rule("diagnosis", ResourceType::Condition, "code.text", None),
+                rule("medication", ResourceType::MedicationStatement, "medicationCodeableConcept.text", None),
+                rule("blood_pressure", ResourceType::Observation, "valueString", Some(("85354-9", "Blood pressure panel"))),
+                rule("heart_rate", ResourceType::Observation, "valueString", Some(("8867-4", "Heart rate"))),
+                rule("respiratory_rate", ResourceType::Observation, "valueString", Some(("9279-1", "Respiratory rate"))),
+                rule("body_temperature", ResourceType::Observation, "valueString", Some(("8310-5", "Body temperature"))),
+                rule("body_weight", ResourceType::Observation, "valueString", Some(("29463-7", "Body weight"))),
+                rule("body_height", ResourceType::Observation, "valueString", Some(("8302-2", "Body height")))
+            ],
+            include_unmapped: true,
+        }
+    }
+}
+
+impl FhirMapping {
+    pub const LOINC: &'static str = "http://loinc.org";
+
+    /// top level elements managed by the renderer, rules may not overwrite them
+    const RESERVED: [&'static str; 6] = ["resourceType", "id", "subject", "encounter", "context", "meta"];
+
+    pub fn new(rules: Vec<MappingRule>, include_unmapped: bool) -> Result<Self, String> {
+        for rule in rules.iter() {
+            Self::validate(rule)?;
+        }
+
+        Ok(Self { rules, include_unmapped })
+    }
+
+    fn validate(rule: &MappingRule) -> Result<(), String> {
+        let mut segments = rule.path.split('.');
+        let first = segments.next().unwrap_or_default();
+
+        if first.is_empty() || first.parse::<usize>().is_ok() {
+            return Err(format!("invalid path {} for key {}", rule.path, rule.key));
+        }
+
+        if Self::RESERVED.contains(&first) {
+            return Err(format!("path {} of key {} overwrites a reserved element", rule.path, rule.key));
+        }
+
+        if segments.any(|segment| segment.is_empty()) {
+            return Err(format!("invalid path {} for key {}", rule.path, rule.key));
+        }
+
+        Ok(())
+    }
+
+    pub fn rule_of(&self, key: &str) -> Option<&MappingRule> {
+        self.rules.iter().find(|rule| rule.key == key)
+    }
+
+    /// returns every rule rendering into `resource`
+    pub fn rules_for(&self, resource: ResourceType) -> impl Iterator<Item = &MappingRule> {
+        self.rules.iter().filter(move |rule| rule.resource == resource)
+    }
+
+    pub fn include_unmapped(&self) -> bool {
+        self.include_unmapped
+    }
+}
+
+/// write `value` at the dot separated `path` inside `target`, creating intermediate objects and arrays.
+/// numeric segments index into arrays, missing array elements are padded with empty objects.
+pub fn set_path(target: &mut Value, path: &str, value: Value) {
+    let mut current = target;
+
+    for segment in path.split('.') {
+        current = match segment.parse::<usize>() {
+            Ok(index) => {
+                if !current.is_array() {
+                    *current = Value::Array(vec![]);
+                }
+
+                let array = current.as_array_mut().unwrap();
+                while array.len() <= index {
+                    array.push(Value::Object(Map::new()));
+                }
+
+                &mut array[index]
+            }
+            Err(_) => {
+                if !current.is_object() {
+                    *current = Value::Object(Map::new());
+                }
+
+                current
+                    .as_object_mut()
+                    .unwrap()
+                    .entry(segment.to_string())
+                    .or_insert(Value::Null)
+            }
+        };
+    }
+
+    *current = value;
+}
+
+/// read the value at the dot separated `path` inside `target`, the inverse of [set_path]
+pub fn get_path<'a>(target: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(target, |current, segment| {
+        match segment.parse::<usize>() {
+            Ok(index) => current.as_array()?.get(index),
+            Err(_) => current.as_object()?.get(segment),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_path() {
+        let mut target = Value::Object(Map::new());
+
+        set_path(&mut target, "name.0.text", "Budi".into());
+        set_path(&mut target, "name.0.use", "official".into());
+        set_path(&mut target, "gender", "male".into());
+
+        assert_eq!(target.to_string(), r#"{"gender":"male","name":[{"text":"Budi","use":"official"}]}"#);
+        assert_eq!(get_path(&target, "name.0.text"), Some(&Value::from("Budi")));
+        assert_eq!(get_path(&target, "name.1.text"), None);
+    }
+
+    #[test]
+    fn test_reserved_path_rejected() {
+        let rule = |path: &str| MappingRule {
+            key: "key".to_string(),
+            resource: ResourceType::Observation,
+            path: path.to_string(),
+            code: None,
+        };
+
+        assert!(FhirMapping::new(vec![rule("subject.reference")], true).is_err());
+        assert!(FhirMapping::new(vec![rule("0.text")], true).is_err());
+        assert!(FhirMapping::new(vec![rule("code..text")], true).is_err());
+        assert!(FhirMapping::new(vec![rule("valueString")], true).is_ok());
+    }
+}
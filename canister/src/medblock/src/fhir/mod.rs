@@ -0,0 +1,311 @@
+pub mod import;
+pub mod mapping;
+
+use serde_json::{ json, Map, Value };
+
+use crate::{
+    emr::{ patient::NIK, providers::InternalProviderId, EmrDisplay },
+    types::Timestamp,
+};
+
+use self::mapping::{ set_path, FhirMapping, ResourceType };
+
+/// issuing provider of an emr, rendered as a FHIR Organization
+pub struct Organization {
+    pub id: InternalProviderId,
+    pub name: Option<String>,
+}
+
+/// Renders emrs as FHIR R4 collection Bundles driven by a [FhirMapping].
+///
+/// every emr becomes an Encounter referencing its Patient and issuing Organization. records are written into
+/// the Patient or Encounter, or into their own Observation, Condition or MedicationStatement depending on the
+/// mapping rule of their key.
+pub struct FhirRenderer<'a> {
+    mapping: &'a FhirMapping,
+}
+
+impl<'a> FhirRenderer<'a> {
+    /// patient identifiers are stored hashed, the identifier system says which kind of identifier was hashed
+    /// so receivers don't mistake the value for the identifier itself.
+    const IDENTIFIER_SYSTEM: &'static str = "urn:medblock:binding-key";
+
+    const ENCOUNTER_CLASS_SYSTEM: &'static str = "http://terminology.hl7.org/CodeSystem/v3-ActCode";
+
+    pub fn new(mapping: &'a FhirMapping) -> Self {
+        Self { mapping }
+    }
+
+    pub fn render(
+        &self,
+        emr: &EmrDisplay,
+        patient: &NIK,
+        organization: Option<&Organization>
+    ) -> Value {
+        // the same hash under another identifier kind is another patient, keep the kind in the resource id
+        let patient_id = patient.to_text();
+        let encounter_id = emr.emr_id().to_string();
+
+        let mut patient_resource =
+            json!({
+            "resourceType": "Patient",
+            "id": patient_id,
+            "identifier": [{
+                "system": format!("{}:{}", Self::IDENTIFIER_SYSTEM, patient.kind().prefix()),
+                "value": patient.to_hex(),
+            }],
+        });
+
+        let mut encounter =
+            json!({
+            "resourceType": "Encounter",
+            "id": encounter_id,
+            "meta": { "lastUpdated": to_datetime(emr.updated_at()) },
+            "status": "finished",
+            "class": { "system": Self::ENCOUNTER_CLASS_SYSTEM, "code": "AMB", "display": "ambulatory" },
+            "subject": { "reference": format!("Patient/{}", patient_id) },
+            "period": { "start": to_datetime(emr.created_at()) },
+        });
+
+        let organization = organization.map(|organization| {
+            let id = organization.id.to_string();
+            set_path(&mut encounter, "serviceProvider.reference", format!("Organization/{}", id).into());
+
+            let mut resource = json!({ "resourceType": "Organization", "id": id });
+            if let Some(name) = &organization.name {
+                set_path(&mut resource, "name", name.clone().into());
+            }
+
+            resource
+        });
+
+        let mut per_record = vec![];
+        let empty = Map::new();
+        let records = emr.records().as_object().unwrap_or(&empty);
+
+        for (key, value) in records {
+            let (resource_type, path, code) = match self.mapping.rule_of(key) {
+                Some(rule) => (rule.resource, rule.path.as_str(), rule.code.as_ref()),
+                None if self.mapping.include_unmapped() => {
+                    (ResourceType::Observation, "valueString", None)
+                }
+                None => {
+                    continue;
+                }
+            };
+
+            let value = value.clone();
+
+            match resource_type {
+                ResourceType::Patient => set_path(&mut patient_resource, path, value),
+                ResourceType::Encounter => set_path(&mut encounter, path, value),
+                _ => {
+                    let mut resource = self.record_resource(resource_type, key, &encounter_id, &patient_id);
+
+                    if let Some(code) = code {
+                        let element = match resource_type {
+                            ResourceType::MedicationStatement => "medicationCodeableConcept",
+                            _ => "code",
+                        };
+                        set_path(&mut resource, &format!("{}.coding", element), json!([code.to_value()]));
+                    }
+
+                    set_path(&mut resource, path, value);
+                    per_record.push(resource);
+                }
+            }
+        }
+
+        let entries = [patient_resource]
+            .into_iter()
+            .chain(organization)
+            .chain([encounter])
+            .chain(per_record)
+            .map(|resource| json!({ "resource": resource }))
+            .collect::<Vec<_>>();
+
+        json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "timestamp": to_datetime(emr.updated_at()),
+            "entry": entries,
+        })
+    }
+
+    /// skeleton of a per record resource with its required elements filled, the key is used as code text
+    /// until the mapping rule provides something better
+    fn record_resource(
+        &self,
+        resource_type: ResourceType,
+        key: &str,
+        encounter_id: &str,
+        patient_id: &str
+    ) -> Value {
+        let id = format!("{}-{}", encounter_id, fhir_id_safe(key));
+        let subject = json!({ "reference": format!("Patient/{}", patient_id) });
+        let encounter = json!({ "reference": format!("Encounter/{}", encounter_id) });
+
+        match resource_type {
+            ResourceType::MedicationStatement =>
+                json!({
+                "resourceType": resource_type.as_str(),
+                "id": id,
+                "status": "active",
+                "medicationCodeableConcept": { "text": key },
+                "subject": subject,
+                "context": encounter,
+            }),
+            ResourceType::Condition =>
+                json!({
+                "resourceType": resource_type.as_str(),
+                "id": id,
+                "code": { "text": key },
+                "subject": subject,
+                "encounter": encounter,
+            }),
+            _ =>
+                json!({
+                "resourceType": resource_type.as_str(),
+                "id": id,
+                "status": "final",
+                "code": { "text": key },
+                "subject": subject,
+                "encounter": encounter,
+            }),
+        }
+    }
+}
+
+/// FHIR ids only allow `[A-Za-z0-9\-\.]`, records keys may contain anything ascii
+fn fhir_id_safe(key: &str) -> String {
+    key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' { c } else { '-' })
+        .collect()
+}
+
+/// render a timestamp as a FHIR dateTime in UTC with second precision, e.g. `2023-11-14T22:13:20Z`
+pub fn to_datetime(timestamp: Timestamp) -> String {
+    const NANOS_PER_SEC: u64 = 1_000_000_000;
+    const SECS_PER_DAY: u64 = 86_400;
+
+    let secs = timestamp.inner() / NANOS_PER_SEC;
+    let days = (secs / SECS_PER_DAY) as i64;
+    let time = secs % SECS_PER_DAY;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        (time % 3600) / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ emr::{ DisplayV001, RecrodsDisplay }, types::Id };
+
+    fn sample_emr() -> EmrDisplay {
+        let records =
+            json!({
+            "patient_name": "Budi Santoso",
+            "gender": "male",
+            "reason": "fever for three days",
+            "diagnosis": "Typhoid fever",
+            "medication": "Chloramphenicol 500mg",
+            "body_temperature": "38.9 Cel",
+            "nurse_note": "patient is dehydrated",
+        });
+
+        EmrDisplay::V001(
+            DisplayV001::with_timestamps(
+                Id::from(uuid::Uuid::parse_str("018bd2a8-7c3e-7000-8000-000000000001").unwrap()),
+                Timestamp(1_700_000_000_000_000_000),
+                Timestamp(1_700_003_600_000_000_000),
+                RecrodsDisplay(records)
+            )
+        )
+    }
+
+    fn patient() -> NIK {
+        "3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709".parse().unwrap()
+    }
+
+    fn organization() -> Organization {
+        Organization {
+            id: Id::from(uuid::Uuid::parse_str("018bd2a8-7c3e-7000-8000-0000000000aa").unwrap()),
+            name: Some("Klinik Sehat".to_string()),
+        }
+    }
+
+    fn golden(name: &str, rendered: &Value) {
+        let expected = match name {
+            "default_mapping" => include_str!("testdata/default_mapping.json"),
+            "custom_mapping" => include_str!("testdata/custom_mapping.json"),
+            _ => unreachable!(),
+        };
+
+        let expected: Value = serde_json::from_str(expected).unwrap();
+        assert_eq!(rendered, &expected, "rendered bundle:\n{}", rendered);
+    }
+
+    #[test]
+    fn test_datetime() {
+        assert_eq!(to_datetime(Timestamp(0)), "1970-01-01T00:00:00Z");
+        assert_eq!(to_datetime(Timestamp(1_700_000_000_000_000_000)), "2023-11-14T22:13:20Z");
+        assert_eq!(to_datetime(Timestamp(951_782_400_000_000_000)), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn test_default_mapping_golden() {
+        let mapping = FhirMapping::default();
+        let bundle = FhirRenderer::new(&mapping).render(&sample_emr(), &patient(), Some(&organization()));
+
+        golden("default_mapping", &bundle);
+    }
+
+    #[test]
+    fn test_custom_mapping_golden() {
+        let rules = vec![mapping::MappingRule {
+            key: "diagnosis".to_string(),
+            resource: ResourceType::Condition,
+            path: "note.0.text".to_string(),
+            code: Some(mapping::Coding {
+                system: "http://hl7.org/fhir/sid/icd-10".to_string(),
+                code: "A01.0".to_string(),
+                display: None,
+            }),
+        }];
+
+        let mapping = FhirMapping::new(rules, false).unwrap();
+        let bundle = FhirRenderer::new(&mapping).render(&sample_emr(), &patient(), None);
+
+        golden("custom_mapping", &bundle);
+    }
+
+    #[test]
+    fn test_patient_id_includes_identifier_kind() {
+        let mapping = FhirMapping::default();
+        let passport = format!("passport:{}", patient().to_hex()).parse::<NIK>().unwrap();
+
+        let bundle = FhirRenderer::new(&mapping).render(&sample_emr(), &passport, None);
+        let patient_id = bundle["entry"][0]["resource"]["id"].as_str().unwrap();
+
+        assert_eq!(patient_id, format!("passport:{}", patient().to_hex()));
+        assert_eq!(bundle["entry"][0]["resource"]["identifier"][0]["value"], patient().to_hex());
+    }
+}
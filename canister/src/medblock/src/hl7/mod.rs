@@ -0,0 +1,450 @@
+pub mod parser;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::{ emr::patient::{ IdentifierType, NIK }, fhir::to_datetime, types::{ AsciiRecordsKey, Id, Timestamp } };
+
+use self::parser::{ Message, Segment };
+
+/// supported message types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// ADT^A01, admission of a patient. creates a new emr for the visit.
+    Admit,
+    /// ORU^R01, unsolicited observation results. adds the results to the emr of the visit.
+    Results,
+}
+
+/// what happened to a single segment of an ingested message
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SegmentStatus {
+    /// the segment was mapped into the given records keys
+    Accepted(Vec<String>),
+    Rejected(String),
+    /// the segment carries no records, e.g. MSH or NTE
+    Ignored,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SegmentOutcome {
+    /// position of the segment in the message, 0 is MSH
+    pub index: u32,
+    pub segment: String,
+    pub status: SegmentStatus,
+}
+
+/// HL7 acknowledgment code, written in MSA-1
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckCode {
+    /// application accept, every segment was processed
+    AA,
+    /// application error, some segments were rejected
+    AE,
+    /// application reject, nothing was applied
+    AR,
+}
+
+impl AckCode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::AA => "AA",
+            Self::AE => "AE",
+            Self::AR => "AR",
+        }
+    }
+}
+
+/// Detailed acknowledgment of an ingested message, with the rendered HL7 ACK message for systems
+/// that expect one.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hl7Ack {
+    pub control_id: String,
+    pub code: AckCode,
+    /// emr created or updated by the message
+    pub emr_id: Option<Id>,
+    /// reason the whole message was rejected
+    pub reason: Option<String>,
+    pub segments: Vec<SegmentOutcome>,
+    /// HL7 v2 ACK message with one ERR segment per rejected segment
+    pub message: String,
+}
+
+/// Records extracted from a message and the outcome of every segment, applied by the caller.
+#[derive(Debug)]
+pub struct Ingestion {
+    kind: MessageKind,
+    patient: NIK,
+    /// emr the results belong to, only set for [MessageKind::Results]
+    target: Option<Id>,
+    records: Vec<(String, String)>,
+    outcomes: Vec<SegmentOutcome>,
+}
+
+impl Ingestion {
+    /// HL7 table 0203 identifier type codes in PID-3.5 mapped to the identifier the hash was computed from
+    fn identifier_type(code: &str) -> Option<IdentifierType> {
+        match code {
+            "NI" | "NIK" => Some(IdentifierType::Nik),
+            "PPN" => Some(IdentifierType::Passport),
+            "SB" | "BPJS" => Some(IdentifierType::Bpjs),
+            "MR" | "MRN" => Some(IdentifierType::MedicalRecordNumber),
+            _ => None,
+        }
+    }
+
+    /// map a parsed message into records. returns an error if the message as a whole can't be processed,
+    /// e.g. an unsupported message type or a patient that can't be identified.
+    pub fn plan(message: &Message) -> Result<Self, String> {
+        let msh = message.segment("MSH").ok_or("MSH segment missing".to_string())?;
+
+        let kind = match (message.value(msh, 9, 1).as_str(), message.value(msh, 9, 2).as_str()) {
+            ("ADT", "A01") => MessageKind::Admit,
+            ("ORU", "R01") => MessageKind::Results,
+            (code, trigger) => {
+                return Err(format!("unsupported message type {}^{}", code, trigger));
+            }
+        };
+
+        let pid = message.segment("PID").ok_or("PID segment missing".to_string())?;
+
+        // senders hash the identifier, the same way providers do when registering patients
+        let patient = message
+            .repetitions(pid, 3)
+            .into_iter()
+            .find_map(|identifier| {
+                let kind = Self::identifier_type(&message.component(identifier, 5))?;
+                format!("{}:{}", kind.prefix(), message.component(identifier, 1)).parse::<NIK>().ok()
+            })
+            .ok_or("PID-3 has no hashed patient identifier of a supported type".to_string())?;
+
+        let target = match kind {
+            MessageKind::Admit => None,
+            MessageKind::Results => {
+                let visit = message.segment("PV1").map(|pv1| message.value(pv1, 19, 1));
+                let order = message.segment("OBR").map(|obr| message.value(obr, 2, 1));
+
+                let target = [visit, order]
+                    .into_iter()
+                    .flatten()
+                    .find_map(|reference| uuid::Uuid::parse_str(&reference).ok())
+                    .ok_or("no emr id in PV1-19 or OBR-2".to_string())?;
+
+                Some(Id::from(target))
+            }
+        };
+
+        let mut ingestion = Self { kind, patient, target, records: vec![], outcomes: vec![] };
+
+        for (index, segment) in message.segments().iter().enumerate() {
+            let records = match (kind, segment.name()) {
+                (MessageKind::Admit, "PID") => Ok(Self::patient_records(message, segment)),
+                (MessageKind::Admit, "PV1") => Ok(Self::visit_records(message, segment)),
+                (MessageKind::Results, "OBX") => Self::observation_record(message, segment).map(|r| vec![r]),
+                _ => Ok(vec![]),
+            };
+
+            let status = match records {
+                Ok(records) if records.is_empty() => SegmentStatus::Ignored,
+                Ok(records) => ingestion.accept(records),
+                Err(reason) => SegmentStatus::Rejected(reason),
+            };
+
+            ingestion.outcomes.push(SegmentOutcome {
+                index: index as u32,
+                segment: segment.name().to_string(),
+                status,
+            });
+        }
+
+        Ok(ingestion)
+    }
+
+    fn accept(&mut self, records: Vec<(String, String)>) -> SegmentStatus {
+        if let Some((key, _)) = records.iter().find(|(key, _)| self.records.iter().any(|(k, _)| k == key)) {
+            return SegmentStatus::Rejected(format!("duplicate record key {}", key));
+        }
+
+        let keys = records
+            .iter()
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        self.records.extend(records);
+        SegmentStatus::Accepted(keys)
+    }
+
+    /// PID demographics, keys match the default FHIR mapping
+    fn patient_records(message: &Message, pid: &Segment) -> Vec<(String, String)> {
+        let name = [message.value(pid, 5, 2), message.value(pid, 5, 1)]
+            .into_iter()
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let birth_date = message.value(pid, 7, 1);
+        let birth_date = match birth_date.len() >= 8 && birth_date[..8].chars().all(|c| c.is_ascii_digit()) {
+            true => format!("{}-{}-{}", &birth_date[..4], &birth_date[4..6], &birth_date[6..8]),
+            false => birth_date,
+        };
+
+        let gender = match message.value(pid, 8, 1).as_str() {
+            "M" => "male".to_string(),
+            "F" => "female".to_string(),
+            "O" | "A" => "other".to_string(),
+            "U" => "unknown".to_string(),
+            other => other.to_string(),
+        };
+
+        Self::non_empty([("patient_name", name), ("birth_date", birth_date), ("gender", gender)])
+    }
+
+    fn visit_records(message: &Message, pv1: &Segment) -> Vec<(String, String)> {
+        Self::non_empty([
+            ("patient_class", message.value(pv1, 2, 1)),
+            ("location", message.value(pv1, 3, 1)),
+            ("admitted_at", message.value(pv1, 44, 1)),
+        ])
+    }
+
+    /// OBX results are keyed by their coded observation identifier, e.g. `lab.ln.2345-7`, and valued with
+    /// the result, its units and abnormal flag, e.g. `5.4 mmol/L (H)`
+    fn observation_record(message: &Message, obx: &Segment) -> Result<(String, String), String> {
+        let status = message.value(obx, 11, 1);
+        if matches!(status.as_str(), "D" | "W" | "X") {
+            return Err(format!("result status {} is not supported", status));
+        }
+
+        let code = message.value(obx, 3, 1);
+        if code.is_empty() {
+            return Err("OBX-3 has no observation identifier".to_string());
+        }
+
+        let key = match message.value(obx, 3, 3) {
+            system if system.is_empty() => format!("lab.{}", code),
+            system => format!("lab.{}.{}", system.to_lowercase(), code),
+        };
+
+        AsciiRecordsKey::new(&key).map_err(|e| format!("invalid record key {} : {}", key, e))?;
+
+        // coded results carry their text in the second component
+        let value = match message.value(obx, 2, 1).as_str() {
+            "CE" | "CWE" => Some(message.value(obx, 5, 2)).filter(|text| !text.is_empty()).unwrap_or(message.value(obx, 5, 1)),
+            _ => message.value(obx, 5, 1),
+        };
+
+        if value.is_empty() {
+            return Err("OBX-5 has no value".to_string());
+        }
+
+        let mut value = value;
+
+        let units = message.value(obx, 6, 1);
+        if !units.is_empty() {
+            value = format!("{} {}", value, units);
+        }
+
+        let flag = message.value(obx, 8, 1);
+        if !flag.is_empty() {
+            value = format!("{} ({})", value, flag);
+        }
+
+        Ok((key, value))
+    }
+
+    fn non_empty<const N: usize>(records: [(&str, String); N]) -> Vec<(String, String)> {
+        records
+            .into_iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| (key.to_string(), value))
+            .collect()
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+
+    pub fn patient(&self) -> &NIK {
+        &self.patient
+    }
+
+    pub fn target(&self) -> Option<&Id> {
+        self.target.as_ref()
+    }
+
+    pub fn records(&self) -> &[(String, String)] {
+        &self.records
+    }
+
+    /// acknowledge the message with the result of applying its records
+    pub fn into_ack(self, message: &Message, result: Result<Id, String>, now: Timestamp) -> Hl7Ack {
+        match result {
+            Ok(emr_id) => {
+                let rejected = self.outcomes
+                    .iter()
+                    .any(|outcome| matches!(outcome.status, SegmentStatus::Rejected(_)));
+
+                let code = if rejected { AckCode::AE } else { AckCode::AA };
+
+                acknowledge(message, code, Some(emr_id), None, self.outcomes, now)
+            }
+            Err(reason) => reject(message, reason, now),
+        }
+    }
+}
+
+/// reject the whole message
+pub fn reject(message: &Message, reason: String, now: Timestamp) -> Hl7Ack {
+    acknowledge(message, AckCode::AR, None, Some(reason), vec![], now)
+}
+
+fn acknowledge(
+    message: &Message,
+    code: AckCode,
+    emr_id: Option<Id>,
+    reason: Option<String>,
+    segments: Vec<SegmentOutcome>,
+    now: Timestamp
+) -> Hl7Ack {
+    let msh = &message.segments()[0];
+    let control_id = message.value(msh, 10, 1);
+    let d = message.delimiters();
+
+    // HL7 TS, e.g. 20231114221320
+    let timestamp = to_datetime(now)
+        .chars()
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+
+    let header = [
+        "MSH".to_string(),
+        message.raw(msh, 2).to_string(),
+        "MEDBLOCK".to_string(),
+        "MEDBLOCK".to_string(),
+        message.raw(msh, 3).to_string(),
+        message.raw(msh, 4).to_string(),
+        timestamp,
+        String::new(),
+        format!("ACK{}{}{}ACK", d.component, message.value(msh, 9, 2), d.component),
+        format!("ACK{}", control_id),
+        message.raw(msh, 11).to_string(),
+        message.raw(msh, 12).to_string(),
+    ];
+
+    let msa = ["MSA".to_string(), code.as_str().to_string(), control_id.clone()];
+
+    // ERR-2 error location, ERR-3 HL7 table 0357 error code, ERR-4 severity, ERR-8 user message
+    let error = |location: String, text: &str| {
+        [
+            "ERR".to_string(),
+            String::new(),
+            location,
+            format!("207{}Application internal error{}HL70357", d.component, d.component),
+            "E".to_string(),
+            String::new(),
+            String::new(),
+            String::new(),
+            message.escape(text),
+        ]
+    };
+
+    let errors = reason
+        .iter()
+        .map(|reason| error(String::new(), reason))
+        .chain(
+            segments.iter().filter_map(|outcome| {
+                match &outcome.status {
+                    SegmentStatus::Rejected(reason) =>
+                        Some(error(format!("{}{}{}", outcome.segment, d.component, outcome.index), reason)),
+                    _ => None,
+                }
+            })
+        )
+        .collect::<Vec<_>>();
+
+    let text = std::iter
+        ::once(header.join(&d.field.to_string()))
+        .chain(std::iter::once(msa.join(&d.field.to_string())))
+        .chain(errors.iter().map(|error| error.join(&d.field.to_string())))
+        .collect::<Vec<_>>()
+        .join("\r");
+
+    Hl7Ack { control_id, code, emr_id, reason, segments, message: text }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709";
+
+    #[test]
+    fn test_admit() {
+        let message = format!(
+            "MSH|^~\\&|SIMRS|RSUD|MEDBLOCK|MEDBLOCK|20231114221320||ADT^A01|ADT001|P|2.5\r\
+             EVN|A01|20231114221320\r\
+             PID|1||{}^^^^NI||Santoso^Budi||19900101|M\r\
+             PV1|1|I|WARD-3",
+            HASH
+        );
+
+        let message = Message::parse(&message).unwrap();
+        let ingestion = Ingestion::plan(&message).unwrap();
+
+        assert_eq!(ingestion.kind(), MessageKind::Admit);
+        assert_eq!(ingestion.patient(), &HASH.parse::<NIK>().unwrap());
+        assert_eq!(ingestion.records(), &[
+            ("patient_name".to_string(), "Budi Santoso".to_string()),
+            ("birth_date".to_string(), "1990-01-01".to_string()),
+            ("gender".to_string(), "male".to_string()),
+            ("patient_class".to_string(), "I".to_string()),
+            ("location".to_string(), "WARD-3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_results_ack() {
+        let emr_id = "018bd2a8-7c3e-7000-8000-000000000001";
+        let message = format!(
+            "MSH|^~\\&|LIS|LAB|MEDBLOCK|MEDBLOCK|20231114221320||ORU^R01|LAB042|P|2.5\r\
+             PID|1||{}^^^^MR\r\
+             PV1|1|O|||||||||||||||||{}\r\
+             OBR|1||LAB042|CBC^Complete blood count\r\
+             OBX|1|NM|2345-7^Glucose^LN||5.4|mmol/L||H|||F\r\
+             OBX|2|NM|718-7^Hemoglobin^LN||||||||F\r\
+             OBX|3|NM|2345-7^Glucose^LN||5.5|mmol/L|||||C",
+            HASH,
+            emr_id
+        );
+
+        let message = Message::parse(&message).unwrap();
+        let ingestion = Ingestion::plan(&message).unwrap();
+
+        let emr_id = Id::from(uuid::Uuid::parse_str(emr_id).unwrap());
+        assert_eq!(ingestion.target(), Some(&emr_id));
+        assert_eq!(ingestion.records(), &[("lab.ln.2345-7".to_string(), "5.4 mmol/L (H)".to_string())]);
+
+        let ack = ingestion.into_ack(&message, Ok(emr_id), Timestamp(1_700_000_000_000_000_000));
+
+        assert_eq!(ack.code, AckCode::AE);
+        assert_eq!(
+            ack.message,
+            "MSH|^~\\&|MEDBLOCK|MEDBLOCK|LIS|LAB|20231114221320||ACK^R01^ACK|ACKLAB042|P|2.5\r\
+             MSA|AE|LAB042\r\
+             ERR||OBX^5|207^Application internal error^HL70357|E||||OBX-5 has no value\r\
+             ERR||OBX^6|207^Application internal error^HL70357|E||||duplicate record key lab.ln.2345-7"
+        );
+    }
+
+    #[test]
+    fn test_reject_unsupported() {
+        let message = Message::parse("MSH|^~\\&|A|B|C|D|20231114221320||ORM^O01|X1|P|2.5").unwrap();
+
+        let reason = Ingestion::plan(&message).unwrap_err();
+        assert_eq!(reason, "unsupported message type ORM^O01");
+
+        let ack = reject(&message, reason, Timestamp(0));
+        assert_eq!(ack.code, AckCode::AR);
+        assert!(ack.message.contains("MSA|AR|X1"));
+    }
+}
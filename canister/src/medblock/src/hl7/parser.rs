@@ -0,0 +1,234 @@
+/// delimiters declared in MSH-1 and MSH-2
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delimiters {
+    pub field: char,
+    pub component: char,
+    pub repetition: char,
+    pub escape: char,
+    pub subcomponent: char,
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Self { field: '|', component: '^', repetition: '~', escape: '\\', subcomponent: '&' }
+    }
+}
+
+/// a single segment, fields are kept raw and only split and unescaped when accessed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    name: String,
+    /// fields after the segment name, index 0 is field 1. for MSH, field 1 is the field separator itself
+    fields: Vec<String>,
+}
+
+impl Segment {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Parsed HL7 v2 message. only the structure is parsed, message semantics are left to the caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    delimiters: Delimiters,
+    segments: Vec<Segment>,
+}
+
+impl Message {
+    /// parse a message, segments may be separated by `\r`, `\n` or `\r\n`. returns an error if the message
+    /// does not start with a valid MSH segment.
+    pub fn parse(message: &str) -> Result<Self, String> {
+        let mut lines = message
+            .split(['\r', '\n'])
+            .filter(|line| !line.trim().is_empty());
+
+        let header = lines.next().ok_or("empty message".to_string())?;
+
+        if !header.starts_with("MSH") {
+            return Err("message must start with a MSH segment".to_string());
+        }
+
+        let mut chars = header.chars().skip(3);
+        let field = chars.next().ok_or("MSH segment has no field separator".to_string())?;
+        let encoding = chars.take_while(|c| *c != field).collect::<Vec<_>>();
+
+        if encoding.len() < 4 {
+            return Err("MSH-2 must declare component, repetition, escape and subcomponent separators".to_string());
+        }
+
+        let delimiters = Delimiters {
+            field,
+            component: encoding[0],
+            repetition: encoding[1],
+            escape: encoding[2],
+            subcomponent: encoding[3],
+        };
+
+        let segments = std::iter
+            ::once(header)
+            .chain(lines)
+            .map(|line| {
+                let mut fields = line.split(field).map(String::from);
+                let name = fields.next().unwrap_or_default();
+
+                let fields = match name.as_str() {
+                    // MSH-1 is the field separator, which the split above consumed
+                    "MSH" => std::iter::once(field.to_string()).chain(fields).collect(),
+                    _ => fields.collect(),
+                };
+
+                Segment { name, fields }
+            })
+            .collect();
+
+        Ok(Self { delimiters, segments })
+    }
+
+    pub fn delimiters(&self) -> Delimiters {
+        self.delimiters
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// first segment named `name`
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        self.segments.iter().find(|segment| segment.name == name)
+    }
+
+    /// raw value of field `field` (1 based) of `segment`, empty if absent
+    pub fn raw<'a>(&self, segment: &'a Segment, field: usize) -> &'a str {
+        field
+            .checked_sub(1)
+            .and_then(|index| segment.fields.get(index))
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    /// repetitions of field `field` of `segment`
+    pub fn repetitions<'a>(&self, segment: &'a Segment, field: usize) -> Vec<&'a str> {
+        let raw = self.raw(segment, field);
+
+        match raw.is_empty() {
+            true => vec![],
+            false => raw.split(self.delimiters.repetition).collect(),
+        }
+    }
+
+    /// unescaped component `component` (1 based) of a single field repetition, empty if absent
+    pub fn component(&self, repetition: &str, component: usize) -> String {
+        let value = repetition
+            .split(self.delimiters.component)
+            .nth(component.saturating_sub(1))
+            .unwrap_or_default();
+
+        self.unescape(value)
+    }
+
+    /// unescaped component `component` of the first repetition of field `field` of `segment`
+    pub fn value(&self, segment: &Segment, field: usize, component: usize) -> String {
+        let raw = self.raw(segment, field);
+        let first = raw.split(self.delimiters.repetition).next().unwrap_or_default();
+
+        self.component(first, component)
+    }
+
+    /// resolve the delimiter escape sequences, unknown sequences such as formatting commands are kept as is
+    fn unescape(&self, value: &str) -> String {
+        let escape = self.delimiters.escape;
+
+        if !value.contains(escape) {
+            return value.to_string();
+        }
+
+        let mut unescaped = String::with_capacity(value.len());
+        let mut parts = value.split(escape);
+        unescaped.push_str(parts.next().unwrap_or_default());
+
+        // parts alternate between escape sequence and literal text
+        let mut in_sequence = true;
+        for part in parts {
+            if !in_sequence {
+                unescaped.push_str(part);
+                in_sequence = true;
+                continue;
+            }
+
+            match part {
+                "F" => unescaped.push(self.delimiters.field),
+                "S" => unescaped.push(self.delimiters.component),
+                "R" => unescaped.push(self.delimiters.repetition),
+                "E" => unescaped.push(escape),
+                "T" => unescaped.push(self.delimiters.subcomponent),
+                sequence => {
+                    unescaped.push(escape);
+                    unescaped.push_str(sequence);
+                    unescaped.push(escape);
+                }
+            }
+
+            in_sequence = false;
+        }
+
+        unescaped
+    }
+
+    /// escape delimiters in `value` so it can be written into a field
+    pub fn escape(&self, value: &str) -> String {
+        let d = self.delimiters;
+
+        value
+            .chars()
+            .map(|c| {
+                let sequence = match c {
+                    c if c == d.escape => "E",
+                    c if c == d.field => "F",
+                    c if c == d.component => "S",
+                    c if c == d.repetition => "R",
+                    c if c == d.subcomponent => "T",
+                    c => {
+                        return c.to_string();
+                    }
+                };
+
+                format!("{}{}{}", d.escape, sequence, d.escape)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_fields_and_components() {
+        let message = Message::parse(
+            "MSH|^~\\&|LAB|RSUD|MEDBLOCK|CLINIC|20231114221320||ORU^R01|MSG0001|P|2.5\r\
+             PID|1||abc^^^^NI~def^^^^MR||Santoso^Budi\r\
+             OBX|1|NM|2345-7^Glucose^LN||5.4|mmol/L|||||F\r\
+             NTE|1||see \\F\\ note \\H\\bold\\N\\"
+        ).unwrap();
+
+        let msh = message.segment("MSH").unwrap();
+        assert_eq!(message.raw(msh, 1), "|");
+        assert_eq!(message.raw(msh, 2), "^~\\&");
+        assert_eq!(message.value(msh, 9, 1), "ORU");
+        assert_eq!(message.value(msh, 9, 2), "R01");
+        assert_eq!(message.value(msh, 10, 1), "MSG0001");
+
+        let pid = message.segment("PID").unwrap();
+        let identifiers = message.repetitions(pid, 3);
+        assert_eq!(identifiers.len(), 2);
+        assert_eq!(message.component(identifiers[1], 5), "MR");
+        assert_eq!(message.value(pid, 5, 2), "Budi");
+
+        let nte = message.segment("NTE").unwrap();
+        assert_eq!(message.value(nte, 3, 1), "see | note \\H\\bold\\N\\");
+
+        assert_eq!(message.escape("a|b^c"), "a\\F\\b\\S\\c");
+        assert!(Message::parse("PID|1").is_err());
+    }
+}
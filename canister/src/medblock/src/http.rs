@@ -0,0 +1,274 @@
+use candid::{ CandidType, Principal };
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+use sha2::{ Digest, Sha256 };
+use uuid::Uuid;
+
+use crate::types::{ Id, Timestamp };
+
+pub type HeaderField = (String, String);
+
+/// request forwarded by the boundary nodes, see the HTTP gateway protocol
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderField>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// json response, never cached by the boundary nodes or browsers since it may contain medical data
+    pub fn json(status_code: u16, body: &impl Serialize) -> Self {
+        let body = serde_json::to_vec(body).unwrap_or_default();
+
+        Self {
+            status_code,
+            headers: [
+                ("Content-Type", "application/json"),
+                ("Cache-Control", "no-store"),
+                ("Access-Control-Allow-Origin", "*"),
+            ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body,
+        }
+    }
+
+    pub fn error(status_code: u16, message: impl Into<String>) -> Self {
+        Self::json(status_code, &serde_json::json!({ "error": message.into() }))
+    }
+
+    /// answer to a CORS preflight, web clinics send the token in the Authorization header
+    pub fn preflight() -> Self {
+        Self {
+            status_code: 204,
+            headers: [
+                ("Access-Control-Allow-Origin", "*"),
+                ("Access-Control-Allow-Methods", "GET, OPTIONS"),
+                ("Access-Control-Allow-Headers", "Authorization"),
+                ("Access-Control-Max-Age", "600"),
+            ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: vec![],
+        }
+    }
+}
+
+/// routes served over plain HTTP, everything is read only
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Route {
+    /// `GET /health`
+    Health,
+    /// `GET /metadata`, public canister metadata
+    Metadata,
+    /// `GET /emr/{emr_id}`, requires a bearer token minted for the emr
+    Emr(Id),
+    /// `OPTIONS` on any route
+    Preflight,
+    MethodNotAllowed,
+    NotFound,
+}
+
+impl Route {
+    pub fn of(request: &HttpRequest) -> Self {
+        let path = request.url.split(['?', '#']).next().unwrap_or_default();
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+
+        let route = match segments.as_slice() {
+            ["health"] => Self::Health,
+            ["metadata"] => Self::Metadata,
+            ["emr", emr_id] =>
+                match Uuid::parse_str(emr_id) {
+                    Ok(emr_id) => Self::Emr(Id::from(emr_id)),
+                    Err(_) => Self::NotFound,
+                }
+            _ => Self::NotFound,
+        };
+
+        match (request.method.to_ascii_uppercase().as_str(), route) {
+            (_, Self::NotFound) => Self::NotFound,
+            ("OPTIONS", _) => Self::Preflight,
+            ("GET" | "HEAD", route) => route,
+            _ => Self::MethodNotAllowed,
+        }
+    }
+}
+
+/// bearer token of a request, if any
+pub fn bearer_token(request: &HttpRequest) -> Option<&str> {
+    request.headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// short lived token allowing plain HTTP reads of a single emr, returned by the update call minting it
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct HttpToken {
+    pub token: String,
+    pub expires_at: Timestamp,
+}
+
+/// what a verified token allows, reads are still checked against the current permissions of `principal`
+/// so revoking access also revokes outstanding tokens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenClaims {
+    pub principal: Principal,
+    pub emr_id: Id,
+    pub expires_at: Timestamp,
+}
+
+/// Signs and verifies [HttpToken]s with HMAC-SHA256.
+///
+/// the key lives on the heap only, so upgrading the canister invalidates every outstanding token.
+/// tokens are short lived so that is acceptable, and keeps the key out of stable memory snapshots.
+pub struct TokenSigner {
+    key: [u8; 32],
+}
+
+impl TokenSigner {
+    const BLOCK_SIZE: usize = 64;
+
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// token format is `hex(expires_at ++ emr_id ++ principal).hex(mac)`
+    pub fn sign(&self, claims: &TokenClaims) -> HttpToken {
+        let mut payload = claims.expires_at.inner().to_be_bytes().to_vec();
+        payload.extend(Uuid::from(claims.emr_id.clone()).into_bytes());
+        payload.extend(claims.principal.as_slice());
+
+        let token = format!("{}.{}", hex::encode(&payload), hex::encode(self.mac(&payload)));
+
+        HttpToken { token, expires_at: claims.expires_at }
+    }
+
+    pub fn verify(&self, token: &str, now: &Timestamp) -> Result<TokenClaims, String> {
+        let invalid = || "invalid token".to_string();
+
+        let (payload, mac) = token.split_once('.').ok_or_else(invalid)?;
+        let payload = hex::decode(payload).map_err(|_| invalid())?;
+        let mac = hex::decode(mac).map_err(|_| invalid())?;
+
+        // compare without short circuiting so the comparison time doesn't leak the mac
+        let expected = self.mac(&payload);
+        let matches =
+            mac.len() == expected.len() &&
+            mac
+                .iter()
+                .zip(expected.iter())
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0;
+
+        if !matches || payload.len() < 24 {
+            return Err(invalid());
+        }
+
+        let (expires_at, rest) = payload.split_at(8);
+        let (emr_id, principal) = rest.split_at(16);
+
+        let expires_at = Timestamp(u64::from_be_bytes(expires_at.try_into().map_err(|_| invalid())?));
+        if expires_at.le(now) {
+            return Err("token expired".to_string());
+        }
+
+        Ok(TokenClaims {
+            principal: Principal::try_from_slice(principal).map_err(|_| invalid())?,
+            emr_id: Id::from(Uuid::from_slice(emr_id).map_err(|_| invalid())?),
+            expires_at,
+        })
+    }
+
+    /// HMAC-SHA256 as defined in RFC 2104
+    fn mac(&self, message: &[u8]) -> [u8; 32] {
+        let mut key = [0u8; Self::BLOCK_SIZE];
+        key[..self.key.len()].copy_from_slice(&self.key);
+
+        let pad = |byte: u8| key.iter().map(move |k| k ^ byte).collect::<Vec<_>>();
+
+        let inner = Sha256::new().chain_update(pad(0x36)).chain_update(message).finalize();
+        Sha256::new().chain_update(pad(0x5c)).chain_update(inner).finalize().into()
+    }
+}
+
+/// public metadata served on `/metadata`
+pub fn metadata(token_ttl: u64) -> Value {
+    serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "routes": ["/health", "/metadata", "/emr/{emr_id}"],
+        "token_ttl_seconds": token_ttl / 1_000_000_000,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: &str, url: &str) -> HttpRequest {
+        HttpRequest { method: method.to_string(), url: url.to_string(), headers: vec![], body: vec![] }
+    }
+
+    #[test]
+    fn test_routes() {
+        let emr_id = "018bd2a8-7c3e-7000-8000-000000000001";
+
+        assert_eq!(Route::of(&request("GET", "/health")), Route::Health);
+        assert_eq!(Route::of(&request("GET", "/metadata?x=1")), Route::Metadata);
+        assert_eq!(
+            Route::of(&request("GET", &format!("/emr/{}", emr_id))),
+            Route::Emr(Id::from(Uuid::parse_str(emr_id).unwrap()))
+        );
+        assert_eq!(Route::of(&request("OPTIONS", &format!("/emr/{}", emr_id))), Route::Preflight);
+        assert_eq!(Route::of(&request("POST", "/health")), Route::MethodNotAllowed);
+        assert_eq!(Route::of(&request("GET", "/emr/not-an-id")), Route::NotFound);
+        assert_eq!(Route::of(&request("GET", "/")), Route::NotFound);
+    }
+
+    #[test]
+    fn test_hmac_rfc_4231() {
+        // RFC 4231 test case 2
+        let mut key = [0u8; 32];
+        key[..4].copy_from_slice(b"Jefe");
+        let signer = TokenSigner::new(key);
+
+        assert_eq!(
+            hex::encode(signer.mac(b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_token_roundtrip() {
+        let signer = TokenSigner::new([7; 32]);
+        let claims = TokenClaims {
+            principal: Principal::from_text("2vxsx-fae").unwrap(),
+            emr_id: Id::from(Uuid::from_bytes([1; 16])),
+            expires_at: Timestamp(1_000),
+        };
+
+        let token = signer.sign(&claims);
+        assert_eq!(signer.verify(&token.token, &Timestamp(999)).unwrap(), claims);
+        assert_eq!(signer.verify(&token.token, &Timestamp(1_000)), Err("token expired".to_string()));
+
+        // tampering with the payload or using another key invalidates the token
+        let tampered = token.token.replacen("00", "01", 1);
+        assert!(signer.verify(&tampered, &Timestamp(999)).is_err());
+        assert!(TokenSigner::new([8; 32]).verify(&token.token, &Timestamp(999)).is_err());
+    }
+}
@@ -1,28 +1,93 @@
-use std::{ cell::RefCell, rc::Rc };
+use std::{ cell::RefCell, rc::Rc, time::Duration };
 
 use candid::Principal;
+use attestation::{ Attestation, AttestationSigner, ThresholdEcdsaSigner };
 use config::CanisterConfig;
 use emr::{
-    providers::ProviderRegistry,
+    access::{
+        AccessAction,
+        AccessGrantDisplay,
+        AccessHistoryFilter,
+        AccessRecordDisplay,
+        AccessScope,
+        BreakGlassEventDisplay,
+    },
+    attachment::{ AttachmentDisplay, AttachmentUsage },
+    certificate::{ CertificateDisplay, CertificateKind, CertificateVerification, IssuedCertificate },
+    certification::{ encode_witness, CertifiedEmrs },
+    prescription::{ PrescriptionCode, PrescriptionDisplay, PrescriptionInput },
+    lab::{ LabOrderDisplay, LabOrderInput, LabResultInput },
+    chain::ChainVerification,
+    delegation::{
+        AssignGuardianRequest,
+        Delegation,
+        DelegationDisplay,
+        DelegationScope,
+        GuardianRequestDisplay,
+    },
+    encounter::{ EncounterDisplay, EncounterInput },
+    lifecycle::LifecycleDisplay,
+    providers::{ FacilityKind, ProviderRegistry },
     EmrRegistry,
     EmrDisplay,
     FromStableRef,
     patient::NIK,
     RecrodsDisplay,
     Records,
+    retention::{ ErasureRequestDisplay, Tombstone },
+    summary::ClinicalSummary,
+    vitals::{ ObservationSource, VitalInput, VitalSeries, VitalsQuery },
+};
+use export::{ ExportBundle, ExportChunk, ExportFormat, ExportedEmr, PendingImports };
+use fhir::{
+    import::{ FhirImporter, ResourceOutcome },
+    mapping::{ FhirMapping, MappingRule },
+    FhirRenderer,
+    Organization,
+};
+use http::{ HttpRequest, HttpResponse, HttpToken, Route, TokenClaims, TokenSigner };
+use hl7::{ parser::Message, Hl7Ack, Ingestion, MessageKind };
+use log::{
+    AccessChangeKind,
+    AccessChangeV001,
+    BindingChangeKind,
+    BindingChangeV001,
+    BreakGlassReviewV001,
+    BreakGlassV001,
+    DelegationChangeKind,
+    DelegationChangeV001,
+    EntryLog,
+    EntryRecords,
+    ErasureChangeKind,
+    ErasureChangeV001,
+    PatientImportV001,
 };
 use random::{ CanisterRandomSource, CallError };
-use types::{ Id, AsciiRecordsKey };
+use satusehat::{
+    submission::{ SubmissionDisplay, SubmissionResult },
+    SatusehatContext,
+    SatusehatPayload,
+};
+use sha2::{ Digest, Sha256 };
+use terminology::{ CodeEntry, CodeSystem, Terminology };
+use types::{ Id, AsciiRecordsKey, Timestamp };
 
 use crate::types::UUID_MAX_SOURCE_LEN;
 
+mod attestation;
 mod config;
 mod emr;
 mod encryption;
+mod export;
+mod fhir;
+mod hl7;
+mod http;
 mod log;
 mod macros;
 mod types;
 mod random;
+mod satusehat;
+mod terminology;
 
 // TODO :  make sure no unwrap() in this canister
 
@@ -32,8 +97,12 @@ pub struct State {
     provider_registry: ProviderRegistry,
     config: CanisterConfig,
     rng: Rc<CanisterRandomSource>,
-    // TODO : incorporate logs
-    // log: Log,
+    log: EntryLog,
+    imports: PendingImports,
+    fhir_mapping: FhirMapping,
+    terminology: Terminology,
+    /// created on the first token request, see [mint_http_token]
+    http_tokens: Option<TokenSigner>,
 }
 
 thread_local! {
@@ -45,7 +114,7 @@ fn verified_caller() -> Result<Principal, String> {
 
     ic_cdk::eprintln!("caller : {}", caller);
 
-    if caller.ne(&ic_cdk::export::Principal::anonymous()) {
+    if caller.eq(&ic_cdk::export::Principal::anonymous()) {
         return Err(String::from("anonymous caller is not allowed"));
     }
     Ok(caller)
@@ -53,7 +122,6 @@ fn verified_caller() -> Result<Principal, String> {
 
 // guard function
 fn only_canister_owner() -> Result<(), String> {
-    return Ok(());
     STATE.with(|state| {
         let state = state.borrow();
         let state = state.as_ref().unwrap();
@@ -101,8 +169,54 @@ fn only_patients() -> Result<(), String> {
 }
 
 // guard function
-fn only_patients_or_provider() -> Result<(), String> {
-    only_patients().or_else(|_| only_provider())
+fn only_guardians() -> Result<(), String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.emr_registry.is_valid_guardian(&caller, state.config.age_of_majority()) {
+            return Err("only guardian can call this method".to_string());
+        }
+
+        Ok(())
+    })
+}
+
+// guard function
+fn only_pharmacy() -> Result<(), String> {
+    only_facility(FacilityKind::Pharmacy)
+}
+
+// guard function
+fn only_laboratory() -> Result<(), String> {
+    only_facility(FacilityKind::Laboratory)
+}
+
+fn only_facility(kind: FacilityKind) -> Result<(), String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.provider_registry.is_valid_facility(kind, &caller) {
+            return Err(format!("only {} can call this method", kind.name()));
+        }
+
+        Ok(())
+    })
+}
+
+// guard function
+fn only_patients_or_guardians() -> Result<(), String> {
+    only_patients().or_else(|_| only_guardians())
+}
+
+// guard function
+fn only_patients_guardians_or_provider() -> Result<(), String> {
+    only_patients_or_guardians().or_else(|_| only_provider())
 }
 
 async fn generate_id() -> Result<Id, CallError> {
@@ -116,6 +230,49 @@ async fn generate_id() -> Result<Id, CallError> {
     rng.get_random_bytes::<UUID_MAX_SOURCE_LEN>().await.map(|bytes| Id::new(&bytes))
 }
 
+/// revoke expired access grants in bounded batches, called periodically by the timer set in [init]
+fn sweep_expired_grants() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+
+        state.emr_registry.sweep_expired_grants(CanisterConfig::GRANT_SWEEP_BATCH_SIZE);
+    })
+}
+
+/// purge emrs of approved erasure requests once their retention period has passed, called periodically by the timer
+/// set in [init]
+fn purge_expired_emrs() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+
+        let batch_size = CanisterConfig::RETENTION_PURGE_BATCH_SIZE;
+        let min_retention = state.config.min_retention_period();
+
+        if let Err(e) = state.emr_registry.purge_expired_emrs(batch_size, min_retention) {
+            ic_cdk::eprintln!("failed to purge expired emrs : {}", e);
+        }
+    })
+}
+
+/// release the quota of attachment uploads that were never finished, called periodically by the timer set in [init]
+fn sweep_abandoned_attachments() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+
+        let cutoff = Timestamp(Timestamp::new().inner().saturating_sub(CanisterConfig::ATTACHMENT_UPLOAD_TIMEOUT));
+        state.emr_registry.sweep_abandoned_attachments(&cutoff, CanisterConfig::ATTACHMENT_SWEEP_BATCH_SIZE);
+    })
+}
+
 #[ic_cdk::init]
 fn init() {
     ic_stable_memory::stable_memory_init();
@@ -123,6 +280,21 @@ fn init() {
     STATE.with(|state| {
         *state.borrow_mut() = Some(State::default());
     });
+
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(CanisterConfig::GRANT_SWEEP_INTERVAL_SECS),
+        sweep_expired_grants
+    );
+
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(CanisterConfig::RETENTION_PURGE_INTERVAL_SECS),
+        purge_expired_emrs
+    );
+
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(CanisterConfig::ATTACHMENT_SWEEP_INTERVAL_SECS),
+        sweep_abandoned_attachments
+    );
 }
 
 #[ic_cdk::update(guard = "only_canister_owner")]
@@ -137,7 +309,7 @@ async fn register_new_provider(new_provider: Principal, encryted_display_name: S
 
         state.provider_registry
             .register_new_provider(new_provider, encryted_display_name, id)
-            .unwrap()
+            .unwrap_or_else(|e| ic_cdk::trap(&e))
     })
 }
 
@@ -153,127 +325,2363 @@ fn suspend_provider(provider: Principal) {
     });
 }
 
-#[ic_cdk::query(guard = "only_patients_or_provider")]
-#[candid::candid_method(query)]
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// register a pharmacy allowed to look up and dispense prescriptions
+async fn register_new_pharmacy(new_pharmacy: Principal, encryted_display_name: String) -> Result<(), String> {
+    register_new_facility(FacilityKind::Pharmacy, new_pharmacy, encryted_display_name).await
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+fn suspend_pharmacy(pharmacy: Principal) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.provider_registry.suspend_facility(FacilityKind::Pharmacy, &pharmacy)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// register a laboratory allowed to receive lab orders and report their results
+async fn register_new_laboratory(new_laboratory: Principal, encryted_display_name: String) -> Result<(), String> {
+    register_new_facility(FacilityKind::Laboratory, new_laboratory, encryted_display_name).await
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+fn suspend_laboratory(laboratory: Principal) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.provider_registry.suspend_facility(FacilityKind::Laboratory, &laboratory)
+    })
+}
+
+async fn register_new_facility(kind: FacilityKind, principal: Principal, display_name: String) -> Result<(), String> {
+    let id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.provider_registry.register_new_facility(kind, principal, display_name, id)
+    })
+}
+
+/// check if `caller` may act for `patient` with the `required` scope, either as the patient itself or as one of its
+/// guardians. minority of the patient is derived from the currently configured age of majority.
+fn can_act_for(state: &State, caller: &Principal, patient: &NIK, required: DelegationScope) -> bool {
+    state.emr_registry.can_act_for(caller, patient, required, state.config.age_of_majority())
+}
+
+/// check if `caller` may read the emr. providers may only read emrs they issued or have been granted access to,
+/// patients and guardians may only read emrs they own or act for. returns the internal id of the caller if it is
+/// a provider, so the access can be recorded in the patient's access history.
+fn read_access(
+    state: &State,
+    caller: &Principal,
+    emr_id: &Id
+) -> Result<Option<emr::providers::InternalProviderId>, String> {
+    let provider = state.provider_registry.internal_id(caller);
+    let allowed = match provider {
+        Some(ref provider) =>
+            state.provider_registry.is_issued_by(caller, emr_id) ||
+                state.emr_registry.has_access_to_emr(provider, emr_id),
+        None => state.emr_registry.can_read_emr(caller, emr_id, state.config.age_of_majority()),
+    };
+
+    match allowed {
+        true => Ok(provider),
+        false => Err("not allowed to read this emr".to_string()),
+    }
+}
+
+/// check if `caller` may add to the emr. only the issuing provider and providers granted [AccessScope::Write] may,
+/// returns the internal id of the caller so the change can be recorded in the patient's access history.
+fn write_access(state: &State, caller: &Principal, emr_id: &Id) -> Result<emr::providers::InternalProviderId, String> {
+    let provider = state.provider_registry.internal_id(caller).ok_or("provider not found".to_string())?;
+
+    let allowed =
+        state.provider_registry.is_issued_by(caller, emr_id) ||
+        state.emr_registry.has_write_access_to_emr(&provider, emr_id);
+
+    match allowed {
+        true => Ok(provider),
+        false => Err("not allowed to update this emr".to_string()),
+    }
+}
+
+/// check if `caller` may access data kept per patient rather than per emr. providers need an active grant for the
+/// patient or an emr they issued to the patient, patients and guardians need the `required` scope.
+/// returns the internal id of the caller if it is a provider.
+fn patient_access(
+    state: &State,
+    caller: &Principal,
+    patient: &NIK,
+    required: DelegationScope
+) -> Result<Option<emr::providers::InternalProviderId>, String> {
+    let provider = state.provider_registry.internal_id(caller);
+    let allowed = match provider {
+        Some(ref provider) =>
+            state.emr_registry.has_access_to_patient(provider, patient) ||
+                state.emr_registry
+                    .emr_list(patient)
+                    .iter()
+                    .any(|emr_id| state.provider_registry.is_issued_by(caller, emr_id)),
+        None => can_act_for(state, caller, patient, required),
+    };
+
+    match allowed {
+        true => Ok(provider),
+        false => Err("not allowed to access this patient".to_string()),
+    }
+}
+
+// this is an update call because provider reads are recorded in the patient's access history,
+// state changes made during a query call are discarded.
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
 // TODO : move arguments to a candid struct
 fn read_emr_by_id(emr_id: types::Id) -> Option<emr::EmrDisplay> {
-    // TODO : make a mechanism to control who provider has access to the emr,
-    // currently, as long as you are the provider and has the emr id, you can read without user permission.
     STATE.with(|state| {
-        let state = state.borrow();
-        let state = state.as_ref().unwrap();
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller().unwrap();
+
+        let provider = match read_access(state, &caller, &emr_id) {
+            Ok(provider) => provider,
+            Err(e) => ic_cdk::trap(&e),
+        };
+
+        if let Some(provider) = provider {
+            state.emr_registry.record_access(&emr_id, &provider, AccessAction::Read).unwrap();
+        }
 
-        let emr = state.emr_registry.get_emr(&emr_id).unwrap();
+        // erased emrs only leave a tombstone behind
+        let emr = EmrDisplay::from_stable_ref(&*state.emr_registry.get_emr(&emr_id)?);
+        let coded = state.terminology.codings(emr.records());
 
-        Some(EmrDisplay::from_stable_ref(&*emr))
+        Some(emr.with_codings(coded))
     })
 }
 
-// TODO : return the emr id
 #[ic_cdk::update(guard = "only_provider")]
 #[candid::candid_method(update)]
 // TODO : move arguments to a candid struct
-async fn create_emr_for_user(owner: NIK, emr_records: RecrodsDisplay) {
-    ic_cdk::eprintln!("create_emr_for_user: {}", emr_records.0);
+/// emergency read access to every emr of a patient without prior consent. the access is time limited,
+/// recorded as a high severity audit entry and shown to the patient and admins for review.
+/// returns the break glass event id.
+async fn break_glass(patient: NIK, justification: String) -> Result<Id, String> {
+    if justification.trim().is_empty() {
+        return Err("justification is required".to_string());
+    }
 
-    let records = Records::try_from(emr_records).unwrap();
-    let id = generate_id().await.unwrap();
+    let event_id = generate_id().await.map_err(|e| e.to_string())?;
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        let expires_at = Timestamp(
+            Timestamp::new().inner().saturating_add(state.config.break_glass_duration())
+        );
+
+        state.emr_registry.break_glass(
+            event_id.clone(),
+            &patient,
+            &provider,
+            justification.clone(),
+            expires_at
+        )?;
+
+        let entry = BreakGlassV001::new(
+            event_id.clone(),
+            patient,
+            provider,
+            justification,
+            expires_at
+        );
+
+        state.log.record(EntryRecords::BreakGlass(entry), entry_id)?;
+
+        Ok(event_id)
+    })
+}
+
+#[ic_cdk::update(guard = "only_patients_or_guardians")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// grant a provider access with `scope` to every emr of a patient until `expires_at` (nanoseconds).
+/// callable by the patient or a guardian with [DelegationScope::Manage], expired grants are swept by a timer.
+async fn grant_access(
+    patient: NIK,
+    provider: Principal,
+    scope: AccessScope,
+    expires_at: Timestamp
+) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
 
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let state = state.as_mut().unwrap();
 
-        // change the emr version if upgrade happens
-        let emr = emr::V001::new(id, records).into();
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Manage) {
+            return Err("not allowed to manage access for this patient".to_string());
+        }
+
+        let provider = state.provider_registry
+            .internal_id(&provider)
+            .ok_or("provider not found".to_string())?;
 
-        let emr_id = state.emr_registry.register_emr(emr, owner).unwrap();
+        state.emr_registry.grant_access(&patient, &provider, scope, expires_at)?;
 
-        let caller = verified_caller().unwrap();
+        let entry = AccessChangeV001::new(
+            AccessChangeKind::Granted { scope, expires_at },
+            patient,
+            provider,
+            caller
+        );
 
-        // increment session
-        state.provider_registry.issue_emr(&caller, emr_id);
+        state.log.record(EntryRecords::AccessChange(entry), entry_id).map_err(String::from)
     })
 }
 
-#[ic_cdk::update(guard = "only_provider")]
+#[ic_cdk::update(guard = "only_patients_or_guardians")]
 #[candid::candid_method(update)]
 // TODO : move arguments to a candid struct
-fn update_emr(emr_id: Id, key_val: Vec<(AsciiRecordsKey, String)>) {
+async fn revoke_access(patient: NIK, provider: Principal) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let state = state.as_mut().unwrap();
 
-        let caller = verified_caller().unwrap();
-        // closure fo readability
-        let is_issued_by = || state.provider_registry.is_issued_by(&caller, &emr_id);
+        let caller = verified_caller()?;
 
-        // check if the caller is the issuer,
-        // if not, trap
-        if !is_issued_by() {
-            ic_cdk::trap("only issuer can update emr");
+        if !can_act_for(state, &caller, &patient, DelegationScope::Manage) {
+            return Err("not allowed to manage access for this patient".to_string());
         }
 
-        // batch update the emr
-        key_val
-            .into_iter()
-            .map(|(key, value)| { state.emr_registry.update_emr(&emr_id, key, value).unwrap() })
-            .collect::<Vec<_>>();
+        let provider = state.provider_registry
+            .internal_id(&provider)
+            .ok_or("provider not found".to_string())?;
+
+        state.emr_registry.revoke_access(&patient, &provider)?;
+
+        let entry = AccessChangeV001::new(AccessChangeKind::Revoked, patient, provider, caller);
+
+        state.log.record(EntryRecords::AccessChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients")]
+#[candid::candid_method(query)]
+/// provider reads, updates, exports and break glass accesses of the caller's emrs, in chronological order
+fn access_history(filter: AccessHistoryFilter) -> Result<Vec<AccessRecordDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let patient = state.emr_registry.patient_of(&caller).ok_or("patient not found".to_string())?;
+
+        let max = state.config.max_item_per_response().min(filter.max as usize) as u8;
+        let filter = AccessHistoryFilter { max, ..filter };
+
+        Ok(
+            state.emr_registry.access_history(&patient, &filter, |provider| {
+                state.provider_registry.display_name(provider)
+            })
+        )
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_or_guardians")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// active access grants of a patient, `anchor` is the number of grants to skip and `max` the page size
+fn access_grants_of_patient(patient: NIK, anchor: u64, max: u8) -> Result<Vec<AccessGrantDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Read) {
+            return Err("not allowed to read access grants of this patient".to_string());
+        }
+
+        let max = state.config.max_item_per_response().min(max as usize);
+
+        Ok(state.emr_registry.access_grants_of_patient(&patient, anchor, max))
     })
 }
 
 #[ic_cdk::query(guard = "only_provider")]
 #[candid::candid_method(query)]
-// TODO : fix anchor
+fn access_grants_of_provider() -> Result<Vec<AccessGrantDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        Ok(state.emr_registry.access_grants_of_provider(&provider))
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_or_guardians")]
+#[candid::candid_method(query)]
+fn break_glass_events(patient: NIK) -> Result<Vec<BreakGlassEventDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Read) {
+            return Err("not allowed to read break glass events of this patient".to_string());
+        }
+
+        Ok(state.emr_registry.break_glass_events_of(&patient))
+    })
+}
+
+#[ic_cdk::query(guard = "only_canister_owner")]
+#[candid::candid_method(query)]
 // TODO : move arguments to a candid struct
-fn emr_list_provider(anchor: u64, max: u8) -> Vec<Id> {
+/// break glass events waiting for review, `anchor` is the number of events to skip and `max` the page size
+fn pending_break_glass_reviews(anchor: u64, max: u8) -> Vec<BreakGlassEventDisplay> {
     STATE.with(|state| {
         let state = state.borrow();
         let state = state.as_ref().unwrap();
 
-        let provider = verified_caller().unwrap();
+        let max = state.config.max_item_per_response().min(max as usize);
 
-        state.provider_registry.get_issued(&provider, anchor, max).unwrap()
+        state.emr_registry.pending_break_glass_reviews(anchor, max)
     })
 }
 
-#[ic_cdk::update(guard = "only_provider")]
+#[ic_cdk::update(guard = "only_canister_owner")]
 #[candid::candid_method(update)]
 // TODO : move arguments to a candid struct
-fn register_patient(owner: Principal, hashed_nik: NIK) -> Result<(), String> {
+async fn review_break_glass(event_id: Id, note: String) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let state = state.as_mut().unwrap();
 
-        state.emr_registry.register_patient(owner, hashed_nik).unwrap();
+        let admin = verified_caller()?;
 
-        Ok(())
+        state.emr_registry.review_break_glass(&event_id, admin, note.clone())?;
+
+        let entry = BreakGlassReviewV001::new(event_id, admin, note);
+
+        state.log.record(EntryRecords::BreakGlassReview(entry), entry_id).map_err(String::from)
     })
 }
 
 #[ic_cdk::update(guard = "only_provider")]
 #[candid::candid_method(update)]
 // TODO : move arguments to a candid struct
-fn rebind_patient(owner: Principal, hashed_nik: NIK) {
+/// create a new emr for `owner` issued by the caller, returns the id of the created emr.
+/// records with unknown ICD-10 or LOINC codes are rejected.
+async fn create_emr_for_user(owner: NIK, emr_records: RecrodsDisplay) -> Result<Id, String> {
+    ic_cdk::eprintln!("create_emr_for_user: {}", emr_records.0);
+
+    let records = Records::try_from(emr_records)?;
+    let id = generate_id().await.map_err(|e| e.to_string())?;
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let state = state.as_mut().unwrap();
 
-        state.emr_registry.rebind_patient(owner, hashed_nik).unwrap();
+        let caller = verified_caller()?;
+
+        issue_emr(state, &caller, owner, records, id)
     })
 }
 
+/// create a new emr for `owner` issued by `provider`, shared by every endpoint that creates emrs
+fn issue_emr(
+    state: &mut State,
+    provider: &Principal,
+    owner: NIK,
+    records: Records,
+    id: Id
+) -> Result<Id, String> {
+    for (key, value) in records.sorted_pairs() {
+        state.terminology.validate(key.to_ascii_str(), &value)?;
+    }
+
+    // change the emr version if upgrade happens
+    let emr = emr::V001::new(id, records).into();
+
+    let emr_id = state.emr_registry.register_emr(emr, owner, *provider)?;
+
+    // increment session
+    state.provider_registry.issue_emr(provider, emr_id.clone())?;
+
+    Ok(emr_id)
+}
+
 #[ic_cdk::update(guard = "only_provider")]
 #[candid::candid_method(update)]
 // TODO : move arguments to a candid struct
-fn revoke_patient_access(owner: Principal) {
+/// import a FHIR R4 Bundle in json, e.g. from a clinic migrating to medblock. every Encounter becomes a new emr
+/// for `owner` and supported resources referencing it are mapped into its records using the configured
+/// [FhirMapping]. returns the outcome of every bundle entry with its rejection reason, if any.
+async fn import_fhir_bundle(owner: NIK, bundle: String) -> Result<Vec<ResourceOutcome>, String> {
+    let mut plan = STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        FhirImporter::new(&state.fhir_mapping).plan(&bundle)
+    })?;
+
+    let mut ids = Vec::with_capacity(plan.encounters().len());
+    for _ in plan.encounters() {
+        ids.push(generate_id().await.map_err(|e| e.to_string())?);
+    }
+
     STATE.with(|state| {
         let mut state = state.borrow_mut();
         let state = state.as_mut().unwrap();
 
-        state.emr_registry.revoke_patient_access(&owner);
+        let caller = verified_caller()?;
+
+        for (encounter, id) in ids.into_iter().enumerate() {
+            let result = Records::from_pairs(plan.encounters()[encounter].records().clone()).and_then(|records| {
+                issue_emr(state, &caller, owner.clone(), records, id)
+            });
+
+            plan.settle(encounter, result);
+        }
+
+        Ok(plan.into_outcomes())
     })
 }
 
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+fn update_emr(emr_id: Id, key_val: Vec<(AsciiRecordsKey, String)>) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller().unwrap();
+        // closure fo readability
+        let is_issued_by = || state.provider_registry.is_issued_by(&caller, &emr_id);
+
+        // check if the caller is the issuer,
+        // if not, trap
+        if !is_issued_by() {
+            ic_cdk::trap("only issuer can update emr");
+        }
+
+        // validate the whole batch before updating anything
+        for (key, value) in key_val.iter() {
+            if let Err(e) = state.terminology.validate(key.to_ascii_str(), value) {
+                ic_cdk::trap(&e);
+            }
+        }
+
+        let provider = state.provider_registry.internal_id(&caller).unwrap();
+        state.emr_registry.record_access(&emr_id, &provider, AccessAction::Update).unwrap();
+
+        // batch update the emr
+        key_val
+            .into_iter()
+            .map(|(key, value)| { state.emr_registry.update_emr(&emr_id, key, value, caller).unwrap() })
+            .collect::<Vec<_>>();
+    })
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+#[candid::candid_method(query)]
+// TODO : fix anchor
+// TODO : move arguments to a candid struct
+fn emr_list_provider(anchor: u64, max: u8) -> Vec<Id> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let provider = verified_caller().unwrap();
+
+        state.provider_registry.get_issued(&provider, anchor, max).unwrap()
+    })
+}
+
+/// certified response for `emr_ids`, ids of erased emrs are proven absent and left out of the response
+fn certified_emrs(state: &State, emr_ids: &[Id]) -> Result<CertifiedEmrs, String> {
+    let certificate = ic_cdk::api
+        ::data_certificate()
+        .ok_or("certificate is only available in query calls".to_string())?;
+
+    let emrs = emr_ids
+        .iter()
+        .filter_map(|emr_id| state.emr_registry.get_emr(emr_id))
+        .map(|emr| EmrDisplay::from_stable_ref(&*emr))
+        .collect();
+
+    Ok(CertifiedEmrs {
+        emrs,
+        certificate,
+        witness: encode_witness(&state.emr_registry.emr_witness(emr_ids)),
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// read an emr with a certificate and witness proving its content, see [CertifiedEmrs] for verification.
+/// queries can't be recorded in the access history, so providers can only read emrs they issued here and
+/// must use [read_emr_by_id] for emrs shared with them.
+fn read_emr_certified(emr_id: Id) -> Result<CertifiedEmrs, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let allowed =
+            state.emr_registry.can_read_emr(&caller, &emr_id, state.config.age_of_majority()) ||
+            state.provider_registry.is_issued_by(&caller, &emr_id);
+
+        if !allowed {
+            return Err("not allowed to read this emr".to_string());
+        }
+
+        certified_emrs(state, &[emr_id])
+    })
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// certified variant of [emr_list_provider], returns the issued emrs with a certificate and witness
+/// proving their content
+fn emr_list_provider_certified(anchor: u64, max: u8) -> Result<CertifiedEmrs, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let provider = verified_caller()?;
+        let emr_ids = state.provider_registry.get_issued(&provider, anchor, max)?;
+
+        certified_emrs(state, &emr_ids)
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// recompute the change history chain of an emr and check its current records against it.
+/// only hashes are returned, so this doesn't need to be recorded in the access history.
+fn verify_emr_chain(emr_id: Id) -> Result<ChainVerification, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        read_access(state, &caller, &emr_id)?;
+
+        state.emr_registry.verify_emr_chain(&emr_id).ok_or_else(|| "emr not found".to_string())
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+fn register_patient(owner: Principal, hashed_nik: NIK) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.emr_registry.register_patient(owner, hashed_nik).unwrap();
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+fn rebind_patient(owner: Principal, hashed_nik: NIK) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.emr_registry.rebind_patient(owner, hashed_nik).unwrap();
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+fn revoke_patient_access(owner: Principal) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.emr_registry.revoke_patient_access(&owner);
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// link an additional identifier (e.g. BPJS member id) to the patient known by `identifier`.
+/// meant to be called by the admin after verifying both identifiers belong to the same person, the change is recorded in the audit log.
+async fn link_patient_identifier(identifier: NIK, alias: NIK, reason: String) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let admin = verified_caller()?;
+        let transition = state.emr_registry.link_patient_identifier(&identifier, alias)?;
+
+        let entry = BindingChangeV001::new(BindingChangeKind::Link, admin, reason, transition);
+
+        state.log.record(EntryRecords::BindingChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider reads are recorded in the access history
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// every identifier linked to the patient, the caller must be allowed to access the patient
+fn patient_identifiers(identifier: NIK) -> Result<Vec<NIK>, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if let Some(provider) = patient_access(state, &caller, &identifier, DelegationScope::Read)? {
+            state.emr_registry.record_patient_access(&identifier, &provider, AccessAction::Read)?;
+        }
+
+        Ok(state.emr_registry.patient_identifiers(&identifier))
+    })
+}
+
+#[ic_cdk::update(guard = "only_patients_or_guardians")]
+#[candid::candid_method(update)]
+/// assign a guardian for a patient. callable by the patient itself or by a guardian with [DelegationScope::Manage],
+/// a guardian can't reassign themselves nor grant more than their own delegation.
+async fn assign_guardian(patient: NIK, request: AssignGuardianRequest) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Manage) {
+            return Err("not allowed to manage guardians for this patient".to_string());
+        }
+
+        let delegation = Delegation::new(&request, caller, None)?;
+        let age_of_majority = state.config.age_of_majority();
+        state.emr_registry.assign_guardian(&patient, request.guardian, delegation, age_of_majority)?;
+
+        let entry = DelegationChangeV001::new(
+            DelegationChangeKind::Assigned(request.scope),
+            patient,
+            request.guardian,
+            caller,
+            None
+        );
+
+        state.log.record(EntryRecords::DelegationChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+/// request a guardian for a patient that can't do it themselves, e.g. a newborn or an incapacitated patient.
+/// only providers treating the patient may file the request and the delegation is only assigned once an admin
+/// approves it. `authority` must reference the document giving the provider authority to do so, it's kept
+/// in the audit log. returns the guardian request id.
+async fn assign_guardian_by_provider(
+    patient: NIK,
+    request: AssignGuardianRequest,
+    authority: String
+) -> Result<Id, String> {
+    if authority.trim().is_empty() {
+        return Err("authority document reference is required".to_string());
+    }
+
+    let request_id = generate_id().await.map_err(|e| e.to_string())?;
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        patient_access(state, &caller, &patient, DelegationScope::Manage)?;
+
+        state.emr_registry.request_guardian(
+            request_id.clone(),
+            &patient,
+            &request,
+            caller,
+            authority.clone()
+        )?;
+
+        let entry = DelegationChangeV001::new(
+            DelegationChangeKind::Requested(request.scope),
+            patient,
+            request.guardian,
+            caller,
+            Some(authority)
+        );
+
+        state.log.record(EntryRecords::DelegationChange(entry), entry_id).map_err(String::from)?;
+
+        Ok(request_id)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// approve a guardian request filed by a provider after reviewing its authority document, the delegation is
+/// assigned immediately.
+async fn approve_guardian_request(request_id: Id) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let admin = verified_caller()?;
+        let age_of_majority = state.config.age_of_majority();
+        let approved = state.emr_registry.approve_guardian_request(&request_id, admin, age_of_majority)?;
+
+        let entry = DelegationChangeV001::new(
+            DelegationChangeKind::Assigned(approved.request.scope),
+            approved.patient,
+            approved.request.guardian,
+            admin,
+            Some(approved.authority)
+        );
+
+        state.log.record(EntryRecords::DelegationChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+async fn reject_guardian_request(request_id: Id, reason: String) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let admin = verified_caller()?;
+        let rejected = state.emr_registry.reject_guardian_request(&request_id, admin, reason)?;
+
+        let entry = DelegationChangeV001::new(
+            DelegationChangeKind::Rejected,
+            rejected.patient,
+            rejected.request.guardian,
+            admin,
+            Some(rejected.authority)
+        );
+
+        state.log.record(EntryRecords::DelegationChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::query(guard = "only_canister_owner")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// guardian requests waiting for review, `anchor` is the number of requests to skip and `max` the page size
+fn pending_guardian_requests(anchor: u64, max: u8) -> Vec<GuardianRequestDisplay> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let max = state.config.max_item_per_response().min(max as usize);
+
+        state.emr_registry.pending_guardian_requests(anchor, max)
+    })
+}
+
+#[ic_cdk::update(guard = "only_patients_or_guardians")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// revoke a guardian of a patient. callable by the patient, a guardian with [DelegationScope::Manage],
+/// or the guardian itself to step down.
+async fn revoke_guardian(patient: NIK, guardian: Principal) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        let can_manage = can_act_for(state, &caller, &patient, DelegationScope::Manage);
+        if !can_manage && caller.ne(&guardian) {
+            return Err("not allowed to manage guardians for this patient".to_string());
+        }
+
+        state.emr_registry.revoke_guardian(&patient, &guardian)?;
+
+        let entry = DelegationChangeV001::new(
+            DelegationChangeKind::Revoked,
+            patient,
+            guardian,
+            caller,
+            None
+        );
+
+        state.log.record(EntryRecords::DelegationChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_or_guardians")]
+#[candid::candid_method(query)]
+fn guardians_of(patient: NIK) -> Result<Vec<DelegationDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Read) {
+            return Err("not allowed to read guardians of this patient".to_string());
+        }
+
+        Ok(state.emr_registry.guardians_of(&patient, state.config.age_of_majority()))
+    })
+}
+
+#[ic_cdk::query(guard = "only_guardians")]
+#[candid::candid_method(query)]
+fn patients_of_guardian() -> Vec<NIK> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let guardian = verified_caller().unwrap();
+
+        state.emr_registry.patients_of_guardian(&guardian, state.config.age_of_majority())
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_or_guardians")]
+#[candid::candid_method(query)]
+fn emr_list_patient(patient: NIK) -> Result<Vec<Id>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Read) {
+            return Err("not allowed to read emrs of this patient".to_string());
+        }
+
+        Ok(state.emr_registry.emr_list(&patient))
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+fn set_age_of_majority(age: u8) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.config.set_age_of_majority(age);
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// merge all emrs of a patient registered under a mistyped [NIK] into the correct one.
+/// meant to be called by the admin after reviewing the duplicate, the change is recorded in the audit log.
+async fn merge_patients(from: NIK, into: NIK, reason: String) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let admin = verified_caller()?;
+        let transition = state.emr_registry.merge_patients(&from, &into)?;
+
+        let entry = BindingChangeV001::new(BindingChangeKind::Merge, admin, reason, transition);
+
+        state.log.record(EntryRecords::BindingChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// move a single emr that was attached to the wrong patient.
+/// meant to be called by the admin after reviewing the mistake, the change is recorded in the audit log.
+async fn move_emr(emr_id: Id, from: NIK, to: NIK, reason: String) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let admin = verified_caller()?;
+        let transition = state.emr_registry.move_emr(&emr_id, &from, &to)?;
+
+        let entry = BindingChangeV001::new(
+            BindingChangeKind::Move(emr_id),
+            admin,
+            reason,
+            transition
+        );
+
+        state.log.record(EntryRecords::BindingChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::update(guard = "only_patients_or_guardians")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// request erasure of every emr of a patient, callable by the patient or a guardian with [DelegationScope::Manage].
+/// the request must be approved by an admin and emrs are only purged after the minimum retention period.
+/// returns the erasure request id.
+async fn request_erasure(patient: NIK) -> Result<Id, String> {
+    let request_id = generate_id().await.map_err(|e| e.to_string())?;
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Manage) {
+            return Err("not allowed to request erasure for this patient".to_string());
+        }
+
+        state.emr_registry.request_erasure(request_id.clone(), &patient, caller)?;
+
+        let entry = ErasureChangeV001::new(
+            ErasureChangeKind::Requested,
+            request_id.clone(),
+            patient,
+            caller,
+            None
+        );
+
+        state.log.record(EntryRecords::ErasureChange(entry), entry_id).map_err(String::from)?;
+
+        Ok(request_id)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// approve a pending erasure request, the patient's emrs are purged by a timer once their retention period has passed
+async fn approve_erasure(request_id: Id) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let admin = verified_caller()?;
+        let min_retention = state.config.min_retention_period();
+        let patient = state.emr_registry.approve_erasure(&request_id, admin, min_retention)?;
+
+        let entry = ErasureChangeV001::new(ErasureChangeKind::Approved, request_id, patient, admin, None);
+
+        state.log.record(EntryRecords::ErasureChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+async fn reject_erasure(request_id: Id, reason: String) -> Result<(), String> {
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let admin = verified_caller()?;
+        let patient = state.emr_registry.reject_erasure(&request_id, admin, reason.clone())?;
+
+        let entry = ErasureChangeV001::new(
+            ErasureChangeKind::Rejected,
+            request_id,
+            patient,
+            admin,
+            Some(reason)
+        );
+
+        state.log.record(EntryRecords::ErasureChange(entry), entry_id).map_err(String::from)
+    })
+}
+
+#[ic_cdk::query(guard = "only_canister_owner")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// erasure requests waiting for review, `anchor` is the number of requests to skip and `max` the page size
+fn pending_erasure_requests(anchor: u64, max: u8) -> Vec<ErasureRequestDisplay> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let max = state.config.max_item_per_response().min(max as usize);
+
+        state.emr_registry.pending_erasure_requests(anchor, max)
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_or_guardians")]
+#[candid::candid_method(query)]
+fn erasure_requests_of(patient: NIK) -> Result<Vec<ErasureRequestDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Read) {
+            return Err("not allowed to read erasure requests of this patient".to_string());
+        }
+
+        Ok(state.emr_registry.erasure_requests_of(&patient))
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(query)]
+/// returns the tombstone left behind by an erased emr, none if the emr was never erased
+fn emr_tombstone(emr_id: Id) -> Option<Tombstone> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        state.emr_registry.emr_tombstone(&emr_id)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+/// set the minimum time in nanoseconds an emr is kept after its last update before it can be erased
+fn set_min_retention_period(period: u64) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.config.set_min_retention_period(period);
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// export every emr of the caller, with timestamps and issuing provider ids, as a versioned bundle.
+/// the bundle is split into chunks to fit the response limit, fetch chunks `0..total` and concatenate them.
+fn export_patient_data(format: ExportFormat, chunk: u32) -> Result<ExportChunk, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let patient = state.emr_registry.patient_of(&caller).ok_or("patient not found".to_string())?;
+
+        let emrs = state.emr_registry
+            .emr_list(&patient)
+            .iter()
+            .filter_map(|emr_id| {
+                let emr = state.emr_registry.get_emr(emr_id)?;
+                Some(ExportedEmr::new(&emr, state.provider_registry.issuer_of(emr_id)))
+            })
+            .collect();
+
+        let bundle = ExportBundle::new(&patient, emrs).encode(format)?;
+
+        ExportChunk::from_bundle(format, &bundle, chunk)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// import a patient export bundle produced by [export_patient_data] at another facility, chunk by chunk and in order.
+/// once the last chunk arrives every emr in the bundle is created for `owner` and issued by the caller,
+/// the ids of the created emrs are returned. returns none while more chunks are expected.
+async fn import_patient_data(owner: NIK, chunk: ExportChunk) -> Result<Option<Vec<Id>>, String> {
+    let assembled = STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        state.imports.push(caller, chunk, CanisterConfig::MAX_IMPORT_BUNDLE_SIZE, CanisterConfig::MAX_PENDING_IMPORTS)
+    })?;
+
+    let Some((format, bytes)) = assembled else {
+        return Ok(None);
+    };
+
+    let bundle = ExportBundle::decode(format, &bytes)?;
+
+    let mut ids = Vec::with_capacity(bundle.emrs().len());
+    for _ in bundle.emrs() {
+        ids.push(generate_id().await.map_err(|e| e.to_string())?);
+    }
+
+    let entry_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        // validate every emr before creating any so a malformed bundle is not partially imported
+        let records = bundle
+            .emrs()
+            .iter()
+            .map(|emr| Records::from_pairs(emr.records().clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut imported = Vec::with_capacity(ids.len());
+
+        for ((exported, records), id) in bundle.emrs().iter().zip(records).zip(ids) {
+            let emr_id = issue_emr(state, &caller, owner.clone(), records, id)?;
+
+            imported.push((exported.emr_id().clone(), emr_id));
+        }
+
+        let entry = PatientImportV001::new(
+            owner,
+            provider,
+            bundle.patient().to_string(),
+            bundle.as_of(),
+            imported.clone()
+        );
+
+        state.log.record(EntryRecords::PatientImport(entry), entry_id).map_err(String::from)?;
+
+        Ok(Some(imported.into_iter().map(|(_, emr_id)| emr_id).collect()))
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider exports are recorded in the access history
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// render an emr with its patient and issuing provider as a FHIR R4 collection Bundle in json,
+/// records are mapped to FHIR resources according to the configured [FhirMapping].
+fn export_emr_fhir(emr_id: Id) -> Result<String, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = read_access(state, &caller, &emr_id)?;
+
+        let emr = state.emr_registry.get_emr(&emr_id).ok_or("emr not found".to_string())?;
+        let emr = EmrDisplay::from_stable_ref(&*emr);
+
+        let patient = state.emr_registry.owner_of_emr(&emr_id).ok_or("emr owner not found".to_string())?;
+        let organization = state.provider_registry.issuer_of(&emr_id).map(|id| Organization {
+            name: state.provider_registry.display_name(&id),
+            id,
+        });
+
+        let bundle = FhirRenderer::new(&state.fhir_mapping).render(&emr, &patient, organization.as_ref());
+
+        if let Some(provider) = provider {
+            state.emr_registry.record_access(&emr_id, &provider, AccessAction::Export)?;
+        }
+
+        Ok(bundle.to_string())
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// ingest a HL7 v2 message from a lab or hospital feed. the patient is matched with the hashed identifier in PID-3.
+/// ADT^A01 creates a new emr for the visit, ORU^R01 adds its results to the emr referenced in PV1-19 or OBR-2,
+/// which must belong to the patient and be issued by or shared with the caller. a result for a test already on
+/// the emr is kept next to the earlier one, see [emr::EmrRegistry::add_emr_results].
+/// returns an ACK describing the accepted and rejected segments.
+async fn ingest_hl7(message: String) -> Result<Hl7Ack, String> {
+    let message = Message::parse(&message)?;
+
+    let ingestion = match Ingestion::plan(&message) {
+        Ok(ingestion) => ingestion,
+        Err(reason) => {
+            return Ok(hl7::reject(&message, reason, Timestamp::new()));
+        }
+    };
+
+    let id = match ingestion.kind() {
+        MessageKind::Admit => Some(generate_id().await.map_err(|e| e.to_string())?),
+        MessageKind::Results => None,
+    };
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        let result = match (ingestion.target(), id) {
+            (Some(emr_id), _) => apply_hl7_results(state, &caller, &provider, &ingestion, emr_id),
+            (None, Some(id)) => {
+                Records::from_pairs(ingestion.records().to_vec()).and_then(|records| {
+                    issue_emr(state, &caller, ingestion.patient().clone(), records, id)
+                })
+            }
+            (None, None) => Err("message has no target emr".to_string()),
+        };
+
+        Ok(ingestion.into_ack(&message, result, Timestamp::new()))
+    })
+}
+
+fn apply_hl7_results(
+    state: &mut State,
+    caller: &Principal,
+    provider: &emr::providers::InternalProviderId,
+    ingestion: &Ingestion,
+    emr_id: &Id
+) -> Result<Id, String> {
+    if !state.emr_registry.emr_list(ingestion.patient()).contains(emr_id) {
+        return Err("emr does not belong to the patient in PID".to_string());
+    }
+
+    // results may come from the issuer or from a lab the patient granted write access to
+    write_access(state, caller, emr_id)?;
+
+    // the whole message is checked before anything is written, so a rejected message leaves the emr untouched
+    let records = ingestion
+        .records()
+        .iter()
+        .map(|(key, value)| Ok((AsciiRecordsKey::new(key).map_err(|e| e.to_string())?, value.clone())))
+        .collect::<Result<Vec<_>, String>>()?;
+
+    state.emr_registry.add_emr_results(emr_id, records, *caller)?;
+    state.emr_registry.record_access(emr_id, provider, AccessAction::Update)?;
+
+    Ok(emr_id.clone())
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// replace the records key to FHIR path mapping used by [export_emr_fhir]
+fn set_fhir_mapping(rules: Vec<MappingRule>, include_unmapped: bool) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.fhir_mapping = FhirMapping::new(rules, include_unmapped)?;
+
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+fn fhir_mapping() -> FhirMapping {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        state.fhir_mapping.clone()
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// produce the SATUSEHAT transaction Bundle in json for an emr issued by the caller and mark its submission
+/// as pending. the payload is checked against the SATUSEHAT profile first, a payload that does not conform
+/// is recorded as rejected and the violations are returned instead.
+fn satusehat_payload(emr_id: Id, context: SatusehatContext) -> Result<String, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        if !state.provider_registry.is_issued_by(&caller, &emr_id) {
+            return Err("only the issuing provider can submit this emr".to_string());
+        }
+
+        let emr = state.emr_registry.get_emr(&emr_id).ok_or("emr not found".to_string())?;
+        let emr = EmrDisplay::from_stable_ref(&*emr);
+        let patient = state.emr_registry.owner_of_emr(&emr_id).ok_or("emr owner not found".to_string())?;
+
+        let rendered = FhirRenderer::new(&state.fhir_mapping).render(&emr, &patient, None);
+        let payload = SatusehatPayload::build(&emr_id, &rendered, &context)?;
+
+        state.emr_registry.prepare_submission(&emr_id, &provider)?;
+
+        if let Err(errors) = satusehat::validate(&payload) {
+            let reason = satusehat::describe(&errors);
+            state.emr_registry.reject_submission(&emr_id, reason.clone())?;
+
+            return Err(reason);
+        }
+
+        state.emr_registry.record_access(&emr_id, &provider, AccessAction::Export)?;
+
+        Ok(payload.to_string())
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// report the SATUSEHAT response to a pending submission of an emr issued by the caller
+fn report_satusehat_submission(emr_id: Id, result: SubmissionResult) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.provider_registry.is_issued_by(&caller, &emr_id) {
+            return Err("only the issuing provider can report this submission".to_string());
+        }
+
+        state.emr_registry.report_submission(&emr_id, result)
+    })
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// SATUSEHAT submission status of an emr issued by the caller
+fn satusehat_submission(emr_id: Id) -> Result<Option<SubmissionDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.provider_registry.is_issued_by(&caller, &emr_id) {
+            return Err("only the issuing provider can view this submission".to_string());
+        }
+
+        Ok(state.emr_registry.submission_status(&emr_id))
+    })
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+#[candid::candid_method(query)]
+/// SATUSEHAT submissions of the caller still waiting for a response
+fn pending_satusehat_submissions() -> Result<Vec<SubmissionDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        Ok(state.emr_registry.pending_submissions_of(&provider))
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// start a chunked upload of a binary attachment such as an image or scanned document, linked to records key `key`
+/// of an emr the caller issued or was granted write access to. `size` bytes are reserved from the caller storage
+/// quota and `sha256` is the hex encoded hash of the whole content, checked when the upload finishes.
+/// returns the attachment id to upload the chunks to.
+async fn begin_attachment_upload(
+    emr_id: Id,
+    key: String,
+    mime_type: String,
+    size: u64,
+    sha256: String
+) -> Result<Id, String> {
+    let key = AsciiRecordsKey::new(key).map_err(|e| e.to_string())?;
+    let sha256: [u8; 32] = hex
+        ::decode(sha256)
+        .ok()
+        .and_then(|digest| digest.try_into().ok())
+        .ok_or("sha256 must be 64 hex characters".to_string())?;
+
+    let id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = write_access(state, &caller, &emr_id)?;
+
+        let default_quota = state.config.default_attachment_quota();
+        state.emr_registry.begin_attachment(
+            id.clone(),
+            &emr_id,
+            key,
+            provider.clone(),
+            mime_type,
+            size,
+            sha256,
+            default_quota
+        )?;
+
+        state.emr_registry.record_access(&emr_id, &provider, AccessAction::Update)?;
+
+        Ok(id)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// upload chunk `index` of an attachment started by the caller, chunks must be uploaded in order
+/// and each must be at most 1.5MB
+fn upload_attachment_chunk(attachment_id: Id, index: u64, data: Vec<u8>) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        state.emr_registry.append_attachment_chunk(&attachment_id, &provider, index, data)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// finish an attachment upload of the caller once every chunk is uploaded. an attachment whose content
+/// doesn't match the declared sha256 is discarded.
+fn finish_attachment_upload(attachment_id: Id) -> Result<AttachmentDisplay, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        state.emr_registry.finish_attachment(&attachment_id, &provider)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// discard an unfinished attachment upload of the caller and release its reserved quota
+fn abort_attachment_upload(attachment_id: Id) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        state.emr_registry.abort_attachment(&attachment_id, &provider)
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// list the attachments of an emr the caller can read, without their content
+fn attachments_of_emr(emr_id: Id) -> Result<Vec<AttachmentDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        read_access(state, &caller, &emr_id)?;
+
+        Ok(state.emr_registry.attachments_of_emr(&emr_id))
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider downloads are recorded in the access history
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// download chunk `index` of a finished attachment of an emr the caller can read,
+/// the number of chunks is listed in [attachments_of_emr]
+fn download_attachment_chunk(attachment_id: Id, index: u64) -> Result<Vec<u8>, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let attachment = state.emr_registry
+            .attachment(&attachment_id)
+            .ok_or("attachment not found".to_string())?;
+        let emr_id = attachment.emr_id();
+
+        let provider = read_access(state, &caller, emr_id)?;
+        let chunk = state.emr_registry.attachment_chunk(&attachment_id, index)?;
+
+        // every chunk is recorded, chunks can be fetched in any order so no single index marks a download
+        if let Some(provider) = provider {
+            state.emr_registry.record_access(emr_id, &provider, AccessAction::Read)?;
+        }
+
+        Ok(chunk)
+    })
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+#[candid::candid_method(query)]
+/// attachment storage used by the caller and its quota, in bytes
+fn attachment_usage() -> Result<AttachmentUsage, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        Ok(state.emr_registry.attachment_usage(&provider, state.config.default_attachment_quota()))
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// set the attachment storage quota of a provider in bytes, overriding the default quota
+fn set_attachment_quota(provider: Principal, quota: u64) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let provider = state.provider_registry
+            .internal_id(&provider)
+            .ok_or("provider not found".to_string())?;
+
+        Ok(state.emr_registry.set_attachment_quota(provider, quota)?)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+/// set the attachment storage quota in bytes of providers without a quota of their own
+fn set_default_attachment_quota(quota: u64) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.config.set_default_attachment_quota(quota);
+    })
+}
+
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// mint a short lived bearer token for reading an emr over plain HTTP at `GET /emr/{emr_id}`.
+/// provider reads over HTTP can't be recorded since `http_request` is a query, so minting the token is
+/// recorded in the access history instead.
+async fn mint_http_token(emr_id: Id) -> Result<HttpToken, String> {
+    let has_signer = STATE.with(|state| state.borrow().as_ref().unwrap().http_tokens.is_some());
+
+    let key = match has_signer {
+        true => None,
+        false => {
+            let rng = STATE.with(|state| state.borrow().as_ref().unwrap().rng.clone());
+            Some(rng.get_random_bytes::<32>().await.map_err(|e| e.to_string())?)
+        }
+    };
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = read_access(state, &caller, &emr_id)?;
+
+        if let (None, Some(key)) = (&state.http_tokens, key) {
+            state.http_tokens = Some(TokenSigner::new(key));
+        }
+
+        let claims = TokenClaims {
+            principal: caller,
+            emr_id: emr_id.clone(),
+            expires_at: Timestamp(Timestamp::new().inner() + CanisterConfig::HTTP_TOKEN_TTL),
+        };
+
+        let token = state.http_tokens.as_ref().unwrap().sign(&claims);
+
+        if let Some(provider) = provider {
+            state.emr_registry.record_access(&emr_id, &provider, AccessAction::Read)?;
+        }
+
+        Ok(token)
+    })
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+/// read only HTTP interface for the IC boundary nodes, see [Route] for the served routes.
+/// responses are not certified yet, so clients must use the raw domain.
+fn http_request(request: HttpRequest) -> HttpResponse {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        match Route::of(&request) {
+            Route::Health => HttpResponse::json(200, &serde_json::json!({ "status": "ok" })),
+            Route::Metadata => HttpResponse::json(200, &http::metadata(CanisterConfig::HTTP_TOKEN_TTL)),
+            Route::Emr(emr_id) => http_read_emr(state, &request, &emr_id).unwrap_or_else(|error| error),
+            Route::Preflight => HttpResponse::preflight(),
+            Route::MethodNotAllowed => HttpResponse::error(405, "method not allowed"),
+            Route::NotFound => HttpResponse::error(404, "not found"),
+        }
+    })
+}
+
+fn http_read_emr(state: &State, request: &HttpRequest, emr_id: &Id) -> Result<HttpResponse, HttpResponse> {
+    let unauthorized = |message: String| HttpResponse::error(401, message);
+
+    let token = http::bearer_token(request).ok_or_else(|| unauthorized("missing bearer token".to_string()))?;
+    let signer = state.http_tokens.as_ref().ok_or_else(|| unauthorized("invalid token".to_string()))?;
+    let claims = signer.verify(token, &Timestamp::new()).map_err(unauthorized)?;
+
+    if claims.emr_id.ne(emr_id) {
+        return Err(HttpResponse::error(403, "token was not minted for this emr"));
+    }
+
+    read_access(state, &claims.principal, emr_id).map_err(|e| HttpResponse::error(403, e))?;
+
+    let emr = state.emr_registry.get_emr(emr_id).ok_or_else(|| HttpResponse::error(404, "emr not found"))?;
+
+    Ok(HttpResponse::json(200, &EmrDisplay::from_stable_ref(&*emr)))
+}
+
+#[ic_cdk::update(guard = "only_patients_or_guardians")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// sign an emr, or only the records in `keys`, with the threshold ECDSA key of the canister so the patient
+/// can prove its content off chain. see [Attestation] for verification.
+async fn attest_emr(emr_id: Id, keys: Option<Vec<String>>) -> Result<Attestation, String> {
+    let (payload, key_name) = STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.emr_registry.can_read_emr(&caller, &emr_id, state.config.age_of_majority()) {
+            return Err("not allowed to read this emr".to_string());
+        }
+
+        // an emr entered in error is only kept for the audit trail, it must not be vouched for
+        state.emr_registry.check_in_use(&emr_id)?;
+
+        let emr = state.emr_registry.get_emr(&emr_id).ok_or_else(|| "emr not found".to_string())?;
+        let emr = EmrDisplay::from_stable_ref(&*emr);
+
+        let payload = attestation::payload(&emr, keys.as_deref(), &ic_cdk::id(), Timestamp::new())?;
+
+        Ok((payload, state.config.ecdsa_key_name().to_string()))
+    })?;
+
+    attestation
+        ::attest(&ThresholdEcdsaSigner::new(key_name), payload).await
+        .map_err(|e| e.to_string())
+}
+
+#[ic_cdk::update]
+#[candid::candid_method(update)]
+/// SEC1 compressed public key emr attestations are signed with, verifiers should pin it
+async fn attestation_public_key() -> Result<Vec<u8>, String> {
+    let key_name = STATE.with(|state| state.borrow().as_ref().unwrap().config.ecdsa_key_name().to_string());
+
+    ThresholdEcdsaSigner::new(key_name)
+        .public_key().await
+        .map_err(|e| e.to_string())
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+/// set the threshold ECDSA key emr attestations are signed with, changing it invalidates pinned public keys
+fn set_ecdsa_key_name(name: String) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.config.set_ecdsa_key_name(name);
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// issue a vaccination or lab result certificate stating the records in `keys` of an emr the caller can read.
+/// the certificate is signed with the attestation key, its `code` verifies with [verify_certificate].
+async fn issue_certificate(emr_id: Id, kind: CertificateKind, keys: Vec<String>) -> Result<CertificateDisplay, String> {
+    let certificate_id = generate_id().await.map_err(|e| e.to_string())?;
+    let issued_at = Timestamp::new();
+
+    let (issuer, claims, key_name) = STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let issuer = read_access(state, &caller, &emr_id)?.ok_or("provider not found".to_string())?;
+        let issuer_name = state.provider_registry.display_name(&issuer).unwrap_or_default();
+
+        state.emr_registry.check_in_use(&emr_id)?;
+
+        let emr = state.emr_registry.get_emr(&emr_id).ok_or("emr not found".to_string())?;
+        let emr = EmrDisplay::from_stable_ref(&*emr);
+
+        let claims = emr::certificate::claims(&certificate_id, kind, issuer_name, &emr, &keys, issued_at)?;
+
+        Ok::<_, String>((issuer, claims, state.config.ecdsa_key_name().to_string()))
+    })?;
+
+    let signer = ThresholdEcdsaSigner::new(key_name);
+    let public_key = signer.public_key().await.map_err(|e| e.to_string())?;
+    let signature = signer.sign(Sha256::digest(&claims).into()).await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let certificate = state.emr_registry.store_certificate(IssuedCertificate {
+            certificate_id,
+            emr_id: emr_id.clone(),
+            issuer: issuer.clone(),
+            kind,
+            issued_at,
+            claims,
+            signature,
+            public_key,
+        })?;
+
+        state.emr_registry.record_access(&emr_id, &issuer, AccessAction::Export)?;
+
+        Ok(certificate)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// revoke a certificate issued by the caller, its code verifies as revoked afterwards
+fn revoke_certificate(certificate_id: Id, reason: String) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry
+            .internal_id(&caller)
+            .ok_or("provider not found".to_string())?;
+
+        state.emr_registry.revoke_certificate(&certificate_id, &provider, reason)
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// certificates issued from an emr, including revoked ones
+fn certificates_of_emr(emr_id: Id) -> Result<Vec<CertificateDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        read_access(state, &caller, &emr_id)?;
+
+        Ok(state.emr_registry.certificates_of_emr(&emr_id))
+    })
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+/// public verification of a certificate QR code text, checks its signature and revocation status.
+/// only the records stated by the certificate are returned.
+fn verify_certificate(code: String) -> CertificateVerification {
+    STATE.with(|state| state.borrow().as_ref().unwrap().emr_registry.verify_certificate(&code))
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// issue a prescription against an emr issued by the caller. the returned code is what the patient presents
+/// at the pharmacy.
+async fn issue_prescription(emr_id: Id, prescription: PrescriptionInput) -> Result<PrescriptionDisplay, String> {
+    let prescription_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    let rng = STATE.with(|state| state.borrow().as_ref().unwrap().rng.clone());
+    let code = rng.get_random_bytes::<10>().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.provider_registry.is_issued_by(&caller, &emr_id) {
+            return Err("only the issuing provider can prescribe against this emr".to_string());
+        }
+
+        let provider = state.provider_registry.internal_id(&caller).ok_or("provider not found".to_string())?;
+
+        state.emr_registry.issue_prescription(
+            prescription_id,
+            PrescriptionCode::new(code),
+            &emr_id,
+            provider,
+            prescription
+        )
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// cancel an active prescription written by the caller
+fn cancel_prescription(prescription_id: Id, reason: String) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry.internal_id(&caller).ok_or("provider not found".to_string())?;
+
+        state.emr_registry.cancel_prescription(&prescription_id, &provider, reason)
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider reads are recorded in the access history
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// prescriptions issued against an emr, including their codes and dispensing history
+fn prescriptions_of_emr(emr_id: Id) -> Result<Vec<PrescriptionDisplay>, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if let Some(provider) = read_access(state, &caller, &emr_id)? {
+            state.emr_registry.record_access(&emr_id, &provider, AccessAction::Read)?;
+        }
+
+        Ok(state.emr_registry.prescriptions_of_emr(&emr_id))
+    })
+}
+
+#[ic_cdk::query(guard = "only_pharmacy")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// look up a prescription by the code the patient presents
+fn lookup_prescription(code: String) -> Result<PrescriptionDisplay, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let code = PrescriptionCode::parse(&code)?;
+
+        state.emr_registry.prescription_by_code(&code).ok_or("prescription not found".to_string())
+    })
+}
+
+#[ic_cdk::update(guard = "only_pharmacy")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// dispense `amount` units of the prescription with the code the patient presents, partially or fully
+fn dispense_prescription(code: String, amount: u32) -> Result<PrescriptionDisplay, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let pharmacy = state.provider_registry
+            .facility_internal_id(FacilityKind::Pharmacy, &caller)
+            .ok_or("pharmacy not found".to_string())?;
+
+        let code = PrescriptionCode::parse(&code)?;
+
+        state.emr_registry.dispense_prescription(&code, pharmacy, amount)
+    })
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+#[candid::candid_method(query)]
+/// laboratories lab orders can be routed to, as internal id and encrypted display name
+fn laboratories() -> Vec<(Id, String)> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        state.provider_registry.active_facilities(FacilityKind::Laboratory)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// order lab tests for an emr the caller can read, the order is routed to the chosen laboratory
+async fn create_lab_order(emr_id: Id, order: LabOrderInput) -> Result<LabOrderDisplay, String> {
+    let order_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = read_access(state, &caller, &emr_id)?.ok_or("provider not found".to_string())?;
+
+        if !state.provider_registry.is_active_facility_id(FacilityKind::Laboratory, &order.laboratory) {
+            return Err("laboratory not found".to_string());
+        }
+
+        state.emr_registry.create_lab_order(order_id, &emr_id, provider, order)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// cancel a pending lab order placed by the caller
+fn cancel_lab_order(order_id: Id) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry.internal_id(&caller).ok_or("provider not found".to_string())?;
+
+        state.emr_registry.cancel_lab_order(&order_id, &provider)
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider reads are recorded in the access history
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// lab orders placed against an emr, including their reported results
+fn lab_orders_of_emr(emr_id: Id) -> Result<Vec<LabOrderDisplay>, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if let Some(provider) = read_access(state, &caller, &emr_id)? {
+            state.emr_registry.record_access(&emr_id, &provider, AccessAction::Read)?;
+        }
+
+        Ok(state.emr_registry.lab_orders_of_emr(&emr_id))
+    })
+}
+
+#[ic_cdk::query(guard = "only_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// a lab order placed by the caller, visible even after the caller lost access to the emr
+fn lab_order(order_id: Id) -> Result<LabOrderDisplay, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry.internal_id(&caller).ok_or("provider not found".to_string())?;
+
+        state.emr_registry
+            .lab_order(&order_id)
+            .filter(|order| order.is_ordered_by(&provider))
+            .ok_or("lab order not found".to_string())
+    })
+}
+
+#[ic_cdk::query(guard = "only_laboratory")]
+#[candid::candid_method(query)]
+/// lab orders routed to the calling laboratory that are still waiting for results
+fn pending_lab_orders() -> Result<Vec<LabOrderDisplay>, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        let laboratory = state.provider_registry
+            .facility_internal_id(FacilityKind::Laboratory, &caller)
+            .ok_or("laboratory not found".to_string())?;
+
+        Ok(state.emr_registry.pending_lab_orders(&laboratory))
+    })
+}
+
+#[ic_cdk::update(guard = "only_laboratory")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// report the results of a lab order routed to the calling laboratory, the results are attached to the
+/// originating emr with the caller recorded as their author
+fn submit_lab_results(order_id: Id, results: Vec<LabResultInput>) -> Result<LabOrderDisplay, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let laboratory = state.provider_registry
+            .facility_internal_id(FacilityKind::Laboratory, &caller)
+            .ok_or("laboratory not found".to_string())?;
+
+        state.emr_registry.report_lab_results(&order_id, &laboratory, caller, results)
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider reads are recorded in the access history
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// active problems, allergies and current medications of a patient aggregated over every emr bound to them
+fn clinical_summary(patient: NIK) -> Result<ClinicalSummary, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if let Some(provider) = patient_access(state, &caller, &patient, DelegationScope::Read)? {
+            state.emr_registry.record_patient_access(&patient, &provider, AccessAction::Read)?;
+        }
+
+        Ok(state.emr_registry.clinical_summary(&patient))
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// record vital signs or other measurements of a patient taken by the caller
+fn record_vital_signs(patient: NIK, observations: Vec<VitalInput>) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = patient_access(state, &caller, &patient, DelegationScope::Manage)?.ok_or(
+            "provider not found".to_string()
+        )?;
+
+        state.emr_registry.record_vitals(&patient, ObservationSource::Provider(provider), observations)
+    })
+}
+
+#[ic_cdk::update(guard = "only_patients_or_guardians")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// submit home measurements, recorded as patient reported. callable by the patient or a guardian with
+/// [DelegationScope::Manage].
+fn submit_home_measurements(patient: NIK, observations: Vec<VitalInput>) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !can_act_for(state, &caller, &patient, DelegationScope::Manage) {
+            return Err("not allowed to submit measurements for this patient".to_string());
+        }
+
+        state.emr_registry.record_vitals(&patient, ObservationSource::Patient(caller), observations)
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider reads are recorded in the access history
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// measurements of a single kind of a patient within a time range, optionally downsampled
+fn vital_signs(patient: NIK, query: VitalsQuery) -> Result<VitalSeries, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if let Some(provider) = patient_access(state, &caller, &patient, DelegationScope::Read)? {
+            state.emr_registry.record_patient_access(&patient, &provider, AccessAction::Read)?;
+        }
+
+        state.emr_registry.vitals_of(&patient, &query)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// sign off a draft emr issued by the caller. final emrs can't be edited anymore, only amended with addenda.
+fn finalize_emr(emr_id: Id) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.provider_registry.is_issued_by(&caller, &emr_id) {
+            return Err("only the issuing provider can finalize this emr".to_string());
+        }
+
+        state.emr_registry.finalize_emr(&emr_id, caller)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// append an addendum to a final emr the caller issued or may write to, returns the record key the addendum was
+/// written to
+fn add_emr_addendum(emr_id: Id, text: String) -> Result<AsciiRecordsKey, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = write_access(state, &caller, &emr_id)?;
+
+        let key = state.emr_registry.add_addendum(&emr_id, text, caller)?;
+        state.emr_registry.record_access(&emr_id, &provider, AccessAction::Update)?;
+
+        Ok(key)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// mark an emr issued by the caller as entered in error, e.g. written for the wrong patient
+fn mark_emr_entered_in_error(emr_id: Id, reason: String) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.provider_registry.is_issued_by(&caller, &emr_id) {
+            return Err("only the issuing provider can mark this emr as entered in error".to_string());
+        }
+
+        state.emr_registry.mark_emr_entered_in_error(&emr_id, caller, reason)
+    })
+}
+
+#[ic_cdk::query(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// lifecycle state of an emr, who finalized it and when, and its addenda
+fn emr_lifecycle(emr_id: Id) -> Result<LifecycleDisplay, String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let state = state.as_ref().unwrap();
+
+        let caller = verified_caller()?;
+        read_access(state, &caller, &emr_id)?;
+
+        state.emr_registry.emr_lifecycle(&emr_id).ok_or("emr not found".to_string())
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// open an encounter of a patient attended by the caller, emrs written during the visit are linked to it.
+/// like [create_emr_for_user] any provider may open one, a first visit starts before the provider has any
+/// emr or grant for the patient.
+async fn open_encounter(patient: NIK, encounter: EncounterInput) -> Result<EncounterDisplay, String> {
+    let encounter_id = generate_id().await.map_err(|e| e.to_string())?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry.internal_id(&caller).ok_or("provider not found".to_string())?;
+
+        state.emr_registry.open_encounter(encounter_id, &patient, provider, encounter)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// close an encounter attended by the caller, `ended_at` defaults to now
+fn close_encounter(encounter_id: Id, ended_at: Option<Timestamp>) -> Result<EncounterDisplay, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+        let provider = state.provider_registry.internal_id(&caller).ok_or("provider not found".to_string())?;
+
+        state.emr_registry.close_encounter(&encounter_id, &provider, ended_at)
+    })
+}
+
+#[ic_cdk::update(guard = "only_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// link an emr issued by the caller to an encounter of the same patient, replacing its previous encounter
+fn link_emr_to_encounter(emr_id: Id, encounter_id: Id) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if !state.provider_registry.is_issued_by(&caller, &emr_id) {
+            return Err("only the issuing provider can link this emr".to_string());
+        }
+
+        state.emr_registry.link_emr_to_encounter(&emr_id, &encounter_id)
+    })
+}
+
+// update call for the same reason as [read_emr_by_id], provider reads are recorded in the access history
+#[ic_cdk::update(guard = "only_patients_guardians_or_provider")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// encounters of a patient in chronological order, with the ids of the emrs linked to each
+fn encounters_of_patient(patient: NIK) -> Result<Vec<EncounterDisplay>, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        let caller = verified_caller()?;
+
+        if let Some(provider) = patient_access(state, &caller, &patient, DelegationScope::Read)? {
+            state.emr_registry.record_patient_access(&patient, &provider, AccessAction::Read)?;
+        }
+
+        Ok(state.emr_registry.encounters_of(&patient))
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// load a chunk of a code table, call repeatedly to load a whole table. returns the number of loaded codes
+/// of the system.
+fn load_code_table_chunk(system: CodeSystem, entries: Vec<CodeEntry>) -> Result<u64, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.terminology.load(system, entries)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// remove a chunk of the codes of a system, call until it returns 0 to clear the whole table
+fn clear_code_table(system: CodeSystem) -> Result<u64, String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        state.terminology.clear(system)
+    })
+}
+
+#[ic_cdk::update(guard = "only_canister_owner")]
+#[candid::candid_method(update)]
+// TODO : move arguments to a candid struct
+/// declare the values of a record key as codes of a system, records written afterwards must use a loaded code.
+/// none removes the declaration.
+fn declare_coded_key(key: AsciiRecordsKey, system: Option<CodeSystem>) -> Result<(), String> {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let state = state.as_mut().unwrap();
+
+        Ok(state.terminology.declare(key, system)?)
+    })
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+fn coded_keys() -> Vec<(AsciiRecordsKey, CodeSystem)> {
+    STATE.with(|state| state.borrow().as_ref().unwrap().terminology.coded_keys())
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+/// number of loaded codes of every code system
+fn code_tables() -> Vec<(CodeSystem, u64)> {
+    STATE.with(|state| state.borrow().as_ref().unwrap().terminology.tables())
+}
+
+#[ic_cdk::query]
+#[candid::candid_method(query)]
+// TODO : move arguments to a candid struct
+/// display name of a code, e.g. to show a preview while a clinician types it
+fn lookup_code(system: CodeSystem, code: String) -> Option<String> {
+    STATE.with(|state| state.borrow().as_ref().unwrap().terminology.display(system, &code))
+}
+
 #[ic_cdk::query(name = "__get_candid_interface_tmp_hack")]
 fn export_candid() -> String {
     ic_cdk::export::candid::export_service!();
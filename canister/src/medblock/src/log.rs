@@ -1,9 +1,20 @@
-use crate::{ deref, types::{ Id, Timestamp } };
+use crate::{
+    deref,
+    emr::{
+        access::AccessScope,
+        delegation::DelegationScope,
+        patient::{ BindingTransition, NIK },
+        providers::InternalProviderId,
+        OutOfMemory,
+    },
+    types::{ Id, Timestamp },
+};
 use candid::{ CandidType, Principal };
 use ic_stable_memory::{
     collections::SLog as Log,
     derive::{ AsFixedSizeBytes, CandidAsDynSizeBytes, StableType },
     SBox,
+    StableType,
 };
 use serde::Deserialize;
 // TODO : rearrange this to session logs
@@ -19,16 +30,270 @@ impl RecordsV001 {
     }
 }
 
+/// kind of admin correction applied to patient bindings
+#[derive(CandidType, Debug, Deserialize, Clone)]
+pub enum BindingChangeKind {
+    /// every emr of one patient was merged into another
+    Merge,
+    /// a single emr was moved between patients
+    Move(Id),
+    /// an additional identifier was linked to a patient
+    Link,
+}
+
+/// admin correction of patient bindings, records the binding state before and after the change
+#[derive(CandidType, Debug, Deserialize)]
+pub struct BindingChangeV001 {
+    kind: BindingChangeKind,
+    admin: Principal,
+    reason: String,
+    transition: BindingTransition,
+}
+
+// heap only type, lives inside the [SBox] of [Entry]
+impl StableType for BindingChangeV001 {}
+
+impl BindingChangeV001 {
+    pub fn new(
+        kind: BindingChangeKind,
+        admin: Principal,
+        reason: String,
+        transition: BindingTransition
+    ) -> Self {
+        Self { kind, admin, reason, transition }
+    }
+}
+
+/// kind of change applied to a guardian delegation
+#[derive(CandidType, Debug, Deserialize, Clone)]
+pub enum DelegationChangeKind {
+    /// a provider requested a delegation, waiting for admin approval
+    Requested(DelegationScope),
+    Assigned(DelegationScope),
+    /// the admin rejected a provider request
+    Rejected,
+    Revoked,
+}
+
+/// guardian delegation assigned or revoked for a patient
+#[derive(CandidType, Debug, Deserialize)]
+pub struct DelegationChangeV001 {
+    kind: DelegationChangeKind,
+    patient: NIK,
+    guardian: Principal,
+    actor: Principal,
+    /// document reference giving a provider authority to assign the guardian
+    authority: Option<String>,
+}
+
+// heap only type, lives inside the [SBox] of [Entry]
+impl StableType for DelegationChangeV001 {}
+
+impl DelegationChangeV001 {
+    pub fn new(
+        kind: DelegationChangeKind,
+        patient: NIK,
+        guardian: Principal,
+        actor: Principal,
+        authority: Option<String>
+    ) -> Self {
+        Self { kind, patient, guardian, actor, authority }
+    }
+}
+
+/// emergency access of a provider to a patient's emrs without consent
+#[derive(CandidType, Debug, Deserialize)]
+pub struct BreakGlassV001 {
+    event_id: Id,
+    patient: NIK,
+    provider: InternalProviderId,
+    justification: String,
+    expires_at: Timestamp,
+}
+
+// heap only type, lives inside the [SBox] of [Entry]
+impl StableType for BreakGlassV001 {}
+
+impl BreakGlassV001 {
+    pub fn new(
+        event_id: Id,
+        patient: NIK,
+        provider: InternalProviderId,
+        justification: String,
+        expires_at: Timestamp
+    ) -> Self {
+        Self { event_id, patient, provider, justification, expires_at }
+    }
+}
+
+/// admin review of a break glass event
+#[derive(CandidType, Debug, Deserialize)]
+pub struct BreakGlassReviewV001 {
+    event_id: Id,
+    admin: Principal,
+    note: String,
+}
+
+// heap only type, lives inside the [SBox] of [Entry]
+impl StableType for BreakGlassReviewV001 {}
+
+impl BreakGlassReviewV001 {
+    pub fn new(event_id: Id, admin: Principal, note: String) -> Self {
+        Self { event_id, admin, note }
+    }
+}
+
+/// kind of change applied to a provider access grant
+#[derive(CandidType, Debug, Deserialize, Clone)]
+pub enum AccessChangeKind {
+    Granted {
+        scope: AccessScope,
+        expires_at: Timestamp,
+    },
+    Revoked,
+}
+
+/// provider access to a patient's emrs granted or revoked on the patient's behalf
+#[derive(CandidType, Debug, Deserialize)]
+pub struct AccessChangeV001 {
+    kind: AccessChangeKind,
+    patient: NIK,
+    provider: InternalProviderId,
+    actor: Principal,
+}
+
+// heap only type, lives inside the [SBox] of [Entry]
+impl StableType for AccessChangeV001 {}
+
+impl AccessChangeV001 {
+    pub fn new(
+        kind: AccessChangeKind,
+        patient: NIK,
+        provider: InternalProviderId,
+        actor: Principal
+    ) -> Self {
+        Self { kind, patient, provider, actor }
+    }
+}
+
+/// kind of change applied to an erasure request
+#[derive(CandidType, Debug, Deserialize, Clone)]
+pub enum ErasureChangeKind {
+    Requested,
+    Approved,
+    Rejected,
+}
+
+/// patient erasure request filed, approved or rejected
+#[derive(CandidType, Debug, Deserialize)]
+pub struct ErasureChangeV001 {
+    kind: ErasureChangeKind,
+    request_id: Id,
+    patient: NIK,
+    actor: Principal,
+    /// rejection reason given by the admin
+    reason: Option<String>,
+}
+
+// heap only type, lives inside the [SBox] of [Entry]
+impl StableType for ErasureChangeV001 {}
+
+impl ErasureChangeV001 {
+    pub fn new(
+        kind: ErasureChangeKind,
+        request_id: Id,
+        patient: NIK,
+        actor: Principal,
+        reason: Option<String>
+    ) -> Self {
+        Self { kind, request_id, patient, actor, reason }
+    }
+}
+
+/// emrs imported by a provider from a patient export bundle
+#[derive(CandidType, Debug, Deserialize)]
+pub struct PatientImportV001 {
+    patient: NIK,
+    provider: InternalProviderId,
+    /// patient identifier as written in the bundle
+    source_patient: String,
+    /// time the bundle reflects the source data as of
+    as_of: Timestamp,
+    /// emr id in the bundle and the id of the emr created from it
+    emrs: Vec<(Id, Id)>,
+}
+
+// heap only type, lives inside the [SBox] of [Entry]
+impl StableType for PatientImportV001 {}
+
+impl PatientImportV001 {
+    pub fn new(
+        patient: NIK,
+        provider: InternalProviderId,
+        source_patient: String,
+        as_of: Timestamp,
+        emrs: Vec<(Id, Id)>
+    ) -> Self {
+        Self { patient, provider, source_patient, as_of, emrs }
+    }
+}
+
+/// how important an entry is when reviewing the log
+#[derive(
+    StableType,
+    AsFixedSizeBytes,
+    CandidType,
+    Debug,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord
+)]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
 #[derive(StableType, CandidType, Debug, CandidAsDynSizeBytes, Deserialize)]
 #[non_exhaustive]
 pub enum EntryRecords {
     V001(RecordsV001),
+    BindingChange(BindingChangeV001),
+    DelegationChange(DelegationChangeV001),
+    BreakGlass(BreakGlassV001),
+    BreakGlassReview(BreakGlassReviewV001),
+    AccessChange(AccessChangeV001),
+    ErasureChange(ErasureChangeV001),
+    PatientImport(PatientImportV001),
+}
+
+impl EntryRecords {
+    pub fn severity(&self) -> Severity {
+        match self {
+            Self::V001(_) | Self::BreakGlassReview(_) => Severity::Low,
+            | Self::BindingChange(_)
+            | Self::DelegationChange(_)
+            | Self::AccessChange(_)
+            | Self::PatientImport(_) => Severity::Medium,
+            Self::ErasureChange(entry) =>
+                match entry.kind {
+                    ErasureChangeKind::Approved => Severity::High,
+                    _ => Severity::Medium,
+                }
+            Self::BreakGlass(_) => Severity::High,
+        }
+    }
 }
 
 #[derive(CandidType, StableType, Debug, AsFixedSizeBytes)]
 pub struct Entry {
     entry_id: Id,
     timestamp: Timestamp,
+    severity: Severity,
     records: SBox<EntryRecords>,
 }
 
@@ -37,6 +302,7 @@ impl Entry {
         Ok(Self {
             entry_id: id,
             timestamp: Timestamp::new(),
+            severity: entry.severity(),
             records: SBox::new(entry)?,
         })
     }
@@ -45,6 +311,15 @@ impl Entry {
 pub struct EntryLog(Log<Entry>);
 deref!(EntryLog: Log<Entry>);
 
+impl EntryLog {
+    /// append a new entry to the log, returns [OutOfMemory] if stable memory is exhausted
+    pub fn record(&mut self, entry: EntryRecords, id: Id) -> Result<(), OutOfMemory> {
+        let entry = Entry::new(entry, id)?;
+
+        self.0.push(entry).map_err(OutOfMemory::from)
+    }
+}
+
 impl Default for EntryLog {
     fn default() -> Self {
         Self(Log::new())
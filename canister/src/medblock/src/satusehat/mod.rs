@@ -0,0 +1,370 @@
+pub mod submission;
+
+use candid::CandidType;
+use serde::Deserialize;
+use serde_json::{ json, Value };
+use sha2::{ Digest, Sha256 };
+
+use crate::{ fhir::mapping::get_path, types::Id };
+
+/// SATUSEHAT identifiers of everything an encounter references. EMRs only know the hashed NIK of the patient,
+/// the facility resolves the IHS numbers from the real identifiers before requesting a payload.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SatusehatContext {
+    /// SATUSEHAT organization id of the facility
+    pub organization_id: String,
+    /// IHS number of the patient
+    pub patient_ihs_number: String,
+    pub patient_name: String,
+    /// IHS number of the attending practitioner
+    pub practitioner_ihs_number: String,
+    pub practitioner_name: String,
+    /// SATUSEHAT location id of the room or unit the encounter took place in
+    pub location_id: String,
+    pub location_name: String,
+}
+
+const ICD_10: &str = "http://hl7.org/fhir/sid/icd-10";
+const ENCOUNTER_IDENTIFIER_SYSTEM: &str = "http://sys-ids.kemkes.go.id/encounter";
+
+/// Builds SATUSEHAT transaction bundles from the generic FHIR rendering of an emr, see [crate::fhir::FhirRenderer].
+/// only the Encounter and its ICD-10 coded Conditions are reported.
+pub struct SatusehatPayload;
+
+impl SatusehatPayload {
+    pub fn build(emr_id: &Id, rendered: &Value, context: &SatusehatContext) -> Result<Value, String> {
+        let resources = rendered
+            .get("entry")
+            .and_then(Value::as_array)
+            .ok_or("rendered bundle has no entry".to_string())?
+            .iter()
+            .filter_map(|entry| entry.get("resource"))
+            .collect::<Vec<_>>();
+
+        let of_type = |resource_type: &'static str| {
+            resources
+                .iter()
+                .copied()
+                .filter(move |resource| resource.get("resourceType").and_then(Value::as_str) == Some(resource_type))
+        };
+
+        let encounter = of_type("Encounter").next().ok_or("rendered bundle has no encounter".to_string())?;
+
+        let encounter_url = format!("urn:uuid:{}", emr_id);
+        let subject = json!({
+            "reference": format!("Patient/{}", context.patient_ihs_number),
+            "display": context.patient_name,
+        });
+
+        let conditions = of_type("Condition")
+            .map(|condition| {
+                let key = condition.get("id").and_then(Value::as_str).unwrap_or_default();
+                let url = format!("urn:uuid:{}", derived_uuid(emr_id, key));
+
+                let codings = get_path(condition, "code.coding")
+                    .and_then(Value::as_array)
+                    .into_iter()
+                    .flatten()
+                    .filter(|coding| coding.get("system").and_then(Value::as_str) == Some(ICD_10))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                let mut code = json!({ "coding": codings });
+                if let Some(text) = get_path(condition, "code.text") {
+                    code["text"] = text.clone();
+                }
+
+                let resource =
+                    json!({
+                    "resourceType": "Condition",
+                    "clinicalStatus": { "coding": [{
+                        "system": "http://terminology.hl7.org/CodeSystem/condition-clinical",
+                        "code": "active",
+                        "display": "Active",
+                    }] },
+                    "category": [{ "coding": [{
+                        "system": "http://terminology.hl7.org/CodeSystem/condition-category",
+                        "code": "encounter-diagnosis",
+                        "display": "Encounter Diagnosis",
+                    }] }],
+                    "code": code,
+                    "subject": subject,
+                    "encounter": { "reference": encounter_url },
+                });
+
+                (url, resource)
+            })
+            .collect::<Vec<_>>();
+
+        let start = get_path(encounter, "period.start").cloned().unwrap_or(Value::Null);
+        let end = get_path(encounter, "meta.lastUpdated").cloned().unwrap_or(Value::Null);
+
+        let diagnosis = conditions
+            .iter()
+            .enumerate()
+            .map(|(rank, (url, resource))| {
+                json!({
+                    "condition": { "reference": url, "display": get_path(resource, "code.text") },
+                    "use": { "coding": [{
+                        "system": "http://terminology.hl7.org/CodeSystem/diagnosis-role",
+                        "code": "DD",
+                        "display": "Discharge diagnosis",
+                    }] },
+                    "rank": rank + 1,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut satusehat_encounter =
+            json!({
+            "resourceType": "Encounter",
+            "identifier": [{
+                "system": format!("{}/{}", ENCOUNTER_IDENTIFIER_SYSTEM, context.organization_id),
+                "value": emr_id.to_string(),
+            }],
+            "status": "finished",
+            "class": encounter.get("class"),
+            "subject": subject,
+            "participant": [{
+                "type": [{ "coding": [{
+                    "system": "http://terminology.hl7.org/CodeSystem/v3-ParticipationType",
+                    "code": "ATND",
+                    "display": "attender",
+                }] }],
+                "individual": {
+                    "reference": format!("Practitioner/{}", context.practitioner_ihs_number),
+                    "display": context.practitioner_name,
+                },
+            }],
+            "period": { "start": start, "end": end },
+            "location": [{ "location": {
+                "reference": format!("Location/{}", context.location_id),
+                "display": context.location_name,
+            } }],
+            "statusHistory": [
+                { "status": "arrived", "period": { "start": start, "end": end } },
+                { "status": "finished", "period": { "start": end, "end": end } },
+            ],
+            "serviceProvider": { "reference": format!("Organization/{}", context.organization_id) },
+        });
+
+        if !diagnosis.is_empty() {
+            satusehat_encounter["diagnosis"] = Value::Array(diagnosis);
+        }
+
+        if let Some(reason) = encounter.get("reasonCode") {
+            satusehat_encounter["reasonCode"] = reason.clone();
+        }
+
+        let entries = std::iter
+            ::once((encounter_url, satusehat_encounter))
+            .chain(conditions)
+            .map(|(url, resource)| {
+                let resource_type = resource["resourceType"].clone();
+                json!({
+                    "fullUrl": url,
+                    "resource": resource,
+                    "request": { "method": "POST", "url": resource_type },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        Ok(json!({ "resourceType": "Bundle", "type": "transaction", "entry": entries }))
+    }
+}
+
+/// stable uuid for a resource derived from an emr, so rebuilding the payload yields the same fullUrl
+fn derived_uuid(emr_id: &Id, key: &str) -> uuid::Uuid {
+    let digest = Sha256::digest(format!("{}/{}", emr_id, key));
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+
+    uuid::Builder::from_custom_bytes(bytes).into_uuid()
+}
+
+/// Local stand-in for the SATUSEHAT profile validation, checks the elements SATUSEHAT rejects submissions for.
+/// returns every violation found, not just the first one.
+pub fn validate(bundle: &Value) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    let mut check = |ok: bool, error: String| {
+        if !ok {
+            errors.push(error);
+        }
+    };
+
+    let text = |resource: &Value, path: &str| {
+        get_path(resource, path)
+            .and_then(Value::as_str)
+            .map(String::from)
+            .unwrap_or_default()
+    };
+
+    check(text(bundle, "resourceType") == "Bundle", "resourceType must be Bundle".to_string());
+    check(text(bundle, "type") == "transaction", "Bundle.type must be transaction".to_string());
+
+    let entries = bundle.get("entry").and_then(Value::as_array).cloned().unwrap_or_default();
+    let urls = entries
+        .iter()
+        .map(|entry| text(entry, "fullUrl"))
+        .collect::<Vec<_>>();
+
+    for (index, entry) in entries.iter().enumerate() {
+        let resource = entry.get("resource").cloned().unwrap_or(Value::Null);
+        let resource_type = text(&resource, "resourceType");
+        let at = |element: &str| format!("entry[{}].{}.{}", index, resource_type, element);
+
+        check(urls[index].starts_with("urn:uuid:"), format!("entry[{}].fullUrl must be a urn:uuid", index));
+        check(text(entry, "request.method") == "POST", format!("entry[{}].request.method must be POST", index));
+        check(text(entry, "request.url") == resource_type, format!("entry[{}].request.url must be {}", index, resource_type));
+        check(text(&resource, "subject.reference").starts_with("Patient/"), at("subject must reference a Patient"));
+
+        match resource_type.as_str() {
+            "Encounter" => {
+                check(
+                    text(&resource, "identifier.0.system").starts_with(ENCOUNTER_IDENTIFIER_SYSTEM),
+                    at("identifier must use the kemkes encounter system")
+                );
+                check(!text(&resource, "identifier.0.value").is_empty(), at("identifier.value is required"));
+                check(!text(&resource, "class.code").is_empty(), at("class is required"));
+                check(!text(&resource, "period.start").is_empty(), at("period.start is required"));
+                check(
+                    text(&resource, "participant.0.individual.reference").starts_with("Practitioner/"),
+                    at("participant must reference a Practitioner")
+                );
+                check(
+                    text(&resource, "location.0.location.reference").starts_with("Location/"),
+                    at("location must reference a Location")
+                );
+                check(
+                    text(&resource, "serviceProvider.reference").starts_with("Organization/"),
+                    at("serviceProvider must reference an Organization")
+                );
+                check(
+                    get_path(&resource, "statusHistory").and_then(Value::as_array).is_some_and(|h| !h.is_empty()),
+                    at("statusHistory is required")
+                );
+            }
+            "Condition" => {
+                check(!text(&resource, "clinicalStatus.coding.0.code").is_empty(), at("clinicalStatus is required"));
+                check(!text(&resource, "category.0.coding.0.code").is_empty(), at("category is required"));
+                check(
+                    text(&resource, "code.coding.0.system") == ICD_10 && !text(&resource, "code.coding.0.code").is_empty(),
+                    at("code must have an ICD-10 coding")
+                );
+                check(urls.contains(&text(&resource, "encounter.reference")), at("encounter must reference an entry in the bundle"));
+            }
+            other => check(false, format!("entry[{}] has unsupported resource type {}", index, other)),
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors),
+    }
+}
+
+/// render the validation errors as a single rejection reason
+pub fn describe(errors: &[String]) -> String {
+    format!("payload does not match the SATUSEHAT profile : {}", errors.join("; "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        emr::{ patient::NIK, DisplayV001, EmrDisplay, RecrodsDisplay },
+        fhir::{ mapping::{ Coding, FhirMapping, MappingRule, ResourceType }, FhirRenderer },
+        types::Timestamp,
+    };
+
+    fn emr_id() -> Id {
+        Id::from(uuid::Uuid::parse_str("018bd2a8-7c3e-7000-8000-000000000001").unwrap())
+    }
+
+    fn rendered(code: Option<Coding>) -> Value {
+        let records = json!({ "diagnosis": "Typhoid fever", "reason": "fever for three days" });
+        let emr = EmrDisplay::V001(
+            DisplayV001::with_timestamps(
+                emr_id(),
+                Timestamp(1_700_000_000_000_000_000),
+                Timestamp(1_700_003_600_000_000_000),
+                RecrodsDisplay(records)
+            )
+        );
+
+        let rules = vec![
+            MappingRule {
+                key: "diagnosis".to_string(),
+                resource: ResourceType::Condition,
+                path: "code.text".to_string(),
+                code,
+            },
+            MappingRule {
+                key: "reason".to_string(),
+                resource: ResourceType::Encounter,
+                path: "reasonCode.0.text".to_string(),
+                code: None,
+            }
+        ];
+
+        let mapping = FhirMapping::new(rules, false).unwrap();
+        let patient: NIK = "3fe93da886732fd563ba71f136f10dffc6a8955f911b36064b9e01b32f8af709".parse().unwrap();
+
+        FhirRenderer::new(&mapping).render(&emr, &patient, None)
+    }
+
+    fn context() -> SatusehatContext {
+        SatusehatContext {
+            organization_id: "10000004".to_string(),
+            patient_ihs_number: "P02478375538".to_string(),
+            patient_name: "Budi Santoso".to_string(),
+            practitioner_ihs_number: "N10000001".to_string(),
+            practitioner_name: "dr. Alexander".to_string(),
+            location_id: "b017aa54-f1df-4ec2-9d84-8823815d7228".to_string(),
+            location_name: "Ruang 1A, Poliklinik Umum".to_string(),
+        }
+    }
+
+    fn icd_10() -> Option<Coding> {
+        Some(Coding { system: ICD_10.to_string(), code: "A01.0".to_string(), display: Some("Typhoid fever".to_string()) })
+    }
+
+    #[test]
+    fn test_payload_matches_profile() {
+        let payload = SatusehatPayload::build(&emr_id(), &rendered(icd_10()), &context()).unwrap();
+        validate(&payload).unwrap();
+
+        let entries = payload["entry"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let encounter = &entries[0]["resource"];
+        assert_eq!(encounter["period"]["start"], "2023-11-14T22:13:20Z");
+        assert_eq!(encounter["period"]["end"], "2023-11-14T23:13:20Z");
+        assert_eq!(encounter["reasonCode"][0]["text"], "fever for three days");
+        assert_eq!(encounter["diagnosis"][0]["condition"]["reference"], entries[1]["fullUrl"]);
+
+        let condition = &entries[1]["resource"];
+        assert_eq!(condition["code"]["coding"][0]["code"], "A01.0");
+        assert_eq!(condition["encounter"]["reference"], entries[0]["fullUrl"]);
+
+        // rebuilding yields the same fullUrls, so a retried submission references the same resources
+        let again = SatusehatPayload::build(&emr_id(), &rendered(icd_10()), &context()).unwrap();
+        assert_eq!(again, payload);
+    }
+
+    #[test]
+    fn test_condition_without_icd_10_is_rejected() {
+        let snomed = Coding { system: "http://snomed.info/sct".to_string(), code: "4834000".to_string(), display: None };
+        let payload = SatusehatPayload::build(&emr_id(), &rendered(Some(snomed)), &context()).unwrap();
+
+        let errors = validate(&payload).unwrap_err();
+        assert_eq!(errors, vec!["entry[1].Condition.code must have an ICD-10 coding".to_string()]);
+
+        let mut missing_location = context();
+        missing_location.location_id = String::new();
+        let mut payload = SatusehatPayload::build(&emr_id(), &rendered(icd_10()), &missing_location).unwrap();
+        payload["entry"][0]["resource"]["location"] = json!([]);
+
+        assert!(validate(&payload).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use candid::CandidType;
+use ic_stable_memory::{
+    collections::SBTreeMap,
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::Deserialize;
+
+use crate::{
+    emr::{ providers::InternalProviderId, OutOfMemory },
+    types::{ Id, Timestamp },
+};
+
+type EmrId = Id;
+
+#[derive(StableType, AsFixedSizeBytes, CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmissionState {
+    /// payload was handed to the provider, waiting for the provider to report the SATUSEHAT response
+    Pending,
+    /// accepted by SATUSEHAT
+    Submitted,
+    /// rejected by SATUSEHAT or by the local profile validation
+    Rejected,
+}
+
+/// outcome of a submission as reported by the provider
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum SubmissionResult {
+    /// accepted, carries the SATUSEHAT encounter id
+    Submitted(String),
+    /// rejected, carries the reason returned by SATUSEHAT
+    Rejected(String),
+}
+
+/// submission status of a single emr
+#[derive(StableType, AsFixedSizeBytes, Debug)]
+pub struct Submission {
+    state: SubmissionState,
+    provider: InternalProviderId,
+    updated_at: Timestamp,
+    attempts: u32,
+    /// SATUSEHAT encounter id when submitted, rejection reason when rejected
+    detail: Option<SBox<String>>,
+}
+
+/// heap copy of a [Submission]
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SubmissionDisplay {
+    emr_id: EmrId,
+    state: SubmissionState,
+    provider: InternalProviderId,
+    updated_at: Timestamp,
+    attempts: u32,
+    detail: Option<String>,
+}
+
+impl SubmissionDisplay {
+    pub fn new(emr_id: EmrId, submission: &Submission) -> Self {
+        Self {
+            emr_id,
+            state: submission.state,
+            provider: submission.provider.clone(),
+            updated_at: submission.updated_at,
+            attempts: submission.attempts,
+            detail: submission.detail.as_ref().map(|detail| (**detail).clone()),
+        }
+    }
+}
+
+/// SATUSEHAT submission status of every emr a payload was produced for.
+#[derive(Default)]
+pub struct Submissions(SBTreeMap<EmrId, Submission>);
+
+impl Submissions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// mark `emr_id` as pending after a payload was produced for it. resubmitting a rejected or still pending
+    /// emr is allowed, an emr already accepted by SATUSEHAT is not submitted twice.
+    pub fn prepare(&mut self, emr_id: EmrId, provider: InternalProviderId) -> Result<(), String> {
+        if let Some(mut submission) = self.0.get_mut(&emr_id) {
+            if submission.state == SubmissionState::Submitted {
+                return Err("emr is already submitted to SATUSEHAT".to_string());
+            }
+
+            submission.state = SubmissionState::Pending;
+            submission.provider = provider;
+            submission.updated_at = Timestamp::new();
+            submission.attempts += 1;
+            submission.detail = None;
+
+            return Ok(());
+        }
+
+        let submission = Submission {
+            state: SubmissionState::Pending,
+            provider,
+            updated_at: Timestamp::new(),
+            attempts: 1,
+            detail: None,
+        };
+
+        Ok(
+            self.0
+                .insert(emr_id, submission)
+                .map_err(OutOfMemory::from)
+                .map(|_| ())?
+        )
+    }
+
+    /// record the SATUSEHAT response of a pending submission
+    pub fn report(&mut self, emr_id: &EmrId, result: SubmissionResult) -> Result<(), String> {
+        let Some(mut submission) = self.0.get_mut(emr_id) else {
+            return Err("no SATUSEHAT submission for this emr".to_string());
+        };
+
+        if submission.state != SubmissionState::Pending {
+            return Err("SATUSEHAT submission is not pending".to_string());
+        }
+
+        let (state, detail) = match result {
+            SubmissionResult::Submitted(encounter_id) => (SubmissionState::Submitted, encounter_id),
+            SubmissionResult::Rejected(reason) => (SubmissionState::Rejected, reason),
+        };
+
+        submission.detail = Some(SBox::new(detail).map_err(OutOfMemory::from)?);
+        submission.state = state;
+        submission.updated_at = Timestamp::new();
+
+        Ok(())
+    }
+
+    /// record a payload that failed the local profile validation, so it never leaves pending without a reason
+    pub fn reject(&mut self, emr_id: &EmrId, reason: String) -> Result<(), String> {
+        self.report(emr_id, SubmissionResult::Rejected(reason))
+    }
+
+    pub fn status(&self, emr_id: &EmrId) -> Option<SubmissionDisplay> {
+        self.0.get(emr_id).map(|submission| SubmissionDisplay::new(emr_id.clone(), &submission))
+    }
+
+    /// drop the submission of an erased emr
+    pub fn remove_emr(&mut self, emr_id: &EmrId) {
+        self.0.remove(emr_id);
+    }
+
+    /// returns every submission of `provider` still waiting for a SATUSEHAT response
+    pub fn pending_of(&self, provider: &InternalProviderId) -> Vec<SubmissionDisplay> {
+        self.0
+            .iter()
+            .filter(|(_, submission)| {
+                submission.state == SubmissionState::Pending && submission.provider.eq(provider)
+            })
+            .map(|(emr_id, submission)| SubmissionDisplay::new((*emr_id).clone(), &submission))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::fixtures::id;
+
+    #[test]
+    fn test_submission_transitions() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut submissions = Submissions::new();
+        let provider = id(9);
+
+        assert!(submissions.report(&id(1), SubmissionResult::Submitted("enc-1".to_string())).is_err());
+
+        submissions.prepare(id(1), provider.clone()).unwrap();
+        submissions.prepare(id(2), provider.clone()).unwrap();
+        assert_eq!(submissions.pending_of(&provider).len(), 2);
+
+        submissions.reject(&id(1), "Condition.code must have an ICD-10 coding".to_string()).unwrap();
+        assert_eq!(submissions.status(&id(1)).unwrap().state, SubmissionState::Rejected);
+        assert!(submissions.report(&id(1), SubmissionResult::Submitted("enc-1".to_string())).is_err());
+
+        // resubmitting after a rejection starts a new attempt
+        submissions.prepare(id(1), provider.clone()).unwrap();
+        submissions.report(&id(1), SubmissionResult::Submitted("enc-1".to_string())).unwrap();
+
+        let status = submissions.status(&id(1)).unwrap();
+        assert_eq!(status.state, SubmissionState::Submitted);
+        assert_eq!(status.attempts, 2);
+        assert_eq!(status.detail.as_deref(), Some("enc-1"));
+
+        assert!(submissions.prepare(id(1), provider.clone()).is_err());
+        assert_eq!(submissions.pending_of(&provider).len(), 1);
+    }
+}
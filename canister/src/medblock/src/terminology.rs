@@ -0,0 +1,298 @@
+use candid::CandidType;
+use ic_stable_memory::{
+    collections::SBTreeMap,
+    derive::{ AsFixedSizeBytes, StableType },
+    SBox,
+};
+use serde::{ Deserialize, Serialize };
+use serde_json::Value;
+
+use crate::{ emr::OutOfMemory, types::AsciiRecordsKey };
+
+/// max entries loaded or cleared in a single call, keeps every call well within the instruction limit
+const MAX_CHUNK: usize = 2000;
+
+/// longest code of the supported systems is an ICD-10 code with extensions, e.g. `S52.521A`
+const MAX_CODE_LEN: usize = 16;
+
+const MAX_DISPLAY_LEN: usize = 256;
+
+#[derive(
+    StableType,
+    AsFixedSizeBytes,
+    CandidType,
+    Deserialize,
+    Serialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Ord,
+    PartialOrd
+)]
+pub enum CodeSystem {
+    Icd10,
+    Loinc,
+    Atc,
+}
+
+impl CodeSystem {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Icd10 => "ICD-10",
+            Self::Loinc => "LOINC",
+            Self::Atc => "ATC",
+        }
+    }
+
+    /// codes are compared case insensitively, clinicians type `a01.0` as often as `A01.0`
+    fn normalize(code: &str) -> String {
+        code.trim().to_ascii_uppercase()
+    }
+}
+
+/// a code with its display name, as loaded by admins
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CodeEntry {
+    pub code: String,
+    pub display: String,
+}
+
+/// value of a coded record resolved against its code table, returned alongside the records on reads
+#[derive(CandidType, Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct CodedRecord {
+    pub key: String,
+    pub system: CodeSystem,
+    pub code: String,
+    /// none if the code was removed from the table after the record was written
+    pub display: Option<String>,
+}
+
+#[derive(StableType, AsFixedSizeBytes, Eq, PartialEq, Ord, PartialOrd, Clone, Debug)]
+struct CodeKey {
+    system: CodeSystem,
+    code: [u8; MAX_CODE_LEN],
+    len: u8,
+}
+
+impl CodeKey {
+    fn new(system: CodeSystem, code: &str) -> Result<Self, String> {
+        let code = CodeSystem::normalize(code);
+
+        if code.is_empty() || code.len() > MAX_CODE_LEN || !code.is_ascii() {
+            return Err(format!("{} code must be 1 to {} ascii characters", system.name(), MAX_CODE_LEN));
+        }
+
+        let mut bytes = [0u8; MAX_CODE_LEN];
+        bytes[..code.len()].copy_from_slice(code.as_bytes());
+
+        Ok(Self { system, code: bytes, len: code.len() as u8 })
+    }
+}
+
+/// Code tables of the supported code systems and the record keys whose values must be a code of one of them.
+/// a declaration for `diagnosis` also covers `diagnosis.secondary` and any other key with the same prefix.
+#[derive(Default)]
+pub struct Terminology {
+    codes: SBTreeMap<CodeKey, SBox<String>>,
+    /// number of codes loaded per system
+    sizes: SBTreeMap<CodeSystem, u64>,
+    coded_keys: SBTreeMap<AsciiRecordsKey, CodeSystem>,
+}
+
+impl Terminology {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn size(&self, system: CodeSystem) -> u64 {
+        self.sizes.get(&system).map(|size| *size).unwrap_or_default()
+    }
+
+    fn set_size(&mut self, system: CodeSystem, size: u64) -> Result<(), OutOfMemory> {
+        self.sizes.insert(system, size).map_err(OutOfMemory::from)?;
+
+        Ok(())
+    }
+
+    /// load a chunk of a code table, existing codes get their display name replaced.
+    /// returns the number of codes of the system after loading the chunk.
+    pub fn load(&mut self, system: CodeSystem, entries: Vec<CodeEntry>) -> Result<u64, String> {
+        if entries.is_empty() || entries.len() > MAX_CHUNK {
+            return Err(format!("a chunk must contain between 1 and {} codes", MAX_CHUNK));
+        }
+
+        // validate the whole chunk first so a rejected chunk can simply be fixed and sent again
+        let entries = entries
+            .into_iter()
+            .map(|entry| {
+                let display = entry.display.trim().to_string();
+
+                if display.is_empty() || display.len() > MAX_DISPLAY_LEN {
+                    return Err(format!("display of {} must be between 1 and {} characters", entry.code, MAX_DISPLAY_LEN));
+                }
+
+                Ok((CodeKey::new(system, &entry.code)?, display))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let mut size = self.size(system);
+
+        for (key, display) in entries {
+            let display = SBox::new(display).map_err(OutOfMemory::from)?;
+
+            if self.codes.insert(key, display).map_err(OutOfMemory::from)?.is_none() {
+                size += 1;
+            }
+        }
+
+        self.set_size(system, size)?;
+
+        Ok(size)
+    }
+
+    /// remove up to a chunk of codes of a system, e.g. before loading a new edition of its table.
+    /// returns the number of codes left.
+    pub fn clear(&mut self, system: CodeSystem) -> Result<u64, String> {
+        let keys = self.codes
+            .iter()
+            .filter(|(key, _)| key.system == system)
+            .take(MAX_CHUNK)
+            .map(|(key, _)| (*key).clone())
+            .collect::<Vec<_>>();
+
+        for key in keys.iter() {
+            self.codes.remove(key);
+        }
+
+        let size = self.size(system).saturating_sub(keys.len() as u64);
+        self.set_size(system, size)?;
+
+        Ok(size)
+    }
+
+    /// number of loaded codes of every system
+    pub fn tables(&self) -> Vec<(CodeSystem, u64)> {
+        [CodeSystem::Icd10, CodeSystem::Loinc, CodeSystem::Atc]
+            .into_iter()
+            .map(|system| (system, self.size(system)))
+            .collect()
+    }
+
+    pub fn display(&self, system: CodeSystem, code: &str) -> Option<String> {
+        let key = CodeKey::new(system, code).ok()?;
+
+        self.codes.get(&key).map(|display| (**display).clone())
+    }
+
+    /// declare the values of `key` as codes of `system`, or remove the declaration if `system` is none.
+    /// records written before the declaration are not checked.
+    pub fn declare(&mut self, key: AsciiRecordsKey, system: Option<CodeSystem>) -> Result<(), OutOfMemory> {
+        match system {
+            Some(system) => {
+                self.coded_keys.insert(key, system).map_err(OutOfMemory::from)?;
+            }
+            None => {
+                self.coded_keys.remove(&key);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn coded_keys(&self) -> Vec<(AsciiRecordsKey, CodeSystem)> {
+        self.coded_keys
+            .iter()
+            .map(|(key, system)| ((*key).clone(), *system))
+            .collect()
+    }
+
+    /// code system of a record key, declared for the key itself or for its prefix before the first `.`
+    pub fn system_of(&self, key: &str) -> Option<CodeSystem> {
+        let prefix = key.split('.').next().unwrap_or_default();
+
+        [key, prefix]
+            .into_iter()
+            .filter_map(|candidate| AsciiRecordsKey::new(candidate).ok())
+            .find_map(|candidate| self.coded_keys.get(&candidate).map(|system| *system))
+    }
+
+    /// check the value of a coded record is a known code of its system, values of other keys are not checked
+    pub fn validate(&self, key: &str, value: &str) -> Result<(), String> {
+        let Some(system) = self.system_of(key) else {
+            return Ok(());
+        };
+
+        match self.display(system, value) {
+            Some(_) => Ok(()),
+            None => Err(format!("{} is not a known {} code for {}", value.trim(), system.name(), key)),
+        }
+    }
+
+    /// resolve every coded record of an emr, `records` as returned by [crate::emr::EmrDisplay::records]
+    pub fn codings(&self, records: &Value) -> Vec<CodedRecord> {
+        let Value::Object(records) = records else {
+            return vec![];
+        };
+
+        records
+            .iter()
+            .filter_map(|(key, value)| {
+                let system = self.system_of(key)?;
+                let code = CodeSystem::normalize(value.as_str()?);
+
+                Some(CodedRecord {
+                    key: key.clone(),
+                    system,
+                    display: self.display(system, &code),
+                    code,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(code: &str, display: &str) -> CodeEntry {
+        CodeEntry { code: code.to_string(), display: display.to_string() }
+    }
+
+    #[test]
+    fn test_coded_records() {
+        ic_stable_memory::stable_memory_init();
+
+        let mut terminology = Terminology::new();
+
+        assert_eq!(terminology.load(CodeSystem::Icd10, vec![entry("A01.0", "Typhoid fever")]).unwrap(), 1);
+        assert_eq!(
+            terminology.load(CodeSystem::Icd10, vec![entry("a01.0", "Typhoid fever"), entry("A90", "Dengue fever")]).unwrap(),
+            2
+        );
+        assert!(terminology.load(CodeSystem::Loinc, vec![entry("2345-7", "")]).is_err());
+
+        terminology.declare(AsciiRecordsKey::new("diagnosis").unwrap(), Some(CodeSystem::Icd10)).unwrap();
+
+        // the declaration covers keys sharing its prefix, other keys stay free text
+        assert!(terminology.validate("diagnosis", "a90").is_ok());
+        assert!(terminology.validate("diagnosis.secondary", "Typhoid").is_err());
+        assert!(terminology.validate("note", "Typhoid").is_ok());
+
+        let records = serde_json::json!({ "diagnosis": "A01.0", "note": "rest" });
+        assert_eq!(
+            terminology.codings(&records),
+            vec![CodedRecord {
+                key: "diagnosis".to_string(),
+                system: CodeSystem::Icd10,
+                code: "A01.0".to_string(),
+                display: Some("Typhoid fever".to_string()),
+            }]
+        );
+
+        assert_eq!(terminology.clear(CodeSystem::Icd10).unwrap(), 0);
+        assert!(terminology.validate("diagnosis", "A90").is_err());
+    }
+}
@@ -7,6 +7,9 @@ use crate::{ deref, random::CanisterRandomSource };
 use serde::{ Deserialize, Serialize };
 use uuid::Uuid;
 
+/// approximate length of a year in nanoseconds (365.2425 days)
+pub const YEAR_IN_NANOS: u64 = 31_556_952_000_000_000;
+
 /// timestamp in nanoseconds
 #[derive(
     CandidType,
@@ -156,6 +159,19 @@ impl Id {
     }
 }
 
+/// labels the emr in the certified data tree, see [crate::emr::certification]
+impl ic_stable_memory::AsHashableBytes for Id {
+    fn as_hashable_bytes(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+impl std::fmt::Display for Id {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Uuid::from_bytes_ref(&self.0).hyphenated().fmt(f)
+    }
+}
+
 impl From<Uuid> for Id {
     fn from(value: Uuid) -> Self {
         Self(value.into_bytes())
@@ -232,7 +248,24 @@ mod deserialize {
 
     impl<'de> serde::Serialize for Id {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: serde::Serializer {
-            Uuid::from_bytes_ref(&self.0).serialize(serializer)
+            // always serialize as string, binary formats would otherwise get raw bytes that [Deserialize] rejects
+            serializer.collect_str(&Uuid::from_bytes_ref(&self.0).hyphenated())
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::Id;
+    use crate::emr::patient::{ IdentifierType, NIK };
+
+    /// deterministic id built from a single repeated byte
+    pub fn id(byte: u8) -> Id {
+        Id::from(uuid::Uuid::from_bytes([byte; 16]))
+    }
+
+    /// deterministic nik patient identifier built from a single repeated byte
+    pub fn nik(byte: u8) -> NIK {
+        NIK::new(IdentifierType::Nik, [byte; 32])
+    }
+}